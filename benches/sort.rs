@@ -88,9 +88,9 @@ async fn main() {
 
     let context = setup().await;
 
-    let subgroup_size = guess_workgroup_size(&context.device, &context.queue).await.expect("could not find a valid subgroup size");
+    let subgroup_size = guess_workgroup_size(&context.device, &context.queue).await.best().expect("could not find a valid subgroup size");
 
-    let sorter = GPUSorter::new(&context.device, subgroup_size);
+    let mut sorter = GPUSorter::new(&context.device, subgroup_size);
 
 
     for n in [10_000,100_000,1_000_000,8_000_000,20_000_000]{
@@ -98,5 +98,18 @@ async fn main() {
         let d = sort(&context,&sorter, &buffers,n,10000).await;
         println!("{n}: {d:?}");
     }
+
+    // time of every phase of a single sort
+    sorter.enable_profiling(&context.device);
+    for n in [10_000,100_000,1_000_000,8_000_000,20_000_000]{
+        let buffers = sorter.create_sort_buffers(&context.device, NonZeroU32::new(n).unwrap());
+        let mut encoder = context.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: None,
+        });
+        sorter.sort(&mut encoder,&context.queue,&buffers,Some(n));
+        context.queue.submit([encoder.finish()]);
+        let timings = sorter.read_timings(&context.device, &context.queue).await.unwrap();
+        println!("{n}: {timings:?}");
+    }
   }
   
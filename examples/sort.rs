@@ -23,7 +23,7 @@ async fn main(){
         )
         .await
         .unwrap();
-    let subgroup_size = guess_workgroup_size(&device, &queue).await.expect("could not find a valid subgroup size");
+    let subgroup_size = guess_workgroup_size(&device, &queue).await.best().expect("could not find a valid subgroup size");
     println!("using subgroup size {subgroup_size}");
     let sorter = GPUSorter::new(&device, subgroup_size);
 
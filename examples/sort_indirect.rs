@@ -1,10 +1,9 @@
 // this example creates an array with 10 key-value (f32,u32) pairs and sorts them on the gpu
-// Important: sorting by f32 keys only works for non negative key values. Also NaN and inf values give unexpected results
 use std::num::NonZeroU32;
 
 use bytemuck::bytes_of;
 use wgpu::util::DeviceExt;
-use wgpu_sort::{utils::{download_buffer, guess_workgroup_size, upload_to_buffer}, GPUSorter, HISTO_BLOCK_KVS};
+use wgpu_sort::{utils::guess_workgroup_size, GPUSorter, HISTO_BLOCK_KVS};
 
 
 #[pollster::main]
@@ -26,15 +25,15 @@ async fn main(){
         )
         .await
         .unwrap();
-    let subgroup_size = guess_workgroup_size(&device, &queue).await.expect("could not find a valid subgroup size");
+    let subgroup_size = guess_workgroup_size(&device, &queue).await.best().expect("could not find a valid subgroup size");
     println!("using subgroup size {subgroup_size}");
     let sorter = GPUSorter::new(&device, subgroup_size);
 
     let n = 10;
-    let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+    let sort_buffers = sorter.create_typed_sort_buffers::<f32, u32>(&device, NonZeroU32::new(n).unwrap());
 
 
-    let keys_scrambled: Vec<f32> = (1..=n).map(|v| 1./v as f32).collect();
+    let keys_scrambled: Vec<f32> = (1..=n).map(|v| if v % 2 == 0 { 1. / v as f32 } else { -1. / v as f32 }).collect();
     let values_scrambled:Vec<u32> = (1..=n).collect();
 
 
@@ -42,18 +41,8 @@ async fn main(){
         label: Some("GPURSSorter test_sort"),
     });
 
-    upload_to_buffer(
-        &mut encoder,
-        &sort_buffers.keys(),
-        &device,
-        keys_scrambled.as_slice(),
-    );
-    upload_to_buffer(
-        &mut encoder,
-        &sort_buffers.values(),
-        &device,
-        values_scrambled.as_slice(),
-    );
+    sort_buffers.write_keys(&queue, &keys_scrambled);
+    sort_buffers.write_values(&queue, &values_scrambled);
 
     println!("before: {:?}",keys_scrambled.iter().zip(values_scrambled.iter()).collect::<Vec<(_,_)>>());
    
@@ -81,21 +70,9 @@ async fn main(){
     device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
 
     // keys buffer has padding at the end
-    // so we only download the "valid" data
-    let keys_sorted = download_buffer::<f32>(
-        &sort_buffers.keys(),
-        &device,
-        &queue,
-        0..sort_buffers.keys_valid_size()
-    )
-    .await;
-    let value_sorted = download_buffer::<u32>(
-        &sort_buffers.values(),
-        &device,
-        &queue,
-        ..
-    )
-    .await;
+    // read_keys only downloads the "valid" data
+    let keys_sorted = sort_buffers.read_keys(&device, &queue).await;
+    let value_sorted = sort_buffers.read_values(&device, &queue).await;
 
     println!("after: {:?}",keys_sorted.iter().zip(value_sorted.iter()).collect::<Vec<(_,_)>>());
 }
@@ -0,0 +1,482 @@
+/*
+    Out-of-core sorting for key-value pairs that do not fit into a single storage buffer binding.
+
+    The pairs are split into chunks that are sorted independently by the radix sort.
+    Afterwards the sorted runs are merged on the gpu: every key computes its position in the
+    merged sequence (its rank) by binary searching all other chunks. Finally all pairs are scattered
+    into output chunks according to their rank.
+    Ties between chunks are broken by the chunk index, so the merge is stable.
+    Every chunk fills its own storage buffer binding, so every (source chunk, other chunk) pair needs
+    its own bind group and dispatch. The infos of all pairs are stored in a single buffer,
+    every bind group binds the entry of its pair.
+
+    The merge shaders can be found in merge.wgsl
+*/
+
+use std::{
+    mem,
+    num::{NonZeroU32, NonZeroU64},
+};
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label, storage_layout_entry, GPUSorter, SortBuffers, BYTES_PER_PAYLOAD_ELEM,
+    MAX_WORKGROUPS, RS_KEYVAL_SIZE,
+};
+
+/// workgroup size of the merge shaders
+const MERGE_WG_SIZE: u32 = 256;
+
+/// Sorting pipeline for key-value pairs that are stored in multiple chunks ([ChunkedSortBuffers]).
+///
+/// Every chunk is sorted with a [GPUSorter]. The sorted chunks are then merged into one globally sorted sequence.
+pub struct ChunkedSorter {
+    sorter: GPUSorter,
+    rank_p: wgpu::ComputePipeline,
+    scatter_p: wgpu::ComputePipeline,
+}
+
+impl ChunkedSorter {
+    pub fn new(device: &wgpu::Device, subgroup_size: u32) -> Self {
+        Self::create(device, GPUSorter::new(device, subgroup_size))
+    }
+
+    /// Creates a chunked sorter with a name that is added to the labels of all its pipelines,
+    /// buffers, bind groups and passes, see [GPUSorter::new_named].
+    pub fn new_named(device: &wgpu::Device, subgroup_size: u32, name: &str) -> Self {
+        Self::create(device, GPUSorter::new_named(device, subgroup_size, name))
+    }
+
+    fn create(device: &wgpu::Device, sorter: GPUSorter) -> Self {
+        let label = |label: &str| named_label(sorter.name(), label);
+
+        let raw_shader: &str = include_str!("merge.wgsl");
+        let shader_code = format!("const merge_wg_size: u32 = {:}u;\n{:}", MERGE_WG_SIZE, raw_shader);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&label("Merge shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+
+        let rank_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&label("merge rank pipeline layout")),
+            bind_group_layouts: &[&Self::rank_bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let rank_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(&label("calculate_ranks")),
+            layout: Some(&rank_layout),
+            module: &shader,
+            entry_point: "calculate_ranks",
+            compilation_options: Default::default(),
+        });
+
+        let scatter_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&label("merge scatter pipeline layout")),
+            bind_group_layouts: &[&Self::scatter_bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let scatter_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(&label("scatter_ranked")),
+            layout: Some(&scatter_layout),
+            module: &shader,
+            entry_point: "scatter_ranked",
+            compilation_options: Default::default(),
+        });
+
+        Self {
+            sorter,
+            rank_p,
+            scatter_p,
+        }
+    }
+
+    /// The sorter used to sort the individual chunks
+    pub fn sorter(&self) -> &GPUSorter {
+        &self.sorter
+    }
+
+    /// Largest chunk length whose buffers fit into a single storage buffer binding
+    pub fn max_chunk_len(&self, limits: &wgpu::Limits) -> u32 {
+        // the radix sort keys buffers reserve RS_KEYVAL_SIZE words per key
+        let max_bytes = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        let max_elements = (max_bytes / (BYTES_PER_PAYLOAD_ELEM * RS_KEYVAL_SIZE) as u64) as u32;
+        // the keys buffer is padded to a multiple of the histogram block of the sorter
+        max_elements - max_elements % self.sorter.histo_block_kvs()
+    }
+
+    fn rank_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("merge rank bind group layout"),
+            entries: &[
+                info_layout_entry(0),
+                storage_layout_entry(1, true),
+                storage_layout_entry(2, true),
+                storage_layout_entry(3, false),
+            ],
+        })
+    }
+
+    fn scatter_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("merge scatter bind group layout"),
+            entries: &[
+                info_layout_entry(0),
+                storage_layout_entry(1, true),
+                storage_layout_entry(4, true),
+                storage_layout_entry(5, true),
+                storage_layout_entry(6, false),
+                storage_layout_entry(7, false),
+            ],
+        })
+    }
+
+    /// Writes sort commands to command encoder.
+    ///
+    /// All chunks are sorted individually and then merged into the output chunks
+    /// ([ChunkedSortBuffers::output_keys] and [ChunkedSortBuffers::output_values]).
+    ///
+    /// **IMPORTANT**: the keys buffers of the input chunks are sorted in place and their padding is overwritten
+    pub fn sort(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &ChunkedSortBuffers,
+    ) {
+        for chunk in sort_buffers.chunks.iter() {
+            self.sorter.sort(encoder, queue, chunk, None);
+        }
+        self.record_ranks(sort_buffers, encoder);
+        self.record_scatter(sort_buffers, encoder);
+    }
+
+    fn record_ranks(&self, sort_buffers: &ChunkedSortBuffers, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&named_label(self.sorter.name(), "merge calculate ranks")),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.rank_p);
+        for (i, chunk) in sort_buffers.chunks.iter().enumerate() {
+            // the first bind group of every chunk initializes the ranks and has to be dispatched first
+            for bind_group in sort_buffers.rank_bind_groups[i].iter() {
+                pass.set_bind_group(0, bind_group, &[]);
+                pass.dispatch_workgroups(merge_workgroups(chunk.len()), 1, 1);
+            }
+        }
+    }
+
+    fn record_scatter(&self, sort_buffers: &ChunkedSortBuffers, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&named_label(self.sorter.name(), "merge scatter keyvals")),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.scatter_p);
+        for (i, chunk) in sort_buffers.chunks.iter().enumerate() {
+            for bind_group in sort_buffers.scatter_bind_groups[i].iter() {
+                pass.set_bind_group(0, bind_group, &[]);
+                pass.dispatch_workgroups(merge_workgroups(chunk.len()), 1, 1);
+            }
+        }
+    }
+
+    /// creates all buffers necessary for sorting `length` key-value pairs in chunks of `chunk_len`
+    pub fn create_sort_buffers(
+        &self,
+        device: &wgpu::Device,
+        length: NonZeroU32,
+        chunk_len: NonZeroU32,
+    ) -> ChunkedSortBuffers {
+        let max_chunk_len = self.max_chunk_len(&device.limits());
+        assert!(
+            chunk_len.get() <= max_chunk_len,
+            "chunk length {} exceeds the maximum chunk length {} of this device",
+            chunk_len,
+            max_chunk_len
+        );
+        let length = length.get();
+        let chunk_len = chunk_len.get().min(length);
+        let num_chunks = length.div_ceil(chunk_len);
+        let chunk_lengths: Vec<u32> = (0..num_chunks)
+            .map(|i| chunk_len.min(length - i * chunk_len))
+            .collect();
+
+        let chunks: Vec<SortBuffers> = chunk_lengths
+            .iter()
+            .map(|l| self.sorter.create_sort_buffers(device, NonZeroU32::new(*l).unwrap()))
+            .collect();
+
+        let ranks: Vec<wgpu::Buffer> = chunk_lengths
+            .iter()
+            .map(|l| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(&named_label(self.sorter.name(), "merge ranks buffer")),
+                    size: (l * BYTES_PER_PAYLOAD_ELEM) as u64,
+                    usage: wgpu::BufferUsages::STORAGE,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+
+        let out_usage = wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST;
+        let (output_keys, output_values): (Vec<wgpu::Buffer>, Vec<wgpu::Buffer>) = chunk_lengths
+            .iter()
+            .map(|l| {
+                let size = (l * BYTES_PER_PAYLOAD_ELEM) as u64;
+                let keys = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(&named_label(self.sorter.name(), "merge output keys buffer")),
+                    size,
+                    usage: out_usage,
+                    mapped_at_creation: false,
+                });
+                let values = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(&named_label(self.sorter.name(), "merge output payload buffer")),
+                    size,
+                    usage: out_usage,
+                    mapped_at_creation: false,
+                });
+                (keys, values)
+            })
+            .unzip();
+
+        let rank_layout = Self::rank_bind_group_layout(device);
+        let scatter_layout = Self::scatter_bind_group_layout(device);
+
+        // infos of all rank pairs followed by the infos of all scatter pairs
+        let mut infos = Vec::with_capacity(2 * chunks.len() * chunks.len());
+        for (i, src) in chunks.iter().enumerate() {
+            // the source chunk itself comes first as it initializes the ranks
+            let order = std::iter::once(i).chain((0..chunks.len()).filter(|j| *j != i));
+            infos.extend(order.map(|j| {
+                let mode = match j.cmp(&i) {
+                    std::cmp::Ordering::Equal => 0,
+                    std::cmp::Ordering::Greater => 1,
+                    std::cmp::Ordering::Less => 2,
+                };
+                let info = MergeInfo {
+                    src_len: src.len(),
+                    other_len: chunks[j].len(),
+                    mode,
+                    out_offset: 0,
+                };
+                (j, info)
+            }));
+        }
+        for src in chunks.iter() {
+            infos.extend((0..chunks.len()).map(|o| {
+                let info = MergeInfo {
+                    src_len: src.len(),
+                    other_len: chunk_lengths[o],
+                    mode: 0,
+                    out_offset: o as u32 * chunk_len,
+                };
+                (o, info)
+            }));
+        }
+        let info_stride = info_stride(&device.limits());
+        let mut info_data = vec![0u8; infos.len() * info_stride as usize];
+        for (k, (_, info)) in infos.iter().enumerate() {
+            let start = k * info_stride as usize;
+            info_data[start..start + mem::size_of::<MergeInfo>()].copy_from_slice(bytes_of(info));
+        }
+        let info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(self.sorter.name(), "merge info buffer")),
+            contents: &info_data,
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let info_entry = |k: usize| wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer: &info_buffer,
+                offset: k as u64 * info_stride,
+                size: NonZeroU64::new(mem::size_of::<MergeInfo>() as u64),
+            }),
+        };
+
+        let (rank_infos, scatter_infos) = infos.split_at(chunks.len() * chunks.len());
+        let mut rank_bind_groups = Vec::with_capacity(num_chunks as usize);
+        let mut scatter_bind_groups = Vec::with_capacity(num_chunks as usize);
+        for (i, src) in chunks.iter().enumerate() {
+            let first = i * chunks.len();
+            let groups = rank_infos[first..first + chunks.len()]
+                .iter()
+                .enumerate()
+                .map(|(k, (j, _))| {
+                    device.create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some(&named_label(self.sorter.name(), "merge rank bind group")),
+                        layout: &rank_layout,
+                        entries: &[
+                            info_entry(first + k),
+                            buffer_entry(1, src.keys()),
+                            buffer_entry(2, chunks[*j].keys()),
+                            buffer_entry(3, &ranks[i]),
+                        ],
+                    })
+                })
+                .collect();
+            rank_bind_groups.push(groups);
+
+            let groups = scatter_infos[first..first + chunks.len()]
+                .iter()
+                .enumerate()
+                .map(|(k, (o, _))| {
+                    device.create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some(&named_label(self.sorter.name(), "merge scatter bind group")),
+                        layout: &scatter_layout,
+                        entries: &[
+                            info_entry(rank_infos.len() + first + k),
+                            buffer_entry(1, src.keys()),
+                            buffer_entry(4, src.values()),
+                            buffer_entry(5, &ranks[i]),
+                            buffer_entry(6, &output_keys[*o]),
+                            buffer_entry(7, &output_values[*o]),
+                        ],
+                    })
+                })
+                .collect();
+            scatter_bind_groups.push(groups);
+        }
+
+        ChunkedSortBuffers {
+            chunks,
+            ranks,
+            info_buffer,
+            output_keys,
+            output_values,
+            rank_bind_groups,
+            scatter_bind_groups,
+            chunk_len,
+            length,
+        }
+    }
+}
+
+/// Data of one chunk pair of the merge shaders
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct MergeInfo {
+    src_len: u32,
+    other_len: u32,
+    mode: u32,
+    out_offset: u32,
+}
+
+/// Struct containing all buffers necessary for sorting in chunks.
+///
+/// The key-value pairs are uploaded into the input chunks ([ChunkedSortBuffers::chunk]).
+/// After sorting the globally sorted sequence can be found in the output chunks
+/// ([ChunkedSortBuffers::output_keys] and [ChunkedSortBuffers::output_values]).
+/// Output chunk `i` contains the elements `i * chunk_len..(i + 1) * chunk_len` of the sorted sequence.
+pub struct ChunkedSortBuffers {
+    /// input chunks, each sorted individually
+    chunks: Vec<SortBuffers>,
+    /// position of each key of the input chunks in the merged sequence
+    #[allow(dead_code)]
+    ranks: Vec<wgpu::Buffer>,
+    /// [MergeInfo] of every rank and scatter bind group
+    #[allow(dead_code)]
+    info_buffer: wgpu::Buffer,
+    /// merged keys
+    output_keys: Vec<wgpu::Buffer>,
+    /// merged values
+    output_values: Vec<wgpu::Buffer>,
+
+    /// bind groups for the rank calculation, one per (source chunk, other chunk) combination
+    rank_bind_groups: Vec<Vec<wgpu::BindGroup>>,
+    /// bind groups for the scatter, one per (source chunk, output chunk) combination
+    scatter_bind_groups: Vec<Vec<wgpu::BindGroup>>,
+
+    // maximum number of key-value pairs per chunk
+    chunk_len: u32,
+    // total number of key-value pairs
+    length: u32,
+}
+
+impl ChunkedSortBuffers {
+    /// total number of key-value pairs that can be stored in this buffer
+    pub fn len(&self) -> u32 {
+        self.length
+    }
+
+    /// true if the buffers hold no key-value pairs
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// maximum number of key-value pairs per chunk
+    pub fn chunk_len(&self) -> u32 {
+        self.chunk_len
+    }
+
+    /// number of input and output chunks
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Input chunk `i`. It holds the elements `i * chunk_len..(i + 1) * chunk_len`.
+    pub fn chunk(&self, i: usize) -> &SortBuffers {
+        &self.chunks[i]
+    }
+
+    /// Buffer storing the merged keys of output chunk `i`
+    pub fn output_keys(&self, i: usize) -> &wgpu::Buffer {
+        &self.output_keys[i]
+    }
+
+    /// Buffer storing the merged values of output chunk `i`
+    pub fn output_values(&self, i: usize) -> &wgpu::Buffer {
+        &self.output_values[i]
+    }
+
+    /// Uploads the key-value pairs into the input chunks.
+    /// Only one chunk is staged at a time, so the data can be streamed from host memory.
+    ///
+    /// `keys` and `values` must have [ChunkedSortBuffers::len] elements.
+    /// The chunks are merged by comparing the raw u32 keys, so only u32 keys are supported.
+    /// Values can be of any 4 byte type.
+    pub fn upload<V: bytemuck::Pod>(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        keys: &[u32],
+        values: &[V],
+    ) {
+        const { assert!(mem::size_of::<V>() == BYTES_PER_PAYLOAD_ELEM as usize, "values must be 4 bytes large") };
+        assert_eq!(keys.len(), self.length as usize, "wrong number of keys");
+        assert_eq!(values.len(), self.length as usize, "wrong number of values");
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            let start = i * self.chunk_len as usize;
+            let end = start + chunk.len() as usize;
+            queue.write_buffer(chunk.keys(), 0, bytemuck::cast_slice(&keys[start..end]));
+            queue.write_buffer(chunk.values(), 0, bytemuck::cast_slice(&values[start..end]));
+            // flush the staged data so that only one chunk is kept in host memory
+            queue.submit([]);
+            device.poll(wgpu::Maintain::Wait);
+        }
+    }
+}
+
+/// distance of the [MergeInfo] entries in the info buffer, every entry is bound at its own offset
+fn info_stride(limits: &wgpu::Limits) -> u64 {
+    (mem::size_of::<MergeInfo>() as u64).next_multiple_of(limits.min_storage_buffer_offset_alignment as u64)
+}
+
+fn info_layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: true },
+            has_dynamic_offset: false,
+            min_binding_size: Some(NonZeroU64::new(mem::size_of::<MergeInfo>() as u64).unwrap()),
+        },
+        count: None,
+    }
+}
+
+/// number of workgroups needed to process n elements with the merge shaders
+fn merge_workgroups(n: u32) -> u32 {
+    n.div_ceil(MERGE_WG_SIZE).min(MAX_WORKGROUPS)
+}
@@ -0,0 +1,381 @@
+/*
+    Stream compaction of key-value pairs.
+
+    GPUCompactor copies the pairs of sort buffers that pass a predicate contiguously into output sort buffers,
+    keeping their order:
+    - compact_flags evaluates the predicate for every pair. The predicate is either a flags buffer
+      (a pair is kept if its flag is not zero) or a WGSL expression over the raw key bits `key: u32`
+      and the position `index: u32` of the pair, e.g. "key != 0xFFFFFFFFu"
+    - the flags are summed up with the GPUScanner (inclusive scan), the sum is the position of a kept pair
+    - compact_scatter moves the kept pairs to their position
+
+    The number of kept pairs is written to the state buffer of the output sort buffers
+    and the dispatch buffer contains the workgroup counts for GPUSorter::sort_indirect,
+    so the output can be sorted without a round trip to the cpu:
+
+    let compactor = GPUCompactor::with_predicate(&device, "bitcast<f32>(key) > 0.0");
+    let compact_buffers = compactor.create_compact_buffers(&device, &sorter, &sort_buffers, None);
+    compactor.compact(&mut encoder, &queue, &sort_buffers, &compact_buffers, None);
+    sorter.sort_indirect(&mut encoder, compact_buffers.output(), compact_buffers.dispatch_buffer());
+
+    The shaders can be found in compact.wgsl
+*/
+
+use std::{mem, num::NonZeroU32};
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label,
+    scan::{GPUScanner, ScanBuffers, ScanMode, ScanOp, SCAN_BLOCK_KVS},
+    storage_layout_entry,
+    typed::{self, KeyKind, SortKey, SortValue, TypedDispatch},
+    GPUSorter, SortBuffers, MAX_WORKGROUPS,
+};
+
+/// workgroup size of the compaction shaders
+const COMPACT_WG_SIZE: u32 = 256;
+
+/// Dispatch arguments for sorting the output, has to be synced with CompactDispatch in compact.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct CompactDispatch {
+    x: u32,
+    y: u32,
+    z: u32,
+    value_words: u32,
+    /// see [GPUSorter::histo_block_kvs]
+    histo_block_kvs: u32,
+}
+
+/// Output and intermediate buffers for compacting one [SortBuffers]
+pub struct CompactBuffers<K = u32, V = u32> {
+    /// the kept pairs
+    output: SortBuffers<K, V>,
+    /// dispatch arguments for sorting the output
+    dispatch_buffer: wgpu::Buffer,
+    scan_buffers: ScanBuffers,
+    scan_dispatch_buffer: wgpu::Buffer,
+    flags_bind_group: wgpu::BindGroup,
+    scatter_bind_group: wgpu::BindGroup,
+    /// number of pairs of the input buffers
+    input_length: u32,
+}
+
+impl<K: SortKey, V: SortValue> CompactBuffers<K, V> {
+    /// Buffers containing the kept pairs.
+    ///
+    /// The number of kept pairs is written to [SorterState::num_keys](crate::SorterState) in [SortBuffers::state_buffer].
+    pub fn output(&self) -> &SortBuffers<K, V> {
+        &self.output
+    }
+
+    /// Buffer containing [wgpu::util::DispatchIndirectArgs] for sorting the kept pairs with [GPUSorter::sort_indirect]
+    pub fn dispatch_buffer(&self) -> &wgpu::Buffer {
+        &self.dispatch_buffer
+    }
+
+    /// Buffers of the scan computing the positions of the kept pairs,
+    /// e.g. for limiting its lookback with [ScanBuffers::set_lookback_limit]
+    pub fn scan_buffers(&self) -> &ScanBuffers {
+        &self.scan_buffers
+    }
+
+    /// Downloads the kept keys. The function waits for the gpu to finish.
+    pub async fn read_keys(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<K> {
+        let count = self.read_count(device, queue).await as usize;
+        let mut keys = self.output.read_keys(device, queue).await;
+        keys.truncate(count);
+        keys
+    }
+
+    /// Downloads the kept values. The function waits for the gpu to finish.
+    pub async fn read_values(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<V> {
+        let count = self.read_count(device, queue).await as usize;
+        let mut values = self.output.read_values(device, queue).await;
+        values.truncate(count);
+        values
+    }
+
+    /// Downloads the number of kept pairs. The function waits for the gpu to finish.
+    pub async fn read_count(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> u32 {
+        crate::utils::download_buffer::<u32>(self.output.state_buffer(), device, queue, ..).await[0]
+    }
+}
+
+/// Pipelines for stream compaction with one predicate
+pub struct GPUCompactor {
+    flags_p: wgpu::ComputePipeline,
+    scatter_p: wgpu::ComputePipeline,
+    scanner: GPUScanner,
+    /// true if the predicate reads a flags buffer
+    uses_flags: bool,
+}
+
+impl GPUCompactor {
+    /// Creates a compactor keeping the pairs whose entry in a flags buffer (one u32 per pair) is not zero.
+    /// The flags buffer is passed to [GPUCompactor::create_compact_buffers].
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self::create(device, None, None)
+    }
+
+    /// Creates a compactor keeping the pairs for which the WGSL expression `predicate` is true.
+    ///
+    /// The expression can use the raw bits of the key `key: u32` and the position of the pair `index: u32`,
+    /// e.g. `"key != 0xFFFFFFFFu"` or `"bitcast<f32>(key) < 10.0"`.
+    pub fn with_predicate(device: &wgpu::Device, predicate: &str) -> Self {
+        Self::create(device, Some(predicate), None)
+    }
+
+    /// Same as [GPUCompactor::with_predicate] (or [GPUCompactor::new] if `predicate` is None)
+    /// but adds `name` to the labels of the pipelines
+    pub fn new_named(device: &wgpu::Device, predicate: Option<&str>, name: &str) -> Self {
+        Self::create(device, predicate, Some(name))
+    }
+
+    fn create(device: &wgpu::Device, predicate: Option<&str>, name: Option<&str>) -> Self {
+        let keep_fn = match predicate {
+            Some(predicate) => format!("fn keep(index: u32, key: u32) -> bool {{ return {predicate}; }}"),
+            None => "@group(0) @binding(2)\nvar<storage, read> flags: array<u32>;\n\
+                     fn keep(index: u32, key: u32) -> bool { return flags[index] != 0u; }"
+                .to_string(),
+        };
+        let raw_shader: &str = include_str!("compact.wgsl");
+        let shader_code = format!(
+            "const compact_wg_size: u32 = {:}u;\nconst scan_block_kvs: u32 = {:}u;\n\
+             const compact_max_workgroups: u32 = {:}u;\n{:}\n{:}",
+            COMPACT_WG_SIZE, SCAN_BLOCK_KVS, MAX_WORKGROUPS, keep_fn, raw_shader
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Compact shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let uses_flags = predicate.is_none();
+        let pipeline = |layout: wgpu::BindGroupLayout, entry_point: &str| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(&named_label(name, &format!("{entry_point} pipeline layout"))),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&named_label(name, entry_point)),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        Self {
+            flags_p: pipeline(Self::flags_bind_group_layout(device, uses_flags), "compact_flags"),
+            scatter_p: pipeline(Self::scatter_bind_group_layout(device), "compact_scatter"),
+            scanner: match name {
+                Some(name) => GPUScanner::new_named(device, name),
+                None => GPUScanner::new(device),
+            },
+            uses_flags,
+        }
+    }
+
+    fn flags_bind_group_layout(device: &wgpu::Device, uses_flags: bool) -> wgpu::BindGroupLayout {
+        let mut entries = vec![
+            storage_layout_entry(0, true),
+            storage_layout_entry(1, true),
+            storage_layout_entry(3, false),
+            storage_layout_entry(4, false),
+            storage_layout_entry(5, false),
+        ];
+        if uses_flags {
+            entries.push(storage_layout_entry(2, true));
+        }
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("compact flags bind group layout"),
+            entries: &entries,
+        })
+    }
+
+    fn scatter_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("compact scatter bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, true),
+                storage_layout_entry(6, true),
+                storage_layout_entry(7, true),
+                storage_layout_entry(8, false),
+                storage_layout_entry(9, false),
+                storage_layout_entry(10, false),
+                storage_layout_entry(11, false),
+            ],
+        })
+    }
+
+    /// Creates the output and intermediate buffers for compacting `sort_buffers`.
+    /// The output can be sorted by `sorter`.
+    ///
+    /// `flags` must contain one u32 per pair and is required if and only if the compactor was created without a predicate.
+    /// Only keys with 4 bytes (u32, i32 and f32) are supported.
+    pub fn create_compact_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        sorter: &GPUSorter,
+        sort_buffers: &SortBuffers<K, V>,
+        flags: Option<&wgpu::Buffer>,
+    ) -> CompactBuffers<K, V> {
+        assert!(K::KIND != KeyKind::U64, "compaction only supports keys with 4 bytes");
+        assert_eq!(
+            flags.is_some(),
+            self.uses_flags,
+            "a flags buffer is required if and only if the compactor has no predicate"
+        );
+        let length = sort_buffers.len();
+        let name = sort_buffers.name();
+        let word_size = mem::size_of::<u32>() as u64;
+        if let Some(flags) = flags {
+            assert!(flags.size() >= length as u64 * word_size, "the flags buffer must contain one u32 per pair");
+        }
+
+        let output =
+            sorter.create_sort_buffers_with_name(device, NonZeroU32::new(length).unwrap(), Some(&named_label(name, "compacted")));
+        let dispatch = CompactDispatch {
+            x: 0,
+            y: 1,
+            z: 1,
+            value_words: V::WORDS,
+            histo_block_kvs: output.histo_block_kvs(),
+        };
+        let dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "compact dispatch buffer")),
+            contents: bytes_of(&dispatch),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_SRC,
+        });
+        let scratch_buffer = |label: &str| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&named_label(name, label)),
+                size: length as u64 * word_size,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let keep_buffer = scratch_buffer("compact keep buffer");
+        let positions_buffer = scratch_buffer("compact positions buffer");
+        let scan_buffers = self.scanner.create_scan_buffers(
+            device,
+            &keep_buffer,
+            &positions_buffer,
+            NonZeroU32::new(length).unwrap(),
+            ScanOp::Add,
+            ScanMode::Inclusive,
+        );
+        let scan_dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "compact scan dispatch buffer")),
+            contents: wgpu::util::DispatchIndirectArgs { x: 0, y: 1, z: 1 }.as_bytes(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+        });
+
+        let mut flags_entries = vec![
+            buffer_entry(0, &sort_buffers.state_buffer),
+            buffer_entry(1, sort_buffers.keys()),
+            buffer_entry(3, &keep_buffer),
+            buffer_entry(4, scan_buffers.state_buffer()),
+            buffer_entry(5, &scan_dispatch_buffer),
+        ];
+        if let Some(flags) = flags {
+            flags_entries.push(buffer_entry(2, flags));
+        }
+        let flags_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "compact flags bind group")),
+            layout: &Self::flags_bind_group_layout(device, self.uses_flags),
+            entries: &flags_entries,
+        });
+        let scatter_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "compact scatter bind group")),
+            layout: &Self::scatter_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.keys()),
+                buffer_entry(6, sort_buffers.values()),
+                buffer_entry(7, &positions_buffer),
+                buffer_entry(8, output.keys()),
+                buffer_entry(9, output.values()),
+                buffer_entry(10, &output.state_buffer),
+                buffer_entry(11, &dispatch_buffer),
+            ],
+        });
+        CompactBuffers {
+            output,
+            dispatch_buffer,
+            scan_buffers,
+            scan_dispatch_buffer,
+            flags_bind_group,
+            scatter_bind_group,
+            input_length: length,
+        }
+    }
+
+    /// Compacts the first `compact_first_n` pairs (or all pairs) of `sort_buffers` into [CompactBuffers::output].
+    ///
+    /// `sort_buffers` must be the buffers `compact_buffers` were created for.
+    pub fn compact<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, V>,
+        compact_buffers: &CompactBuffers<K, V>,
+        compact_first_n: Option<u32>,
+    ) {
+        let num_elements = compact_first_n.unwrap_or(compact_buffers.input_length);
+        assert!(
+            num_elements <= compact_buffers.input_length,
+            "more pairs compacted than the buffers contain"
+        );
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
+
+        self.record(encoder, compact_buffers, &TypedDispatch::Direct(num_elements));
+    }
+
+    /// Compacts the number of pairs stored in [SortBuffers::state_buffer] of the input,
+    /// e.g. written by a culling pass on the gpu.
+    ///
+    /// The dispatch buffer must contain the struct [wgpu::util::DispatchIndirectArgs]
+    /// with at least one workgroup if there are pairs to compact.
+    /// The same dispatch buffer as for [GPUSorter::sort_indirect] can be used.
+    pub fn compact_indirect<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        compact_buffers: &CompactBuffers<K, V>,
+        dispatch_buffer: &wgpu::Buffer,
+    ) {
+        self.record(encoder, compact_buffers, &TypedDispatch::Indirect(dispatch_buffer));
+    }
+
+    fn record<K, V>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        compact_buffers: &CompactBuffers<K, V>,
+        dispatch: &TypedDispatch,
+    ) {
+        let name = compact_buffers.output.name();
+        encoder.push_debug_group(&named_label(name, "compact"));
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "compact flags")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.flags_p);
+            pass.set_bind_group(0, &compact_buffers.flags_bind_group, &[]);
+            typed::dispatch(&mut pass, dispatch, COMPACT_WG_SIZE);
+        }
+        self.scanner.scan_indirect(encoder, &compact_buffers.scan_buffers, &compact_buffers.scan_dispatch_buffer);
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "compact scatter")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.scatter_p);
+            pass.set_bind_group(0, &compact_buffers.scatter_bind_group, &[]);
+            typed::dispatch(&mut pass, dispatch, COMPACT_WG_SIZE);
+        }
+        encoder.pop_debug_group();
+    }
+}
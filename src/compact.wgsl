@@ -0,0 +1,111 @@
+// shader compacting the key-value pairs that pass a predicate. More information in compact.rs
+//
+// compact_flags evaluates the predicate for every pair, the flags are scanned by scan.wgsl (inclusive sum)
+// and compact_scatter moves the pairs to their scanned position.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const compact_wg_size
+// const scan_block_kvs
+// const compact_max_workgroups
+// fn keep(index: u32, key: u32) -> bool (reads the flags buffer at binding 2 or evaluates the user predicate)
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct ScanState {
+    length: u32,
+    op: u32,
+    inclusive: u32,
+    lookback_limit: u32,
+    error_flags: u32,
+};
+
+// dispatch arguments for the indirect sort of the output, followed by the number of words per value
+// and the number of keys per histogram block of the sorter that created the output
+struct CompactDispatch {
+    x: u32,
+    y: u32,
+    z: u32,
+    value_words: u32,
+    histo_block_kvs: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read> infos: GeneralInfo;
+@group(0) @binding(1)
+var<storage, read> keys: array<u32>;
+
+// compact_flags
+@group(0) @binding(3)
+var<storage, read_write> keep_flags: array<u32>;
+@group(0) @binding(4)
+var<storage, read_write> scan_state: ScanState;
+@group(0) @binding(5)
+var<storage, read_write> scan_dispatch: array<u32, 3>;
+
+// compact_scatter
+@group(0) @binding(6)
+var<storage, read> values: array<u32>;
+@group(0) @binding(7)
+var<storage, read> positions: array<u32>;
+@group(0) @binding(8)
+var<storage, read_write> out_keys: array<u32>;
+@group(0) @binding(9)
+var<storage, read_write> out_values: array<u32>;
+@group(0) @binding(10)
+var<storage, read_write> out_infos: GeneralInfo;
+@group(0) @binding(11)
+var<storage, read_write> dispatch: CompactDispatch;
+
+@compute @workgroup_size(compact_wg_size)
+fn compact_flags(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let num_keys = infos.num_keys;
+    if gid.x == 0u {
+        // the scan runs over all pairs of the input
+        scan_state.length = num_keys;
+        scan_dispatch[0] = min((num_keys + scan_block_kvs - 1u) / scan_block_kvs, compact_max_workgroups);
+        scan_dispatch[1] = 1u;
+        scan_dispatch[2] = 1u;
+    }
+    let line_size = nwg.x * compact_wg_size;
+    for (var i = gid.x; i < num_keys; i += line_size) {
+        keep_flags[i] = select(0u, 1u, keep(i, keys[i]));
+    }
+}
+
+@compute @workgroup_size(compact_wg_size)
+fn compact_scatter(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let num_keys = infos.num_keys;
+    let value_words = dispatch.value_words;
+    let line_size = nwg.x * compact_wg_size;
+    for (var i = gid.x; i < num_keys; i += line_size) {
+        // the positions are an inclusive sum, a pair is kept if it increments the sum
+        let pos = positions[i];
+        var before = 0u;
+        if i > 0u {
+            before = positions[i - 1u];
+        }
+        if pos != before {
+            out_keys[pos - 1u] = keys[i];
+            for (var j = 0u; j < value_words; j++) {
+                out_values[(pos - 1u) * value_words + j] = values[i * value_words + j];
+            }
+        }
+    }
+
+    // the number of kept pairs is the last position
+    if gid.x == 0u {
+        var count = 0u;
+        if num_keys > 0u {
+            count = positions[num_keys - 1u];
+        }
+        out_infos.num_keys = count;
+        dispatch.x = (count + dispatch.histo_block_kvs - 1u) / dispatch.histo_block_kvs;
+        dispatch.y = 1u;
+        dispatch.z = 1u;
+    }
+}
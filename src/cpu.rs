@@ -0,0 +1,527 @@
+/*
+    CPU implementation of the key-value sort.
+
+    It is used as a fallback on machines without a suitable adapter and as a reference
+    implementation the gpu results can be compared against.
+    The CPU sorter mirrors the api of the GPUSorter and leaves the buffers in the same state:
+    keys are ordered like the typed gpu sort (see typed.rs), equal keys keep their relative order
+    (the radix sort is stable) and partial sorts overwrite the keys after the first n with 0xFFFFFFFF
+    like the radix sort passes do. The direct sort skips the padding for inputs sorted by the single workgroup,
+    the indirect sort always runs the radix sort passes and always pads. Keys that are stored in their own
+    buffer on the gpu (u64 keys and values with more than one word) have no padding.
+
+    AutoSorter picks the gpu if an adapter and device can be created and the CPU sorter otherwise.
+*/
+
+use std::{mem, num::NonZeroU32, slice};
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    typed::{KeyKind, SortKey, SortValue},
+    utils::{download_buffer, guess_workgroup_size_quick},
+    named_label, GPUSorter, SortBuffers, SortLayout, SorterState, LOCAL_SORT_KVS,
+};
+
+/// Sorts key-value pairs stored in [CPUSortBuffers] on the CPU.
+/// Counterpart of [GPUSorter] with the same sorting semantics.
+pub struct CPUSorter {
+    small_sort_threshold: u32,
+}
+
+impl Default for CPUSorter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CPUSorter {
+    pub fn new() -> Self {
+        Self {
+            small_sort_threshold: LOCAL_SORT_KVS,
+        }
+    }
+
+    /// See [GPUSorter::small_sort_threshold]
+    pub fn small_sort_threshold(&self) -> u32 {
+        self.small_sort_threshold
+    }
+
+    /// See [GPUSorter::set_small_sort_threshold].
+    /// The threshold only decides whether the keys after the first n are overwritten.
+    pub fn set_small_sort_threshold(&mut self, threshold: u32) {
+        self.small_sort_threshold = threshold.min(LOCAL_SORT_KVS);
+    }
+
+    /// Sorts the first n pairs or all pairs if sort_first_n is none.
+    ///
+    /// Like [GPUSorter::sort] the rest of the keys buffer is overwritten,
+    /// unless the pairs are few enough to be sorted by the small sort path.
+    pub fn sort<K: SortKey, V: SortValue>(
+        &self,
+        sort_buffers: &mut CPUSortBuffers<K, V>,
+        sort_first_n: Option<u32>,
+    ) {
+        let num_elements = sort_first_n.unwrap_or(sort_buffers.len());
+        sort_buffers.state.num_keys = num_elements;
+        // like GPUSorter::record_sort_keys, only the radix sort passes pad the keys
+        self.sort_state(sort_buffers, num_elements > self.small_sort_threshold);
+    }
+
+    /// Sorts the number of pairs stored in [CPUSortBuffers::state], the counterpart of [GPUSorter::sort_indirect].
+    ///
+    /// The indirect gpu sort never uses the single workgroup, so the rest of the keys buffer is always overwritten.
+    pub fn sort_indirect<K: SortKey, V: SortValue>(&self, sort_buffers: &mut CPUSortBuffers<K, V>) {
+        self.sort_state(sort_buffers, true);
+    }
+
+    /// Sorts `batch` rows of `row_len` pairs independently, see [GPUSorter::sort_batched].
+    pub fn sort_batched(&self, sort_buffers: &mut CPUSortBuffers, batch: u32, row_len: u32) {
+        assert!(
+            batch as u64 * row_len as u64 <= sort_buffers.len() as u64,
+            "batch of {batch} rows with {row_len} pairs does not fit into sort buffers of length {}",
+            sort_buffers.len()
+        );
+        if batch == 0 || row_len == 0 {
+            return;
+        }
+        sort_buffers.state.num_keys = row_len;
+
+        let row_len = row_len as usize;
+        let len = batch as usize * row_len;
+        for (keys, values) in sort_buffers.keys[..len]
+            .chunks_mut(row_len)
+            .zip(sort_buffers.values[..len].chunks_mut(row_len))
+        {
+            sort_pairs(keys, values);
+        }
+    }
+
+    fn sort_state<K: SortKey, V: SortValue>(&self, sort_buffers: &mut CPUSortBuffers<K, V>, pad: bool) {
+        let num_elements = sort_buffers.state.num_keys;
+        assert!(
+            num_elements <= sort_buffers.len(),
+            "can not sort {num_elements} pairs in sort buffers of length {}",
+            sort_buffers.len()
+        );
+        let n = num_elements as usize;
+        sort_pairs(
+            &mut sort_buffers.keys[..n],
+            &mut sort_buffers.values[..n],
+        );
+        if pad && CPUSortBuffers::<K, V>::padded() {
+            // the radix sort fills the padding with max keys
+            let padded_size = sort_buffers.state.padded_size as usize;
+            let padding: &mut [u32] = bytemuck::cast_slice_mut(&mut sort_buffers.keys[n..padded_size]);
+            padding.fill(u32::MAX);
+        }
+    }
+
+    /// creates all buffers necessary for sorting
+    pub fn create_sort_buffers(&self, length: NonZeroU32) -> CPUSortBuffers {
+        self.create_typed_sort_buffers(length)
+    }
+
+    /// Creates all buffers necessary for sorting keys of type `K` with values of type `V`,
+    /// see [GPUSorter::create_typed_sort_buffers].
+    pub fn create_typed_sort_buffers<K: SortKey, V: SortValue>(
+        &self,
+        length: NonZeroU32,
+    ) -> CPUSortBuffers<K, V> {
+        let length = length.get();
+        let keys_len = if CPUSortBuffers::<K, V>::padded() {
+            // padded like the buffers of a sorter created with GPUSorter::new
+            SortLayout::DEFAULT.keys_buffer_size(length)
+        } else {
+            length
+        };
+        CPUSortBuffers {
+            keys: vec![K::zeroed(); keys_len as usize],
+            values: vec![V::zeroed(); length as usize],
+            state: GPUSorter::general_info_data(length, SortLayout::DEFAULT),
+            length,
+        }
+    }
+
+    /// Creates all buffers necessary for sorting `batch` rows of `row_len` pairs with [CPUSorter::sort_batched].
+    pub fn create_batched_sort_buffers(&self, batch: NonZeroU32, row_len: NonZeroU32) -> CPUSortBuffers {
+        let length = batch
+            .checked_mul(row_len)
+            .expect("number of key-value pairs exceeds u32::MAX");
+        self.create_sort_buffers(length)
+    }
+}
+
+/// maps a key onto an unsigned integer with the same order, like encode_key in typed.wgsl
+fn ordered_key<K: SortKey>(key: &K) -> u64 {
+    let words: &[u32] = bytemuck::cast_slice(slice::from_ref(key));
+    match K::KIND {
+        KeyKind::U32 => words[0] as u64,
+        KeyKind::I32 => (words[0] ^ 0x80000000) as u64,
+        KeyKind::F32 => {
+            // negative floats are ordered in reverse
+            if words[0] & 0x80000000 != 0 {
+                !words[0] as u64
+            } else {
+                (words[0] | 0x80000000) as u64
+            }
+        }
+        KeyKind::U64 => (words[1] as u64) << 32 | words[0] as u64,
+    }
+}
+
+/// stable sort of the pairs by their keys
+fn sort_pairs<K: SortKey, V: SortValue>(keys: &mut [K], values: &mut [V]) {
+    let mut pairs: Vec<(K, V)> = keys.iter().copied().zip(values.iter().copied()).collect();
+    pairs.sort_by_key(|(k, _)| ordered_key(k));
+    for (i, (k, v)) in pairs.into_iter().enumerate() {
+        keys[i] = k;
+        values[i] = v;
+    }
+}
+
+/// Key and value buffers for the [CPUSorter], the counterpart of [SortBuffers].
+pub struct CPUSortBuffers<K = u32, V = u32> {
+    /// keys including padding
+    keys: Vec<K>,
+    values: Vec<V>,
+    state: SorterState,
+    // number of key-value pairs
+    length: u32,
+}
+
+impl<K: SortKey, V: SortValue> CPUSortBuffers<K, V> {
+    /// the keys are padded if the gpu sorts them in the radix sort keys buffer (see typed.rs)
+    fn padded() -> bool {
+        mem::size_of::<K>() == mem::size_of::<u32>() && V::WORDS == 1
+    }
+
+    /// number of key-value pairs that can be stored in this buffer
+    pub fn len(&self) -> u32 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// The keys including the padding at the end, see [SortBuffers::keys].
+    pub fn keys(&self) -> &[K] {
+        &self.keys
+    }
+
+    pub fn keys_mut(&mut self) -> &mut [K] {
+        &mut self.keys
+    }
+
+    /// The number of valid key bytes without padding, see [SortBuffers::keys_valid_size].
+    pub fn keys_valid_size(&self) -> u64 {
+        self.len() as u64 * mem::size_of::<K>() as u64
+    }
+
+    pub fn values(&self) -> &[V] {
+        &self.values
+    }
+
+    pub fn values_mut(&mut self) -> &mut [V] {
+        &mut self.values
+    }
+
+    /// The [SorterState], the counterpart of [SortBuffers::state_buffer]
+    pub fn state(&self) -> &SorterState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut SorterState {
+        &mut self.state
+    }
+}
+
+/// Sorter that runs on the gpu if possible and falls back to the [CPUSorter] otherwise.
+///
+/// The gpu variant owns its device and queue and submits the sort commands itself.
+#[allow(clippy::large_enum_variant)]
+pub enum AutoSorter {
+    GPU {
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        sorter: GPUSorter,
+    },
+    CPU(CPUSorter),
+}
+
+/// Buffers created by an [AutoSorter]. They can only be used with the sorter that created them.
+#[allow(clippy::large_enum_variant)]
+pub enum AutoSortBuffers<K = u32, V = u32> {
+    GPU {
+        buffers: SortBuffers<K, V>,
+        /// [wgpu::util::DispatchIndirectArgs] for [AutoSorter::sort_indirect], written by [AutoSorter::write_num_keys]
+        dispatch_buffer: wgpu::Buffer,
+    },
+    CPU(CPUSortBuffers<K, V>),
+}
+
+impl<K: SortKey, V: SortValue> AutoSortBuffers<K, V> {
+    /// number of key-value pairs that can be stored in this buffer
+    pub fn len(&self) -> u32 {
+        match self {
+            AutoSortBuffers::GPU { buffers, .. } => buffers.len(),
+            AutoSortBuffers::CPU(buffers) => buffers.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// workgroup counts of [GPUSorter::sort_indirect] for sorting `num_keys` pairs
+fn dispatch_args(sorter: &GPUSorter, num_keys: u32) -> wgpu::util::DispatchIndirectArgs {
+    wgpu::util::DispatchIndirectArgs {
+        x: num_keys.div_ceil(sorter.histo_block_kvs()),
+        y: 1,
+        z: 1,
+    }
+}
+
+/// dispatch buffer of [AutoSortBuffers::GPU], sorts all `length` pairs until [AutoSorter::write_num_keys] is called
+fn create_dispatch_buffer(device: &wgpu::Device, sorter: &GPUSorter, length: u32) -> wgpu::Buffer {
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&named_label(sorter.name(), "AutoSorter dispatch indirect buffer")),
+        contents: dispatch_args(sorter, length).as_bytes(),
+        usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+impl AutoSorter {
+    /// Creates a gpu sorter for the default adapter (see [wgpu::util::initialize_adapter_from_env_or_default]).
+    /// Falls back to the CPU if no adapter or device can be created or no subgroup size is found.
+    pub async fn new() -> Self {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let Some(adapter) = wgpu::util::initialize_adapter_from_env_or_default(&instance, None).await
+        else {
+            log::warn!("no suitable adapter found, falling back to cpu sorting");
+            return Self::cpu();
+        };
+        let device = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    required_features: wgpu::Features::empty(),
+                    required_limits: adapter.limits(),
+                    label: Some("wgpu_sort device"),
+                },
+                None,
+            )
+            .await;
+        match device {
+            Ok((device, queue)) => Self::from_device(device, queue).await,
+            Err(err) => {
+                log::warn!("could not create device ({err}), falling back to cpu sorting");
+                Self::cpu()
+            }
+        }
+    }
+
+    /// Creates a gpu sorter for the given device, the subgroup size is found with [guess_workgroup_size_quick].
+    /// Falls back to the CPU if no valid subgroup size is found.
+    pub async fn from_device(device: wgpu::Device, queue: wgpu::Queue) -> Self {
+        match guess_workgroup_size_quick(&device, &queue).await.best() {
+            Some(subgroup_size) => {
+                let sorter = GPUSorter::new(&device, subgroup_size);
+                AutoSorter::GPU {
+                    device,
+                    queue,
+                    sorter,
+                }
+            }
+            None => {
+                log::warn!("could not find a valid subgroup size, falling back to cpu sorting");
+                Self::cpu()
+            }
+        }
+    }
+
+    pub fn cpu() -> Self {
+        AutoSorter::CPU(CPUSorter::new())
+    }
+
+    pub fn is_gpu(&self) -> bool {
+        matches!(self, AutoSorter::GPU { .. })
+    }
+
+    pub fn create_sort_buffers(&self, length: NonZeroU32) -> AutoSortBuffers {
+        self.create_typed_sort_buffers(length)
+    }
+
+    /// Creates all buffers necessary for sorting keys of type `K` with values of type `V`,
+    /// see [GPUSorter::create_typed_sort_buffers].
+    pub fn create_typed_sort_buffers<K: SortKey, V: SortValue>(
+        &self,
+        length: NonZeroU32,
+    ) -> AutoSortBuffers<K, V> {
+        match self {
+            AutoSorter::GPU { device, sorter, .. } => AutoSortBuffers::GPU {
+                buffers: sorter.create_typed_sort_buffers(device, length),
+                dispatch_buffer: create_dispatch_buffer(device, sorter, length.get()),
+            },
+            AutoSorter::CPU(sorter) => {
+                AutoSortBuffers::CPU(sorter.create_typed_sort_buffers(length))
+            }
+        }
+    }
+
+    pub fn create_batched_sort_buffers(
+        &self,
+        batch: NonZeroU32,
+        row_len: NonZeroU32,
+    ) -> AutoSortBuffers {
+        match self {
+            AutoSorter::GPU { device, sorter, .. } => {
+                let buffers = sorter.create_batched_sort_buffers(device, batch, row_len);
+                let dispatch_buffer = create_dispatch_buffer(device, sorter, buffers.len());
+                AutoSortBuffers::GPU { buffers, dispatch_buffer }
+            }
+            AutoSorter::CPU(sorter) => {
+                AutoSortBuffers::CPU(sorter.create_batched_sort_buffers(batch, row_len))
+            }
+        }
+    }
+
+    /// Writes keys and values to the start of the buffers.
+    pub fn write<K: SortKey, V: SortValue>(
+        &self,
+        sort_buffers: &mut AutoSortBuffers<K, V>,
+        keys: &[K],
+        values: &[V],
+    ) {
+        assert!(
+            keys.len() <= sort_buffers.len() as usize && values.len() <= sort_buffers.len() as usize,
+            "keys and values do not fit into sort buffers of length {}",
+            sort_buffers.len()
+        );
+        match (self, sort_buffers) {
+            (AutoSorter::GPU { queue, .. }, AutoSortBuffers::GPU { buffers, .. }) => {
+                queue.write_buffer(buffers.keys(), 0, bytemuck::cast_slice(keys));
+                queue.write_buffer(buffers.values(), 0, bytemuck::cast_slice(values));
+            }
+            (AutoSorter::CPU(_), AutoSortBuffers::CPU(buffers)) => {
+                buffers.keys[..keys.len()].copy_from_slice(keys);
+                buffers.values[..values.len()].copy_from_slice(values);
+            }
+            _ => panic!("sort buffers were created by a different backend"),
+        }
+    }
+
+    /// Sorts the first n pairs or all pairs if sort_first_n is none and waits for the sort to finish.
+    /// See [GPUSorter::sort]
+    pub fn sort<K: SortKey, V: SortValue>(
+        &self,
+        sort_buffers: &mut AutoSortBuffers<K, V>,
+        sort_first_n: Option<u32>,
+    ) {
+        match (self, sort_buffers) {
+            (AutoSorter::GPU { device, queue, sorter }, AutoSortBuffers::GPU { buffers, .. }) => {
+                let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("AutoSorter sort"),
+                });
+                sorter.sort(&mut encoder, queue, buffers, sort_first_n);
+                let idx = queue.submit([encoder.finish()]);
+                device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+            }
+            (AutoSorter::CPU(sorter), AutoSortBuffers::CPU(buffers)) => {
+                sorter.sort(buffers, sort_first_n)
+            }
+            _ => panic!("sort buffers were created by a different backend"),
+        }
+    }
+
+    /// Sets the number of pairs sorted by [AutoSorter::sort_indirect],
+    /// see [SortBuffers::state_buffer] and [CPUSortBuffers::state_mut].
+    /// The gpu variant writes the workgroup counts for the pairs into the dispatch buffer of the sort buffers as well.
+    pub fn write_num_keys<K: SortKey, V: SortValue>(&self, sort_buffers: &mut AutoSortBuffers<K, V>, num_keys: u32) {
+        assert!(
+            num_keys <= sort_buffers.len(),
+            "can not sort {num_keys} pairs in sort buffers of length {}",
+            sort_buffers.len()
+        );
+        match (self, sort_buffers) {
+            (AutoSorter::GPU { queue, sorter, .. }, AutoSortBuffers::GPU { buffers, dispatch_buffer }) => {
+                queue.write_buffer(buffers.state_buffer(), 0, bytes_of(&num_keys));
+                queue.write_buffer(dispatch_buffer, 0, dispatch_args(sorter, num_keys).as_bytes());
+            }
+            (AutoSorter::CPU(_), AutoSortBuffers::CPU(buffers)) => buffers.state.num_keys = num_keys,
+            _ => panic!("sort buffers were created by a different backend"),
+        }
+    }
+
+    /// Sorts the number of pairs set with [AutoSorter::write_num_keys] and waits for the sort to finish.
+    /// See [GPUSorter::sort_indirect] and [CPUSorter::sort_indirect].
+    ///
+    /// Sorts all pairs if [AutoSorter::write_num_keys] was not called.
+    pub fn sort_indirect<K: SortKey, V: SortValue>(&self, sort_buffers: &mut AutoSortBuffers<K, V>) {
+        match (self, sort_buffers) {
+            (AutoSorter::GPU { device, queue, sorter }, AutoSortBuffers::GPU { buffers, dispatch_buffer }) => {
+                let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("AutoSorter sort_indirect"),
+                });
+                sorter.sort_indirect(&mut encoder, buffers, dispatch_buffer);
+                let idx = queue.submit([encoder.finish()]);
+                device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+            }
+            (AutoSorter::CPU(sorter), AutoSortBuffers::CPU(buffers)) => sorter.sort_indirect(buffers),
+            _ => panic!("sort buffers were created by a different backend"),
+        }
+    }
+
+    /// Sorts `batch` rows of `row_len` pairs independently and waits for the sort to finish.
+    /// See [GPUSorter::sort_batched]
+    pub fn sort_batched(&self, sort_buffers: &mut AutoSortBuffers, batch: u32, row_len: u32) {
+        match (self, sort_buffers) {
+            (AutoSorter::GPU { device, queue, sorter }, AutoSortBuffers::GPU { buffers, .. }) => {
+                let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("AutoSorter sort_batched"),
+                });
+                sorter.sort_batched(&mut encoder, queue, buffers, batch, row_len);
+                let idx = queue.submit([encoder.finish()]);
+                device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+            }
+            (AutoSorter::CPU(sorter), AutoSortBuffers::CPU(buffers)) => {
+                sorter.sort_batched(buffers, batch, row_len)
+            }
+            _ => panic!("sort buffers were created by a different backend"),
+        }
+    }
+
+    /// Reads the keys without padding
+    pub async fn read_keys<K: SortKey, V: SortValue>(
+        &self,
+        sort_buffers: &AutoSortBuffers<K, V>,
+    ) -> Vec<K> {
+        match (self, sort_buffers) {
+            (AutoSorter::GPU { device, queue, .. }, AutoSortBuffers::GPU { buffers, .. }) => {
+                download_buffer(buffers.keys(), device, queue, 0..buffers.keys_valid_size()).await
+            }
+            (AutoSorter::CPU(_), AutoSortBuffers::CPU(buffers)) => {
+                buffers.keys[..buffers.len() as usize].to_vec()
+            }
+            _ => panic!("sort buffers were created by a different backend"),
+        }
+    }
+
+    /// Reads the values
+    pub async fn read_values<K: SortKey, V: SortValue>(
+        &self,
+        sort_buffers: &AutoSortBuffers<K, V>,
+    ) -> Vec<V> {
+        match (self, sort_buffers) {
+            (AutoSorter::GPU { device, queue, .. }, AutoSortBuffers::GPU { buffers, .. }) => {
+                download_buffer(buffers.values(), device, queue, ..).await
+            }
+            (AutoSorter::CPU(_), AutoSortBuffers::CPU(buffers)) => {
+                buffers.values.clone()
+            }
+            _ => panic!("sort buffers were created by a different backend"),
+        }
+    }
+}
@@ -0,0 +1,375 @@
+/*
+    View dependent depth keys for sorting splats or transparent geometry.
+
+    GPUDepthEncoder writes the quantized view space depth and the index of every visible point into sort buffers:
+    - depth_flags transforms the points with the view matrix, culls them against the near and far plane
+      and the sides of the frustum of the projection matrix and stores the visibility and key of every point
+    - the visibility flags are summed up with the GPUScanner (inclusive scan), the sum is the slot of a visible point
+    - depth_scatter writes the key and index of every visible point to its slot,
+      the number of visible points to SorterState::num_keys and the dispatch arguments for GPUSorter::sort_indirect
+
+    The keys quantize the depth between near and far to the full range of u32, so the visible points
+    can be sorted front to back or back to front without a round trip to the cpu:
+
+    let depth_encoder = GPUDepthEncoder::new(&device);
+    let depth_buffers = depth_encoder.create_depth_buffers(
+        &device, &sort_buffers, &positions, PositionLayout::VEC4, DepthOrder::BackToFront, 0.3,
+    );
+    depth_encoder.generate_depth_keys(&mut encoder, &queue, &depth_buffers, &camera, None);
+    sorter.sort_indirect(&mut encoder, &sort_buffers, depth_buffers.dispatch_buffer());
+
+    The visible points keep the order of their indices and the sort is stable,
+    so points with equal keys do not change their order between frames.
+
+    The shaders can be found in depth.wgsl
+*/
+
+use std::{mem, num::NonZeroU32};
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label,
+    scan::{GPUScanner, ScanBuffers, ScanMode, ScanOp},
+    spatial::PositionLayout,
+    storage_layout_entry,
+    typed::{self, TypedDispatch},
+    utils::download_buffer,
+    word_aligned, SortBuffers,
+};
+
+/// workgroup size of the depth shader
+const DEPTH_WG_SIZE: u32 = 256;
+
+/// Order of the sorted keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthOrder {
+    /// nearest points first
+    FrontToBack,
+    /// farthest points first, e.g. for alpha blending
+    BackToFront,
+}
+
+/// Camera for which the depth keys are generated.
+///
+/// The matrices are column major, like `mat4x4<f32>` in WGSL.
+/// The camera looks along -z in view space (right-handed), the depth of a point is its negated view space z.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthCamera {
+    /// world to view space
+    pub view: [[f32; 4]; 4],
+    /// view to clip space, only used for culling against the sides of the frustum
+    pub proj: [[f32; 4]; 4],
+    /// points closer than near are culled
+    pub near: f32,
+    /// points farther than far are culled
+    pub far: f32,
+}
+
+/// Camera and layout of the positions, has to be synced with DepthInfo in depth.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct DepthInfo {
+    view: [[f32; 4]; 4],
+    proj: [[f32; 4]; 4],
+    near: f32,
+    far: f32,
+    frustum_margin: f32,
+    num_positions: u32,
+    offset: u32,
+    stride: u32,
+    descending: u32,
+    _pad: u32,
+}
+
+/// Dispatch arguments for sorting the visible points, has to be synced with DepthDispatch in depth.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct DepthDispatch {
+    x: u32,
+    y: u32,
+    z: u32,
+    visible: u32,
+    /// see [GPUSorter::histo_block_kvs](crate::GPUSorter::histo_block_kvs)
+    histo_block_kvs: u32,
+}
+
+/// Buffers for generating the depth keys of one [SortBuffers]
+pub struct DepthBuffers {
+    /// [DepthInfo], written every time the keys are generated
+    info_buffer: wgpu::Buffer,
+    /// dispatch arguments for sorting the visible points
+    dispatch_buffer: wgpu::Buffer,
+    scan_buffers: ScanBuffers,
+    flags_bind_group: wgpu::BindGroup,
+    scatter_bind_group: wgpu::BindGroup,
+    /// layout of the positions, offset and stride in words
+    offset: u32,
+    stride: u32,
+    descending: bool,
+    frustum_margin: f32,
+    /// number of pairs of the sort buffers
+    input_length: u32,
+    name: Option<String>,
+}
+
+impl DepthBuffers {
+    /// Buffer containing [wgpu::util::DispatchIndirectArgs] for sorting the visible points with
+    /// [GPUSorter::sort_indirect](crate::GPUSorter::sort_indirect), followed by the number of visible points as u32
+    pub fn dispatch_buffer(&self) -> &wgpu::Buffer {
+        &self.dispatch_buffer
+    }
+
+    /// Buffers of the scan computing the slots of the visible points,
+    /// e.g. for limiting its lookback with [ScanBuffers::set_lookback_limit]
+    pub fn scan_buffers(&self) -> &ScanBuffers {
+        &self.scan_buffers
+    }
+
+    /// Downloads the number of visible points. The function waits for the gpu to finish.
+    pub async fn read_visible_count(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> u32 {
+        download_buffer::<DepthDispatch>(&self.dispatch_buffer, device, queue, ..).await[0].visible
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// Pipelines for generating depth keys of the visible points
+pub struct GPUDepthEncoder {
+    flags_p: wgpu::ComputePipeline,
+    scatter_p: wgpu::ComputePipeline,
+    scanner: GPUScanner,
+}
+
+impl GPUDepthEncoder {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self::create(device, None)
+    }
+
+    /// Same as [GPUDepthEncoder::new] but adds `name` to the labels of the pipelines
+    pub fn new_named(device: &wgpu::Device, name: &str) -> Self {
+        Self::create(device, Some(name))
+    }
+
+    fn create(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("depth.wgsl");
+        let shader_code = format!(
+            "const depth_wg_size: u32 = {:}u;\n{:}",
+            DEPTH_WG_SIZE, raw_shader
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Depth shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let pipeline = |layout: wgpu::BindGroupLayout, entry_point: &str| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(&named_label(name, &format!("{entry_point} pipeline layout"))),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&named_label(name, entry_point)),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        Self {
+            flags_p: pipeline(Self::flags_bind_group_layout(device), "depth_flags"),
+            scatter_p: pipeline(Self::scatter_bind_group_layout(device), "depth_scatter"),
+            scanner: match name {
+                Some(name) => GPUScanner::new_named(device, name),
+                None => GPUScanner::new(device),
+            },
+        }
+    }
+
+    fn flags_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("depth flags bind group layout"),
+            entries: &[
+                storage_layout_entry(3, true),
+                storage_layout_entry(4, true),
+                storage_layout_entry(6, false),
+                storage_layout_entry(7, false),
+            ],
+        })
+    }
+
+    fn scatter_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("depth scatter bind group layout"),
+            entries: &[
+                storage_layout_entry(0, false),
+                storage_layout_entry(1, false),
+                storage_layout_entry(2, false),
+                storage_layout_entry(4, true),
+                storage_layout_entry(5, false),
+                storage_layout_entry(7, false),
+                storage_layout_entry(8, true),
+            ],
+        })
+    }
+
+    /// Creates the buffers for writing the depth keys of `positions` into `sort_buffers`,
+    /// with the index of every visible point as value.
+    ///
+    /// `frustum_margin` widens the sides of the frustum relative to its size,
+    /// e.g. 0.3 keeps splats whose center is slightly outside of the view. The near and far plane are not widened.
+    pub fn create_depth_buffers(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers,
+        positions: &wgpu::Buffer,
+        layout: PositionLayout,
+        order: DepthOrder,
+        frustum_margin: f32,
+    ) -> DepthBuffers {
+        let word_size = mem::size_of::<f32>() as u32;
+        assert!(
+            word_aligned(layout.offset) && word_aligned(layout.stride),
+            "offset and stride of the positions must be multiples of 4 bytes"
+        );
+        assert!(layout.stride >= 3 * word_size, "the stride must be at least the size of a position");
+        assert!(frustum_margin >= 0.0, "the frustum margin must not be negative");
+        let length = sort_buffers.len();
+        assert!(
+            positions.size() >= layout.offset as u64 + (length - 1) as u64 * layout.stride as u64 + 3 * word_size as u64,
+            "the positions buffer must contain a position for every pair"
+        );
+        let name = sort_buffers.name();
+
+        let info_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "depth info buffer")),
+            size: mem::size_of::<DepthInfo>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let dispatch = DepthDispatch {
+            x: 0,
+            y: 1,
+            z: 1,
+            visible: 0,
+            histo_block_kvs: sort_buffers.histo_block_kvs(),
+        };
+        let dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "depth dispatch buffer")),
+            contents: bytes_of(&dispatch),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_SRC,
+        });
+        let scratch_buffer = |label: &str| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&named_label(name, label)),
+                size: length as u64 * word_size as u64,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let visible_buffer = scratch_buffer("depth visible buffer");
+        let point_keys_buffer = scratch_buffer("depth point keys buffer");
+        let slots_buffer = scratch_buffer("depth slots buffer");
+        let scan_buffers = self.scanner.create_scan_buffers(
+            device,
+            &visible_buffer,
+            &slots_buffer,
+            NonZeroU32::new(length).unwrap(),
+            ScanOp::Add,
+            ScanMode::Inclusive,
+        );
+        let flags_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "depth flags bind group")),
+            layout: &Self::flags_bind_group_layout(device),
+            entries: &[
+                buffer_entry(3, positions),
+                buffer_entry(4, &info_buffer),
+                buffer_entry(6, &visible_buffer),
+                buffer_entry(7, &point_keys_buffer),
+            ],
+        });
+        let scatter_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "depth scatter bind group")),
+            layout: &Self::scatter_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.keys()),
+                buffer_entry(2, sort_buffers.values()),
+                buffer_entry(4, &info_buffer),
+                buffer_entry(5, &dispatch_buffer),
+                buffer_entry(7, &point_keys_buffer),
+                buffer_entry(8, &slots_buffer),
+            ],
+        });
+        DepthBuffers {
+            info_buffer,
+            dispatch_buffer,
+            scan_buffers,
+            flags_bind_group,
+            scatter_bind_group,
+            offset: layout.offset / word_size,
+            stride: layout.stride / word_size,
+            descending: order == DepthOrder::BackToFront,
+            frustum_margin,
+            input_length: length,
+            name: name.map(str::to_string),
+        }
+    }
+
+    /// Writes the depth keys and indices of the visible points among the first `num_positions` (or all) positions
+    /// into the sort buffers `depth_buffers` were created for, sets [SorterState::num_keys](crate::SorterState)
+    /// to the number of visible points and writes the dispatch arguments for sorting them into [DepthBuffers::dispatch_buffer].
+    pub fn generate_depth_keys(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        depth_buffers: &DepthBuffers,
+        camera: &DepthCamera,
+        num_positions: Option<u32>,
+    ) {
+        let num_positions = num_positions.unwrap_or(depth_buffers.input_length);
+        assert!(
+            num_positions <= depth_buffers.input_length,
+            "more positions than the sort buffers can contain"
+        );
+        assert!(camera.near < camera.far, "the near plane must be closer than the far plane");
+        let info = DepthInfo {
+            view: camera.view,
+            proj: camera.proj,
+            near: camera.near,
+            far: camera.far,
+            frustum_margin: depth_buffers.frustum_margin,
+            num_positions,
+            offset: depth_buffers.offset,
+            stride: depth_buffers.stride,
+            descending: depth_buffers.descending as u32,
+            _pad: 0,
+        };
+        queue.write_buffer(&depth_buffers.info_buffer, 0, bytes_of(&info));
+
+        let name = depth_buffers.name.as_deref();
+        encoder.push_debug_group(&named_label(name, "depth keys"));
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "depth flags")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.flags_p);
+            pass.set_bind_group(0, &depth_buffers.flags_bind_group, &[]);
+            typed::dispatch(&mut pass, &TypedDispatch::Direct(num_positions), DEPTH_WG_SIZE);
+        }
+        self.scanner.scan(encoder, queue, &depth_buffers.scan_buffers, Some(num_positions));
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "depth scatter")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.scatter_p);
+            pass.set_bind_group(0, &depth_buffers.scatter_bind_group, &[]);
+            typed::dispatch(&mut pass, &TypedDispatch::Direct(num_positions), DEPTH_WG_SIZE);
+        }
+        encoder.pop_debug_group();
+    }
+}
@@ -0,0 +1,123 @@
+// shader generating depth keys of the visible points for view dependent sorting. More information in depth.rs
+//
+// depth_flags transforms every point into view space, culls it against the view frustum and stores
+// its visibility and quantized depth. The visibility flags are scanned by scan.wgsl (inclusive sum)
+// and depth_scatter writes the key and index of every visible point to its scanned position,
+// so the visible points keep the order of their indices.
+// depth_scatter also writes the number of visible points and the dispatch arguments for sorting them.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const depth_wg_size
+
+struct SorterState {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct DepthInfo {
+    view: mat4x4<f32>,
+    proj: mat4x4<f32>,
+    near: f32,
+    far: f32,
+    frustum_margin: f32,
+    num_positions: u32,
+    // offset and stride of the positions in words
+    offset: u32,
+    stride: u32,
+    descending: u32,
+    _pad: u32,
+};
+
+// dispatch arguments for the indirect sort, followed by the number of visible points
+// and the number of keys per histogram block of the sorter that created the sort buffers
+struct DepthDispatch {
+    x: u32,
+    y: u32,
+    z: u32,
+    visible: u32,
+    histo_block_kvs: u32,
+};
+
+@group(0) @binding(4)
+var<storage, read> depth_info: DepthInfo;
+// quantized depth of every point
+@group(0) @binding(7)
+var<storage, read_write> point_keys: array<u32>;
+
+// depth_flags
+@group(0) @binding(3)
+var<storage, read> positions: array<f32>;
+@group(0) @binding(6)
+var<storage, read_write> visible_flags: array<u32>;
+
+// depth_scatter
+@group(0) @binding(0)
+var<storage, read_write> state: SorterState;
+@group(0) @binding(1)
+var<storage, read_write> keys: array<u32>;
+@group(0) @binding(2)
+var<storage, read_write> values: array<u32>;
+@group(0) @binding(5)
+var<storage, read_write> dispatch: DepthDispatch;
+@group(0) @binding(8)
+var<storage, read> slots: array<u32>;
+
+// largest f32 below 2^32, the depth is quantized to the full range of u32
+const MAX_KEY: f32 = 4294967040.0;
+
+@compute @workgroup_size(depth_wg_size)
+fn depth_flags(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let num_positions = depth_info.num_positions;
+    let line_size = nwg.x * depth_wg_size;
+    for (var i = gid.x; i < num_positions; i += line_size) {
+        let base = depth_info.offset + i * depth_info.stride;
+        let position = vec4<f32>(positions[base], positions[base + 1u], positions[base + 2u], 1.0);
+        let view_pos = depth_info.view * position;
+        let clip = depth_info.proj * view_pos;
+        // the camera looks along -z in view space
+        let depth = -view_pos.z;
+        let limit = clip.w * (1.0 + depth_info.frustum_margin);
+        let visible = depth >= depth_info.near && depth <= depth_info.far && clip.w > 0.0
+            && abs(clip.x) <= limit && abs(clip.y) <= limit;
+        let t = clamp((depth - depth_info.near) / (depth_info.far - depth_info.near), 0.0, 1.0);
+        var key = u32(t * MAX_KEY);
+        if depth_info.descending != 0u {
+            key = ~key;
+        }
+        visible_flags[i] = select(0u, 1u, visible);
+        point_keys[i] = key;
+    }
+}
+
+@compute @workgroup_size(depth_wg_size)
+fn depth_scatter(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let num_positions = depth_info.num_positions;
+    let line_size = nwg.x * depth_wg_size;
+    for (var i = gid.x; i < num_positions; i += line_size) {
+        // the slots are an inclusive sum, a point is visible if it increments the sum
+        let slot = slots[i];
+        var before = 0u;
+        if i > 0u {
+            before = slots[i - 1u];
+        }
+        if slot != before {
+            keys[slot - 1u] = point_keys[i];
+            values[slot - 1u] = i;
+        }
+    }
+
+    // the number of visible points is the last slot
+    if gid.x == 0u {
+        var visible = 0u;
+        if num_positions > 0u {
+            visible = slots[num_positions - 1u];
+        }
+        state.num_keys = visible;
+        dispatch.x = (visible + dispatch.histo_block_kvs - 1u) / dispatch.histo_block_kvs;
+        dispatch.y = 1u;
+        dispatch.z = 1u;
+        dispatch.visible = visible;
+    }
+}
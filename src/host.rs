@@ -0,0 +1,114 @@
+/*
+    Convenience api for sorting key-value pairs stored in host memory.
+
+    GPUSorter::sort_slices uploads the keys and values, sorts them and downloads the result.
+    The sort buffers and the staging buffer used for downloading are cached in the sorter and reused
+    by later calls as long as they are large enough.
+*/
+
+use std::{mem, num::NonZeroU32, sync::Mutex};
+
+use crate::{
+    named_label,
+    typed::{KeyKind, SortKey, SortValue},
+    GPUSorter, SortBuffers,
+};
+
+/// Buffers reused by [GPUSorter::sort_slices]
+pub(crate) struct HostSortCache {
+    /// typed sort buffers, cast to the default types
+    sort_buffers: SortBuffers,
+    /// key kind and value words the sort buffers were created for
+    types: (KeyKind, u32),
+    /// keys followed by values, mappable for reading
+    staging_buffer: wgpu::Buffer,
+}
+
+/// cache slot of a sorter, empty until the first call of [GPUSorter::sort_slices]
+pub(crate) type HostSortCacheSlot = Mutex<Option<HostSortCache>>;
+
+impl HostSortCache {
+    fn new<K: SortKey, V: SortValue>(sorter: &GPUSorter, device: &wgpu::Device, length: NonZeroU32) -> Self {
+        let sort_buffers = sorter.create_typed_sort_buffers::<K, V>(device, length);
+        let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(sorter.name(), "radix sort staging buffer")),
+            size: length.get() as u64 * (mem::size_of::<K>() + mem::size_of::<V>()) as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            sort_buffers: sort_buffers.cast(),
+            types: (K::KIND, V::WORDS),
+            staging_buffer,
+        }
+    }
+}
+
+impl GPUSorter {
+    /// Sorts the key-value pairs and returns the sorted keys and values.
+    ///
+    /// The sort buffers and staging buffers are cached and reused by the next call
+    /// if it sorts at most as many pairs of the same types. The function waits for the gpu to finish.
+    pub async fn sort_slices<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        keys: &[K],
+        values: &[V],
+    ) -> (Vec<K>, Vec<V>) {
+        assert_eq!(keys.len(), values.len(), "number of keys and values differ");
+        let Some(length) = u32::try_from(keys.len()).ok().and_then(NonZeroU32::new) else {
+            assert!(keys.is_empty(), "number of key-value pairs exceeds u32::MAX");
+            return (Vec::new(), Vec::new());
+        };
+
+        // the cache is taken out of the sorter while in use, concurrent calls create their own buffers
+        let cached = self.host_cache.lock().unwrap().take();
+        let cache = match cached {
+            Some(cache) if cache.types == (K::KIND, V::WORDS) && cache.sort_buffers.len() >= length.get() => cache,
+            _ => HostSortCache::new::<K, V>(self, device, length),
+        };
+
+        let sort_buffers: SortBuffers<K, V> = cache.sort_buffers.cast();
+        let keys_bytes = length.get() as u64 * mem::size_of::<K>() as u64;
+        let values_bytes = length.get() as u64 * mem::size_of::<V>() as u64;
+        sort_buffers.write_keys(queue, keys);
+        sort_buffers.write_values(queue, values);
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some(&named_label(self.name(), "GPUSorter sort_slices")),
+        });
+        self.sort(&mut encoder, queue, &sort_buffers, Some(length.get()));
+        encoder.copy_buffer_to_buffer(sort_buffers.keys(), 0, &cache.staging_buffer, 0, keys_bytes);
+        encoder.copy_buffer_to_buffer(
+            sort_buffers.values(),
+            0,
+            &cache.staging_buffer,
+            keys_bytes,
+            values_bytes,
+        );
+        queue.submit([encoder.finish()]);
+
+        let buffer_slice = cache.staging_buffer.slice(..keys_bytes + values_bytes);
+        let (tx, rx) = futures_intrusive::channel::shared::oneshot_channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| tx.send(result).unwrap());
+        device.poll(wgpu::Maintain::Wait);
+        rx.receive().await.unwrap().unwrap();
+
+        let (keys_sorted, values_sorted) = {
+            let data = buffer_slice.get_mapped_range();
+            let (keys_data, values_data) = data.split_at(keys_bytes as usize);
+            (
+                bytemuck::cast_slice(keys_data).to_vec(),
+                bytemuck::cast_slice(values_data).to_vec(),
+            )
+        };
+        cache.staging_buffer.unmap();
+
+        *self.host_cache.lock().unwrap() = Some(HostSortCache {
+            sort_buffers: sort_buffers.cast(),
+            ..cache
+        });
+        (keys_sorted, values_sorted)
+    }
+}
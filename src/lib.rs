@@ -6,16 +6,40 @@
     The gpu radix sort implemented here is a re-implementation of the Vulkan radix sort found in the fuchsia repos: https://fuchsia.googlesource.com/fuchsia/+/refs/heads/main/src/graphics/lib/compute/radix_sort/
     Currently only the sorting for 32-bit key-value pairs is implemented
 
+    The sort is stable: the scatter passes rank equal digits in the order of their positions
+    and the single workgroup sort breaks ties with the original index (see local_sort.wgsl).
+
     All shaders can be found in radix_sort.wgsl
 */
 
 use std::{
+    marker::PhantomData,
     mem,
     num::{NonZeroU32, NonZeroU64},
+    sync::OnceLock,
 };
+pub mod chunked;
+pub mod compact;
+pub mod cpu;
+pub mod depth;
+mod host;
+pub mod permute;
+pub mod profiling;
+pub mod ranges;
+pub mod reduce;
+pub mod scan;
+pub mod segmented;
+pub mod select;
+pub mod simulator;
+pub mod spatial;
+pub mod tuning;
+pub mod typed;
 pub mod utils;
+pub mod verify;
 
 use bytemuck::bytes_of;
+use profiling::{SortPhase, SortProfiler};
+use typed::{RecordTarget, SortKey, SortValue, TypedBuffers, TypedDispatch, TypedPipelines};
 use wgpu::{util::DeviceExt, ComputePassDescriptor};
 
 // IMPORTANT: the following constants have to be synced with the numbers in radix_sort.wgsl
@@ -23,22 +47,20 @@ use wgpu::{util::DeviceExt, ComputePassDescriptor};
 /// workgroup size of histogram shader
 const HISTOGRAM_WG_SIZE: u32 = 256;
 
-/// one thread operates on 2 prefixes at the same time
-const PREFIX_WG_SIZE: u32 = 1 << 7;
-
 /// scatter compute shader work group size
 const SCATTER_WG_SIZE: u32 = 1 << 8;
 
-/// we sort 8 bits per pass
+/// we sort 8 bits per pass by default, see [GPUSorter::radix_log2]
 const RS_RADIX_LOG2: u32 = 8;
 
-/// 256 entries into the radix table
-const RS_RADIX_SIZE: u32 = 1 << RS_RADIX_LOG2;
+/// smallest supported digit width, every scatter pass sorts at least 4 bits
+const RS_RADIX_LOG2_MIN: u32 = 4;
 
 /// number of bytes our keys and values have
-const RS_KEYVAL_SIZE: u32 = 32 / RS_RADIX_LOG2;
+pub(crate) const RS_KEYVAL_SIZE: u32 = 32 / RS_RADIX_LOG2;
 
-/// TODO describe me
+/// number of keys loaded by every invocation of the histogram and scatter shaders by default,
+/// see [GPUSorter::block_rows]. More rows do not fit into the workgroup storage
 const RS_HISTOGRAM_BLOCK_ROWS: u32 = 15;
 
 /// DO NOT CHANGE, shader assume this!!!
@@ -47,7 +69,8 @@ const RS_SCATTER_BLOCK_ROWS: u32 = RS_HISTOGRAM_BLOCK_ROWS;
 /// number of elements scattered by one work group
 const SCATTER_BLOCK_KVS: u32 = HISTOGRAM_WG_SIZE * RS_SCATTER_BLOCK_ROWS;
 
-/// number of elements scattered by one work group
+/// number of elements scattered by one work group of a sorter created with [GPUSorter::new],
+/// see [GPUSorter::histo_block_kvs]
 pub const HISTO_BLOCK_KVS: u32 = HISTOGRAM_WG_SIZE * RS_HISTOGRAM_BLOCK_ROWS;
 
 /// bytes per value
@@ -58,26 +81,105 @@ const BYTES_PER_PAYLOAD_ELEM: u32 = 4;
 /// we sort 8 bits per pass so 4 passes are required for a 32 bit value
 const NUM_PASSES: u32 = BYTES_PER_PAYLOAD_ELEM;
 
+/// number of scatter passes of the smallest digit width
+const MAX_NUM_PASSES: u32 = 32 / RS_RADIX_LOG2_MIN;
+
+/// workgroup size of the local sort shader (local_sort.wgsl)
+const LOCAL_SORT_WG_SIZE: u32 = 256;
+
+/// maximum number of pairs that can be sorted by a single workgroup in shared memory
+/// keys and indices take 8 bytes per pair, which keeps the local sort within the 16384 bytes
+/// of workgroup storage guaranteed by WebGPU
+pub const LOCAL_SORT_KVS: u32 = 1024;
+
+/// maximum number of workgroups per dimension guaranteed by WebGPU.
+/// Shaders processing more elements than fit into one dispatch loop over them
+pub(crate) const MAX_WORKGROUPS: u32 = 65535;
+
+/// error bit in [SorterState] set if the lookback of a scatter pass waited longer than
+/// the limit set with [SortBuffers::set_lookback_limit]. The sorted keys and values are invalid.
+pub const SORT_ERROR_LOOKBACK_TIMEOUT: u32 = 1;
+
+/// the status of a partition of the decoupled lookback is stored in the two highest bits of the partition word,
+/// the lower bits contain the published value
+const PARTITION_STATUS_SHIFT: u32 = 30;
+const PARTITION_STATUS_INVALID: u32 = 0;
+const PARTITION_STATUS_REDUCTION: u32 = 1;
+const PARTITION_STATUS_PREFIX: u32 = 2;
+
 
 /// Sorting pipeline. It can be used to sort key-value pairs stored in [SortBuffers]
+///
+/// All sorts are stable: pairs with equal keys keep their relative order.
+/// This holds for direct and indirect sorts, partial sorts (`sort_first_n`), sorts recorded into a pass,
+/// the single workgroup sort and all typed keys. Subgroup sizes that break the order are rejected by
+/// [utils::guess_workgroup_size].
 pub struct GPUSorter {
     zero_p: wgpu::ComputePipeline,
     histogram_p: wgpu::ComputePipeline,
     prefix_p: wgpu::ComputePipeline,
     scatter_even_p: wgpu::ComputePipeline,
     scatter_odd_p: wgpu::ComputePipeline,
+    zero_batched_p: wgpu::ComputePipeline,
+    histogram_batched_p: wgpu::ComputePipeline,
+    prefix_batched_p: wgpu::ComputePipeline,
+    scatter_even_batched_p: wgpu::ComputePipeline,
+    scatter_odd_batched_p: wgpu::ComputePipeline,
+    local_sort_p: wgpu::ComputePipeline,
+    layout: SortLayout,
+    small_sort_threshold: u32,
+    host_cache: host::HostSortCacheSlot,
+    typed_p: TypedPipelines,
+    // only created once top k or order statistics buffers are created, most sorters never select
+    select_p: OnceLock<select::SelectPipelines>,
+    profiler: Option<SortProfiler>,
+    name: Option<String>,
 }
 
 impl GPUSorter {
+    /// Creates a sorter with [HISTO_BLOCK_KVS] keys per block and 8 bit digits,
+    /// [GPUSorter::from_config] creates sorters with other block rows and digit widths.
     pub fn new(device: &wgpu::Device, subgroup_size: u32) -> Self {
+        Self::create(device, subgroup_size, SortLayout::DEFAULT, None)
+    }
+
+    /// Creates a sorter with a name that is added to the labels of all its pipelines,
+    /// buffers, bind groups and passes, e.g. to find the sort in a frame capture.
+    pub fn new_named(device: &wgpu::Device, subgroup_size: u32, name: &str) -> Self {
+        Self::create(device, subgroup_size, SortLayout::DEFAULT, Some(name.to_string()))
+    }
+
+    /// Creates a sorter whose first scatter block never publishes its partition, so the lookback of
+    /// every other block times out if it is limited (see [SortBuffers::set_lookback_limit]).
+    /// Only used for testing the timeout.
+    #[cfg(test)]
+    fn new_unpublished_first_partition(device: &wgpu::Device, subgroup_size: u32) -> Self {
+        Self::create_with_first_partition(device, subgroup_size, SortLayout::DEFAULT, None, false)
+    }
+
+    fn create(device: &wgpu::Device, subgroup_size: u32, layout: SortLayout, name: Option<String>) -> Self {
+        Self::create_with_first_partition(device, subgroup_size, layout, name, true)
+    }
+
+    fn create_with_first_partition(
+        device: &wgpu::Device,
+        subgroup_size: u32,
+        layout: SortLayout,
+        name: Option<String>,
+        publish_first_partition: bool,
+    ) -> Self {
+        let label = |label: &str| named_label(name.as_deref(), label);
+        let rs_radix_size = layout.radix_size();
+        // one thread operates on 2 prefixes at the same time
+        let prefix_wg_size = rs_radix_size / 2;
         // special variables for scatter shade
         let histogram_sg_size = subgroup_size;
-        let rs_sweep_0_size = RS_RADIX_SIZE / histogram_sg_size;
+        let rs_sweep_0_size = rs_radix_size / histogram_sg_size;
         let rs_sweep_1_size = rs_sweep_0_size / histogram_sg_size;
         let rs_sweep_2_size = rs_sweep_1_size / histogram_sg_size;
         let rs_sweep_size = rs_sweep_0_size + rs_sweep_1_size + rs_sweep_2_size;
-        let _rs_smem_phase_1 = RS_RADIX_SIZE + RS_RADIX_SIZE + rs_sweep_size;
-        let rs_smem_phase_2 = RS_RADIX_SIZE + RS_SCATTER_BLOCK_ROWS * SCATTER_WG_SIZE;
+        let _rs_smem_phase_1 = rs_radix_size + rs_radix_size + rs_sweep_size;
+        let rs_smem_phase_2 = rs_radix_size + layout.block_rows * SCATTER_WG_SIZE;
         // rs_smem_phase_2 will always be larger, so always use phase2
         let rs_mem_dwords = rs_smem_phase_2;
         let rs_mem_sweep_0_offset = 0;
@@ -88,7 +190,7 @@ impl GPUSorter {
 
         let pipeline_layout: wgpu::PipelineLayout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("radix sort pipeline layout"),
+                label: Some(&label("radix sort pipeline layout")),
                 bind_group_layouts: &[&bind_group_layout],
                 push_constant_ranges: &[],
             });
@@ -107,77 +209,171 @@ impl GPUSorter {
             const rs_mem_dwords: u32 = {:}u;\n\
             const rs_mem_sweep_0_offset: u32 = {:}u;\n\
             const rs_mem_sweep_1_offset: u32 = {:}u;\n\
-            const rs_mem_sweep_2_offset: u32 = {:}u;\n{:}",
+            const rs_mem_sweep_2_offset: u32 = {:}u;\n\
+            const rs_publish_first_partition: bool = {:};\n{:}{:}",
             histogram_sg_size,
             HISTOGRAM_WG_SIZE,
-            RS_RADIX_LOG2,
-            RS_RADIX_SIZE,
-            RS_KEYVAL_SIZE,
-            RS_HISTOGRAM_BLOCK_ROWS,
-            RS_SCATTER_BLOCK_ROWS,
+            layout.radix_log2,
+            rs_radix_size,
+            layout.num_passes(),
+            layout.block_rows,
+            layout.block_rows,
             rs_mem_dwords,
             rs_mem_sweep_0_offset,
             rs_mem_sweep_1_offset,
             rs_mem_sweep_2_offset,
+            publish_first_partition,
+            partition_status_shader_constants(),
             raw_shader
         );
+        // small inputs are sorted in shared memory by the local sort
+        let shader_w_const =
+            shader_w_const + &local_sort_shader_constants() + include_str!("local_sort.wgsl");
         let shader_code = shader_w_const
             .replace(
                 "{histogram_wg_size}",
                 HISTOGRAM_WG_SIZE.to_string().as_str(),
             )
-            .replace("{prefix_wg_size}", PREFIX_WG_SIZE.to_string().as_str())
+            .replace("{prefix_wg_size}", prefix_wg_size.to_string().as_str())
             .replace("{scatter_wg_size}", SCATTER_WG_SIZE.to_string().as_str());
 
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Radix sort shader"),
+            label: Some(&label("Radix sort shader")),
             source: wgpu::ShaderSource::Wgsl(shader_code.into()),
         });
         let zero_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("Zero the histograms"),
+            label: Some(&label("Zero the histograms")),
             layout: Some(&pipeline_layout),
             module: &shader,
             entry_point: "zero_histograms",
             compilation_options: Default::default(),
         });
         let histogram_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("calculate_histogram"),
+            label: Some(&label("calculate_histogram")),
             layout: Some(&pipeline_layout),
             module: &shader,
             entry_point: "calculate_histogram",
             compilation_options: Default::default(),
         });
         let prefix_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("prefix_histogram"),
+            label: Some(&label("prefix_histogram")),
             layout: Some(&pipeline_layout),
             module: &shader,
             entry_point: "prefix_histogram",
             compilation_options: Default::default(),
         });
         let scatter_even_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("scatter_even"),
+            label: Some(&label("scatter_even")),
             layout: Some(&pipeline_layout),
             module: &shader,
             entry_point: "scatter_even",
             compilation_options: Default::default(),
         });
         let scatter_odd_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("scatter_odd"),
+            label: Some(&label("scatter_odd")),
             layout: Some(&pipeline_layout),
             module: &shader,
             entry_point: "scatter_odd",
             compilation_options: Default::default(),
         });
 
+        // pipelines for batched sorting
+        let batched_pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&label(entry_point)),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        let zero_batched_p = batched_pipeline("zero_histograms_batched");
+        let histogram_batched_p = batched_pipeline("calculate_histogram_batched");
+        let prefix_batched_p = batched_pipeline("prefix_histogram_batched");
+        let scatter_even_batched_p = batched_pipeline("scatter_even_batched");
+        let scatter_odd_batched_p = batched_pipeline("scatter_odd_batched");
+
+        let local_sort_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(&label("sort_single_workgroup")),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "sort_single_workgroup",
+            compilation_options: Default::default(),
+        });
+
         return Self {
             zero_p,
             histogram_p,
             prefix_p,
             scatter_even_p,
             scatter_odd_p,
+            zero_batched_p,
+            histogram_batched_p,
+            prefix_batched_p,
+            scatter_even_batched_p,
+            scatter_odd_batched_p,
+            local_sort_p,
+            layout,
+            small_sort_threshold: LOCAL_SORT_KVS,
+            host_cache: Default::default(),
+            typed_p: TypedPipelines::new(device, name.as_deref()),
+            select_p: OnceLock::new(),
+            profiler: None,
+            name,
         };
     }
 
+    /// Inputs with at most this many keys are sorted by [GPUSorter::sort] in a single workgroup
+    /// instead of running the radix sort passes.
+    pub fn small_sort_threshold(&self) -> u32 {
+        self.small_sort_threshold
+    }
+
+    /// Sets the number of keys up to which [GPUSorter::sort] sorts in a single workgroup.
+    /// The threshold is clamped to [LOCAL_SORT_KVS]. A threshold of 0 always uses the radix sort passes.
+    pub fn set_small_sort_threshold(&mut self, threshold: u32) {
+        self.small_sort_threshold = threshold.min(LOCAL_SORT_KVS);
+    }
+
+    /// number of keys loaded by every invocation of the histogram and scatter shaders
+    pub fn block_rows(&self) -> u32 {
+        self.layout.block_rows
+    }
+
+    /// number of bits of the keys sorted by every scatter pass
+    pub fn radix_log2(&self) -> u32 {
+        self.layout.radix_log2
+    }
+
+    /// number of scatter passes, one per digit of the keys
+    pub fn num_passes(&self) -> u32 {
+        self.layout.num_passes()
+    }
+
+    /// block rows and digit width, e.g. to simulate the sort with [simulator::RadixSortSimulator]
+    pub fn layout(&self) -> SortLayout {
+        self.layout
+    }
+
+    /// Number of keys per histogram block.
+    /// Indirect sorts dispatch one workgroup per block (see [GPUSorter::sort_indirect]).
+    pub fn histo_block_kvs(&self) -> u32 {
+        self.layout.block_kvs()
+    }
+
+    /// name given with [GPUSorter::new_named]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // name of sort buffers created by this sorter, combines the names of the sorter and the buffers
+    fn buffers_name(&self, name: Option<&str>) -> Option<String> {
+        match (self.name.as_deref(), name) {
+            (Some(sorter), Some(buffers)) => Some(format!("{sorter}/{buffers}")),
+            (sorter, buffers) => sorter.or(buffers).map(str::to_string),
+        }
+    }
+
     fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
         return device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("radix sort bind group layout"),
@@ -249,15 +445,17 @@ impl GPUSorter {
     }
 
     fn create_keyval_buffers(
+        &self,
         device: &wgpu::Device,
         length: u32,
+        name: Option<&str>,
     ) -> (wgpu::Buffer, wgpu::Buffer, wgpu::Buffer, wgpu::Buffer) {
         // add padding so that our buffer size is a multiple of keys_per_workgroup
-        let count_ru_histo = keys_buffer_size(length) * RS_KEYVAL_SIZE;
+        let count_ru_histo = self.layout.keys_buffer_size(length) * RS_KEYVAL_SIZE;
 
         // creating the two needed buffers for sorting
         let keys = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("radix sort keys buffer"),
+            label: Some(&named_label(name, "radix sort keys buffer")),
             size: (count_ru_histo * BYTES_PER_PAYLOAD_ELEM) as u64,
             usage: wgpu::BufferUsages::STORAGE
                 | wgpu::BufferUsages::COPY_DST
@@ -267,15 +465,15 @@ impl GPUSorter {
 
         // auxiliary buffer for keys
         let keys_aux = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("radix sort keys auxiliary buffer"),
+            label: Some(&named_label(name, "radix sort keys auxiliary buffer")),
             size: (count_ru_histo * BYTES_PER_PAYLOAD_ELEM) as u64,
-            usage: wgpu::BufferUsages::STORAGE,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
         let payload_size = length * BYTES_PER_PAYLOAD_ELEM; // make sure that we have at least 1 byte of data;
         let payload = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("radix sort payload buffer"),
+            label: Some(&named_label(name, "radix sort payload buffer")),
             size: payload_size as u64,
             usage: wgpu::BufferUsages::STORAGE
                 | wgpu::BufferUsages::COPY_DST
@@ -284,9 +482,9 @@ impl GPUSorter {
         });
         // auxiliary buffer for payload/values
         let payload_aux = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("radix sort payload auxiliary buffer"),
+            label: Some(&named_label(name, "radix sort payload auxiliary buffer")),
             size: payload_size as u64,
-            usage: wgpu::BufferUsages::STORAGE,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
         return (keys, keys_aux, payload, payload_aux);
@@ -295,10 +493,15 @@ impl GPUSorter {
     // calculates and allocates a buffer that is sufficient for holding all needed information for
     // sorting. This includes the histograms and the temporary scatter buffer
     // @return: tuple containing [internal memory buffer (should be bound at shader binding 1, count_ru_histo (padded size needed for the keyval buffer)]
-    fn create_internal_mem_buffer(&self, device: &wgpu::Device, length: u32) -> wgpu::Buffer {
+    fn create_internal_mem_buffer(
+        &self,
+        device: &wgpu::Device,
+        length: u32,
+        name: Option<&str>,
+    ) -> wgpu::Buffer {
         // currently only a few different key bits are supported, maybe has to be extended
 
-        // The "internal" memory map looks like this:
+        // The "internal" memory map looks like this (see simulator::RadixSortSimulator::histogram_offset and partition_offset):
         //   +---------------------------------+ <-- 0
         //   | histograms[keyval_size]         |
         //   +---------------------------------+ <-- keyval_size                           * histo_size
@@ -307,176 +510,407 @@ impl GPUSorter {
         //   | workgroup_ids[keyval_size]      |
         //   +---------------------------------+ <-- (keyval_size + scatter_blocks_ru - 1) * histo_size + workgroup_ids_size
 
-        let scatter_blocks_ru = scatter_blocks_ru(length);
-
-        let histo_size = RS_RADIX_SIZE * std::mem::size_of::<u32>() as u32;
-
-        let internal_size = (RS_KEYVAL_SIZE + scatter_blocks_ru) * histo_size; // +1 safety
+        let internal_size = self.layout.internal_mem_size(length);
 
         let buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Internal radix sort buffer"),
-            size: internal_size as u64,
-            usage: wgpu::BufferUsages::STORAGE,
+            label: Some(&named_label(name, "Internal radix sort buffer")),
+            size: internal_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
         return buffer;
     }
 
-    fn general_info_data(length: u32) -> SorterState {
+    // every row of a batched sort has its own histograms and partitions
+    // (see the batched sorting section in radix_sort.wgsl)
+    fn batched_internal_mem_size(&self, batch: u32, row_len: u32) -> u64 {
+        batch as u64 * self.layout.internal_mem_size(row_len)
+    }
+
+    fn general_info_data(length: u32, layout: SortLayout) -> SorterState {
         SorterState {
             num_keys: length,
-            padded_size: keys_buffer_size(length),
+            padded_size: layout.keys_buffer_size(length),
             even_pass: 0,
             odd_pass: 0,
+            lookback_limit: 0,
+            error_flags: 0,
+        }
+    }
+
+    /// Records the zeroing of the histograms and the padding of the keys buffer into `pass`.
+    ///
+    /// First phase of the radix sort, the number of keys is read from [SortBuffers::state_buffer].
+    /// `num_keys` only determines the number of workgroups.
+    ///
+    /// The phase functions work on the raw u32 keys in [SortBuffers::keys] and do not transform typed keys,
+    /// use [GPUSorter::sort_into_pass] to sort typed buffers.
+    pub fn record_zero_histograms<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        num_keys: u32,
+    ) {
+        pass.push_debug_group(&named_label(sort_buffers.name(), "zeroing the histogram"));
+        pass.set_pipeline(&self.zero_p);
+        pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+        pass.dispatch_workgroups(self.layout.histo_blocks_ru(num_keys), 1, 1);
+        pass.pop_debug_group();
+    }
+
+    /// Indirect version of [GPUSorter::record_zero_histograms], see [GPUSorter::sort_indirect] for the dispatch buffer.
+    pub fn record_zero_histograms_indirect<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        dispatch_buffer: &'a wgpu::Buffer,
+    ) {
+        pass.push_debug_group(&named_label(sort_buffers.name(), "zeroing the histogram"));
+        pass.set_pipeline(&self.zero_p);
+        pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+        pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
+        pass.pop_debug_group();
+    }
+
+    /// Records the calculation of the histograms of all passes into `pass`.
+    /// Has to be recorded after [GPUSorter::record_zero_histograms].
+    pub fn record_calculate_histogram<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        num_keys: u32,
+    ) {
+        pass.push_debug_group(&named_label(sort_buffers.name(), "calculate histogram"));
+        pass.set_pipeline(&self.histogram_p);
+        pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+        pass.dispatch_workgroups(self.layout.histo_blocks_ru(num_keys), 1, 1);
+        pass.pop_debug_group();
+    }
+
+    /// Indirect version of [GPUSorter::record_calculate_histogram]
+    pub fn record_calculate_histogram_indirect<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        dispatch_buffer: &'a wgpu::Buffer,
+    ) {
+        pass.push_debug_group(&named_label(sort_buffers.name(), "calculate histogram"));
+        pass.set_pipeline(&self.histogram_p);
+        pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+        pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
+        pass.pop_debug_group();
+    }
+
+    /// Records the prefix sum over the histograms into `pass`.
+    /// Has to be recorded after [GPUSorter::record_calculate_histogram].
+    ///
+    /// There does not exist an indirect version as the number of prefixes is determined by the amount of passes.
+    pub fn record_prefix_histogram<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+    ) {
+        pass.push_debug_group(&named_label(sort_buffers.name(), "prefix histogram"));
+        pass.set_pipeline(&self.prefix_p);
+        pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+        pass.dispatch_workgroups(self.layout.num_passes(), 1, 1);
+        pass.pop_debug_group();
+    }
+
+    /// Records all scatter passes (see [GPUSorter::num_passes]) into `pass`, afterwards the keys and values are sorted.
+    /// Has to be recorded after [GPUSorter::record_prefix_histogram].
+    pub fn record_scatter_keys<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        num_keys: u32,
+    ) {
+        pass.push_debug_group(&named_label(sort_buffers.name(), "Scatter keyvals"));
+        for radix_pass in 0..self.layout.num_passes() {
+            self.record_scatter_pass(pass, sort_buffers, radix_pass, num_keys);
+        }
+        pass.pop_debug_group();
+    }
+
+    /// Records a single scatter pass into `pass`, the passes have to be recorded in order
+    /// from 0 to [GPUSorter::num_passes] - 1.
+    /// Useful to inspect the buffers after each pass, [GPUSorter::record_scatter_keys] records all of them.
+    pub fn record_scatter_pass<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        radix_pass: u32,
+        num_keys: u32,
+    ) {
+        assert!(radix_pass < self.layout.num_passes(), "radix pass {radix_pass} out of range");
+        pass.insert_debug_marker(&format!("scatter pass {radix_pass}"));
+        pass.set_pipeline(self.scatter_pipeline(radix_pass));
+        pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+        pass.dispatch_workgroups(self.layout.scatter_blocks_ru(num_keys), 1, 1);
+    }
+
+    /// Indirect version of [GPUSorter::record_scatter_keys]
+    pub fn record_scatter_keys_indirect<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        dispatch_buffer: &'a wgpu::Buffer,
+    ) {
+        pass.push_debug_group(&named_label(sort_buffers.name(), "Scatter keyvals"));
+        pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+        for radix_pass in 0..self.layout.num_passes() {
+            pass.insert_debug_marker(&format!("scatter pass {radix_pass}"));
+            pass.set_pipeline(self.scatter_pipeline(radix_pass));
+            pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
         }
+        pass.pop_debug_group();
+    }
+
+    /// Records the sort of at most [LOCAL_SORT_KVS] keys by a single workgroup into `pass`.
+    /// Replaces all other phases for small inputs.
+    pub fn record_sort_single_workgroup<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+    ) {
+        pass.push_debug_group(&named_label(sort_buffers.name(), "sort single workgroup"));
+        pass.set_pipeline(&self.local_sort_p);
+        pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+        pass.dispatch_workgroups(1, 1, 1);
+        pass.pop_debug_group();
     }
 
-    fn record_calculate_histogram(
+    // all rows are sorted with the same dispatches, the row is the y coordinate of the workgroup
+    fn record_sort_batched(
         &self,
-        bind_group: &wgpu::BindGroup,
-        length: u32,
+        sort_buffers: &SortBuffers,
+        batch: u32,
+        row_len: u32,
         encoder: &mut wgpu::CommandEncoder,
     ) {
-        // as we only deal with 32 bit float values always 4 passes are conducted
-        let hist_blocks_ru = histo_blocks_ru(length);
-
+        let bind_group = &sort_buffers.bind_group;
+        let hist_blocks_ru = self.layout.histo_blocks_ru(row_len);
+        let scatter_blocks_ru = self.layout.scatter_blocks_ru(row_len);
         {
             let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: Some("zeroing the histogram"),
+                label: Some(&named_label(sort_buffers.name(), "zeroing the batched histograms")),
                 timestamp_writes: None,
             });
 
-            pass.set_pipeline(&self.zero_p);
+            pass.set_pipeline(&self.zero_batched_p);
             pass.set_bind_group(0, bind_group, &[]);
-            pass.dispatch_workgroups(hist_blocks_ru as u32, 1, 1);
+            pass.dispatch_workgroups(hist_blocks_ru, batch, 1);
         }
-
         {
             let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: Some("calculate histogram"),
+                label: Some(&named_label(sort_buffers.name(), "calculate batched histograms")),
                 timestamp_writes: None,
             });
 
-            pass.set_pipeline(&self.histogram_p);
+            pass.set_pipeline(&self.histogram_batched_p);
             pass.set_bind_group(0, bind_group, &[]);
-            pass.dispatch_workgroups(hist_blocks_ru as u32, 1, 1);
+            pass.dispatch_workgroups(hist_blocks_ru, batch, 1);
         }
-    }
-
-    fn record_calculate_histogram_indirect(
-        &self,
-        bind_group: &wgpu::BindGroup,
-        dispatch_buffer: &wgpu::Buffer,
-        encoder: &mut wgpu::CommandEncoder,
-    ) {
         {
             let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: Some("zeroing the histogram"),
+                label: Some(&named_label(sort_buffers.name(), "prefix batched histograms")),
                 timestamp_writes: None,
             });
 
-            pass.set_pipeline(&self.zero_p);
+            pass.set_pipeline(&self.prefix_batched_p);
             pass.set_bind_group(0, bind_group, &[]);
-            pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
+            pass.dispatch_workgroups(self.layout.num_passes(), batch, 1);
         }
-
         {
             let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: Some("calculate histogram"),
+                label: Some(&named_label(sort_buffers.name(), "scatter batched keyvals")),
                 timestamp_writes: None,
             });
 
-            pass.set_pipeline(&self.histogram_p);
             pass.set_bind_group(0, bind_group, &[]);
-            pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
+            for _ in 0..self.layout.num_passes() / 2 {
+                pass.set_pipeline(&self.scatter_even_batched_p);
+                pass.dispatch_workgroups(scatter_blocks_ru, batch, 1);
+
+                pass.set_pipeline(&self.scatter_odd_batched_p);
+                pass.dispatch_workgroups(scatter_blocks_ru, batch, 1);
+            }
         }
     }
 
-    // There does not exist an indirect histogram dispatch as the number of prefixes is determined by the amount of passes
-    fn record_prefix_histogram(
+
+    /// Writes sort commands to command encoder.
+    /// If sort_first_n is not none one the first n elements are sorted
+    /// otherwise everything is sorted.
+    ///
+    /// If at most [GPUSorter::small_sort_threshold] elements are sorted,
+    /// they are sorted by a single workgroup in shared memory with one dispatch.
+    ///
+    /// Typed keys and values are transformed or gathered before and after sorting (see [typed]).
+    ///
+    /// If profiling is enabled (see [GPUSorter::enable_profiling]) every phase writes timestamps.
+    ///
+    /// **IMPORTANT**: if less than the whole buffer is sorted the rest of the keys buffer will be be corrupted
+    pub fn sort<K: SortKey, V: SortValue>(
         &self,
-        bind_group: &wgpu::BindGroup,
         encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, V>,
+        sort_first_n: Option<u32>,
     ) {
-        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: Some("prefix histogram"),
-            timestamp_writes: None,
-        });
+        self.check_layout(sort_buffers);
+        let num_elements = sort_first_n.unwrap_or(sort_buffers.len());
 
-        pass.set_pipeline(&self.prefix_p);
-        pass.set_bind_group(0, &bind_group, &[]);
-        pass.dispatch_workgroups(NUM_PASSES as u32, 1, 1);
+        // write number of elements to buffer
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
+
+        encoder.push_debug_group(&named_label(sort_buffers.name(), "radix sort"));
+        let typed = &sort_buffers.typed;
+        let dispatch = TypedDispatch::Direct(num_elements);
+        // only the last radix sort is profiled
+        let profile = self.profiler.is_some();
+        self.typed_p.record_prepare(typed, &dispatch, &mut RecordTarget::Encoder(encoder, sort_buffers.name()));
+        self.record_sort_keys(sort_buffers, num_elements, profile && !typed.two_pass(), encoder);
+        if typed.two_pass() {
+            self.typed_p.record_gather_high_keys(typed, &dispatch, &mut RecordTarget::Encoder(encoder, sort_buffers.name()));
+            self.record_sort_keys(sort_buffers, num_elements, profile, encoder);
+        }
+        self.typed_p.record_finish(typed, &dispatch, &mut RecordTarget::Encoder(encoder, sort_buffers.name()));
+        if let Some(profiler) = &self.profiler {
+            profiler.resolve(encoder, num_elements <= self.small_sort_threshold, self.layout.num_passes());
+        }
+        encoder.pop_debug_group();
     }
 
-    fn record_scatter_keys(
-        &self,
-        bind_group: &wgpu::BindGroup,
-        length: u32,
-        encoder: &mut wgpu::CommandEncoder,
+    /// Records all sort commands into a compute pass provided by the caller.
+    ///
+    /// Same as [GPUSorter::sort] but without beginning any compute passes on its own.
+    /// This allows to record own dispatches (e.g. culling or key generation) before and after the sort
+    /// into the same pass. The dispatches of a pass are executed in order.
+    ///
+    /// The individual phases are not profiled as the timestamps are written per pass.
+    pub fn sort_into_pass<'a, K: SortKey, V: SortValue>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        queue: &wgpu::Queue,
+        sort_buffers: &'a SortBuffers<K, V>,
+        sort_first_n: Option<u32>,
     ) {
-        let scatter_blocks_ru = scatter_blocks_ru(length);
+        self.check_layout(sort_buffers);
+        let num_elements = sort_first_n.unwrap_or(sort_buffers.len());
 
-        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: Some("Scatter keyvals"),
-            timestamp_writes: None,
-        });
+        // write number of elements to buffer
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
 
-        pass.set_bind_group(0, bind_group, &[]);
-        pass.set_pipeline(&self.scatter_even_p);
-        pass.dispatch_workgroups(scatter_blocks_ru as u32, 1, 1);
+        pass.push_debug_group(&named_label(sort_buffers.name(), "radix sort"));
+        let typed = &sort_buffers.typed;
+        let dispatch = TypedDispatch::Direct(num_elements);
+        self.typed_p.record_prepare(typed, &dispatch, &mut RecordTarget::Pass(pass, sort_buffers.name()));
+        self.record_sort_keys_into_pass(pass, sort_buffers, num_elements);
+        if typed.two_pass() {
+            self.typed_p.record_gather_high_keys(typed, &dispatch, &mut RecordTarget::Pass(pass, sort_buffers.name()));
+            self.record_sort_keys_into_pass(pass, sort_buffers, num_elements);
+        }
+        self.typed_p.record_finish(typed, &dispatch, &mut RecordTarget::Pass(pass, sort_buffers.name()));
+        pass.pop_debug_group();
+    }
 
-        pass.set_pipeline(&self.scatter_odd_p);
-        pass.dispatch_workgroups(scatter_blocks_ru as u32, 1, 1);
+    // begins the pass of a phase, the pass writes timestamps if the phase is profiled
+    fn begin_phase_pass<'e, K, V>(
+        &self,
+        encoder: &'e mut wgpu::CommandEncoder,
+        sort_buffers: &SortBuffers<K, V>,
+        label: &str,
+        phase: SortPhase,
+        profile: bool,
+    ) -> wgpu::ComputePass<'e> {
+        encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some(&named_label(sort_buffers.name(), label)),
+            timestamp_writes: self
+                .profiler
+                .as_ref()
+                .filter(|_| profile)
+                .map(|profiler| profiler.timestamp_writes(phase)),
+        })
+    }
 
-        pass.set_pipeline(&self.scatter_even_p);
-        pass.dispatch_workgroups(scatter_blocks_ru as u32, 1, 1);
+    // the sort buffers are laid out for the block rows and digit width of the sorter that created them
+    fn check_layout<K, V>(&self, sort_buffers: &SortBuffers<K, V>) {
+        assert_eq!(
+            sort_buffers.layout, self.layout,
+            "sort buffers were created by a sorter with other block rows or digit width"
+        );
+    }
 
-        pass.set_pipeline(&self.scatter_odd_p);
-        pass.dispatch_workgroups(scatter_blocks_ru as u32, 1, 1);
+    fn scatter_pipeline(&self, radix_pass: u32) -> &wgpu::ComputePipeline {
+        if radix_pass % 2 == 1 {
+            &self.scatter_odd_p
+        } else {
+            &self.scatter_even_p
+        }
     }
 
-    fn record_scatter_keys_indirect(
+    // sorts the u32 keys with the radix sort or in a single workgroup for small inputs
+    fn record_sort_keys<K, V>(
         &self,
-        bind_group: &wgpu::BindGroup,
-        dispatch_buffer: &wgpu::Buffer,
+        sort_buffers: &SortBuffers<K, V>,
+        num_elements: u32,
+        profile: bool,
         encoder: &mut wgpu::CommandEncoder,
     ) {
-        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-            label: Some("radix sort scatter keyvals"),
-            timestamp_writes: None,
-        });
-
-        pass.set_bind_group(0, bind_group, &[]);
-        pass.set_pipeline(&self.scatter_even_p);
-        pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
-
-        pass.set_pipeline(&self.scatter_odd_p);
-        pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
-
-        pass.set_pipeline(&self.scatter_even_p);
-        pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
+        if num_elements <= self.small_sort_threshold {
+            let mut pass =
+                self.begin_phase_pass(encoder, sort_buffers, "sort single workgroup", SortPhase::SingleWorkgroup, profile);
+            self.record_sort_single_workgroup(&mut pass, sort_buffers);
+            return;
+        }
 
-        pass.set_pipeline(&self.scatter_odd_p);
-        pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
+        {
+            let mut pass =
+                self.begin_phase_pass(encoder, sort_buffers, "zeroing the histogram", SortPhase::ZeroHistograms, profile);
+            self.record_zero_histograms(&mut pass, sort_buffers, num_elements);
+        }
+        {
+            let mut pass =
+                self.begin_phase_pass(encoder, sort_buffers, "calculate histogram", SortPhase::CalculateHistogram, profile);
+            self.record_calculate_histogram(&mut pass, sort_buffers, num_elements);
+        }
+        {
+            let mut pass =
+                self.begin_phase_pass(encoder, sort_buffers, "prefix histogram", SortPhase::PrefixHistogram, profile);
+            self.record_prefix_histogram(&mut pass, sort_buffers);
+        }
+        if !profile {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some(&named_label(sort_buffers.name(), "Scatter keyvals")),
+                timestamp_writes: None,
+            });
+            self.record_scatter_keys(&mut pass, sort_buffers, num_elements);
+            return;
+        }
+        // every scatter pass is timed on its own
+        for radix_pass in 0..self.layout.num_passes() {
+            let mut pass =
+                self.begin_phase_pass(encoder, sort_buffers, "Scatter keyvals", SortPhase::Scatter(radix_pass), profile);
+            self.record_scatter_pass(&mut pass, sort_buffers, radix_pass, num_elements);
+        }
     }
 
+    fn record_sort_keys_into_pass<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        num_elements: u32,
+    ) {
+        if num_elements <= self.small_sort_threshold {
+            self.record_sort_single_workgroup(pass, sort_buffers);
+            return;
+        }
 
-    /// Writes sort commands to command encoder.
-    /// If sort_first_n is not none one the first n elements are sorted
-    /// otherwise everything is sorted.
-    ///
-    /// **IMPORTANT**: if less than the whole buffer is sorted the rest of the keys buffer will be be corrupted
-    pub fn sort(&self, encoder: &mut wgpu::CommandEncoder,queue:&wgpu::Queue, sort_buffers: &SortBuffers, sort_first_n:Option<u32>) {
-        let bind_group = &sort_buffers.bind_group;
-        let num_elements = sort_first_n.unwrap_or(sort_buffers.len());
-
-        // write number of elements to buffer
-        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
-
-
-        self.record_calculate_histogram(bind_group, num_elements, encoder);
-        self.record_prefix_histogram(bind_group, encoder);
-        self.record_scatter_keys(bind_group, num_elements, encoder);
+        self.record_zero_histograms(pass, sort_buffers, num_elements);
+        self.record_calculate_histogram(pass, sort_buffers, num_elements);
+        self.record_prefix_histogram(pass, sort_buffers);
+        self.record_scatter_keys(pass, sort_buffers, num_elements);
     }
 
     /// Initiates sorting with an indirect call.
@@ -484,42 +918,248 @@ impl GPUSorter {
     ///
     /// number of y and z workgroups must be 1 
     ///
-    /// x = (N + [HISTO_BLOCK_KVS]- 1 )/[HISTO_BLOCK_KVS], 
-    /// where N are the first N elements to be sorted
+    /// x = (N + [GPUSorter::histo_block_kvs]- 1 )/[GPUSorter::histo_block_kvs], 
+    /// where N are the first N elements to be sorted.
+    /// The number of keys per block is [HISTO_BLOCK_KVS] for sorters created with [GPUSorter::new].
     ///
     /// [SortBuffers::state_buffer] contains the number of keys that will be sorted.
     /// This is set to sort the whole buffer by default.
     ///
     /// **IMPORTANT**: if less than the whole buffer is sorted the rest of the keys buffer will most likely be corrupted. 
-    pub fn sort_indirect(
+    pub fn sort_indirect<K: SortKey, V: SortValue>(
         &self,
         encoder: &mut wgpu::CommandEncoder,
-        sort_buffers: &SortBuffers,
+        sort_buffers: &SortBuffers<K, V>,
         dispatch_buffer: &wgpu::Buffer,
     ) {
-        let bind_group = &sort_buffers.bind_group;
+        self.check_layout(sort_buffers);
+        encoder.push_debug_group(&named_label(sort_buffers.name(), "radix sort"));
+        let typed = &sort_buffers.typed;
+        let dispatch = TypedDispatch::Indirect(dispatch_buffer);
+
+        // only the last radix sort is profiled
+        let profile = self.profiler.is_some();
+        self.typed_p.record_prepare(typed, &dispatch, &mut RecordTarget::Encoder(encoder, sort_buffers.name()));
+        self.record_sort_keys_indirect(sort_buffers, dispatch_buffer, profile && !typed.two_pass(), encoder);
+        if typed.two_pass() {
+            self.typed_p.record_gather_high_keys(typed, &dispatch, &mut RecordTarget::Encoder(encoder, sort_buffers.name()));
+            self.record_sort_keys_indirect(sort_buffers, dispatch_buffer, profile, encoder);
+        }
+        self.typed_p.record_finish(typed, &dispatch, &mut RecordTarget::Encoder(encoder, sort_buffers.name()));
+        if let Some(profiler) = &self.profiler {
+            profiler.resolve(encoder, false, self.layout.num_passes());
+        }
+        encoder.pop_debug_group();
+    }
+
+    /// Records all indirect sort commands into a compute pass provided by the caller.
+    ///
+    /// Same as [GPUSorter::sort_indirect] but without beginning any compute passes on its own.
+    /// The dispatch buffer can be written by a dispatch recorded earlier into the same pass.
+    pub fn sort_indirect_into_pass<'a, K: SortKey, V: SortValue>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        dispatch_buffer: &'a wgpu::Buffer,
+    ) {
+        self.check_layout(sort_buffers);
+        pass.push_debug_group(&named_label(sort_buffers.name(), "radix sort"));
+        let typed = &sort_buffers.typed;
+        let dispatch = TypedDispatch::Indirect(dispatch_buffer);
+
+        self.typed_p.record_prepare(typed, &dispatch, &mut RecordTarget::Pass(pass, sort_buffers.name()));
+        self.record_sort_keys_indirect_into_pass(pass, sort_buffers, dispatch_buffer);
+        if typed.two_pass() {
+            self.typed_p.record_gather_high_keys(typed, &dispatch, &mut RecordTarget::Pass(pass, sort_buffers.name()));
+            self.record_sort_keys_indirect_into_pass(pass, sort_buffers, dispatch_buffer);
+        }
+        self.typed_p.record_finish(typed, &dispatch, &mut RecordTarget::Pass(pass, sort_buffers.name()));
+        pass.pop_debug_group();
+    }
+
+    fn record_sort_keys_indirect<K, V>(
+        &self,
+        sort_buffers: &SortBuffers<K, V>,
+        dispatch_buffer: &wgpu::Buffer,
+        profile: bool,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        {
+            let mut pass =
+                self.begin_phase_pass(encoder, sort_buffers, "zeroing the histogram", SortPhase::ZeroHistograms, profile);
+            self.record_zero_histograms_indirect(&mut pass, sort_buffers, dispatch_buffer);
+        }
+        {
+            let mut pass =
+                self.begin_phase_pass(encoder, sort_buffers, "calculate histogram", SortPhase::CalculateHistogram, profile);
+            self.record_calculate_histogram_indirect(&mut pass, sort_buffers, dispatch_buffer);
+        }
+        {
+            let mut pass =
+                self.begin_phase_pass(encoder, sort_buffers, "prefix histogram", SortPhase::PrefixHistogram, profile);
+            self.record_prefix_histogram(&mut pass, sort_buffers);
+        }
+        if !profile {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some(&named_label(sort_buffers.name(), "radix sort scatter keyvals")),
+                timestamp_writes: None,
+            });
+            self.record_scatter_keys_indirect(&mut pass, sort_buffers, dispatch_buffer);
+            return;
+        }
+        // every scatter pass is timed on its own
+        for radix_pass in 0..self.layout.num_passes() {
+            let mut pass = self.begin_phase_pass(encoder, sort_buffers,
+                "radix sort scatter keyvals",
+                SortPhase::Scatter(radix_pass),
+                profile,
+            );
+            pass.insert_debug_marker(&format!("scatter pass {radix_pass}"));
+            pass.set_pipeline(self.scatter_pipeline(radix_pass));
+            pass.set_bind_group(0, &sort_buffers.bind_group, &[]);
+            pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
+        }
+    }
+
+    fn record_sort_keys_indirect_into_pass<'a, K, V>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        sort_buffers: &'a SortBuffers<K, V>,
+        dispatch_buffer: &'a wgpu::Buffer,
+    ) {
+        self.record_zero_histograms_indirect(pass, sort_buffers, dispatch_buffer);
+        self.record_calculate_histogram_indirect(pass, sort_buffers, dispatch_buffer);
+        self.record_prefix_histogram(pass, sort_buffers);
+        self.record_scatter_keys_indirect(pass, sort_buffers, dispatch_buffer);
+    }
+
+    /// Writes batched sort commands to command encoder.
+    ///
+    /// The first `batch * row_len` key-value pairs are treated as `batch` contiguous rows of `row_len` pairs.
+    /// Each row is sorted independently, all rows are sorted with the same dispatches.
+    /// The rows do not need any padding.
+    ///
+    /// `batch` must not exceed the maximum number of workgroups per dimension (65535 by default)
+    /// and `sort_buffers` must be created with [GPUSorter::create_batched_sort_buffers]
+    /// for at least as many rows of the same or larger length.
+    ///
+    /// **IMPORTANT**: the state buffer is overwritten with the row length
+    pub fn sort_batched(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers,
+        batch: u32,
+        row_len: u32,
+    ) {
+        self.check_layout(sort_buffers);
+        assert!(
+            batch as u64 * row_len as u64 <= sort_buffers.len() as u64,
+            "batch of {batch} rows with {row_len} pairs does not fit into sort buffers of length {}",
+            sort_buffers.len()
+        );
+        assert!(
+            batch <= MAX_WORKGROUPS,
+            "batch of {batch} rows exceeds the maximum number of workgroups per dimension ({MAX_WORKGROUPS})"
+        );
+        assert!(
+            self.batched_internal_mem_size(batch, row_len) <= sort_buffers.internal_mem_buffer.size(),
+            "sort buffers were not created for batched sorting of {batch} rows with {row_len} pairs"
+        );
+        if batch == 0 || row_len == 0 {
+            return;
+        }
+        // every row contains num_keys keys
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&row_len));
 
-        self.record_calculate_histogram_indirect(bind_group, dispatch_buffer, encoder);
-        self.record_prefix_histogram(bind_group, encoder);
-        self.record_scatter_keys_indirect(bind_group, dispatch_buffer, encoder);
+        encoder.push_debug_group(&named_label(sort_buffers.name(), "batched radix sort"));
+        self.record_sort_batched(sort_buffers, batch, row_len, encoder);
+        encoder.pop_debug_group();
     }
 
     /// creates all buffers necessary for sorting
     pub fn create_sort_buffers(&self, device: &wgpu::Device, length: NonZeroU32) -> SortBuffers {
-        let length = length.get();
+        self.create_typed_sort_buffers(device, length)
+    }
+
+    /// Creates all buffers necessary for sorting keys of type `K` with values of type `V`,
+    /// e.g. `sorter.create_typed_sort_buffers::<f32, [u32; 3]>(&device, length)`.
+    pub fn create_typed_sort_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        length: NonZeroU32,
+    ) -> SortBuffers<K, V> {
+        self.create_sort_buffers_with_name(device, length, None)
+    }
+
+    /// Creates typed sort buffers with a name that is added to the labels of all buffers,
+    /// bind groups and passes of the sort (prefixed by the name of the sorter).
+    pub fn create_named_sort_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        length: NonZeroU32,
+        name: &str,
+    ) -> SortBuffers<K, V> {
+        self.create_sort_buffers_with_name(device, length, Some(name))
+    }
+
+    fn create_sort_buffers_with_name<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        length: NonZeroU32,
+        name: Option<&str>,
+    ) -> SortBuffers<K, V> {
+        let name = self.buffers_name(name);
+        let internal_mem_buffer =
+            self.create_internal_mem_buffer(device, length.get(), name.as_deref());
+        self.create_sort_buffers_with_internal_mem(device, length.get(), internal_mem_buffer, name)
+    }
+
+    /// Creates all buffers necessary for sorting `batch` rows of `row_len` key-value pairs
+    /// with [GPUSorter::sort_batched].
+    /// The buffers can be used with [GPUSorter::sort] as well.
+    pub fn create_batched_sort_buffers(
+        &self,
+        device: &wgpu::Device,
+        batch: NonZeroU32,
+        row_len: NonZeroU32,
+    ) -> SortBuffers {
+        let length = batch
+            .checked_mul(row_len)
+            .expect("number of key-value pairs exceeds u32::MAX")
+            .get();
+        // a single row has the same layout as the unbatched internal memory
+        let internal_size = self
+            .batched_internal_mem_size(batch.get(), row_len.get())
+            .max(self.batched_internal_mem_size(1, length));
+        let name = self.buffers_name(None);
+        let internal_mem_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name.as_deref(), "Internal batched radix sort buffer")),
+            size: internal_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        self.create_sort_buffers_with_internal_mem(device, length, internal_mem_buffer, name)
+    }
 
+    fn create_sort_buffers_with_internal_mem<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        length: u32,
+        internal_mem_buffer: wgpu::Buffer,
+        name: Option<String>,
+    ) -> SortBuffers<K, V> {
         let (keys_a, keys_b, payload_a, payload_b) =
-            GPUSorter::create_keyval_buffers(&device, length);
-        let internal_mem_buffer = self.create_internal_mem_buffer(&device, length);
+            self.create_keyval_buffers(device, length, name.as_deref());
 
-        let uniform_infos = Self::general_info_data(length);
+        let uniform_infos = Self::general_info_data(length, self.layout);
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("radix sort uniform buffer"),
+            label: Some(&named_label(name.as_deref(), "radix sort uniform buffer")),
             contents: bytemuck::bytes_of(&uniform_infos),
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
         });
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("radix sort bind group"),
+            label: Some(&named_label(name.as_deref(), "radix sort bind group")),
             layout: &Self::bind_group_layout(device),
             entries: &[
                 wgpu::BindGroupEntry {
@@ -548,6 +1188,14 @@ impl GPUSorter {
                 },
             ],
         });
+        let typed = TypedBuffers::new::<K, V>(
+            device,
+            length,
+            &uniform_buffer,
+            &keys_a,
+            &payload_a,
+            name.as_deref(),
+        );
         // return (uniform_buffer, bind_group);
         SortBuffers {
             keys_a,
@@ -558,6 +1206,10 @@ impl GPUSorter {
             state_buffer: uniform_buffer,
             bind_group,
             length,
+            layout: self.layout,
+            typed,
+            name,
+            _types: PhantomData,
         }
     }
 }
@@ -572,11 +1224,18 @@ pub struct SorterState {
     padded_size: u32,
     even_pass: u32,
     odd_pass: u32,
+    /// see [SortBuffers::set_lookback_limit]
+    lookback_limit: u32,
+    /// sticky error bits set by the shaders, see [SORT_ERROR_LOOKBACK_TIMEOUT]
+    error_flags: u32,
 }
 
-/// Struct containing all buffers necessary for sorting.
+/// Struct containing all buffers necessary for sorting keys of type `K` with values of type `V`.
 /// The key and value buffers can be read and written.
-pub struct SortBuffers {
+///
+/// [GPUSorter::sort_batched] overwrites [SorterState::num_keys] in the state buffer with the row length,
+/// indirect sorts that follow it have to write the number of keys again.
+pub struct SortBuffers<K = u32, V = u32> {
     /// keys that are sorted
     keys_a: wgpu::Buffer,
     /// intermediate key buffer for sorting
@@ -589,7 +1248,6 @@ pub struct SortBuffers {
     payload_b: wgpu::Buffer,
 
     /// buffer used to store intermediate results like histograms and scatter partitions
-    #[allow(dead_code)]
     internal_mem_buffer: wgpu::Buffer,
 
     /// state buffer used for sorting
@@ -600,9 +1258,20 @@ pub struct SortBuffers {
 
     // number of key-value pairs
     length: u32,
+
+    /// block rows and digit width of the sorter that created the buffers
+    layout: SortLayout,
+
+    /// buffers for transforming or gathering typed keys and values
+    typed: TypedBuffers,
+
+    /// name used in the labels of the buffers and passes
+    name: Option<String>,
+
+    _types: PhantomData<fn() -> (K, V)>,
 }
 
-impl SortBuffers {
+impl<K: SortKey, V: SortValue> SortBuffers<K, V> {
     /// number of key-value pairs that can be stored in this buffer
     pub fn len(&self) -> u32 {
         self.length
@@ -613,36 +1282,333 @@ impl SortBuffers {
     /// **WARNING**: this buffer has padding bytes at the end
     ///        use [SortBuffers::keys_valid_size] to get the valid size.
     pub fn keys(&self) -> &wgpu::Buffer {
-        &self.keys_a
+        match &self.typed {
+            TypedBuffers::Gather { keys, .. } => keys,
+            _ => &self.keys_a,
+        }
     }
 
     /// The keys buffer has padding bytes.
     /// This function returns the number of bytes without padding
     pub fn keys_valid_size(&self) -> u64 {
-        (self.len() * RS_KEYVAL_SIZE) as u64
+        self.len() as u64 * mem::size_of::<K>() as u64
     }
 
     /// Buffer containing the values
     pub fn values(&self) -> &wgpu::Buffer {
-        &self.payload_a
+        match &self.typed {
+            TypedBuffers::Gather { values, .. } => values,
+            _ => &self.payload_a,
+        }
+    }
+
+    /// Writes the keys to the start of the keys buffer
+    pub fn write_keys(&self, queue: &wgpu::Queue, keys: &[K]) {
+        assert!(keys.len() <= self.len() as usize, "keys do not fit into sort buffers of length {}", self.len());
+        queue.write_buffer(self.keys(), 0, bytemuck::cast_slice(keys));
+    }
+
+    /// Writes the values to the start of the values buffer
+    pub fn write_values(&self, queue: &wgpu::Queue, values: &[V]) {
+        assert!(values.len() <= self.len() as usize, "values do not fit into sort buffers of length {}", self.len());
+        queue.write_buffer(self.values(), 0, bytemuck::cast_slice(values));
+    }
+
+    /// Downloads all keys without padding
+    pub async fn read_keys(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<K> {
+        utils::download_buffer(self.keys(), device, queue, 0..self.keys_valid_size()).await
+    }
+
+    /// Downloads all values
+    pub async fn read_values(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<V> {
+        let size = self.len() as u64 * mem::size_of::<V>() as u64;
+        utils::download_buffer(self.values(), device, queue, 0..size).await
     }
 
     /// Buffer containing a [SorterState]
     pub fn state_buffer(&self)->&wgpu::Buffer{
         &self.state_buffer
     }
+
+    /// Buffer containing the histograms and scatter partitions.
+    /// Its content can be compared against [simulator::RadixSortSimulator::internal_mem] for debugging.
+    pub fn internal_mem_buffer(&self) -> &wgpu::Buffer {
+        &self.internal_mem_buffer
+    }
+
+    #[doc(hidden)]
+    /// Auxiliary keys buffer the even passes scatter into, compared against
+    /// [simulator::RadixSortSimulator::keys_b] in the tests
+    pub fn keys_b(&self) -> &wgpu::Buffer {
+        &self.keys_b
+    }
+
+    #[doc(hidden)]
+    /// Auxiliary values buffer the even passes scatter into, compared against
+    /// [simulator::RadixSortSimulator::payload_b] in the tests
+    pub fn payload_b(&self) -> &wgpu::Buffer {
+        &self.payload_b
+    }
+}
+
+impl<K, V> SortBuffers<K, V> {
+    /// name given with [GPUSorter::create_named_sort_buffers] combined with the name of the sorter
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Limits the number of spins of the scatter passes on a partition that was not published yet.
+    ///
+    /// If the limit is exceeded, [SORT_ERROR_LOOKBACK_TIMEOUT] is set in [SortBuffers::state_buffer]
+    /// instead of spinning until the device is lost. The result of the sort is invalid in this case.
+    /// `None` (the default) spins until the partition is published.
+    pub fn set_lookback_limit(&self, queue: &wgpu::Queue, limit: Option<NonZeroU32>) {
+        let limit = limit.map_or(0, NonZeroU32::get);
+        queue.write_buffer(&self.state_buffer, mem::offset_of!(SorterState, lookback_limit) as u64, bytes_of(&limit));
+    }
+
+    /// number of keys per histogram block of the sorter that created the buffers
+    pub(crate) fn histo_block_kvs(&self) -> u32 {
+        self.layout.block_kvs()
+    }
+
+    /// Resets the error bits in [SortBuffers::state_buffer], they are not reset by sorting
+    pub fn clear_errors(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(&self.state_buffer, mem::offset_of!(SorterState, error_flags) as u64, bytes_of(&0u32));
+    }
+
+    /// Reads the error bits (see [SORT_ERROR_LOOKBACK_TIMEOUT]).
+    ///
+    /// The bits are set by all sorts since the last [SortBuffers::clear_errors].
+    /// If a bit is set the sort should be repeated with a safer algorithm (e.g. [cpu::CPUSorter]).
+    /// The function waits for the gpu to finish.
+    pub async fn read_errors(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> u32 {
+        let state: Vec<SorterState> = utils::download_buffer(&self.state_buffer, device, queue, ..).await;
+        state[0].error_flags
+    }
+
+    /// true if the lookback of a scatter pass gave up waiting, see [SortBuffers::set_lookback_limit]
+    pub async fn lookback_timed_out(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        self.read_errors(device, queue).await & SORT_ERROR_LOOKBACK_TIMEOUT != 0
+    }
+
+    /// reinterprets the buffers as buffers for other types, the typed buffers are not changed
+    pub(crate) fn cast<K2, V2>(self) -> SortBuffers<K2, V2> {
+        SortBuffers {
+            keys_a: self.keys_a,
+            keys_b: self.keys_b,
+            payload_a: self.payload_a,
+            payload_b: self.payload_b,
+            internal_mem_buffer: self.internal_mem_buffer,
+            state_buffer: self.state_buffer,
+            bind_group: self.bind_group,
+            length: self.length,
+            layout: self.layout,
+            typed: self.typed,
+            name: self.name,
+            _types: PhantomData,
+        }
+    }
+}
+
+/// prefixes a label with the name of a sorter or sort buffers
+pub(crate) fn named_label(name: Option<&str>, label: &str) -> String {
+    match name {
+        Some(name) => format!("{name}: {label}"),
+        None => label.to_string(),
+    }
 }
 
-fn scatter_blocks_ru(n: u32) -> u32 {
-    (n + SCATTER_BLOCK_KVS - 1) / SCATTER_BLOCK_KVS
+/// Block rows and digit width of the radix sort shaders of a [GPUSorter].
+/// They determine the layout of the sort buffers, so buffers can only be sorted by sorters with the same layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SortLayout {
+    /// keys loaded by every invocation of the histogram and scatter shaders
+    block_rows: u32,
+    /// bits sorted per pass
+    radix_log2: u32,
 }
 
-/// number of histogram blocks required
-fn histo_blocks_ru(n: u32) -> u32 {
-    (scatter_blocks_ru(n) * SCATTER_BLOCK_KVS + HISTO_BLOCK_KVS - 1) / HISTO_BLOCK_KVS
+impl SortLayout {
+    /// layout of sorters created with [GPUSorter::new]
+    pub const DEFAULT: Self = Self {
+        block_rows: RS_HISTOGRAM_BLOCK_ROWS,
+        radix_log2: RS_RADIX_LOG2,
+    };
+
+    /// error if the shaders do not support the block rows or the digit width
+    pub fn new(block_rows: u32, radix_log2: u32) -> Result<Self, String> {
+        if !(1..=RS_HISTOGRAM_BLOCK_ROWS).contains(&block_rows) {
+            return Err(format!("block rows must be between 1 and {RS_HISTOGRAM_BLOCK_ROWS}, got {block_rows}"));
+        }
+        // the radix table must not be larger than a workgroup and the last pass has to store into the keys buffer
+        if radix_log2 != RS_RADIX_LOG2_MIN && radix_log2 != RS_RADIX_LOG2 {
+            return Err(format!("digit width must be {RS_RADIX_LOG2_MIN} or {RS_RADIX_LOG2} bits, got {radix_log2}"));
+        }
+        Ok(Self { block_rows, radix_log2 })
+    }
+
+    /// see [GPUSorter::block_rows]
+    pub fn block_rows(self) -> u32 {
+        self.block_rows
+    }
+
+    /// see [GPUSorter::radix_log2]
+    pub fn radix_log2(self) -> u32 {
+        self.radix_log2
+    }
+
+    /// number of entries of every histogram
+    fn radix_size(self) -> u32 {
+        1 << self.radix_log2
+    }
+
+    /// number of scatter passes, called keyval_size in the shaders
+    pub fn num_passes(self) -> u32 {
+        32 / self.radix_log2
+    }
+
+    /// number of elements processed by one histogram or scatter work group
+    fn block_kvs(self) -> u32 {
+        HISTOGRAM_WG_SIZE * self.block_rows
+    }
+
+    fn scatter_blocks_ru(self, n: u32) -> u32 {
+        n.div_ceil(self.block_kvs())
+    }
+
+    /// number of histogram blocks required, the scatter and histogram blocks have the same size
+    fn histo_blocks_ru(self, n: u32) -> u32 {
+        self.scatter_blocks_ru(n)
+    }
+
+    /// keys buffer must be multiple of the histogram block size
+    fn keys_buffer_size(self, n: u32) -> u32 {
+        self.histo_blocks_ru(n) * self.block_kvs()
+    }
+
+    /// size of the histograms and partitions of n keys in bytes
+    fn internal_mem_size(self, n: u32) -> u64 {
+        let histo_size = self.radix_size() * std::mem::size_of::<u32>() as u32;
+        (self.num_passes() + self.scatter_blocks_ru(n)) as u64 * histo_size as u64
+    }
+}
+
+/// true if `bytes` is a multiple of the size of a u32,
+/// u32::is_multiple_of is newer than the rust-version of the crate
+#[allow(clippy::manual_is_multiple_of)]
+fn word_aligned(bytes: u32) -> bool {
+    bytes % mem::size_of::<u32>() as u32 == 0
+}
+
+fn storage_layout_entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn buffer_entry(binding: u32, buffer: &wgpu::Buffer) -> wgpu::BindGroupEntry<'_> {
+    wgpu::BindGroupEntry {
+        binding,
+        resource: buffer.as_entire_binding(),
+    }
+}
+
+/// partition statuses of the decoupled lookback, have to be prepended to radix_sort.wgsl and scan.wgsl
+fn partition_status_shader_constants() -> String {
+    format!(
+        "const partition_status_shift: u32 = {:}u;\n\
+        const partition_mask_status: u32 = {:#x}u;\n\
+        const partition_mask_value: u32 = {:#x}u;\n\
+        const partition_status_invalid: u32 = {:}u;\n\
+        const partition_status_reduction: u32 = {:}u;\n\
+        const partition_status_prefix: u32 = {:}u;\n",
+        PARTITION_STATUS_SHIFT,
+        u32::MAX << PARTITION_STATUS_SHIFT,
+        u32::MAX >> (32 - PARTITION_STATUS_SHIFT),
+        PARTITION_STATUS_INVALID,
+        PARTITION_STATUS_REDUCTION,
+        PARTITION_STATUS_PREFIX,
+    )
+}
+
+/// constants that have to be prepended to local_sort.wgsl
+fn local_sort_shader_constants() -> String {
+    format!(
+        "const local_sort_wg_size: u32 = {:}u;\n\
+        const local_sort_kvs: u32 = {:}u;\n\
+        const local_sort_rows: u32 = {:}u;\n",
+        LOCAL_SORT_WG_SIZE,
+        LOCAL_SORT_KVS,
+        LOCAL_SORT_KVS / LOCAL_SORT_WG_SIZE,
+    )
 }
 
-/// keys buffer must be multiple of HISTO_BLOCK_KVS
-fn keys_buffer_size(n: u32) -> u32 {
-    histo_blocks_ru(n) * HISTO_BLOCK_KVS
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use crate::{utils::guess_workgroup_size_quick, GPUSorter, SORT_ERROR_LOOKBACK_TIMEOUT};
+
+    // GPUSorter::new_unpublished_first_partition only exists in unit tests, so this test cannot live in tests/sort.rs
+
+    /// tests that a lookback that gives up waiting for a partition sets the error bit
+    /// and that the buffers can be used for sorting again afterwards
+    #[pollster::test]
+    async fn sort_lookback_timeout() {
+        let (device, queue) = setup().await;
+        let subgroup_size = guess_workgroup_size_quick(&device, &queue)
+            .await
+            .best()
+            .expect("no working subgroup size");
+        // the first scatter block never publishes its partition, so the second one always gives up
+        let broken_sorter = GPUSorter::new_unpublished_first_partition(&device, subgroup_size);
+        let sorter = GPUSorter::new(&device, subgroup_size);
+
+        let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(1 << 20).unwrap());
+        let keys: Vec<u32> = (0..sort_buffers.len()).rev().collect();
+        let sort = |sorter: &GPUSorter| {
+            sort_buffers.write_keys(&queue, &keys);
+            sort_buffers.write_values(&queue, &keys);
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("GPURSSorter sort_lookback_timeout"),
+            });
+            sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+            queue.submit([encoder.finish()]);
+        };
+
+        sort_buffers.set_lookback_limit(&queue, NonZeroU32::new(16));
+        sort(&broken_sorter);
+        assert!(sort_buffers.lookback_timed_out(&device, &queue).await);
+        assert_eq!(sort_buffers.read_errors(&device, &queue).await, SORT_ERROR_LOOKBACK_TIMEOUT);
+
+        // the error bit stays set until it is cleared
+        sort_buffers.set_lookback_limit(&queue, None);
+        sort(&sorter);
+        assert!(sort_buffers.lookback_timed_out(&device, &queue).await);
+        sort_buffers.clear_errors(&queue);
+        sort(&sorter);
+        let keys_sorted = sort_buffers.read_keys(&device, &queue).await;
+        assert!(keys_sorted.windows(2).all(|w| w[0] <= w[1]));
+        assert!(!sort_buffers.lookback_timed_out(&device, &queue).await);
+    }
+
+    async fn setup() -> (wgpu::Device, wgpu::Queue) {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = wgpu::util::initialize_adapter_from_env_or_default(&instance, None)
+            .await
+            .unwrap();
+        adapter
+            .request_device(&wgpu::DeviceDescriptor::default(), None)
+            .await
+            .unwrap()
+    }
 }
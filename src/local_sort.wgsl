@@ -0,0 +1,85 @@
+// shader sorting up to local_sort_kvs key-value pairs within a single workgroup. More information in segmented.rs
+//
+// The pairs are sorted with a bitonic sort in shared memory.
+// Every key is paired with its original position, which is used to break ties. This makes the sort stable.
+//
+// This code is appended to other shaders which have to declare the following storage buffers:
+// var<storage, read_write> keys : array<u32>;
+// var<storage, read_write> payload_a : array<u32>;
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const local_sort_wg_size
+// const local_sort_kvs
+// const local_sort_rows (local_sort_kvs / local_sort_wg_size)
+
+var<workgroup> local_keys: array<u32, local_sort_kvs>;
+var<workgroup> local_idx: array<u32, local_sort_kvs>;
+var<workgroup> local_broadcast: u32;
+
+// makes the value of the first invocation available to the whole workgroup
+// the returned value is uniform and can be used for control flow around barriers
+fn broadcast(lid: u32, value: u32) -> u32 {
+    if lid == 0u {
+        local_broadcast = value;
+    }
+    return workgroupUniformLoad(&local_broadcast);
+}
+
+// sorts the pairs start..start+len in place, len must not be larger than local_sort_kvs and be uniform
+fn local_sort(lid: u32, start: u32, len: u32) {
+    if len < 2u {
+        return;
+    }
+    // bitonic sort only works for powers of two, the rest is padded with max keys
+    let size = 1u << (32u - countLeadingZeros(len - 1u));
+    for (var i = lid; i < size; i += local_sort_wg_size) {
+        if i < len {
+            local_keys[i] = keys[start + i];
+        } else {
+            local_keys[i] = 0xFFFFFFFFu;
+        }
+        local_idx[i] = i;
+    }
+    workgroupBarrier();
+
+    for (var k = 2u; k <= size; k = k << 1u) {
+        for (var j = k >> 1u; j > 0u; j = j >> 1u) {
+            for (var t = lid; t < size / 2u; t += local_sort_wg_size) {
+                // a and b only differ in bit j
+                let a = ((t & ~(j - 1u)) << 1u) | (t & (j - 1u));
+                let b = a | j;
+                let key_a = local_keys[a];
+                let key_b = local_keys[b];
+                let idx_a = local_idx[a];
+                let idx_b = local_idx[b];
+                let a_greater = key_a > key_b || (key_a == key_b && idx_a > idx_b);
+                let ascending = (a & k) == 0u;
+                if a_greater == ascending {
+                    local_keys[a] = key_b;
+                    local_keys[b] = key_a;
+                    local_idx[a] = idx_b;
+                    local_idx[b] = idx_a;
+                }
+            }
+            workgroupBarrier();
+        }
+    }
+
+    // all payloads have to be loaded before they are overwritten
+    var values: array<u32, local_sort_rows>;
+    var row = 0u;
+    for (var i = lid; i < len; i += local_sort_wg_size) {
+        values[row] = payload_a[start + local_idx[i]];
+        row++;
+    }
+    storageBarrier();
+
+    row = 0u;
+    for (var i = lid; i < len; i += local_sort_wg_size) {
+        keys[start + i] = local_keys[i];
+        payload_a[start + i] = values[row];
+        row++;
+    }
+    // the shared memory may be reused by the next call
+    workgroupBarrier();
+}
@@ -0,0 +1,85 @@
+// shader merging chunks that were sorted independently by the radix sort. More information in chunked.rs
+//
+// Every key computes its position in the merged sequence (its rank) by counting
+// the keys in all other chunks that have to be placed before it.
+// Ties are broken by the chunk index, which keeps the merge stable.
+//
+// before the pipeline is started the following constant definition is prepended to this shadercode
+// const merge_wg_size
+
+struct MergeInfo {
+    // number of keys in the source chunk
+    src_len: u32,
+    // number of keys in the other (or output) chunk
+    other_len: u32,
+    // 0: source chunk itself, 1: other chunk comes after source, 2: other chunk comes before source
+    mode: u32,
+    // global index of the first element of the output chunk
+    out_offset: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read> info: MergeInfo;
+@group(0) @binding(1)
+var<storage, read> keys_src : array<u32>;
+
+// --------------------------------------------------------------------------------------------------------------
+// Calculating the ranks
+// --------------------------------------------------------------------------------------------------------------
+@group(0) @binding(2)
+var<storage, read> keys_other : array<u32>;
+@group(0) @binding(3)
+var<storage, read_write> ranks : array<u32>;
+
+// number of keys in keys_other that are smaller than key (inclusive=false) or smaller or equal (inclusive=true)
+fn count_before(key: u32, inclusive: bool) -> u32 {
+    var lo = 0u;
+    var hi = info.other_len;
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2u;
+        let other = keys_other[mid];
+        if other < key || (inclusive && other == key) {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
+}
+
+@compute @workgroup_size(merge_wg_size)
+fn calculate_ranks(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * merge_wg_size;
+    for (var i = gid.x; i < info.src_len; i += line_size) {
+        if info.mode == 0u {
+            ranks[i] = i;
+        } else {
+            ranks[i] += count_before(keys_src[i], info.mode == 2u);
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Scattering the keys and values into the output chunk
+// --------------------------------------------------------------------------------------------------------------
+@group(0) @binding(4)
+var<storage, read> payload_src : array<u32>;
+@group(0) @binding(5)
+var<storage, read> ranks_src : array<u32>;
+@group(0) @binding(6)
+var<storage, read_write> keys_out : array<u32>;
+@group(0) @binding(7)
+var<storage, read_write> payload_out : array<u32>;
+
+@compute @workgroup_size(merge_wg_size)
+fn scatter_ranked(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * merge_wg_size;
+    for (var i = gid.x; i < info.src_len; i += line_size) {
+        let rank = ranks_src[i];
+        // only the elements that belong to the current output chunk are written
+        if rank >= info.out_offset && rank - info.out_offset < info.other_len {
+            keys_out[rank - info.out_offset] = keys_src[i];
+            payload_out[rank - info.out_offset] = payload_src[i];
+        }
+    }
+}
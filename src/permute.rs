@@ -0,0 +1,297 @@
+/*
+    Reordering of attribute buffers with the indices of a sort.
+
+    After sorting keys with the indices of the elements as values (e.g. depth keys of splats),
+    GPUPermuter applies the sorted indices to other buffers with elements of any size that is a multiple of 4 bytes:
+    - permute_gather writes a reordered copy, destination[i] = source[indices[i]]
+    - permute_scatter applies the inverse permutation, destination[indices[i]] = source[i]
+
+    One sort can reorder many attribute buffers, every pair of source and destination gets its own PermuteBuffers:
+
+    let permuter = GPUPermuter::new(&device);
+    let colors = permuter.create_permute_buffers(&device, &sort_buffers, &colors_in, &colors_sorted, 16);
+    let covariances = permuter.create_permute_buffers(&device, &sort_buffers, &covs_in, &covs_sorted, 24);
+    sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+    permuter.gather(&mut encoder, &queue, &sort_buffers, &colors, None);
+    permuter.gather(&mut encoder, &queue, &sort_buffers, &covariances, None);
+
+    The number of gathered elements is stored in the PermuteBuffers and not in the sort state,
+    so gathering only the first k indices does not change how many keys the sort recorded before it sorts.
+
+    The shaders can be found in permute.wgsl
+*/
+
+use std::mem;
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label, storage_layout_entry,
+    typed::{self, SortKey, TypedDispatch},
+    word_aligned, SortBuffers,
+};
+
+/// workgroup size of the permute shaders
+const PERMUTE_WG_SIZE: u32 = 256;
+
+/// Size and number of the elements, has to be synced with PermuteInfo in permute.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct PermuteInfo {
+    stride: u32,
+    /// number of elements permuted by the direct dispatches,
+    /// the indirect dispatches use the number of keys of the sort state
+    num_elements: u32,
+    _pad: [u32; 2],
+}
+
+/// Bind group for reordering one source buffer into one destination buffer
+pub struct PermuteBuffers {
+    bind_group: wgpu::BindGroup,
+    info_buffer: wgpu::Buffer,
+    /// words per element
+    stride: u32,
+    /// number of pairs of the sort buffers
+    input_length: u32,
+    name: Option<String>,
+}
+
+impl PermuteBuffers {
+    /// Number of bytes per element
+    pub fn element_stride(&self) -> u32 {
+        self.stride * mem::size_of::<u32>() as u32
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// Pipelines for gathering and scattering elements with the indices of a sort
+pub struct GPUPermuter {
+    gather_p: wgpu::ComputePipeline,
+    scatter_p: wgpu::ComputePipeline,
+    gather_indirect_p: wgpu::ComputePipeline,
+    scatter_indirect_p: wgpu::ComputePipeline,
+}
+
+impl GPUPermuter {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self::create(device, None)
+    }
+
+    /// Same as [GPUPermuter::new] but adds `name` to the labels of the pipelines
+    pub fn new_named(device: &wgpu::Device, name: &str) -> Self {
+        Self::create(device, Some(name))
+    }
+
+    fn create(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("permute.wgsl");
+        let shader_code = format!("const permute_wg_size: u32 = {:}u;\n{:}", PERMUTE_WG_SIZE, raw_shader);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Permute shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&named_label(name, "permute pipeline layout")),
+            bind_group_layouts: &[&Self::bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&named_label(name, entry_point)),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        Self {
+            gather_p: pipeline("permute_gather"),
+            scatter_p: pipeline("permute_scatter"),
+            gather_indirect_p: pipeline("permute_gather_indirect"),
+            scatter_indirect_p: pipeline("permute_scatter_indirect"),
+        }
+    }
+
+    fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("permute bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, true),
+                storage_layout_entry(2, true),
+                storage_layout_entry(3, false),
+                storage_layout_entry(4, true),
+            ],
+        })
+    }
+
+    /// Creates the bind group for reordering `source` into `destination` with the values of `sort_buffers` as indices.
+    ///
+    /// Both buffers must contain an element of `element_stride` bytes for every pair and must be different buffers.
+    pub fn create_permute_buffers<K: SortKey>(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers<K, u32>,
+        source: &wgpu::Buffer,
+        destination: &wgpu::Buffer,
+        element_stride: u32,
+    ) -> PermuteBuffers {
+        let word_size = mem::size_of::<u32>() as u32;
+        assert!(
+            element_stride > 0 && word_aligned(element_stride),
+            "the element stride must be a multiple of 4 bytes"
+        );
+        let length = sort_buffers.len();
+        let stride = element_stride / word_size;
+        assert!(
+            length.checked_mul(stride).is_some(),
+            "the number of words of the elements must fit into u32"
+        );
+        let size = length as u64 * element_stride as u64;
+        assert!(source.size() >= size, "the source buffer must contain an element for every pair");
+        assert!(destination.size() >= size, "the destination buffer must contain an element for every pair");
+        let name = sort_buffers.name();
+
+        let info = PermuteInfo {
+            stride,
+            num_elements: length,
+            _pad: [0; 2],
+        };
+        let info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "permute info buffer")),
+            contents: bytes_of(&info),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "permute bind group")),
+            layout: &Self::bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.values()),
+                buffer_entry(2, source),
+                buffer_entry(3, destination),
+                buffer_entry(4, &info_buffer),
+            ],
+        });
+        PermuteBuffers {
+            bind_group,
+            info_buffer,
+            stride,
+            input_length: length,
+            name: name.map(str::to_string),
+        }
+    }
+
+    /// Writes the elements of the first `first_n` (or all) sorted indices into the destination,
+    /// `destination[i] = source[indices[i]]`.
+    ///
+    /// The number of elements is stored in `permute_buffers`, the number of keys of the sort is not changed.
+    /// So the sort and the gather of its first n indices can be recorded into the same encoder.
+    /// As the number is written to the queue, all gathers and scatters with the same `permute_buffers`
+    /// submitted together use the `first_n` of the last call.
+    ///
+    /// `sort_buffers` must be the buffers `permute_buffers` were created for.
+    pub fn gather<K: SortKey>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, u32>,
+        permute_buffers: &PermuteBuffers,
+        first_n: Option<u32>,
+    ) {
+        let num_elements = Self::write_num_elements(queue, sort_buffers, permute_buffers, first_n);
+        self.record(encoder, permute_buffers, &self.gather_p, &TypedDispatch::Direct(num_elements));
+    }
+
+    /// Writes the first `first_n` (or all) elements of the source to their sorted index in the destination,
+    /// `destination[indices[i]] = source[i]`. This is the inverse of [GPUPermuter::gather].
+    ///
+    /// Elements of the destination whose index is not among the first `first_n` indices are not written.
+    /// The number of elements is stored like for [GPUPermuter::gather].
+    /// `sort_buffers` must be the buffers `permute_buffers` were created for.
+    pub fn scatter<K: SortKey>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, u32>,
+        permute_buffers: &PermuteBuffers,
+        first_n: Option<u32>,
+    ) {
+        let num_elements = Self::write_num_elements(queue, sort_buffers, permute_buffers, first_n);
+        self.record(encoder, permute_buffers, &self.scatter_p, &TypedDispatch::Direct(num_elements));
+    }
+
+    /// Same as [GPUPermuter::gather] for the number of indices stored in [SortBuffers::state_buffer],
+    /// e.g. after [GPUSorter::sort_indirect](crate::GPUSorter::sort_indirect).
+    ///
+    /// The dispatch buffer must contain the struct [wgpu::util::DispatchIndirectArgs]
+    /// with at least one workgroup if there are elements.
+    /// The same dispatch buffer as for the indirect sort can be used.
+    pub fn gather_indirect(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        permute_buffers: &PermuteBuffers,
+        dispatch_buffer: &wgpu::Buffer,
+    ) {
+        self.record(encoder, permute_buffers, &self.gather_indirect_p, &TypedDispatch::Indirect(dispatch_buffer));
+    }
+
+    /// Same as [GPUPermuter::scatter] for the number of indices stored in [SortBuffers::state_buffer],
+    /// see [GPUPermuter::gather_indirect]
+    pub fn scatter_indirect(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        permute_buffers: &PermuteBuffers,
+        dispatch_buffer: &wgpu::Buffer,
+    ) {
+        self.record(encoder, permute_buffers, &self.scatter_indirect_p, &TypedDispatch::Indirect(dispatch_buffer));
+    }
+
+    fn write_num_elements<K: SortKey>(
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, u32>,
+        permute_buffers: &PermuteBuffers,
+        first_n: Option<u32>,
+    ) -> u32 {
+        let num_elements = first_n.unwrap_or(permute_buffers.input_length);
+        assert!(
+            num_elements <= permute_buffers.input_length,
+            "more elements permuted than the buffers contain"
+        );
+        assert!(
+            permute_buffers.input_length == sort_buffers.len(),
+            "the permute buffers were created for other sort buffers"
+        );
+        queue.write_buffer(
+            &permute_buffers.info_buffer,
+            mem::offset_of!(PermuteInfo, num_elements) as u64,
+            bytes_of(&num_elements),
+        );
+        num_elements
+    }
+
+    fn record(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        permute_buffers: &PermuteBuffers,
+        pipeline: &wgpu::ComputePipeline,
+        dispatch: &TypedDispatch,
+    ) {
+        let name = permute_buffers.name.as_deref();
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&named_label(name, "permute")),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &permute_buffers.bind_group, &[]);
+        let dispatch = match *dispatch {
+            TypedDispatch::Direct(num_elements) => TypedDispatch::Direct(num_elements * permute_buffers.stride),
+            // the invocations loop over all words, so the workgroups of the sort are enough
+            TypedDispatch::Indirect(dispatch_buffer) => TypedDispatch::Indirect(dispatch_buffer),
+        };
+        typed::dispatch(&mut pass, &dispatch, PERMUTE_WG_SIZE);
+    }
+}
@@ -0,0 +1,78 @@
+// shader reordering elements of arbitrary size with the sorted indices. More information in permute.rs
+//
+// Every invocation copies one word, so the words of an element are read and written by neighbouring invocations.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const permute_wg_size
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct PermuteInfo {
+    // words per element
+    stride: u32,
+    // number of elements of the direct dispatches
+    num_elements: u32,
+    _pad0: u32,
+    _pad1: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read> infos: GeneralInfo;
+@group(0) @binding(1)
+var<storage, read> indices: array<u32>;
+@group(0) @binding(2)
+var<storage, read> source: array<u32>;
+@group(0) @binding(3)
+var<storage, read_write> destination: array<u32>;
+@group(0) @binding(4)
+var<storage, read> permute_info: PermuteInfo;
+
+// destination[i] = source[indices[i]] for the first num_elements indices
+fn gather(gid: u32, nwg: u32, num_elements: u32) {
+    let stride = permute_info.stride;
+    let num_words = num_elements * stride;
+    let line_size = nwg * permute_wg_size;
+    for (var w = gid; w < num_words; w += line_size) {
+        let i = w / stride;
+        let word = w - i * stride;
+        destination[w] = source[indices[i] * stride + word];
+    }
+}
+
+// destination[indices[i]] = source[i] for the first num_elements indices
+fn scatter(gid: u32, nwg: u32, num_elements: u32) {
+    let stride = permute_info.stride;
+    let num_words = num_elements * stride;
+    let line_size = nwg * permute_wg_size;
+    for (var w = gid; w < num_words; w += line_size) {
+        let i = w / stride;
+        let word = w - i * stride;
+        destination[indices[i] * stride + word] = source[w];
+    }
+}
+
+@compute @workgroup_size(permute_wg_size)
+fn permute_gather(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    gather(gid.x, nwg.x, permute_info.num_elements);
+}
+
+@compute @workgroup_size(permute_wg_size)
+fn permute_scatter(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    scatter(gid.x, nwg.x, permute_info.num_elements);
+}
+
+// the indirect dispatches permute the number of keys of the sort
+@compute @workgroup_size(permute_wg_size)
+fn permute_gather_indirect(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    gather(gid.x, nwg.x, infos.num_keys);
+}
+
+@compute @workgroup_size(permute_wg_size)
+fn permute_scatter_indirect(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    scatter(gid.x, nwg.x, infos.num_keys);
+}
@@ -0,0 +1,173 @@
+/*
+    Per-phase gpu timestamp profiling of the radix sort.
+
+    If profiling is enabled with GPUSorter::enable_profiling, every compute pass of the radix sort
+    (zeroing, histogram, prefix and every scatter pass, or the single workgroup sort for small inputs)
+    writes a timestamp at its beginning and end. The timestamps are resolved at the end of the sort
+    and can be read back with GPUSorter::read_timings.
+*/
+
+use std::time::Duration;
+
+use crate::{named_label, utils::download_buffer, GPUSorter, MAX_NUM_PASSES};
+
+// index of the timestamp pair of every profiled pass in the query set
+const PHASE_ZERO: u32 = 0;
+const PHASE_HISTOGRAM: u32 = 1;
+const PHASE_PREFIX: u32 = 2;
+const PHASE_SCATTER: u32 = 3;
+const PHASE_SINGLE_WORKGROUP: u32 = PHASE_SCATTER + MAX_NUM_PASSES;
+const NUM_PHASES: u32 = PHASE_SINGLE_WORKGROUP + 1;
+
+// the timestamps of the single workgroup sort are resolved separately and
+// resolve offsets must be aligned, so they are stored after the radix sort timestamps
+const TIMESTAMP_SIZE: u64 = std::mem::size_of::<u64>() as u64;
+const SINGLE_WORKGROUP_OFFSET: u64 = wgpu::QUERY_RESOLVE_BUFFER_ALIGNMENT;
+
+/// Phases of the radix sort that are timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SortPhase {
+    ZeroHistograms,
+    CalculateHistogram,
+    PrefixHistogram,
+    /// scatter pass of the given digit of the keys
+    Scatter(u32),
+    SingleWorkgroup,
+}
+
+impl SortPhase {
+    fn index(self) -> u32 {
+        match self {
+            SortPhase::ZeroHistograms => PHASE_ZERO,
+            SortPhase::CalculateHistogram => PHASE_HISTOGRAM,
+            SortPhase::PrefixHistogram => PHASE_PREFIX,
+            SortPhase::Scatter(pass) => PHASE_SCATTER + pass,
+            SortPhase::SingleWorkgroup => PHASE_SINGLE_WORKGROUP,
+        }
+    }
+}
+
+/// GPU time spent in every phase of the last profiled sort.
+///
+/// Phases that were not executed (the radix sort phases for small inputs or
+/// the single workgroup sort for large ones) have a duration of zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SortTimings {
+    /// zeroing of the histograms and padding of the keys
+    pub zero_histograms: Duration,
+    pub calculate_histogram: Duration,
+    pub prefix_histogram: Duration,
+    /// one scatter pass per digit of the keys (see [GPUSorter::num_passes]), the remaining entries are zero
+    pub scatter: [Duration; MAX_NUM_PASSES as usize],
+    /// sort of small inputs in a single workgroup
+    pub single_workgroup: Duration,
+}
+
+impl SortTimings {
+    /// sum of all phases
+    pub fn total(&self) -> Duration {
+        self.zero_histograms
+            + self.calculate_histogram
+            + self.prefix_histogram
+            + self.scatter.iter().sum::<Duration>()
+            + self.single_workgroup
+    }
+}
+
+/// Query set and resolve buffer of a profiling sorter
+pub(crate) struct SortProfiler {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+}
+
+impl SortProfiler {
+    fn new(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some(&named_label(name, "radix sort timestamp query set")),
+            ty: wgpu::QueryType::Timestamp,
+            count: NUM_PHASES * 2,
+        });
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "radix sort timestamp resolve buffer")),
+            size: SINGLE_WORKGROUP_OFFSET + 2 * TIMESTAMP_SIZE,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            query_set,
+            resolve_buffer,
+        }
+    }
+
+    pub(crate) fn timestamp_writes(&self, phase: SortPhase) -> wgpu::ComputePassTimestampWrites<'_> {
+        let index = phase.index();
+        wgpu::ComputePassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(index * 2),
+            end_of_pass_write_index: Some(index * 2 + 1),
+        }
+    }
+
+    /// resolves the timestamps written by the radix sort with `num_passes` scatter passes or the single workgroup sort,
+    /// the timestamps of the other phases are set to zero
+    pub(crate) fn resolve(&self, encoder: &mut wgpu::CommandEncoder, single_workgroup: bool, num_passes: u32) {
+        let (phases, offset) = if single_workgroup {
+            (PHASE_SINGLE_WORKGROUP..NUM_PHASES, SINGLE_WORKGROUP_OFFSET)
+        } else {
+            (PHASE_ZERO..PHASE_SCATTER + num_passes, 0)
+        };
+        encoder.clear_buffer(&self.resolve_buffer, 0, None);
+        encoder.resolve_query_set(
+            &self.query_set,
+            phases.start * 2..phases.end * 2,
+            &self.resolve_buffer,
+            offset,
+        );
+    }
+}
+
+impl GPUSorter {
+    /// Enables per-phase timestamp profiling of all following sorts.
+    ///
+    /// Returns false and leaves profiling disabled if the device does not support [wgpu::Features::TIMESTAMP_QUERY].
+    /// The timings of the last sort can be read with [GPUSorter::read_timings].
+    pub fn enable_profiling(&mut self, device: &wgpu::Device) -> bool {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return false;
+        }
+        if self.profiler.is_none() {
+            self.profiler = Some(SortProfiler::new(device, self.name()));
+        }
+        true
+    }
+
+    /// Disables timestamp profiling
+    pub fn disable_profiling(&mut self) {
+        self.profiler = None;
+    }
+
+    /// Reads the timings of the last submitted sort.
+    ///
+    /// Returns None if profiling is not enabled. For two word keys (see [crate::typed])
+    /// only the second radix sort is timed. The function waits for the gpu to finish.
+    pub async fn read_timings(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Option<SortTimings> {
+        let profiler = self.profiler.as_ref()?;
+        let timestamps: Vec<u64> = download_buffer(&profiler.resolve_buffer, device, queue, ..).await;
+        let period = queue.get_timestamp_period() as f64;
+        let duration = |offset: u64| {
+            let index = (offset / TIMESTAMP_SIZE) as usize;
+            let (start, end) = (timestamps[index], timestamps[index + 1]);
+            Duration::from_nanos((end.saturating_sub(start) as f64 * period) as u64)
+        };
+        let radix_duration = |phase: u32| duration(phase as u64 * 2 * TIMESTAMP_SIZE);
+        Some(SortTimings {
+            zero_histograms: radix_duration(PHASE_ZERO),
+            calculate_histogram: radix_duration(PHASE_HISTOGRAM),
+            prefix_histogram: radix_duration(PHASE_PREFIX),
+            scatter: std::array::from_fn(|pass| radix_duration(PHASE_SCATTER + pass as u32)),
+            single_workgroup: duration(SINGLE_WORKGROUP_OFFSET),
+        })
+    }
+}
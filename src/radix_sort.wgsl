@@ -6,19 +6,28 @@
 
 // const histogram_sg_size
 // const histogram_wg_size
-// const rs_radix_log2
+// const rs_radix_log2 (4 or 8 bits per pass)
 // const rs_radix_size
-// const rs_keyval_size
+// const rs_keyval_size (number of passes, always even so that the last pass stores into keys)
 // const rs_histogram_block_rows
 // const rs_scatter_block_rows
+// const rs_publish_first_partition (only false in sorters built for testing the lookback timeout)
+// the partition status constants shared with scan.wgsl (see partition_status_shader_constants in lib.rs)
 
 struct GeneralInfo {
     num_keys: u32,
     padded_size: u32,
     even_pass: u32,
     odd_pass: u32,
+    // maximum number of spins on an unpublished partition in the lookback, 0 spins forever
+    lookback_limit: u32,
+    // sticky error bits, cleared by the host
+    error_flags: atomic<u32>,
 };
 
+// set in error_flags if the lookback of a scatter pass gave up waiting for a partition
+const rs_error_lookback_timeout: u32 = 1u;
+
 @group(0) @binding(0)
 var<storage, read_write> infos: GeneralInfo;
 @group(0) @binding(1)
@@ -40,6 +49,11 @@ var<storage, read_write> payload_b : array<u32>;
 //   +---------------------------------+ <-- (keyval_size + scatter_blocks_ru - 1) * histo_size
 //   | workgroup_ids[keyval_size]      |
 //   +---------------------------------+ <-- (keyval_size + scatter_blocks_ru - 1) * histo_size + workgroup_ids_size
+//
+// for batched sorting every row has its own histograms and partitions (see the end of this file)
+
+// offset of the current row in the histograms buffer, only non zero for batched sorting
+var<private> row_mem_base: u32 = 0u;
 
 // --------------------------------------------------------------------------------------------------------------
 // Filling histograms and keys with default values (also resets the pass infos for odd and even scattering)
@@ -48,7 +62,7 @@ var<storage, read_write> payload_b : array<u32>;
 fn zero_histograms(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
     if gid.x == 0u {
         infos.even_pass = 0u;
-        infos.odd_pass = 1u;    // has to be one, as on the first call to even pass + 1 % 2 is calculated
+        infos.odd_pass = rs_keyval_size / 2u - 1u;    // wraps to 0 on the first call to even pass, see scatter_even
     }
     // here the histograms are set to zero and the partitions are set to 0xfffffffff to avoid sorting problems
     let scatter_wg_size = histogram_wg_size;
@@ -83,7 +97,8 @@ fn zero_histograms(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_w
 // --------------------------------------------------------------------------------------------------------------
 // Calculating the histograms
 // --------------------------------------------------------------------------------------------------------------
-var<workgroup> smem : array<atomic<u32>, rs_radix_size>;
+// the ranking in scatter stores the digit of every invocation, so smem is never smaller than a workgroup
+var<workgroup> smem : array<atomic<u32>, {scatter_wg_size}>;
 var<private> kv : array<u32, rs_histogram_block_rows>;
 fn zero_smem(lid: u32) {
     if lid < rs_radix_size {
@@ -101,7 +116,7 @@ fn histogram_pass(pass_: u32, lid: u32) {
     }
 
     workgroupBarrier();
-    let histogram_offset = rs_radix_size * pass_ + lid;
+    let histogram_offset = row_mem_base + rs_radix_size * pass_ + lid;
     if lid < rs_radix_size && atomicLoad(&smem[lid]) >= 0u {
         atomicAdd(&histograms[histogram_offset], atomicLoad(&smem[lid]));
     }
@@ -130,10 +145,9 @@ fn calculate_histogram(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_inv
     fill_kv(wid.x, lid.x);
     
     // Accumulate and store histograms for passes
-    histogram_pass(3u, lid.x);
-    histogram_pass(2u, lid.x);
-    histogram_pass(1u, lid.x);
-    histogram_pass(0u, lid.x);
+    for (var pass_ = rs_keyval_size; pass_ > 0u; pass_--) {
+        histogram_pass(pass_ - 1u, lid.x);
+    }
 }
 
 // --------------------------------------------------------------------------------------------------------------
@@ -170,8 +184,11 @@ fn prefix_reduce_smem(lid: u32) {
 }
 @compute @workgroup_size({prefix_wg_size})
 fn prefix_histogram(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {
-    // the work group  id is the pass, and is inverted in the next line, such that pass 3 is at the first position in the histogram buffer
-    let histogram_base = (rs_keyval_size - 1u - wid.x) * rs_radix_size;
+    prefix_histogram_pass(wid, lid);
+}
+fn prefix_histogram_pass(wid: vec3<u32>, lid: vec3<u32>) {
+    // the work group  id is the pass, and is inverted in the next line, such that the last pass is at the first position in the histogram buffer
+    let histogram_base = row_mem_base + (rs_keyval_size - 1u - wid.x) * rs_radix_size;
     let histogram_offset = histogram_base + lid.x;
     
     // the following coode now corresponds to the prefix calc code in fuchsia/../shaders/prefix.h
@@ -199,7 +216,7 @@ var<workgroup> scatter_smem: array<u32, rs_mem_dwords>; // note: rs_mem_dwords i
 //  Histogram | 256                                       | 1 KB
 //  Prefix    | 4-84                                      | 16-336
 //  Reorder   | RS_WORKGROUP_SIZE * RS_SCATTER_BLOCK_ROWS | 2-8 KB
-fn partitions_base_offset() -> u32 { return rs_keyval_size * rs_radix_size;}
+fn partitions_base_offset() -> u32 { return row_mem_base + rs_keyval_size * rs_radix_size;}
 fn smem_prefix_offset() -> u32 { return rs_radix_size + rs_radix_size;}
 fn rs_prefix_sweep_0(idx: u32) -> u32 { return scatter_smem[smem_prefix_offset() + rs_mem_sweep_0_offset + idx];}
 fn rs_prefix_sweep_1(idx: u32) -> u32 { return scatter_smem[smem_prefix_offset() + rs_mem_sweep_1_offset + idx];}
@@ -217,8 +234,6 @@ fn histogram_store(digit: u32, count: u32) {
 }
 
 
-const rs_partition_mask_status : u32 = 0xC0000000u;
-const rs_partition_mask_count : u32 = 0x3FFFFFFFu;
 var<private> kr : array<u32, rs_scatter_block_rows>;
 var<private> pv : array<u32, rs_scatter_block_rows>;
 
@@ -253,10 +268,10 @@ fn fill_kv_odd(wid: u32, lid: u32) {
         pv[i] = payload_b[pos];
     }
 }
-fn scatter(pass_: u32, lid: vec3<u32>, gid: vec3<u32>, wid: vec3<u32>, nwg: vec3<u32>, partition_status_invalid: u32, partition_status_reduction: u32, partition_status_prefix: u32) {
-    let partition_mask_invalid = partition_status_invalid << 30u;
-    let partition_mask_reduction = partition_status_reduction << 30u;
-    let partition_mask_prefix = partition_status_prefix << 30u;
+fn scatter(pass_: u32, lid: vec3<u32>, gid: vec3<u32>, wid: vec3<u32>, nwg: vec3<u32>, status_invalid: u32, status_reduction: u32, status_prefix: u32) {
+    let partition_mask_invalid = status_invalid << partition_status_shift;
+    let partition_mask_reduction = status_reduction << partition_status_shift;
+    let partition_mask_prefix = status_prefix << partition_status_shift;
     // kv_filling is done in the scatter_even and scatter_odd functions to account for front and backbuffer switch
     // in the reference there is a nulling of the smmem here, was moved to line 251 as smem is used in the code until then
 
@@ -319,7 +334,7 @@ fn scatter(pass_: u32, lid: vec3<u32>, gid: vec3<u32>, wid: vec3<u32>, nwg: vec3
     if wid.x == 0u {
         // special treating for the first workgroup as the data might be read back by later workgroups
         // corresponds to rs_first_prefix_store
-        let hist_offset = pass_ * rs_radix_size + lid.x;
+        let hist_offset = row_mem_base + pass_ * rs_radix_size + lid.x;
         if lid.x < rs_radix_size {
             // let exc = histograms[hist_offset];
             let exc = atomicLoad(&histograms[hist_offset]);
@@ -329,7 +344,9 @@ fn scatter(pass_: u32, lid: vec3<u32>, gid: vec3<u32>, wid: vec3<u32>, nwg: vec3
             
             let inc = exc + red;
 
-            atomicStore(&histograms[partition_offset], inc | partition_mask_prefix);
+            if rs_publish_first_partition {
+                atomicStore(&histograms[partition_offset], inc | partition_mask_prefix);
+            }
         }
     }
     else {
@@ -345,20 +362,29 @@ fn scatter(pass_: u32, lid: vec3<u32>, gid: vec3<u32>, wid: vec3<u32>, nwg: vec3
         if lid.x < rs_radix_size {
             var partition_base_prev = partition_base - rs_radix_size;
             var exc                 = 0u;
+            let lookback_limit      = infos.lookback_limit;
+            var spins               = 0u;
 
             // Note: Each workgroup invocation can proceed independently.
             // Subgroups and workgroups do NOT have to coordinate.
             while true {
                 //let prev = atomicLoad(&histograms[partition_offset]);// histograms[partition_offset + partition_base_prev];
                 let prev = atomicLoad(&histograms[partition_base_prev + partition_offset]);// histograms[partition_offset + partition_base_prev];
-                if (prev & rs_partition_mask_status) == partition_mask_invalid {
-                    continue;
-                }
-                exc += prev & rs_partition_mask_count;
-                if (prev & rs_partition_mask_status) != partition_mask_prefix {
-                    // continue accumulating reduction
-                    partition_base_prev -= rs_radix_size;
-                    continue;
+                if (prev & partition_mask_status) == partition_mask_invalid {
+                    spins++;
+                    if lookback_limit == 0u || spins < lookback_limit {
+                        continue;
+                    }
+                    // the partition is never published, give up and publish the (wrong) prefix
+                    // so that the following workgroups do not wait for this one
+                    atomicOr(&infos.error_flags, rs_error_lookback_timeout);
+                } else {
+                    exc += prev & partition_mask_value;
+                    if (prev & partition_mask_status) != partition_mask_prefix {
+                        // continue accumulating reduction
+                        partition_base_prev -= rs_radix_size;
+                        continue;
+                    }
                 }
 
                 // otherwise save the exclusive scan and atomically transform the
@@ -366,7 +392,7 @@ fn scatter(pass_: u32, lid: vec3<u32>, gid: vec3<u32>, wid: vec3<u32>, nwg: vec3
                 scatter_smem[lid.x] = exc;
 
                 if wid.x < nwg.x - 1u { // only store when inbetween, skip for last workgrup
-                    atomicAdd(&histograms[partition_offset + partition_base], exc | (1u << 30u));
+                    atomicAdd(&histograms[partition_offset + partition_base], exc | (1u << partition_status_shift));
                 }
                 break;
             }
@@ -451,16 +477,13 @@ fn scatter(pass_: u32, lid: vec3<u32>, gid: vec3<u32>, wid: vec3<u32>, nwg: vec3
 @compute @workgroup_size({scatter_wg_size})
 fn scatter_even(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>, @builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
     if gid.x == 0u {
-        infos.odd_pass = (infos.odd_pass + 1u) % 2u; // for this to work correctly the odd_pass has to start 1
+        infos.odd_pass = (infos.odd_pass + 1u) % (rs_keyval_size / 2u); // for this to work correctly the odd_pass has to start at the last odd pass
     }
     let cur_pass = infos.even_pass * 2u;
     
     // load from keys, store to keys_b
     fill_kv_even(wid.x, lid.x);
 
-    let partition_status_invalid = 0u;
-    let partition_status_reduction = 1u;
-    let partition_status_prefix = 2u;
     scatter(cur_pass, lid, gid, wid, nwg, partition_status_invalid, partition_status_reduction, partition_status_prefix);
 
     // store keyvals to their new locations, corresponds to rs_store
@@ -474,17 +497,18 @@ fn scatter_even(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation
 @compute @workgroup_size({scatter_wg_size})
 fn scatter_odd(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>, @builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
     if gid.x == 0u {
-        infos.even_pass = (infos.even_pass + 1u) % 2u; // for this to work correctly the even_pass has to start at 0
+        infos.even_pass = (infos.even_pass + 1u) % (rs_keyval_size / 2u); // for this to work correctly the even_pass has to start at 0
     }
     let cur_pass = infos.odd_pass * 2u + 1u;
 
     // load from keys_b, store to keys
     fill_kv_odd(wid.x, lid.x);
 
-    let partition_status_invalid = 2u;
-    let partition_status_reduction = 3u;
-    let partition_status_prefix = 0u;
-    scatter(cur_pass, lid, gid, wid, nwg, partition_status_invalid, partition_status_reduction, partition_status_prefix);
+    // the statuses are rotated by two, so the prefixes left by the even pass read as invalid
+    let status_invalid = (partition_status_invalid + 2u) % 4u;
+    let status_reduction = (partition_status_reduction + 2u) % 4u;
+    let status_prefix = (partition_status_prefix + 2u) % 4u;
+    scatter(cur_pass, lid, gid, wid, nwg, status_invalid, status_reduction, status_prefix);
 
     // store keyvals to their new locations, corresponds to rs_store
     for (var i = 0u; i < rs_scatter_block_rows; i++) {
@@ -496,3 +520,154 @@ fn scatter_odd(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_
 
     // the indirect buffer is reset after scattering via write buffer, see record_scatter_indirect for details
 }
+
+// --------------------------------------------------------------------------------------------------------------
+// Batched sorting
+// --------------------------------------------------------------------------------------------------------------
+// The keys buffer is treated as nwg.y contiguous rows of infos.num_keys keys that are sorted independently.
+// workgroup_id.y is the row, workgroup_id.x the block within the row.
+// Rows are not padded, keys outside of a row are treated as 0xFFFFFFFF and are never stored.
+//
+// layout of the histograms buffer for batched sorting
+//   +---------------------------------+ <-- 0
+//   | row 0: histograms[keyval_size]  |
+//   |        partitions[blocks_ru]    |
+//   +---------------------------------+ <-- row_mem_size
+//   | row 1: ...                      |
+
+// number of histogram entries used by a single row
+fn row_mem_size() -> u32 {
+    let scatter_block_kvs = histogram_wg_size * rs_scatter_block_rows;
+    let scatter_blocks_ru = (infos.num_keys + scatter_block_kvs - 1u) / scatter_block_kvs;
+    return (rs_keyval_size + scatter_blocks_ru) * rs_radix_size;
+}
+
+fn load_key_row(row: u32, pos: u32) -> u32 {
+    if pos < infos.num_keys {
+        return keys[row * infos.num_keys + pos];
+    }
+    return 0xFFFFFFFFu;
+}
+
+fn load_key_b_row(row: u32, pos: u32) -> u32 {
+    if pos < infos.num_keys {
+        return keys_b[row * infos.num_keys + pos];
+    }
+    return 0xFFFFFFFFu;
+}
+
+@compute @workgroup_size({histogram_wg_size})
+fn zero_histograms_batched(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    if gid.x == 0u && gid.y == 0u {
+        infos.even_pass = 0u;
+        infos.odd_pass = rs_keyval_size / 2u - 1u;    // wraps to 0 on the first call to even pass
+    }
+    let n = row_mem_size();
+    let base = wid.y * n;
+    let line_size = nwg.x * {histogram_wg_size}u;
+    for (var cur_index = wid.x * {histogram_wg_size}u + lid.x; cur_index < n; cur_index += line_size) {
+        atomicStore(&histograms[base + cur_index], 0u);
+    }
+}
+
+@compute @workgroup_size({histogram_wg_size})
+fn calculate_histogram_batched(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {
+    row_mem_base = wid.y * row_mem_size();
+
+    let rs_block_keyvals: u32 = rs_histogram_block_rows * histogram_wg_size;
+    let kv_in_offset = wid.x * rs_block_keyvals + lid.x;
+    for (var i = 0u; i < rs_histogram_block_rows; i++) {
+        kv[i] = load_key_row(wid.y, kv_in_offset + i * histogram_wg_size);
+    }
+
+    for (var pass_ = rs_keyval_size; pass_ > 0u; pass_--) {
+        histogram_pass(pass_ - 1u, lid.x);
+    }
+}
+
+@compute @workgroup_size({prefix_wg_size})
+fn prefix_histogram_batched(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {
+    row_mem_base = wid.y * row_mem_size();
+    prefix_histogram_pass(wid, lid);
+}
+
+// offset of the first key of a scatter block, corresponds to fill_kv_even and fill_kv_odd
+fn scatter_kv_in_offset(wid: u32, lid: u32) -> u32 {
+    let subgroup_id = lid / histogram_sg_size;
+    let subgroup_invoc_id = lid - subgroup_id * histogram_sg_size;
+    let subgroup_keyvals = rs_scatter_block_rows * histogram_sg_size;
+    let rs_block_keyvals: u32 = rs_histogram_block_rows * histogram_wg_size;
+    return wid * rs_block_keyvals + subgroup_id * subgroup_keyvals + subgroup_invoc_id;
+}
+
+@compute @workgroup_size({scatter_wg_size})
+fn scatter_even_batched(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>, @builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    if gid.x == 0u && gid.y == 0u {
+        infos.odd_pass = (infos.odd_pass + 1u) % (rs_keyval_size / 2u);
+    }
+    let cur_pass = infos.even_pass * 2u;
+    let row = wid.y;
+    row_mem_base = row * row_mem_size();
+
+    // load from keys, store to keys_b
+    let kv_in_offset = scatter_kv_in_offset(wid.x, lid.x);
+    for (var i = 0u; i < rs_scatter_block_rows; i++) {
+        let pos = kv_in_offset + i * histogram_sg_size;
+        kv[i] = load_key_row(row, pos);
+        if pos < infos.num_keys {
+            pv[i] = payload_a[row * infos.num_keys + pos];
+        }
+    }
+
+    scatter(cur_pass, lid, gid, wid, nwg, partition_status_invalid, partition_status_reduction, partition_status_prefix);
+
+    // padding keys are sorted to the end of the row and are dropped
+    for (var i = 0u; i < rs_scatter_block_rows; i++) {
+        if kr[i] < infos.num_keys {
+            keys_b[row * infos.num_keys + kr[i]] = kv[i];
+            payload_b[row * infos.num_keys + kr[i]] = pv[i];
+        }
+    }
+}
+
+@compute @workgroup_size({scatter_wg_size})
+fn scatter_odd_batched(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>, @builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    if gid.x == 0u && gid.y == 0u {
+        infos.even_pass = (infos.even_pass + 1u) % (rs_keyval_size / 2u);
+    }
+    let cur_pass = infos.odd_pass * 2u + 1u;
+    let row = wid.y;
+    row_mem_base = row * row_mem_size();
+
+    // load from keys_b, store to keys
+    let kv_in_offset = scatter_kv_in_offset(wid.x, lid.x);
+    for (var i = 0u; i < rs_scatter_block_rows; i++) {
+        let pos = kv_in_offset + i * histogram_sg_size;
+        kv[i] = load_key_b_row(row, pos);
+        if pos < infos.num_keys {
+            pv[i] = payload_b[row * infos.num_keys + pos];
+        }
+    }
+
+    // rotated like scatter_odd
+    let status_invalid = (partition_status_invalid + 2u) % 4u;
+    let status_reduction = (partition_status_reduction + 2u) % 4u;
+    let status_prefix = (partition_status_prefix + 2u) % 4u;
+    scatter(cur_pass, lid, gid, wid, nwg, status_invalid, status_reduction, status_prefix);
+
+    for (var i = 0u; i < rs_scatter_block_rows; i++) {
+        if kr[i] < infos.num_keys {
+            keys[row * infos.num_keys + kr[i]] = kv[i];
+            payload_a[row * infos.num_keys + kr[i]] = pv[i];
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Sorting small inputs within a single workgroup (local_sort.wgsl is appended to this shader)
+// --------------------------------------------------------------------------------------------------------------
+@compute @workgroup_size(local_sort_wg_size)
+fn sort_single_workgroup(@builtin(local_invocation_id) lid: vec3<u32>) {
+    let len = broadcast(lid.x, min(infos.num_keys, local_sort_kvs));
+    local_sort(lid.x, 0u, len);
+}
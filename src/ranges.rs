@@ -0,0 +1,227 @@
+/*
+    Range extraction of ids in sorted keys.
+
+    After sorting by cell or tile id, neighbor searches and tile based rasterizers need the [start, end) range
+    of the pairs of every id. GPURangeFinder writes these ranges into a buffer with one vec2<u32> per id:
+    - the ranges buffer is cleared, so ids without pairs have the empty range [0, 0)
+    - find_ranges compares the id of every pair with the id of the previous pair,
+      at every change the end of the previous range and the start of the next range are written
+
+    The id of a key is the key shifted right by id_shift bits, e.g. 32 for u64 keys built as (tile << 32) | depth.
+    Pairs with ids outside of the key space are skipped, so invalid keys (e.g. u32::MAX) can be sorted to the end:
+
+    let range_finder = GPURangeFinder::new(&device);
+    let range_buffers = range_finder.create_range_buffers(&device, &sort_buffers, num_tiles, 32);
+    sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+    range_finder.find_ranges(&mut encoder, &queue, &sort_buffers, &range_buffers, None);
+
+    The shader can be found in ranges.wgsl
+*/
+
+use std::{mem, num::NonZeroU32};
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label, storage_layout_entry,
+    typed::{self, KeyKind, SortKey, SortValue, TypedDispatch},
+    utils::download_buffer,
+    SortBuffers,
+};
+
+/// workgroup size of the range shader
+const RANGES_WG_SIZE: u32 = 256;
+
+/// Key space of the ranges, has to be synced with RangeInfo in ranges.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct RangeInfo {
+    num_ids: u32,
+    id_shift: u32,
+    key_words: u32,
+    _pad: u32,
+}
+
+/// Ranges buffer and bind group for finding the ranges of one [SortBuffers]
+pub struct RangeBuffers {
+    /// [start, end) of every id as vec2<u32>
+    ranges_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    num_ids: u32,
+    /// number of pairs of the input buffers
+    input_length: u32,
+    name: Option<String>,
+}
+
+impl RangeBuffers {
+    /// Buffer containing the [start, end) range of every id as `vec2<u32>`.
+    /// Ids without pairs have the range [0, 0).
+    pub fn ranges_buffer(&self) -> &wgpu::Buffer {
+        &self.ranges_buffer
+    }
+
+    /// Number of ids in the key space
+    pub fn num_ids(&self) -> u32 {
+        self.num_ids
+    }
+
+    /// Downloads the [start, end) range of every id. The function waits for the gpu to finish.
+    pub async fn read_ranges(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<[u32; 2]> {
+        download_buffer(&self.ranges_buffer, device, queue, ..).await
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// Pipeline for finding the range of every id in sorted keys
+pub struct GPURangeFinder {
+    ranges_p: wgpu::ComputePipeline,
+}
+
+impl GPURangeFinder {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self::create(device, None)
+    }
+
+    /// Same as [GPURangeFinder::new] but adds `name` to the labels of the pipeline
+    pub fn new_named(device: &wgpu::Device, name: &str) -> Self {
+        Self::create(device, Some(name))
+    }
+
+    fn create(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("ranges.wgsl");
+        let shader_code = format!("const ranges_wg_size: u32 = {:}u;\n{:}", RANGES_WG_SIZE, raw_shader);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Ranges shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&named_label(name, "find_ranges pipeline layout")),
+            bind_group_layouts: &[&Self::bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let ranges_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(&named_label(name, "find_ranges")),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "find_ranges",
+            compilation_options: Default::default(),
+        });
+        Self { ranges_p }
+    }
+
+    fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ranges bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, true),
+                storage_layout_entry(2, false),
+                storage_layout_entry(3, true),
+            ],
+        })
+    }
+
+    /// Creates a ranges buffer with `num_ids` ranges for the keys of `sort_buffers`.
+    ///
+    /// The id of a key is `key >> id_shift`, pairs with ids of `num_ids` or larger are skipped.
+    /// Only u32 and u64 keys are supported.
+    pub fn create_range_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers<K, V>,
+        num_ids: NonZeroU32,
+        id_shift: u32,
+    ) -> RangeBuffers {
+        assert!(
+            K::KIND == KeyKind::U32 || K::KIND == KeyKind::U64,
+            "ranges can only be found for u32 and u64 keys"
+        );
+        let key_words = (mem::size_of::<K>() / mem::size_of::<u32>()) as u32;
+        assert!(id_shift < key_words * 32, "the id shift must be smaller than the number of key bits");
+        let name = sort_buffers.name();
+
+        let ranges_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "ranges buffer")),
+            size: num_ids.get() as u64 * mem::size_of::<[u32; 2]>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let info = RangeInfo {
+            num_ids: num_ids.get(),
+            id_shift,
+            key_words,
+            _pad: 0,
+        };
+        let info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "ranges info buffer")),
+            contents: bytes_of(&info),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "ranges bind group")),
+            layout: &Self::bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.keys()),
+                buffer_entry(2, &ranges_buffer),
+                buffer_entry(3, &info_buffer),
+            ],
+        });
+        RangeBuffers {
+            ranges_buffer,
+            bind_group,
+            num_ids: num_ids.get(),
+            input_length: sort_buffers.len(),
+            name: name.map(str::to_string),
+        }
+    }
+
+    /// Finds the range of every id in the first `first_n` (or all) sorted keys of `sort_buffers`.
+    ///
+    /// `sort_buffers` must be the buffers `range_buffers` were created for.
+    pub fn find_ranges<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, V>,
+        range_buffers: &RangeBuffers,
+        first_n: Option<u32>,
+    ) {
+        let num_elements = first_n.unwrap_or(range_buffers.input_length);
+        assert!(num_elements <= range_buffers.input_length, "more keys than the buffers contain");
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
+        self.record(encoder, range_buffers, &TypedDispatch::Direct(num_elements));
+    }
+
+    /// Same as [GPURangeFinder::find_ranges] for the number of keys stored in [SortBuffers::state_buffer],
+    /// e.g. after [GPUSorter::sort_indirect](crate::GPUSorter::sort_indirect).
+    ///
+    /// The dispatch buffer must contain the struct [wgpu::util::DispatchIndirectArgs]
+    /// with at least one workgroup if there are keys.
+    /// The same dispatch buffer as for the indirect sort can be used.
+    pub fn find_ranges_indirect(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        range_buffers: &RangeBuffers,
+        dispatch_buffer: &wgpu::Buffer,
+    ) {
+        self.record(encoder, range_buffers, &TypedDispatch::Indirect(dispatch_buffer));
+    }
+
+    fn record(&self, encoder: &mut wgpu::CommandEncoder, range_buffers: &RangeBuffers, dispatch: &TypedDispatch) {
+        let name = range_buffers.name.as_deref();
+        // ids without pairs keep the empty range
+        encoder.clear_buffer(&range_buffers.ranges_buffer, 0, None);
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&named_label(name, "find ranges")),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.ranges_p);
+        pass.set_bind_group(0, &range_buffers.bind_group, &[]);
+        typed::dispatch(&mut pass, dispatch, RANGES_WG_SIZE);
+    }
+}
@@ -0,0 +1,80 @@
+// shader finding the [start, end) range of every id in sorted keys. More information in ranges.rs
+//
+// Every invocation looks at the boundary before a pair: if the id changes, the pair is the start of the range
+// of its id and the end of the range of the previous id. The last pair ends the range of its id.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const ranges_wg_size
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct RangeInfo {
+    num_ids: u32,
+    id_shift: u32,
+    key_words: u32,
+    _pad: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read> infos: GeneralInfo;
+@group(0) @binding(1)
+var<storage, read> keys: array<u32>;
+@group(0) @binding(2)
+var<storage, read_write> ranges: array<vec2<u32>>;
+@group(0) @binding(3)
+var<storage, read> range_info: RangeInfo;
+
+// id of the key of pair i, the key is shifted right by id_shift bits
+fn key_id(i: u32) -> u32 {
+    if range_info.key_words == 1u {
+        return keys[i] >> range_info.id_shift;
+    }
+    // 64 bit keys are stored as low word followed by high word
+    let low = keys[2u * i];
+    let high = keys[2u * i + 1u];
+    let shift = range_info.id_shift;
+    if shift < 32u {
+        // the id needs more than 32 bits, so it is larger than every num_ids
+        if (high >> shift) != 0u {
+            return 0xFFFFFFFFu;
+        }
+        if shift == 0u {
+            return low;
+        }
+        return (low >> shift) | (high << (32u - shift));
+    }
+    return high >> (shift - 32u);
+}
+
+@compute @workgroup_size(ranges_wg_size)
+fn find_ranges(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let num_keys = infos.num_keys;
+    let num_ids = range_info.num_ids;
+    let line_size = nwg.x * ranges_wg_size;
+    for (var i = gid.x; i < num_keys; i += line_size) {
+        let id = key_id(i);
+        if i == 0u {
+            if id < num_ids {
+                ranges[id].x = 0u;
+            }
+        } else {
+            let prev_id = key_id(i - 1u);
+            if prev_id != id {
+                if prev_id < num_ids {
+                    ranges[prev_id].y = i;
+                }
+                if id < num_ids {
+                    ranges[id].x = i;
+                }
+            }
+        }
+        if i == num_keys - 1u && id < num_ids {
+            ranges[id].y = num_keys;
+        }
+    }
+}
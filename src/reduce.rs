@@ -0,0 +1,476 @@
+/*
+    Unique keys, run-length encoding and reduce-by-key on sorted key-value pairs.
+
+    GPUReducer finds the runs of equal keys in sort buffers (usually after sorting them):
+    - reduce_heads marks the first pair of every run
+    - the marks are summed up with the GPUScanner (inclusive scan), the sum is the run of a pair
+    - reduce_runs writes the key and the start of every run and counts its pairs.
+      Every invocation counts a chunk of consecutive pairs, the parts of a run are added with atomics.
+    - optionally reduce_sums_int or reduce_sums_float sum up the values of every run, one workgroup per run.
+      The sums are deterministic, f32 values are added in the same order every time.
+
+    The number of runs is written to a small buffer, together with dispatch arguments with one invocation per run,
+    so later passes can process the runs without a round trip to the cpu:
+
+    let reducer = GPUReducer::new(&device);
+    let run_buffers = reducer.create_reduce_buffers(&device, &sort_buffers);
+    sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+    reducer.reduce_by_key(&mut encoder, &queue, &sort_buffers, &run_buffers, None);
+
+    The shaders can be found in reduce.wgsl
+*/
+
+use std::{marker::PhantomData, mem, num::NonZeroU32};
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label,
+    scan::{GPUScanner, ScanBuffers, ScanMode, ScanOp, SCAN_BLOCK_KVS},
+    storage_layout_entry,
+    typed::{self, KeyKind, SortKey, SortValue, TypedDispatch},
+    utils::download_buffer,
+    SortBuffers, MAX_WORKGROUPS,
+};
+
+/// workgroup size of the reduce shaders
+const REDUCE_WG_SIZE: u32 = 256;
+
+/// number of consecutive pairs reduced by every invocation
+const REDUCE_CHUNK_SIZE: u32 = 32;
+
+/// Number of runs per workgroup of the dispatch arguments in [RunBuffers::dispatch_buffer]
+pub const RUNS_PER_WORKGROUP: u32 = 256;
+
+/// Values that can be summed up per run by [GPUReducer::reduce_by_key]
+pub trait SumValue: SortValue {
+    #[doc(hidden)]
+    const REDUCTION: ValueReduction;
+}
+
+impl SumValue for u32 {
+    const REDUCTION: ValueReduction = ValueReduction::SumInt;
+}
+impl SumValue for i32 {
+    const REDUCTION: ValueReduction = ValueReduction::SumInt;
+}
+impl SumValue for f32 {
+    const REDUCTION: ValueReduction = ValueReduction::SumFloat;
+}
+
+/// How the values of a run are reduced, has to be synced with reduce.wgsl
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueReduction {
+    None = 0,
+    /// wrapping sum of u32 or i32 values
+    SumInt = 1,
+    /// sum of f32 values
+    SumFloat = 2,
+}
+
+/// Run of equal keys
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct Run {
+    /// index of the first pair of the run
+    pub start: u32,
+    /// number of pairs of the run
+    pub count: u32,
+}
+
+/// Number of runs and dispatch arguments, has to be synced with RunsInfo in reduce.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct RunsInfo {
+    x: u32,
+    y: u32,
+    z: u32,
+    num_runs: u32,
+}
+
+/// Output and intermediate buffers for finding the runs of one [SortBuffers]
+pub struct RunBuffers<K = u32, V = u32> {
+    /// key of every run
+    unique_keys_buffer: wgpu::Buffer,
+    /// [Run] of every run
+    runs_buffer: wgpu::Buffer,
+    /// sum of the values of every run, a single word if the values are not reduced
+    sums_buffer: wgpu::Buffer,
+    /// [RunsInfo]
+    info_buffer: wgpu::Buffer,
+    scan_buffers: ScanBuffers,
+    scan_dispatch_buffer: wgpu::Buffer,
+    /// dispatch arguments of the sum shaders, one workgroup per run
+    sums_dispatch_buffer: wgpu::Buffer,
+    heads_bind_group: wgpu::BindGroup,
+    runs_bind_group: wgpu::BindGroup,
+    sums_bind_group: wgpu::BindGroup,
+    value_reduction: ValueReduction,
+    /// number of pairs of the input buffers
+    input_length: u32,
+    name: Option<String>,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: SortKey, V: SortValue> RunBuffers<K, V> {
+    /// Buffer containing the key of every run, in the order of the runs
+    pub fn unique_keys_buffer(&self) -> &wgpu::Buffer {
+        &self.unique_keys_buffer
+    }
+
+    /// Buffer containing a [Run] (start and count) for every run
+    pub fn runs_buffer(&self) -> &wgpu::Buffer {
+        &self.runs_buffer
+    }
+
+    /// Buffer containing [wgpu::util::DispatchIndirectArgs] with one invocation per run
+    /// (`x = (num_runs + RUNS_PER_WORKGROUP - 1) / RUNS_PER_WORKGROUP`), followed by the number of runs as u32
+    pub fn dispatch_buffer(&self) -> &wgpu::Buffer {
+        &self.info_buffer
+    }
+
+    /// Buffers of the scan numbering the runs,
+    /// e.g. for limiting its lookback with [ScanBuffers::set_lookback_limit]
+    pub fn scan_buffers(&self) -> &ScanBuffers {
+        &self.scan_buffers
+    }
+
+    /// Downloads the number of runs. The function waits for the gpu to finish.
+    pub async fn read_count(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> u32 {
+        download_buffer::<RunsInfo>(&self.info_buffer, device, queue, ..).await[0].num_runs
+    }
+
+    /// Downloads the key of every run. The function waits for the gpu to finish.
+    pub async fn read_unique_keys(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<K> {
+        let count = self.read_count(device, queue).await as u64;
+        download_buffer(&self.unique_keys_buffer, device, queue, ..count * mem::size_of::<K>() as u64).await
+    }
+
+    /// Downloads the start and length of every run. The function waits for the gpu to finish.
+    pub async fn read_runs(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<Run> {
+        let count = self.read_count(device, queue).await as u64;
+        download_buffer(&self.runs_buffer, device, queue, ..count * mem::size_of::<Run>() as u64).await
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+impl<K: SortKey, V: SumValue> RunBuffers<K, V> {
+    /// Buffer containing the sum of the values of every run.
+    /// Only written for buffers created by [GPUReducer::create_reduce_buffers].
+    pub fn sums_buffer(&self) -> &wgpu::Buffer {
+        &self.sums_buffer
+    }
+
+    /// Downloads the sum of the values of every run. The function waits for the gpu to finish.
+    pub async fn read_sums(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<V> {
+        let count = self.read_count(device, queue).await as u64;
+        download_buffer(&self.sums_buffer, device, queue, ..count * mem::size_of::<V>() as u64).await
+    }
+}
+
+/// Pipelines for finding and reducing runs of equal keys
+pub struct GPUReducer {
+    heads_p: wgpu::ComputePipeline,
+    runs_p: wgpu::ComputePipeline,
+    sums_int_p: wgpu::ComputePipeline,
+    sums_float_p: wgpu::ComputePipeline,
+    scanner: GPUScanner,
+}
+
+impl GPUReducer {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self::create(device, None)
+    }
+
+    /// Same as [GPUReducer::new] but adds `name` to the labels of the pipelines
+    pub fn new_named(device: &wgpu::Device, name: &str) -> Self {
+        Self::create(device, Some(name))
+    }
+
+    fn create(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("reduce.wgsl");
+        let shader_code = format!(
+            "const reduce_wg_size: u32 = {:}u;\nconst reduce_chunk_size: u32 = {:}u;\nconst scan_block_kvs: u32 = {:}u;\n\
+             const runs_per_workgroup: u32 = {:}u;\nconst reduce_max_workgroups: u32 = {:}u;\n{:}",
+            REDUCE_WG_SIZE, REDUCE_CHUNK_SIZE, SCAN_BLOCK_KVS, RUNS_PER_WORKGROUP, MAX_WORKGROUPS, raw_shader
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Reduce shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let pipeline = |layout: wgpu::BindGroupLayout, entry_point: &str| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(&named_label(name, &format!("{entry_point} pipeline layout"))),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&named_label(name, entry_point)),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        Self {
+            heads_p: pipeline(Self::heads_bind_group_layout(device), "reduce_heads"),
+            runs_p: pipeline(Self::runs_bind_group_layout(device), "reduce_runs"),
+            sums_int_p: pipeline(Self::sums_bind_group_layout(device), "reduce_sums_int"),
+            sums_float_p: pipeline(Self::sums_bind_group_layout(device), "reduce_sums_float"),
+            scanner: match name {
+                Some(name) => GPUScanner::new_named(device, name),
+                None => GPUScanner::new(device),
+            },
+        }
+    }
+
+    fn heads_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("reduce heads bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, true),
+                storage_layout_entry(3, false),
+                storage_layout_entry(4, false),
+                storage_layout_entry(5, false),
+            ],
+        })
+    }
+
+    fn runs_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("reduce runs bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, true),
+                storage_layout_entry(6, true),
+                storage_layout_entry(7, false),
+                storage_layout_entry(8, false),
+                storage_layout_entry(10, false),
+                storage_layout_entry(11, false),
+            ],
+        })
+    }
+
+    fn sums_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("reduce sums bind group layout"),
+            entries: &[
+                storage_layout_entry(2, true),
+                storage_layout_entry(8, false),
+                storage_layout_entry(9, false),
+                storage_layout_entry(10, false),
+            ],
+        })
+    }
+
+    /// Creates the buffers for finding the unique keys and runs of `sort_buffers` (run-length encoding).
+    ///
+    /// Only keys with 4 bytes (u32, i32 and f32) are supported, keys are equal if their bits are equal.
+    pub fn create_run_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers<K, V>,
+    ) -> RunBuffers<K, V> {
+        self.create_buffers(device, sort_buffers, ValueReduction::None)
+    }
+
+    /// Same as [GPUReducer::create_run_buffers], but the values of every run are summed up as well (reduce-by-key)
+    pub fn create_reduce_buffers<K: SortKey, V: SumValue>(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers<K, V>,
+    ) -> RunBuffers<K, V> {
+        self.create_buffers(device, sort_buffers, V::REDUCTION)
+    }
+
+    fn create_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers<K, V>,
+        value_reduction: ValueReduction,
+    ) -> RunBuffers<K, V> {
+        assert!(K::KIND != KeyKind::U64, "runs can only be found for keys with 4 bytes");
+        let length = sort_buffers.len();
+        let name = sort_buffers.name();
+        let word_size = mem::size_of::<u32>() as u64;
+
+        let buffer = |label: &str, size: u64, usage: wgpu::BufferUsages| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&named_label(name, label)),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | usage,
+                mapped_at_creation: false,
+            })
+        };
+        let output_usage = wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST;
+        let unique_keys_buffer = buffer("unique keys buffer", length as u64 * word_size, output_usage);
+        let runs_buffer = buffer("runs buffer", length as u64 * mem::size_of::<Run>() as u64, output_usage);
+        let sums_length = if value_reduction == ValueReduction::None { 1 } else { length };
+        let sums_buffer = buffer("run sums buffer", sums_length as u64 * word_size, output_usage);
+        let heads_buffer = buffer("run heads buffer", length as u64 * word_size, wgpu::BufferUsages::empty());
+        let run_ids_buffer = buffer("run ids buffer", length as u64 * word_size, wgpu::BufferUsages::empty());
+
+        let info = RunsInfo {
+            x: 0,
+            y: 1,
+            z: 1,
+            num_runs: 0,
+        };
+        let info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "runs info buffer")),
+            contents: bytes_of(&info),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_SRC,
+        });
+        let scan_buffers = self.scanner.create_scan_buffers(
+            device,
+            &heads_buffer,
+            &run_ids_buffer,
+            NonZeroU32::new(length).unwrap(),
+            ScanOp::Add,
+            ScanMode::Inclusive,
+        );
+        let scan_dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "run heads scan dispatch buffer")),
+            contents: wgpu::util::DispatchIndirectArgs { x: 0, y: 1, z: 1 }.as_bytes(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+        });
+        let sums_dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "run sums dispatch buffer")),
+            contents: wgpu::util::DispatchIndirectArgs { x: 0, y: 1, z: 1 }.as_bytes(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+        });
+
+        let heads_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "reduce heads bind group")),
+            layout: &Self::heads_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.keys()),
+                buffer_entry(3, &heads_buffer),
+                buffer_entry(4, scan_buffers.state_buffer()),
+                buffer_entry(5, &scan_dispatch_buffer),
+            ],
+        });
+        let runs_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "reduce runs bind group")),
+            layout: &Self::runs_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.keys()),
+                buffer_entry(6, &run_ids_buffer),
+                buffer_entry(7, &unique_keys_buffer),
+                buffer_entry(8, &runs_buffer),
+                buffer_entry(10, &info_buffer),
+                buffer_entry(11, &sums_dispatch_buffer),
+            ],
+        });
+        let sums_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "reduce sums bind group")),
+            layout: &Self::sums_bind_group_layout(device),
+            entries: &[
+                buffer_entry(2, sort_buffers.values()),
+                buffer_entry(8, &runs_buffer),
+                buffer_entry(9, &sums_buffer),
+                buffer_entry(10, &info_buffer),
+            ],
+        });
+        RunBuffers {
+            unique_keys_buffer,
+            runs_buffer,
+            sums_buffer,
+            info_buffer,
+            scan_buffers,
+            scan_dispatch_buffer,
+            sums_dispatch_buffer,
+            heads_bind_group,
+            runs_bind_group,
+            sums_bind_group,
+            value_reduction,
+            input_length: length,
+            name: name.map(str::to_string),
+            _types: PhantomData,
+        }
+    }
+
+    /// Finds the runs of equal consecutive keys of the first `reduce_first_n` pairs (or all pairs) of `sort_buffers`.
+    /// Writes the key, start and length of every run and, for buffers created by [GPUReducer::create_reduce_buffers],
+    /// the sum of its values.
+    ///
+    /// The keys are usually sorted before, so every key has exactly one run.
+    /// `sort_buffers` must be the buffers `run_buffers` were created for.
+    pub fn reduce_by_key<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, V>,
+        run_buffers: &RunBuffers<K, V>,
+        reduce_first_n: Option<u32>,
+    ) {
+        let num_elements = reduce_first_n.unwrap_or(run_buffers.input_length);
+        assert!(num_elements <= run_buffers.input_length, "more pairs reduced than the buffers contain");
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
+        self.record(encoder, run_buffers, &TypedDispatch::Direct(num_elements));
+    }
+
+    /// Same as [GPUReducer::reduce_by_key] for the number of pairs stored in [SortBuffers::state_buffer],
+    /// e.g. after [GPUSorter::sort_indirect](crate::GPUSorter::sort_indirect).
+    ///
+    /// The dispatch buffer must contain the struct [wgpu::util::DispatchIndirectArgs]
+    /// with at least one workgroup if there are pairs to reduce.
+    /// The same dispatch buffer as for the indirect sort can be used.
+    pub fn reduce_by_key_indirect<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        run_buffers: &RunBuffers<K, V>,
+        dispatch_buffer: &wgpu::Buffer,
+    ) {
+        self.record(encoder, run_buffers, &TypedDispatch::Indirect(dispatch_buffer));
+    }
+
+    fn record<K, V>(&self, encoder: &mut wgpu::CommandEncoder, run_buffers: &RunBuffers<K, V>, dispatch: &TypedDispatch) {
+        let name = run_buffers.name.as_deref();
+        encoder.push_debug_group(&named_label(name, "reduce by key"));
+        // the counts are accumulated with atomics
+        encoder.clear_buffer(&run_buffers.runs_buffer, 0, None);
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "reduce heads")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.heads_p);
+            pass.set_bind_group(0, &run_buffers.heads_bind_group, &[]);
+            typed::dispatch(&mut pass, dispatch, REDUCE_WG_SIZE);
+        }
+        self.scanner.scan_indirect(encoder, &run_buffers.scan_buffers, &run_buffers.scan_dispatch_buffer);
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "reduce runs")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.runs_p);
+            pass.set_bind_group(0, &run_buffers.runs_bind_group, &[]);
+            typed::dispatch(&mut pass, dispatch, REDUCE_WG_SIZE * REDUCE_CHUNK_SIZE);
+        }
+        let sums_p = match run_buffers.value_reduction {
+            ValueReduction::None => None,
+            ValueReduction::SumInt => Some(&self.sums_int_p),
+            ValueReduction::SumFloat => Some(&self.sums_float_p),
+        };
+        if let Some(sums_p) = sums_p {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "reduce sums")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(sums_p);
+            pass.set_bind_group(0, &run_buffers.sums_bind_group, &[]);
+            pass.dispatch_workgroups_indirect(&run_buffers.sums_dispatch_buffer, 0);
+        }
+        encoder.pop_debug_group();
+    }
+}
@@ -0,0 +1,201 @@
+// shader finding the runs of equal keys and reducing their values. More information in reduce.rs
+//
+// reduce_heads marks the first pair of every run, the marks are scanned by scan.wgsl (inclusive sum)
+// and reduce_runs counts the pairs of every run. Every invocation counts a chunk of consecutive pairs
+// and adds its part of a run to the count of the run with atomics.
+// Afterwards reduce_sums_int or reduce_sums_float sum up the values of every run, one workgroup per run.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const reduce_wg_size
+// const reduce_chunk_size
+// const scan_block_kvs
+// const runs_per_workgroup
+// const reduce_max_workgroups
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct ScanState {
+    length: u32,
+    op: u32,
+    inclusive: u32,
+    lookback_limit: u32,
+    error_flags: u32,
+};
+
+struct Run {
+    start: u32,
+    count: atomic<u32>,
+};
+
+// dispatch arguments with one invocation per run, followed by the number of runs
+struct RunsInfo {
+    x: u32,
+    y: u32,
+    z: u32,
+    num_runs: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read> infos: GeneralInfo;
+@group(0) @binding(1)
+var<storage, read> keys: array<u32>;
+
+// reduce_heads
+@group(0) @binding(3)
+var<storage, read_write> heads: array<u32>;
+@group(0) @binding(4)
+var<storage, read_write> scan_state: ScanState;
+@group(0) @binding(5)
+var<storage, read_write> scan_dispatch: array<u32, 3>;
+
+// reduce_runs and reduce_sums
+@group(0) @binding(2)
+var<storage, read> values: array<u32>;
+@group(0) @binding(6)
+var<storage, read> run_ids: array<u32>;
+@group(0) @binding(7)
+var<storage, read_write> unique_keys: array<u32>;
+@group(0) @binding(8)
+var<storage, read_write> runs: array<Run>;
+@group(0) @binding(9)
+var<storage, read_write> sums: array<u32>;
+@group(0) @binding(10)
+var<storage, read_write> runs_info: RunsInfo;
+// dispatch arguments of the sum shaders, one workgroup per run
+@group(0) @binding(11)
+var<storage, read_write> sums_dispatch: array<u32, 3>;
+
+@compute @workgroup_size(reduce_wg_size)
+fn reduce_heads(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let num_keys = infos.num_keys;
+    if gid.x == 0u {
+        // the scan runs over all pairs of the input
+        scan_state.length = num_keys;
+        scan_dispatch[0] = min((num_keys + scan_block_kvs - 1u) / scan_block_kvs, reduce_max_workgroups);
+        scan_dispatch[1] = 1u;
+        scan_dispatch[2] = 1u;
+    }
+    let line_size = nwg.x * reduce_wg_size;
+    for (var i = gid.x; i < num_keys; i += line_size) {
+        heads[i] = select(0u, 1u, i == 0u || keys[i] != keys[i - 1u]);
+    }
+}
+
+@compute @workgroup_size(reduce_wg_size)
+fn reduce_runs(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let num_keys = infos.num_keys;
+    let num_chunks = (num_keys + reduce_chunk_size - 1u) / reduce_chunk_size;
+    let line_size = nwg.x * reduce_wg_size;
+    for (var chunk = gid.x; chunk < num_chunks; chunk += line_size) {
+        let start = chunk * reduce_chunk_size;
+        let end = min(start + reduce_chunk_size, num_keys);
+        // the run ids are an inclusive sum of the heads
+        var run = run_ids[start] - 1u;
+        var count = 0u;
+        for (var i = start; i < end; i++) {
+            let run_id = run_ids[i] - 1u;
+            if run_id != run {
+                atomicAdd(&runs[run].count, count);
+                run = run_id;
+                count = 0u;
+            }
+            if i == 0u || run_ids[i - 1u] != run_ids[i] {
+                unique_keys[run] = keys[i];
+                runs[run].start = i;
+            }
+            count += 1u;
+        }
+        atomicAdd(&runs[run].count, count);
+    }
+
+    // the number of runs is the last run id
+    if gid.x == 0u {
+        var num_runs = 0u;
+        if num_keys > 0u {
+            num_runs = run_ids[num_keys - 1u];
+        }
+        runs_info.num_runs = num_runs;
+        runs_info.x = (num_runs + runs_per_workgroup - 1u) / runs_per_workgroup;
+        runs_info.y = 1u;
+        runs_info.z = 1u;
+        sums_dispatch[0] = min(num_runs, reduce_max_workgroups);
+        sums_dispatch[1] = 1u;
+        sums_dispatch[2] = 1u;
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Summing up the values of every run
+// --------------------------------------------------------------------------------------------------------------
+var<workgroup> num_runs_uniform: u32;
+var<workgroup> sums_int: array<u32, reduce_wg_size>;
+var<workgroup> sums_float: array<f32, reduce_wg_size>;
+
+@compute @workgroup_size(reduce_wg_size)
+fn reduce_sums_int(
+    @builtin(local_invocation_index) lid: u32,
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(num_workgroups) nwg: vec3<u32>,
+) {
+    if lid == 0u {
+        num_runs_uniform = runs_info.num_runs;
+    }
+    let num_runs = workgroupUniformLoad(&num_runs_uniform);
+    for (var run = wid.x; run < num_runs; run += nwg.x) {
+        let start = runs[run].start;
+        let end = start + atomicLoad(&runs[run].count);
+        var sum = 0u;
+        for (var i = start + lid; i < end; i += reduce_wg_size) {
+            sum += values[i];
+        }
+        sums_int[lid] = sum;
+        for (var offset = reduce_wg_size / 2u; offset > 0u; offset = offset >> 1u) {
+            workgroupBarrier();
+            if lid < offset {
+                sums_int[lid] += sums_int[lid + offset];
+            }
+        }
+        workgroupBarrier();
+        if lid == 0u {
+            sums[run] = sums_int[0];
+        }
+        workgroupBarrier();
+    }
+}
+
+@compute @workgroup_size(reduce_wg_size)
+fn reduce_sums_float(
+    @builtin(local_invocation_index) lid: u32,
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(num_workgroups) nwg: vec3<u32>,
+) {
+    if lid == 0u {
+        num_runs_uniform = runs_info.num_runs;
+    }
+    let num_runs = workgroupUniformLoad(&num_runs_uniform);
+    for (var run = wid.x; run < num_runs; run += nwg.x) {
+        let start = runs[run].start;
+        let end = start + atomicLoad(&runs[run].count);
+        var sum = 0.0;
+        for (var i = start + lid; i < end; i += reduce_wg_size) {
+            sum += bitcast<f32>(values[i]);
+        }
+        sums_float[lid] = sum;
+        for (var offset = reduce_wg_size / 2u; offset > 0u; offset = offset >> 1u) {
+            workgroupBarrier();
+            if lid < offset {
+                sums_float[lid] += sums_float[lid + offset];
+            }
+        }
+        workgroupBarrier();
+        if lid == 0u {
+            sums[run] = bitcast<u32>(sums_float[0]);
+        }
+        workgroupBarrier();
+    }
+}
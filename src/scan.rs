@@ -0,0 +1,288 @@
+/*
+    Device wide prefix scan of u32 values.
+
+    GPUScanner computes the exclusive or inclusive prefix sum (or running maximum/minimum) of an input buffer
+    and writes it to an output buffer in a single dispatch:
+    - every workgroup scans blocks of SCAN_BLOCK_KVS values in shared memory. The blocks are taken from
+      a counter in the order the workgroups start, so inputs with more blocks than workgroups per dispatch
+      are scanned by the same workgroups one block after another
+    - the aggregates of the blocks are combined with a decoupled lookback over a partitions buffer,
+      with the same reduction and prefix statuses as the scatter passes of the radix sort
+
+    let scanner = GPUScanner::new(&device);
+    let scan_buffers = scanner.create_scan_buffers(&device, &input, &output, max_length, ScanOp::Add, ScanMode::Exclusive);
+    scanner.scan(&mut encoder, &queue, &scan_buffers, Some(length));
+
+    The shaders can be found in scan.wgsl
+*/
+
+use std::{mem, num::NonZeroU32};
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label, partition_status_shader_constants, storage_layout_entry, utils, MAX_WORKGROUPS,
+    SORT_ERROR_LOOKBACK_TIMEOUT,
+};
+
+/// workgroup size of the scan shader
+const SCAN_WG_SIZE: u32 = 256;
+
+/// number of consecutive values scanned by every invocation
+const SCAN_BLOCK_ROWS: u32 = 8;
+
+/// number of values scanned by one workgroup at a time.
+/// Indirect scans should use `min((N + SCAN_BLOCK_KVS - 1) / SCAN_BLOCK_KVS, 65535)` workgroups
+pub const SCAN_BLOCK_KVS: u32 = SCAN_WG_SIZE * SCAN_BLOCK_ROWS;
+
+/// Operation combining the values, has to be synced with scan.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanOp {
+    /// prefix sum, wraps on overflow. The exclusive scan starts with 0
+    Add = 0,
+    /// running maximum. The exclusive scan starts with 0
+    Max = 1,
+    /// running minimum. The exclusive scan starts with u32::MAX
+    Min = 2,
+}
+
+/// Whether the value itself is part of its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanMode {
+    /// `output[i] = input[0] + ... + input[i-1]`
+    Exclusive,
+    /// `output[i] = input[0] + ... + input[i]`
+    Inclusive,
+}
+
+/// Struct containing the state of a scan, has to be synced with ScanState in scan.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct ScanState {
+    /// number of first n values that will be scanned
+    pub length: u32,
+    op: u32,
+    inclusive: u32,
+    /// see [ScanBuffers::set_lookback_limit]
+    lookback_limit: u32,
+    /// sticky error bits set by the shader, see [SORT_ERROR_LOOKBACK_TIMEOUT]
+    error_flags: u32,
+}
+
+/// State, partitions and bind group for scanning one input buffer into one output buffer
+pub struct ScanBuffers {
+    state_buffer: wgpu::Buffer,
+    /// index of the next block and aggregates of the blocks for the lookback
+    partitions_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    max_length: u32,
+    name: Option<String>,
+}
+
+impl ScanBuffers {
+    /// maximum number of values that can be scanned
+    pub fn max_length(&self) -> u32 {
+        self.max_length
+    }
+
+    /// Buffer containing a [ScanState].
+    /// [ScanState::length] can be written by the gpu before [GPUScanner::scan_indirect].
+    pub fn state_buffer(&self) -> &wgpu::Buffer {
+        &self.state_buffer
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Limits the number of spins of the lookback on a block that was not published yet.
+    ///
+    /// If the limit is exceeded, [SORT_ERROR_LOOKBACK_TIMEOUT] is set in [ScanBuffers::state_buffer]
+    /// instead of spinning until the device is lost. The output of the scan is invalid in this case.
+    /// `None` (the default) spins until the block is published.
+    pub fn set_lookback_limit(&self, queue: &wgpu::Queue, limit: Option<NonZeroU32>) {
+        let limit = limit.map_or(0, NonZeroU32::get);
+        queue.write_buffer(&self.state_buffer, mem::offset_of!(ScanState, lookback_limit) as u64, bytes_of(&limit));
+    }
+
+    /// Resets the error bits in [ScanBuffers::state_buffer], they are not reset by scanning
+    pub fn clear_errors(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(&self.state_buffer, mem::offset_of!(ScanState, error_flags) as u64, bytes_of(&0u32));
+    }
+
+    /// Reads the error bits (see [SORT_ERROR_LOOKBACK_TIMEOUT]) set by all scans since the last [ScanBuffers::clear_errors].
+    /// The function waits for the gpu to finish.
+    pub async fn read_errors(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> u32 {
+        let state: Vec<ScanState> = utils::download_buffer(&self.state_buffer, device, queue, ..).await;
+        state[0].error_flags
+    }
+
+    /// true if the lookback gave up waiting, see [ScanBuffers::set_lookback_limit]
+    pub async fn lookback_timed_out(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        self.read_errors(device, queue).await & SORT_ERROR_LOOKBACK_TIMEOUT != 0
+    }
+}
+
+/// Pipeline for device wide prefix scans
+pub struct GPUScanner {
+    scan_p: wgpu::ComputePipeline,
+    name: Option<String>,
+}
+
+impl GPUScanner {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self::create(device, None)
+    }
+
+    /// Same as [GPUScanner::new] but adds `name` to the labels of the pipeline and passes
+    pub fn new_named(device: &wgpu::Device, name: &str) -> Self {
+        Self::create(device, Some(name))
+    }
+
+    fn create(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("scan.wgsl");
+        let shader_code = format!(
+            "const scan_wg_size: u32 = {:}u;\nconst scan_block_rows: u32 = {:}u;\n{:}{:}",
+            SCAN_WG_SIZE,
+            SCAN_BLOCK_ROWS,
+            partition_status_shader_constants(),
+            raw_shader
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Scan shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&named_label(name, "scan pipeline layout")),
+            bind_group_layouts: &[&Self::bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let scan_p = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(&named_label(name, "scan")),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "scan",
+            compilation_options: Default::default(),
+        });
+        Self {
+            scan_p,
+            name: name.map(str::to_string),
+        }
+    }
+
+    fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("scan bind group layout"),
+            entries: &[
+                storage_layout_entry(0, false),
+                storage_layout_entry(1, true),
+                storage_layout_entry(2, false),
+                storage_layout_entry(3, false),
+            ],
+        })
+    }
+
+    /// Creates the buffers for scanning up to `max_length` u32 values of `input` into `output`.
+    ///
+    /// Both buffers need [wgpu::BufferUsages::STORAGE] and have to be different buffers.
+    pub fn create_scan_buffers(
+        &self,
+        device: &wgpu::Device,
+        input: &wgpu::Buffer,
+        output: &wgpu::Buffer,
+        max_length: NonZeroU32,
+        op: ScanOp,
+        mode: ScanMode,
+    ) -> ScanBuffers {
+        let max_length = max_length.get();
+        let num_blocks = max_length.div_ceil(SCAN_BLOCK_KVS);
+        let word_size = mem::size_of::<u32>() as u64;
+        assert!(
+            input.size() >= max_length as u64 * word_size && output.size() >= max_length as u64 * word_size,
+            "input and output buffers must hold max_length values"
+        );
+        let name = self.name.as_deref();
+
+        let state = ScanState {
+            length: max_length,
+            op: op as u32,
+            inclusive: (mode == ScanMode::Inclusive) as u32,
+            lookback_limit: 0,
+            error_flags: 0,
+        };
+        let state_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "scan state buffer")),
+            contents: bytes_of(&state),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        });
+        let partitions_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "scan partitions buffer")),
+            size: (1 + 2 * num_blocks as u64) * word_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "scan bind group")),
+            layout: &Self::bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &state_buffer),
+                buffer_entry(1, input),
+                buffer_entry(2, output),
+                buffer_entry(3, &partitions_buffer),
+            ],
+        });
+        ScanBuffers {
+            state_buffer,
+            partitions_buffer,
+            bind_group,
+            max_length,
+            name: name.map(str::to_string),
+        }
+    }
+
+    /// Scans the first `scan_first_n` values (or all `max_length` values) of the input buffer into the output buffer
+    pub fn scan(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        scan_buffers: &ScanBuffers,
+        scan_first_n: Option<u32>,
+    ) {
+        let length = scan_first_n.unwrap_or(scan_buffers.max_length);
+        assert!(length <= scan_buffers.max_length, "more values scanned than the buffers were created for");
+        queue.write_buffer(&scan_buffers.state_buffer, 0, bytes_of(&length));
+
+        encoder.clear_buffer(&scan_buffers.partitions_buffer, 0, None);
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&named_label(scan_buffers.name(), "scan")),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.scan_p);
+        pass.set_bind_group(0, &scan_buffers.bind_group, &[]);
+        pass.dispatch_workgroups(length.div_ceil(SCAN_BLOCK_KVS).clamp(1, MAX_WORKGROUPS), 1, 1);
+    }
+
+    /// Scans the input buffer with a number of workgroups read from `dispatch_buffer`.
+    ///
+    /// The dispatch buffer must contain the struct [wgpu::util::DispatchIndirectArgs]
+    /// with x = min((N + [SCAN_BLOCK_KVS] - 1) / [SCAN_BLOCK_KVS], 65535), y = 1 and z = 1,
+    /// where N is [ScanState::length] in [ScanBuffers::state_buffer].
+    /// Any x of at least one scans all values, the workgroups take the blocks one after another.
+    /// The length defaults to `max_length` or the length of the last direct scan.
+    pub fn scan_indirect(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        scan_buffers: &ScanBuffers,
+        dispatch_buffer: &wgpu::Buffer,
+    ) {
+        encoder.clear_buffer(&scan_buffers.partitions_buffer, 0, None);
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&named_label(scan_buffers.name(), "scan indirect")),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.scan_p);
+        pass.set_bind_group(0, &scan_buffers.bind_group, &[]);
+        pass.dispatch_workgroups_indirect(dispatch_buffer, 0);
+    }
+}
@@ -0,0 +1,192 @@
+// shader implementing a device wide prefix scan (add, max or min) over u32 values. More information in scan.rs
+//
+// Every workgroup scans blocks of values and publishes the aggregate of every block in the partitions buffer,
+// the same way the scatter passes in radix_sort.wgsl publish their histograms:
+// first the reduction of the block, then the inclusive prefix once all previous blocks are known (decoupled lookback).
+// The two highest bits of a partition word contain the status, so every aggregate is split into two words
+// with 16 bits each.
+// The workgroups take the blocks in the order they start from a counter in front of the partitions,
+// so a block only waits for blocks of workgroups that are already running and the dispatch can be smaller
+// than the number of blocks.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const scan_wg_size
+// const scan_block_rows
+// the partition status constants shared with radix_sort.wgsl (see partition_status_shader_constants in lib.rs)
+
+struct ScanState {
+    length: u32,
+    // see ScanOp in scan.rs
+    op: u32,
+    inclusive: u32,
+    // maximum number of spins on an unpublished block in the lookback, 0 spins forever
+    lookback_limit: u32,
+    // sticky error bits, cleared by the host
+    error_flags: atomic<u32>,
+};
+
+const scan_op_max: u32 = 1u;
+const scan_op_min: u32 = 2u;
+
+// set in error_flags if the lookback gave up waiting for a block, same bit as SORT_ERROR_LOOKBACK_TIMEOUT
+const scan_error_lookback_timeout: u32 = 1u;
+
+const scan_block_kvs: u32 = scan_wg_size * scan_block_rows;
+
+// returned by next_block() if all blocks are taken
+const scan_no_block: u32 = 0xFFFFFFFFu;
+
+@group(0) @binding(0)
+var<storage, read_write> state: ScanState;
+@group(0) @binding(1)
+var<storage, read> input: array<u32>;
+@group(0) @binding(2)
+var<storage, read_write> output: array<u32>;
+// the index of the next block followed by two words (low and high half of the aggregate) per block,
+// cleared before every scan
+@group(0) @binding(3)
+var<storage, read_write> partitions: array<atomic<u32>>;
+
+var<workgroup> scan_smem: array<u32, scan_wg_size>;
+// combined aggregate of all previous blocks
+var<workgroup> block_exclusive: u32;
+var<workgroup> block_broadcast: u32;
+
+var<private> vals: array<u32, scan_block_rows>;
+
+fn identity() -> u32 {
+    switch state.op {
+        case scan_op_min: {
+            return 0xFFFFFFFFu;
+        }
+        default: {
+            return 0u;
+        }
+    }
+}
+
+fn combine(a: u32, b: u32) -> u32 {
+    switch state.op {
+        case scan_op_max: {
+            return max(a, b);
+        }
+        case scan_op_min: {
+            return min(a, b);
+        }
+        default: {
+            return a + b;
+        }
+    }
+}
+
+fn partition_offset(block: u32) -> u32 {
+    return 1u + 2u * block;
+}
+
+fn publish(block: u32, status: u32, value: u32) {
+    let mask = status << partition_status_shift;
+    atomicStore(&partitions[partition_offset(block)], mask | (value & 0xFFFFu));
+    atomicStore(&partitions[partition_offset(block) + 1u], mask | (value >> 16u));
+}
+
+// index of the next block that is scanned by the workgroup, the same for all invocations
+fn next_block(lid: u32) -> u32 {
+    if lid == 0u {
+        let num_blocks = (state.length + scan_block_kvs - 1u) / scan_block_kvs;
+        let block = atomicAdd(&partitions[0], 1u);
+        block_broadcast = select(scan_no_block, block, block < num_blocks);
+    }
+    return workgroupUniformLoad(&block_broadcast);
+}
+
+// aggregate of all blocks before the given one, corresponds to the lookback in scatter() of radix_sort.wgsl
+fn lookback(block: u32) -> u32 {
+    var exc = identity();
+    var prev = block - 1u;
+    let lookback_limit = state.lookback_limit;
+    var spins = 0u;
+    while true {
+        let low = atomicLoad(&partitions[partition_offset(prev)]);
+        let high = atomicLoad(&partitions[partition_offset(prev) + 1u]);
+        let status = low >> partition_status_shift;
+        // both halves have to be published with the same status
+        if status == partition_status_invalid || (high >> partition_status_shift) != status {
+            spins++;
+            if lookback_limit == 0u || spins < lookback_limit {
+                continue;
+            }
+            // the block is never published, give up and publish the (wrong) prefix
+            // so that the following workgroups do not wait for this one
+            atomicOr(&state.error_flags, scan_error_lookback_timeout);
+            break;
+        }
+        exc = combine((low & 0xFFFFu) | (high << 16u), exc);
+        if status == partition_status_prefix {
+            break;
+        }
+        prev -= 1u;
+    }
+    return exc;
+}
+
+@compute @workgroup_size(scan_wg_size)
+fn scan(@builtin(local_invocation_index) lid: u32) {
+    for (var block = next_block(lid); block != scan_no_block; block = next_block(lid)) {
+        scan_block(block, lid);
+    }
+}
+
+fn scan_block(block: u32, lid: u32) {
+    // every invocation scans consecutive values sequentially
+    let start = block * scan_block_kvs + lid * scan_block_rows;
+    var sum = identity();
+    for (var i = 0u; i < scan_block_rows; i++) {
+        if start + i < state.length {
+            sum = combine(sum, input[start + i]);
+        }
+        vals[i] = sum;
+    }
+
+    // inclusive scan of the sums of all invocations
+    scan_smem[lid] = sum;
+    workgroupBarrier();
+    for (var offset = 1u; offset < scan_wg_size; offset = offset << 1u) {
+        var s = scan_smem[lid];
+        if lid >= offset {
+            s = combine(s, scan_smem[lid - offset]);
+        }
+        workgroupBarrier();
+        scan_smem[lid] = s;
+        workgroupBarrier();
+    }
+
+    if lid == 0u {
+        let aggregate = scan_smem[scan_wg_size - 1u];
+        if block == 0u {
+            publish(block, partition_status_prefix, aggregate);
+            block_exclusive = identity();
+        } else {
+            publish(block, partition_status_reduction, aggregate);
+            let exc = lookback(block);
+            publish(block, partition_status_prefix, combine(exc, aggregate));
+            block_exclusive = exc;
+        }
+    }
+    workgroupBarrier();
+
+    var offset = block_exclusive;
+    if lid > 0u {
+        offset = combine(offset, scan_smem[lid - 1u]);
+    }
+    for (var i = 0u; i < scan_block_rows; i++) {
+        if start + i < state.length {
+            if state.inclusive != 0u {
+                output[start + i] = combine(offset, vals[i]);
+            } else if i == 0u {
+                output[start + i] = offset;
+            } else {
+                output[start + i] = combine(offset, vals[i - 1u]);
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+/*
+    Segmented sorting: sorts many independent ranges (segments) of a key-value buffer in one call.
+
+    The segments are described by an offsets buffer, segment i contains the pairs offsets[i]..offsets[i+1].
+    Segments with at most LOCAL_SORT_KVS pairs are sorted by a single workgroup each in shared memory.
+    The pairs of the larger segments are copied into scratch buffers with u64 keys that contain the segment
+    in the high word and the key in the low word. One typed radix sort then sorts all large segments at once
+    (see typed.rs) and the sorted pairs are copied back. Small segments never go through the radix sort.
+    All decisions are made on the gpu, so the segments can be generated on the gpu as well.
+
+    The shaders can be found in segmented.wgsl and local_sort.wgsl
+*/
+
+use std::{
+    mem,
+    num::{NonZeroU32, NonZeroU64},
+};
+
+use bytemuck::bytes_of;
+
+use crate::{
+    buffer_entry, local_sort_shader_constants, named_label, storage_layout_entry, GPUSorter,
+    SortBuffers, MAX_WORKGROUPS,
+};
+
+pub use crate::LOCAL_SORT_KVS;
+
+/// workgroup size of the shaders operating on whole segments
+const SEGMENT_WG_SIZE: u32 = 256;
+
+/// offsets of the indirect dispatch arguments within the dispatch buffer, see SegmentDispatch in segmented.wgsl
+/// the arguments for the radix sort are stored at the beginning
+const COPY_DISPATCH_OFFSET: u64 = 12;
+const LOCAL_DISPATCH_OFFSET: u64 = 24;
+const SEGMENTS_DISPATCH_OFFSET: u64 = 36;
+const DISPATCH_BUFFER_SIZE: u64 = 48;
+
+/// size of one entry of the large segment list in the state buffer, see LargeSegment in segmented.wgsl
+const LARGE_SEGMENT_SIZE: usize = 8;
+
+/// Sorting pipeline for many independent segments of a [SortBuffers].
+///
+/// Each segment ends up sorted in place. Ties are resolved by the original order, the sort is stable.
+pub struct SegmentedSorter {
+    sorter: GPUSorter,
+    prepare_p: wgpu::ComputePipeline,
+    find_large_p: wgpu::ComputePipeline,
+    prepare_sort_p: wgpu::ComputePipeline,
+    local_sort_p: wgpu::ComputePipeline,
+    gather_p: wgpu::ComputePipeline,
+    scatter_p: wgpu::ComputePipeline,
+}
+
+impl SegmentedSorter {
+    pub fn new(device: &wgpu::Device, subgroup_size: u32) -> Self {
+        Self::create(device, GPUSorter::new(device, subgroup_size))
+    }
+
+    /// Creates a segmented sorter with a name that is added to the labels of all its pipelines,
+    /// buffers, bind groups and passes, see [GPUSorter::new_named].
+    pub fn new_named(device: &wgpu::Device, subgroup_size: u32, name: &str) -> Self {
+        Self::create(device, GPUSorter::new_named(device, subgroup_size, name))
+    }
+
+    fn create(device: &wgpu::Device, sorter: GPUSorter) -> Self {
+        let label = |label: &str| named_label(sorter.name(), label);
+
+        let shader_code = format!(
+            "const segment_wg_size: u32 = {:}u;\n\
+            const histo_block_kvs: u32 = {:}u;\n\
+            const max_workgroups: u32 = {:}u;\n{:}{:}{:}",
+            SEGMENT_WG_SIZE,
+            sorter.histo_block_kvs(),
+            MAX_WORKGROUPS,
+            local_sort_shader_constants(),
+            include_str!("local_sort.wgsl"),
+            include_str!("segmented.wgsl"),
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&label("Segmented sort shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+
+        let segment_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&label("segmented sort pipeline layout")),
+            bind_group_layouts: &[&Self::bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let dispatch_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&label("segmented sort dispatch pipeline layout")),
+            bind_group_layouts: &[&Self::dispatch_bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = |layout: &wgpu::PipelineLayout, entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&label(entry_point)),
+                layout: Some(layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+
+        Self {
+            prepare_p: pipeline(&dispatch_layout, "prepare_segments"),
+            find_large_p: pipeline(&segment_layout, "find_large_segments"),
+            prepare_sort_p: pipeline(&dispatch_layout, "prepare_sort"),
+            local_sort_p: pipeline(&segment_layout, "sort_segments_local"),
+            gather_p: pipeline(&segment_layout, "gather_segments"),
+            scatter_p: pipeline(&segment_layout, "scatter_segments"),
+            sorter,
+        }
+    }
+
+    /// The sorter used if there are large segments
+    pub fn sorter(&self) -> &GPUSorter {
+        &self.sorter
+    }
+
+    /// bind group layout for the shaders that are dispatched indirectly
+    fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("segmented sort bind group layout"),
+            entries: &[
+                state_layout_entry(0),
+                storage_layout_entry(1, true),
+                storage_layout_entry(2, false),
+                storage_layout_entry(3, false),
+                storage_layout_entry(4, false),
+                storage_layout_entry(5, false),
+            ],
+        })
+    }
+
+    /// bind group layout for the shaders that write the indirect dispatch arguments
+    fn dispatch_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("segmented sort dispatch bind group layout"),
+            entries: &[
+                state_layout_entry(0),
+                storage_layout_entry(1, true),
+                storage_layout_entry(6, false),
+                storage_layout_entry(7, false),
+            ],
+        })
+    }
+
+    /// Writes segmented sort commands to command encoder.
+    /// Sorts the first `num_segments` segments described by the offsets buffer.
+    pub fn sort(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        segment_buffers: &SegmentedSortBuffers,
+        num_segments: u32,
+    ) {
+        queue.write_buffer(&segment_buffers.state_buffer, 0, bytes_of(&num_segments));
+        self.record_sort(encoder, segment_buffers);
+    }
+
+    /// Initiates segmented sorting for a number of segments that is only known on the gpu.
+    ///
+    /// [SegmentedSortBuffers::state_buffer] must contain the number of segments ([SegmentState::num_segments]).
+    pub fn sort_indirect(&self, encoder: &mut wgpu::CommandEncoder, segment_buffers: &SegmentedSortBuffers) {
+        self.record_sort(encoder, segment_buffers);
+    }
+
+    // the same commands are recorded for any number and size of segments,
+    // the unused dispatches get zero workgroups
+    fn record_sort(&self, encoder: &mut wgpu::CommandEncoder, segment_buffers: &SegmentedSortBuffers) {
+        let name = self.sorter.name();
+        let bind_group = &segment_buffers.bind_group;
+        let dispatch_bind_group = &segment_buffers.dispatch_bind_group;
+        let dispatch_buffer = &segment_buffers.dispatch_buffer;
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "segmented sort prepare")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.prepare_p);
+            pass.set_bind_group(0, dispatch_bind_group, &[]);
+            pass.dispatch_workgroups(1, 1, 1);
+
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.set_pipeline(&self.find_large_p);
+            pass.dispatch_workgroups_indirect(dispatch_buffer, SEGMENTS_DISPATCH_OFFSET);
+
+            pass.set_pipeline(&self.prepare_sort_p);
+            pass.set_bind_group(0, dispatch_bind_group, &[]);
+            pass.dispatch_workgroups(1, 1, 1);
+        }
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "segmented sort local")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.local_sort_p);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups_indirect(dispatch_buffer, LOCAL_DISPATCH_OFFSET);
+        }
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "segmented sort gather segments")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.gather_p);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups_indirect(dispatch_buffer, COPY_DISPATCH_OFFSET);
+        }
+        self.sorter
+            .sort_indirect(encoder, &segment_buffers.scratch, dispatch_buffer);
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "segmented sort scatter segments")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.scatter_p);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups_indirect(dispatch_buffer, COPY_DISPATCH_OFFSET);
+        }
+    }
+
+    /// Creates all buffers necessary for sorting segments of `sort_buffers`.
+    ///
+    /// `segment_offsets` must contain `num_segments + 1` u32 offsets in ascending order.
+    /// Segment i contains the pairs `segment_offsets[i]..segment_offsets[i+1]`.
+    ///
+    /// The number of pairs in large segments is only known on the gpu, so the scratch buffers are created
+    /// for all pairs of `sort_buffers`. They hold u64 keys and u32 values together with the buffers of a typed
+    /// radix sort, which takes several times the memory of `sort_buffers`. Only the pairs of large segments
+    /// are sorted, but every one of them goes through the full u64 radix sort plus the copies in and out.
+    /// If the segments rarely exceed [LOCAL_SORT_KVS] pairs, a few large segments are cheaper to sort
+    /// separately with a [GPUSorter].
+    pub fn create_segmented_buffers(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers,
+        segment_offsets: &wgpu::Buffer,
+    ) -> SegmentedSortBuffers {
+        let name = self.sorter.name();
+        let length = sort_buffers.len();
+        // the large segments are sorted at once in the scratch buffers, in the worst case this is the whole buffer
+        let scratch = self
+            .sorter
+            .create_typed_sort_buffers(device, NonZeroU32::new(length).unwrap());
+
+        let state_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "segmented sort state buffer")),
+            size: (mem::size_of::<SegmentState>() + max_large_segments(length) * LARGE_SEGMENT_SIZE) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let dispatch_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "segmented sort dispatch buffer")),
+            size: DISPATCH_BUFFER_SIZE,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "segmented sort bind group")),
+            layout: &Self::bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &state_buffer),
+                buffer_entry(1, segment_offsets),
+                buffer_entry(2, sort_buffers.keys()),
+                buffer_entry(3, sort_buffers.values()),
+                buffer_entry(4, scratch.keys()),
+                buffer_entry(5, scratch.values()),
+            ],
+        });
+        let dispatch_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "segmented sort dispatch bind group")),
+            layout: &Self::dispatch_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &state_buffer),
+                buffer_entry(1, segment_offsets),
+                buffer_entry(6, scratch.state_buffer()),
+                buffer_entry(7, &dispatch_buffer),
+            ],
+        });
+
+        SegmentedSortBuffers {
+            scratch,
+            state_buffer,
+            dispatch_buffer,
+            bind_group,
+            dispatch_bind_group,
+        }
+    }
+}
+
+/// Struct containing information about the state of the segmented sorter.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct SegmentState {
+    /// number of segments that will be sorted
+    pub num_segments: u32,
+    /// number of segments with more than [LOCAL_SORT_KVS] pairs, written by the sort
+    pub large_count: u32,
+    /// number of pairs sorted with the radix sort, written by the sort
+    pub large_pairs: u32,
+}
+
+/// Struct containing all buffers necessary for segmented sorting of a [SortBuffers].
+pub struct SegmentedSortBuffers {
+    /// buffers for sorting all segments by segment and key
+    scratch: SortBuffers<u64, u32>,
+    /// contains a [SegmentState] followed by the list of large segments
+    state_buffer: wgpu::Buffer,
+    /// indirect dispatch arguments, see SegmentDispatch in segmented.wgsl
+    dispatch_buffer: wgpu::Buffer,
+
+    /// bind group for the shaders that are dispatched indirectly
+    bind_group: wgpu::BindGroup,
+    /// bind group for the shaders that write the dispatch buffer
+    dispatch_bind_group: wgpu::BindGroup,
+}
+
+impl SegmentedSortBuffers {
+    /// Buffer containing a [SegmentState]
+    pub fn state_buffer(&self) -> &wgpu::Buffer {
+        &self.state_buffer
+    }
+
+    /// Downloads the [SegmentState] of the last sort. The function waits for the gpu to finish.
+    pub async fn read_state(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> SegmentState {
+        let size = mem::size_of::<SegmentState>() as u64;
+        crate::utils::download_buffer::<SegmentState>(&self.state_buffer, device, queue, ..size).await[0]
+    }
+}
+
+/// a buffer of `length` pairs contains at most this many segments with more than LOCAL_SORT_KVS pairs
+fn max_large_segments(length: u32) -> usize {
+    (length / (LOCAL_SORT_KVS + 1)).max(1) as usize
+}
+
+fn state_layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: Some(
+                NonZeroU64::new((mem::size_of::<SegmentState>() + LARGE_SEGMENT_SIZE) as u64).unwrap(),
+            ),
+        },
+        count: None,
+    }
+}
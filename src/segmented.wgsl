@@ -0,0 +1,192 @@
+// shader for sorting many independent segments of a key-value buffer. More information in segmented.rs
+//
+// Segments with at most local_sort_kvs pairs are sorted by a single workgroup (see local_sort.wgsl).
+// The pairs of the larger segments are copied into scratch buffers with 64 bit keys (segment, key),
+// which are sorted at once with the radix sort and copied back.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const segment_wg_size
+// const histo_block_kvs
+// const max_workgroups
+// const local_sort_wg_size
+// const local_sort_kvs
+// const local_sort_rows
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct LargeSegment {
+    segment: u32,
+    // position of the first pair of the segment in the scratch buffers
+    scratch_start: u32,
+};
+
+struct SegmentState {
+    // number of segments, segment i contains the pairs segment_offsets[i]..segment_offsets[i+1]
+    num_segments: u32,
+    // number of segments that are too large for the local sort
+    large_count: atomic<u32>,
+    // number of pairs of all large segments
+    large_pairs: atomic<u32>,
+    large_segments: array<LargeSegment>,
+};
+
+struct DispatchArgs {
+    x: u32,
+    y: u32,
+    z: u32,
+};
+
+struct SegmentDispatch {
+    // radix sort of the scratch buffers
+    sort: DispatchArgs,
+    // copy of the large segments into and out of the scratch buffers, one workgroup per large segment
+    copy: DispatchArgs,
+    // local sort, one workgroup per segment
+    local: DispatchArgs,
+    // one invocation per segment
+    segments: DispatchArgs,
+};
+
+@group(0) @binding(0)
+var<storage, read_write> segments: SegmentState;
+@group(0) @binding(1)
+var<storage, read> segment_offsets: array<u32>;
+@group(0) @binding(2)
+var<storage, read_write> keys: array<u32>;
+@group(0) @binding(3)
+var<storage, read_write> payload_a: array<u32>;
+// two words per key, the low word is the key and the high word the segment
+@group(0) @binding(4)
+var<storage, read_write> scratch_keys: array<u32>;
+@group(0) @binding(5)
+var<storage, read_write> scratch_payload: array<u32>;
+// the following two bindings can not be bound while the indirect dispatches are running
+@group(0) @binding(6)
+var<storage, read_write> scratch_info: GeneralInfo;
+@group(0) @binding(7)
+var<storage, read_write> dispatch: SegmentDispatch;
+
+fn segment_len(segment: u32) -> u32 {
+    return segment_offsets[segment + 1u] - segment_offsets[segment];
+}
+
+fn limited_workgroups(n: u32, wg_size: u32) -> u32 {
+    return min((n + wg_size - 1u) / wg_size, max_workgroups);
+}
+
+// first position of the pairs of a large segment in the sorted scratch buffers
+fn sorted_start(segment: u32, num_pairs: u32) -> u32 {
+    var low = 0u;
+    var high = num_pairs;
+    while low < high {
+        let mid = (low + high) / 2u;
+        if scratch_keys[2u * mid + 1u] < segment {
+            low = mid + 1u;
+        } else {
+            high = mid;
+        }
+    }
+    return low;
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Resetting the state and setting up the indirect dispatches
+// --------------------------------------------------------------------------------------------------------------
+@compute @workgroup_size(1)
+fn prepare_segments() {
+    atomicStore(&segments.large_count, 0u);
+    atomicStore(&segments.large_pairs, 0u);
+    dispatch.local = DispatchArgs(min(segments.num_segments, max_workgroups), 1u, 1u);
+    dispatch.segments = DispatchArgs(limited_workgroups(segments.num_segments, segment_wg_size), 1u, 1u);
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Collecting the segments that are too large for the local sort
+// --------------------------------------------------------------------------------------------------------------
+@compute @workgroup_size(segment_wg_size)
+fn find_large_segments(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * segment_wg_size;
+    for (var segment = gid.x; segment < segments.num_segments; segment += line_size) {
+        let len = segment_len(segment);
+        if len > local_sort_kvs {
+            let idx = atomicAdd(&segments.large_count, 1u);
+            let scratch_start = atomicAdd(&segments.large_pairs, len);
+            if idx < arrayLength(&segments.large_segments) {
+                segments.large_segments[idx] = LargeSegment(segment, scratch_start);
+            }
+        }
+    }
+}
+
+// The pairs of all large segments are sorted by segment and key with the radix sort
+@compute @workgroup_size(1)
+fn prepare_sort() {
+    let large_count = min(atomicLoad(&segments.large_count), arrayLength(&segments.large_segments));
+    let num_pairs = atomicLoad(&segments.large_pairs);
+
+    let histo_blocks = (num_pairs + histo_block_kvs - 1u) / histo_block_kvs;
+    scratch_info.num_keys = num_pairs;
+    scratch_info.padded_size = histo_blocks * histo_block_kvs;
+    scratch_info.even_pass = 0u;
+    scratch_info.odd_pass = 0u;
+
+    dispatch.sort = DispatchArgs(histo_blocks, 1u, 1u);
+    dispatch.copy = DispatchArgs(min(large_count, max_workgroups), 1u, 1u);
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Sorting the small segments, one workgroup per segment
+// --------------------------------------------------------------------------------------------------------------
+@compute @workgroup_size(local_sort_wg_size)
+fn sort_segments_local(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let num_segments = broadcast(lid.x, segments.num_segments);
+    for (var segment = wid.x; segment < num_segments; segment += nwg.x) {
+        let start = broadcast(lid.x, segment_offsets[segment]);
+        let len = broadcast(lid.x, segment_len(segment));
+        if len <= local_sort_kvs {
+            local_sort(lid.x, start, len);
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Sorting the large segments with the radix sort, one workgroup per large segment copies its pairs
+// --------------------------------------------------------------------------------------------------------------
+@compute @workgroup_size(segment_wg_size)
+fn gather_segments(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let large_count = min(atomicLoad(&segments.large_count), arrayLength(&segments.large_segments));
+    for (var i = wid.x; i < large_count; i += nwg.x) {
+        let large = segments.large_segments[i];
+        let start = segment_offsets[large.segment];
+        let len = segment_len(large.segment);
+        // the pairs keep their order, so the stable radix sort keeps the order of equal keys
+        for (var j = lid.x; j < len; j += segment_wg_size) {
+            let pos = large.scratch_start + j;
+            scratch_keys[2u * pos] = keys[start + j];
+            scratch_keys[2u * pos + 1u] = large.segment;
+            scratch_payload[pos] = payload_a[start + j];
+        }
+    }
+}
+
+// the sorted scratch buffers contain the large segments in ascending order
+@compute @workgroup_size(segment_wg_size)
+fn scatter_segments(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let large_count = min(atomicLoad(&segments.large_count), arrayLength(&segments.large_segments));
+    let num_pairs = atomicLoad(&segments.large_pairs);
+    for (var i = wid.x; i < large_count; i += nwg.x) {
+        let segment = segments.large_segments[i].segment;
+        let start = segment_offsets[segment];
+        let len = segment_len(segment);
+        let scratch_start = sorted_start(segment, num_pairs);
+        for (var j = lid.x; j < len; j += segment_wg_size) {
+            keys[start + j] = scratch_keys[2u * (scratch_start + j)];
+            payload_a[start + j] = scratch_payload[scratch_start + j];
+        }
+    }
+}
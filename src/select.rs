@@ -0,0 +1,517 @@
+/*
+    Top-k selection without a full sort.
+
+    GPUSorter::select_top_k copies the k smallest (or largest) key-value pairs of sort buffers into the output
+    buffers of TopKBuffers. Instead of four scatter passes over all pairs it runs a radix select:
+    - four histogram passes, one per byte of the keys starting with the most significant one.
+      Every pass only counts the keys that match the bytes of the k-th key found so far.
+      After the last pass the k-th key and the number of keys equal to it that are selected are known.
+    - the keys smaller than the k-th key and the first keys equal to it are compacted into the output.
+      The counts per block are scanned, so the selected pairs keep the order of the input.
+    Optionally the k selected pairs are sorted afterwards.
+
+    GPUSorter::nth_element and GPUSorter::percentiles use the same passes to find single keys (order statistics)
+    and write them to a small buffer that can be used by later passes.
+
+    Only keys and values with a single word (u32, i32 and f32) are supported.
+
+    The shaders can be found in select.wgsl
+*/
+
+use std::{marker::PhantomData, mem, num::NonZeroU32};
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label, storage_layout_entry,
+    typed::{self, KeyKind, SortKey, SortValue, TypedDispatch},
+    utils::download_buffer,
+    GPUSorter, SortBuffers, NUM_PASSES,
+};
+
+/// workgroup size of the select shaders
+const SELECT_WG_SIZE: u32 = 256;
+
+/// number of consecutive keys compacted by every invocation
+const SELECT_BLOCK_ROWS: u32 = 16;
+
+/// number of keys compacted by one workgroup
+const SELECT_BLOCK_KVS: u32 = SELECT_WG_SIZE * SELECT_BLOCK_ROWS;
+
+/// number of buckets of the histogram of one byte
+const SELECT_HISTOGRAM_SIZE: u64 = 256;
+
+/// Which pairs are selected by [GPUSorter::select_top_k]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectOrder {
+    /// the k smallest keys, e.g. the k nearest splats
+    Smallest,
+    /// the k largest keys, e.g. the k highest scores
+    Largest,
+}
+
+/// State of the radix select, has to be synced with SelectState in select.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct SelectState {
+    k: u32,
+    key_xor: u32,
+    key_kind: u32,
+    pass: u32,
+    prefix: u32,
+    mask: u32,
+    remaining: u32,
+    num_selected: u32,
+    query: u32,
+    _pad: u32,
+}
+
+/// Order statistic query, its rank is written to the queries buffer
+#[derive(Clone, Copy)]
+enum StatisticQuery {
+    Nth(u32),
+    Percentile(f32),
+}
+
+impl StatisticQuery {
+    /// index of the queried key in the first `num_keys` sorted keys, computed in f64 to be exact for all lengths
+    fn rank(&self, num_keys: u32) -> u32 {
+        let last = num_keys.saturating_sub(1);
+        match *self {
+            StatisticQuery::Nth(n) => n.min(last),
+            // halfway cases are rounded up
+            StatisticQuery::Percentile(p) => ((p as f64 * last as f64 + 0.5).floor() as u32).min(last),
+        }
+    }
+}
+
+/// Output and intermediate buffers for selecting the top k pairs of one [SortBuffers]
+pub struct TopKBuffers<K = u32, V = u32> {
+    /// the selected pairs
+    output: SortBuffers<K, V>,
+    bind_group: wgpu::BindGroup,
+    /// number of pairs of the input buffers
+    input_length: u32,
+}
+
+impl<K: SortKey, V: SortValue> TopKBuffers<K, V> {
+    /// number of selected pairs
+    pub fn k(&self) -> u32 {
+        self.output.len()
+    }
+
+    /// Buffers containing the selected pairs.
+    ///
+    /// If less than k pairs are selected from, only the first pairs are valid.
+    pub fn output(&self) -> &SortBuffers<K, V> {
+        &self.output
+    }
+
+    /// Downloads the selected keys
+    pub async fn read_keys(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<K> {
+        self.output.read_keys(device, queue).await
+    }
+
+    /// Downloads the selected values
+    pub async fn read_values(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<V> {
+        self.output.read_values(device, queue).await
+    }
+}
+
+/// Buffers for computing order statistics (e.g. the median) of one [SortBuffers]
+pub struct OrderStatisticsBuffers<K = u32> {
+    /// one selected key per query
+    results_buffer: wgpu::Buffer,
+    queries_buffer: wgpu::Buffer,
+    /// [SelectState] followed by the histogram
+    select_mem_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    max_queries: u32,
+    _types: PhantomData<fn() -> K>,
+}
+
+impl<K: SortKey> OrderStatisticsBuffers<K> {
+    /// maximum number of keys that can be queried at once
+    pub fn max_queries(&self) -> u32 {
+        self.max_queries
+    }
+
+    /// Buffer containing one key per query of the last [GPUSorter::nth_element] or [GPUSorter::percentiles],
+    /// can be bound by later passes.
+    pub fn results_buffer(&self) -> &wgpu::Buffer {
+        &self.results_buffer
+    }
+
+    /// Downloads the results of all queries, entries after the last query are undefined
+    pub async fn read_results(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<K> {
+        download_buffer(&self.results_buffer, device, queue, ..).await
+    }
+}
+
+/// Pipelines of the radix select
+pub(crate) struct SelectPipelines {
+    init_p: wgpu::ComputePipeline,
+    histogram_p: wgpu::ComputePipeline,
+    digit_p: wgpu::ComputePipeline,
+    count_p: wgpu::ComputePipeline,
+    scan_p: wgpu::ComputePipeline,
+    scatter_p: wgpu::ComputePipeline,
+    statistic_init_p: wgpu::ComputePipeline,
+    statistic_histogram_p: wgpu::ComputePipeline,
+    statistic_digit_p: wgpu::ComputePipeline,
+    statistic_store_p: wgpu::ComputePipeline,
+}
+
+impl SelectPipelines {
+    pub(crate) fn new(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("select.wgsl");
+        let shader_code = format!(
+            "const select_wg_size: u32 = {:}u;\nconst select_block_rows: u32 = {:}u;\n{:}",
+            SELECT_WG_SIZE, SELECT_BLOCK_ROWS, raw_shader
+        );
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Radix select shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&named_label(name, "radix select pipeline layout")),
+            bind_group_layouts: &[&Self::bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let statistics_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&named_label(name, "order statistics pipeline layout")),
+            bind_group_layouts: &[&Self::statistics_bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |layout: &wgpu::PipelineLayout, entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&named_label(name, entry_point)),
+                layout: Some(layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        Self {
+            init_p: pipeline(&pipeline_layout, "select_init"),
+            histogram_p: pipeline(&pipeline_layout, "select_histogram"),
+            digit_p: pipeline(&pipeline_layout, "select_digit"),
+            count_p: pipeline(&pipeline_layout, "select_count"),
+            scan_p: pipeline(&pipeline_layout, "select_scan"),
+            scatter_p: pipeline(&pipeline_layout, "select_scatter"),
+            statistic_init_p: pipeline(&statistics_layout, "statistic_init"),
+            statistic_histogram_p: pipeline(&statistics_layout, "select_histogram"),
+            statistic_digit_p: pipeline(&statistics_layout, "select_digit"),
+            statistic_store_p: pipeline(&statistics_layout, "statistic_store"),
+        }
+    }
+
+    fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("radix select bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, true),
+                storage_layout_entry(2, true),
+                storage_layout_entry(3, false),
+                storage_layout_entry(4, false),
+                storage_layout_entry(5, false),
+            ],
+        })
+    }
+
+    fn statistics_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("order statistics bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, true),
+                storage_layout_entry(5, false),
+                storage_layout_entry(6, true),
+                storage_layout_entry(7, false),
+            ],
+        })
+    }
+}
+
+impl GPUSorter {
+    /// pipelines of the radix select, compiled when the first select buffers are created
+    fn select_pipelines(&self, device: &wgpu::Device) -> &SelectPipelines {
+        self.select_p.get_or_init(|| SelectPipelines::new(device, self.name()))
+    }
+
+    fn created_select_pipelines(&self) -> &SelectPipelines {
+        self.select_p
+            .get()
+            .expect("select buffers have to be created by the sorter that selects from them")
+    }
+
+    /// Creates the buffers for selecting the top `k` pairs of `sort_buffers` with [GPUSorter::select_top_k].
+    pub fn create_top_k_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers<K, V>,
+        k: NonZeroU32,
+        order: SelectOrder,
+    ) -> TopKBuffers<K, V> {
+        assert!(
+            K::KIND != KeyKind::U64 && V::WORDS == 1,
+            "top k selection only supports keys and values with 4 bytes"
+        );
+        self.select_pipelines(device);
+        let name = sort_buffers.name();
+        let output = self.create_sort_buffers_with_name(device, k, Some(&named_label(name, "top k")));
+
+        let state = SelectState {
+            k: k.get(),
+            key_xor: match order {
+                SelectOrder::Smallest => 0,
+                SelectOrder::Largest => u32::MAX,
+            },
+            key_kind: K::KIND as u32,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        let num_blocks = sort_buffers.len().div_ceil(SELECT_BLOCK_KVS) as u64;
+        let mut contents = bytes_of(&state).to_vec();
+        contents.resize(
+            mem::size_of::<SelectState>()
+                + (SELECT_HISTOGRAM_SIZE + 2 * num_blocks) as usize * mem::size_of::<u32>(),
+            0,
+        );
+        let select_mem_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "radix select buffer")),
+            contents: &contents,
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "radix select bind group")),
+            layout: &SelectPipelines::bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.keys()),
+                buffer_entry(2, sort_buffers.values()),
+                buffer_entry(3, output.keys()),
+                buffer_entry(4, output.values()),
+                buffer_entry(5, &select_mem_buffer),
+            ],
+        });
+        TopKBuffers {
+            output,
+            bind_group,
+            input_length: sort_buffers.len(),
+        }
+    }
+
+    /// Copies the k smallest or largest pairs (see [SelectOrder]) of the first `select_first_n` pairs
+    /// (or all pairs) of `sort_buffers` into [TopKBuffers::output].
+    ///
+    /// If `sorted` is false, the selected pairs keep the order of the input.
+    /// Otherwise they are sorted in ascending order of their keys afterwards.
+    /// If several pairs are equal to the k-th key, the first ones in the input are selected.
+    /// The input buffers are not modified, apart from the number of keys in [SortBuffers::state_buffer].
+    /// `top_k` has to be created by this sorter, which compiles the select pipelines.
+    pub fn select_top_k<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, V>,
+        top_k: &TopKBuffers<K, V>,
+        select_first_n: Option<u32>,
+        sorted: bool,
+    ) {
+        assert_eq!(
+            sort_buffers.len(),
+            top_k.input_length,
+            "top k buffers were created for other sort buffers"
+        );
+        let num_elements = select_first_n.unwrap_or(sort_buffers.len());
+        assert!(num_elements <= sort_buffers.len(), "more pairs selected from than the buffers contain");
+
+        // write number of elements to buffer
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
+
+        let select_p = self.created_select_pipelines();
+        let dispatch = TypedDispatch::Direct(num_elements);
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(sort_buffers.name(), "radix select")),
+                timestamp_writes: None,
+            });
+            pass.set_bind_group(0, &top_k.bind_group, &[]);
+
+            pass.set_pipeline(&select_p.init_p);
+            pass.dispatch_workgroups(1, 1, 1);
+            for i in 0..NUM_PASSES {
+                pass.insert_debug_marker(&format!("select pass {i}"));
+                pass.set_pipeline(&select_p.histogram_p);
+                typed::dispatch(&mut pass, &dispatch, SELECT_BLOCK_KVS);
+                pass.set_pipeline(&select_p.digit_p);
+                pass.dispatch_workgroups(1, 1, 1);
+            }
+
+            pass.insert_debug_marker("compaction");
+            pass.set_pipeline(&select_p.count_p);
+            typed::dispatch(&mut pass, &dispatch, SELECT_BLOCK_KVS);
+            pass.set_pipeline(&select_p.scan_p);
+            pass.dispatch_workgroups(1, 1, 1);
+            pass.set_pipeline(&select_p.scatter_p);
+            typed::dispatch(&mut pass, &dispatch, SELECT_BLOCK_KVS);
+        }
+
+        if sorted {
+            let num_selected = num_elements.min(top_k.k());
+            self.sort(encoder, queue, &top_k.output, Some(num_selected));
+        }
+    }
+
+    /// Creates the buffers for querying up to `max_queries` order statistics of `sort_buffers`
+    /// with [GPUSorter::nth_element] or [GPUSorter::percentiles].
+    pub fn create_order_statistics_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers<K, V>,
+        max_queries: NonZeroU32,
+    ) -> OrderStatisticsBuffers<K> {
+        assert!(K::KIND != KeyKind::U64, "order statistics only support keys with 4 bytes");
+        self.select_pipelines(device);
+        let name = sort_buffers.name();
+        let state = SelectState {
+            key_kind: K::KIND as u32,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        let mut contents = bytes_of(&state).to_vec();
+        // the block counts are not used, but the shader expects at least one entry
+        contents.resize(
+            mem::size_of::<SelectState>() + (SELECT_HISTOGRAM_SIZE + 2) as usize * mem::size_of::<u32>(),
+            0,
+        );
+        let select_mem_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "order statistics select buffer")),
+            contents: &contents,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+        let queries_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "order statistics queries buffer")),
+            size: max_queries.get() as u64 * mem::size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let results_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "order statistics results buffer")),
+            size: max_queries.get() as u64 * mem::size_of::<K>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "order statistics bind group")),
+            layout: &SelectPipelines::statistics_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.keys()),
+                buffer_entry(5, &select_mem_buffer),
+                buffer_entry(6, &queries_buffer),
+                buffer_entry(7, &results_buffer),
+            ],
+        });
+        OrderStatisticsBuffers {
+            results_buffer,
+            queries_buffer,
+            select_mem_buffer,
+            bind_group,
+            max_queries: max_queries.get(),
+            _types: PhantomData,
+        }
+    }
+
+    /// Writes the key that would be at index `n` if the first `select_first_n` keys (or all keys) were sorted
+    /// to the first entry of [OrderStatisticsBuffers::results_buffer]. `n` is clamped to the number of keys.
+    ///
+    /// The keys are not modified, apart from the number of keys in [SortBuffers::state_buffer].
+    /// `statistics` has to be created by this sorter, which compiles the select pipelines.
+    pub fn nth_element<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, V>,
+        statistics: &OrderStatisticsBuffers<K>,
+        n: u32,
+        select_first_n: Option<u32>,
+    ) {
+        let query = StatisticQuery::Nth(n);
+        self.record_order_statistics(encoder, queue, sort_buffers, statistics, &[query], select_first_n);
+    }
+
+    /// Writes the keys at the given percentiles (between 0 and 1) of the first `select_first_n` keys (or all keys)
+    /// to [OrderStatisticsBuffers::results_buffer], one key per percentile.
+    ///
+    /// The percentile `p` of `n` keys is the key at index `round(p * (n - 1))` of the sorted keys,
+    /// e.g. 0.5 is the median.
+    pub fn percentiles<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, V>,
+        statistics: &OrderStatisticsBuffers<K>,
+        percentiles: &[f32],
+        select_first_n: Option<u32>,
+    ) {
+        assert!(
+            percentiles.iter().all(|p| (0.0..=1.0).contains(p)),
+            "percentiles must be between 0 and 1"
+        );
+        let queries: Vec<StatisticQuery> = percentiles
+            .iter()
+            .map(|&p| StatisticQuery::Percentile(p))
+            .collect();
+        self.record_order_statistics(encoder, queue, sort_buffers, statistics, &queries, select_first_n);
+    }
+
+    fn record_order_statistics<K: SortKey, V: SortValue>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers<K, V>,
+        statistics: &OrderStatisticsBuffers<K>,
+        queries: &[StatisticQuery],
+        select_first_n: Option<u32>,
+    ) {
+        assert!(
+            queries.len() as u32 <= statistics.max_queries,
+            "more queries than the order statistics buffers can store"
+        );
+        let num_elements = select_first_n.unwrap_or(sort_buffers.len());
+        assert!(num_elements <= sort_buffers.len(), "more keys selected from than the buffers contain");
+
+        // write number of elements, queries and the index of the first query to the buffers
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
+        let ranks: Vec<u32> = queries.iter().map(|query| query.rank(num_elements)).collect();
+        queue.write_buffer(&statistics.queries_buffer, 0, bytemuck::cast_slice(&ranks));
+        queue.write_buffer(
+            &statistics.select_mem_buffer,
+            mem::offset_of!(SelectState, query) as u64,
+            bytes_of(&0u32),
+        );
+
+        let select_p = self.created_select_pipelines();
+        let dispatch = TypedDispatch::Direct(num_elements);
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&named_label(sort_buffers.name(), "order statistics")),
+            timestamp_writes: None,
+        });
+        pass.set_bind_group(0, &statistics.bind_group, &[]);
+        for i in 0..queries.len() {
+            pass.insert_debug_marker(&format!("query {i}"));
+            pass.set_pipeline(&select_p.statistic_init_p);
+            pass.dispatch_workgroups(1, 1, 1);
+            for _ in 0..NUM_PASSES {
+                pass.set_pipeline(&select_p.statistic_histogram_p);
+                typed::dispatch(&mut pass, &dispatch, SELECT_BLOCK_KVS);
+                pass.set_pipeline(&select_p.statistic_digit_p);
+                pass.dispatch_workgroups(1, 1, 1);
+            }
+            pass.set_pipeline(&select_p.statistic_store_p);
+            pass.dispatch_workgroups(1, 1, 1);
+        }
+    }
+}
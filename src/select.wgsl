@@ -0,0 +1,322 @@
+// shader selecting the k smallest or largest key-value pairs without sorting them. More information in select.rs
+//
+// The k-th key is found with a radix select: every pass builds a histogram of one byte of the keys
+// that match the bytes found so far (starting with the most significant byte) and picks the byte
+// in which the k-th key lies. Afterwards the keys smaller than the k-th key and enough keys equal to it
+// are compacted into the output buffers. The compaction keeps the order of the input.
+//
+// Order statistics (e.g. percentiles) run the same passes once per query and only store the k-th key.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const select_wg_size
+// const select_block_rows
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct SelectState {
+    // number of pairs that are selected
+    k: u32,
+    // 0xFFFFFFFF to select the largest keys, 0 for the smallest
+    key_xor: u32,
+    // see KeyKind in typed.rs
+    key_kind: u32,
+    // byte of the keys that is selected next, starting with the most significant one
+    pass_: u32,
+    // bytes of the k-th key found so far
+    prefix: u32,
+    mask: u32,
+    // number of pairs that still have to be selected from the pairs matching prefix,
+    // after the last pass the number of pairs equal to the k-th key that are selected
+    remaining: u32,
+    // min(k, num_keys)
+    num_selected: u32,
+    // index of the current order statistic query
+    query: u32,
+    _pad: u32,
+};
+
+struct SelectMem {
+    state: SelectState,
+    histogram: array<atomic<u32>, 256>,
+    // number of keys smaller than and equal to the k-th key per block,
+    // exclusive prefix sums after select_scan
+    block_counts: array<vec2<u32>>,
+};
+
+const key_kind_i32: u32 = 1u;
+const key_kind_f32: u32 = 2u;
+const select_block_kvs: u32 = select_wg_size * select_block_rows;
+
+@group(0) @binding(0)
+var<storage, read> infos: GeneralInfo;
+@group(0) @binding(1)
+var<storage, read> keys: array<u32>;
+@group(0) @binding(2)
+var<storage, read> values: array<u32>;
+@group(0) @binding(3)
+var<storage, read_write> out_keys: array<u32>;
+@group(0) @binding(4)
+var<storage, read_write> out_values: array<u32>;
+@group(0) @binding(5)
+var<storage, read_write> mem: SelectMem;
+// only used by the order statistics, rank of the queried key in the sorted keys (computed on the host)
+@group(0) @binding(6)
+var<storage, read> query_ranks: array<u32>;
+@group(0) @binding(7)
+var<storage, read_write> results: array<u32>;
+
+var<workgroup> local_histogram: array<atomic<u32>, 256>;
+var<workgroup> local_counts: array<vec2<u32>, select_wg_size>;
+
+// same order preserving transform as in typed.wgsl, the selected order is applied afterwards
+fn select_key(key: u32) -> u32 {
+    var encoded = key;
+    switch mem.state.key_kind {
+        case key_kind_i32: {
+            encoded = key ^ 0x80000000u;
+        }
+        case key_kind_f32: {
+            if (key & 0x80000000u) != 0u {
+                encoded = ~key;
+            } else {
+                encoded = key | 0x80000000u;
+            }
+        }
+        default: {}
+    }
+    return encoded ^ mem.state.key_xor;
+}
+
+// inverse of select_key
+fn unselect_key(key: u32) -> u32 {
+    let encoded = key ^ mem.state.key_xor;
+    switch mem.state.key_kind {
+        case key_kind_i32: {
+            return encoded ^ 0x80000000u;
+        }
+        case key_kind_f32: {
+            if (encoded & 0x80000000u) != 0u {
+                return encoded & 0x7FFFFFFFu;
+            }
+            return ~encoded;
+        }
+        default: {}
+    }
+    return encoded;
+}
+
+fn num_blocks() -> u32 {
+    return (infos.num_keys + select_block_kvs - 1u) / select_block_kvs;
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Finding the k-th key
+// --------------------------------------------------------------------------------------------------------------
+@compute @workgroup_size(select_wg_size)
+fn select_init(@builtin(local_invocation_index) lid: u32) {
+    if lid == 0u {
+        mem.state.pass_ = 0u;
+        mem.state.prefix = 0u;
+        mem.state.mask = 0u;
+        mem.state.num_selected = min(mem.state.k, infos.num_keys);
+        mem.state.remaining = mem.state.num_selected;
+    }
+    for (var i = lid; i < 256u; i += select_wg_size) {
+        atomicStore(&mem.histogram[i], 0u);
+    }
+}
+
+@compute @workgroup_size(select_wg_size)
+fn select_histogram(
+    @builtin(local_invocation_index) lid: u32,
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) nwg: vec3<u32>,
+) {
+    for (var i = lid; i < 256u; i += select_wg_size) {
+        atomicStore(&local_histogram[i], 0u);
+    }
+    workgroupBarrier();
+
+    let shift = 24u - mem.state.pass_ * 8u;
+    let prefix = mem.state.prefix;
+    let mask = mem.state.mask;
+    let line_size = nwg.x * select_wg_size;
+    for (var i = gid.x; i < infos.num_keys; i += line_size) {
+        let key = select_key(keys[i]);
+        if (key & mask) == prefix {
+            atomicAdd(&local_histogram[(key >> shift) & 0xFFu], 1u);
+        }
+    }
+    workgroupBarrier();
+
+    for (var i = lid; i < 256u; i += select_wg_size) {
+        let count = atomicLoad(&local_histogram[i]);
+        if count > 0u {
+            atomicAdd(&mem.histogram[i], count);
+        }
+    }
+}
+
+@compute @workgroup_size(select_wg_size)
+fn select_digit(@builtin(local_invocation_index) lid: u32) {
+    if lid == 0u {
+        // the k-th key lies in the first byte whose inclusive count reaches the remaining number of keys
+        var before = 0u;
+        var digit = 0u;
+        for (; digit < 255u; digit++) {
+            let count = atomicLoad(&mem.histogram[digit]);
+            if before + count >= mem.state.remaining {
+                break;
+            }
+            before += count;
+        }
+        let shift = 24u - mem.state.pass_ * 8u;
+        mem.state.prefix |= digit << shift;
+        mem.state.mask |= 0xFFu << shift;
+        mem.state.remaining -= before;
+        mem.state.pass_ += 1u;
+    }
+    // the histogram is cleared for the next pass after it was read
+    storageBarrier();
+    for (var i = lid; i < 256u; i += select_wg_size) {
+        atomicStore(&mem.histogram[i], 0u);
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Compacting the selected pairs
+// --------------------------------------------------------------------------------------------------------------
+
+// number of keys smaller than and equal to the k-th key in the rows of one invocation
+fn count_rows(block: u32, lid: u32) -> vec2<u32> {
+    let kth = mem.state.prefix;
+    var counts = vec2<u32>(0u);
+    let start = block * select_block_kvs + lid * select_block_rows;
+    for (var i = start; i < min(start + select_block_rows, infos.num_keys); i++) {
+        let key = select_key(keys[i]);
+        if key < kth {
+            counts.x += 1u;
+        } else if key == kth {
+            counts.y += 1u;
+        }
+    }
+    return counts;
+}
+
+// exclusive prefix sum of the counts of all invocations in the workgroup, returns the total count
+fn scan_counts(lid: u32, counts: vec2<u32>) -> vec2<u32> {
+    local_counts[lid] = counts;
+    workgroupBarrier();
+    for (var offset = 1u; offset < select_wg_size; offset = offset << 1u) {
+        var sum = local_counts[lid];
+        if lid >= offset {
+            sum += local_counts[lid - offset];
+        }
+        workgroupBarrier();
+        local_counts[lid] = sum;
+        workgroupBarrier();
+    }
+    let total = local_counts[select_wg_size - 1u];
+    let exclusive = local_counts[lid] - counts;
+    workgroupBarrier();
+    local_counts[lid] = exclusive;
+    workgroupBarrier();
+    return total;
+}
+
+@compute @workgroup_size(select_wg_size)
+fn select_count(
+    @builtin(local_invocation_index) lid: u32,
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(num_workgroups) nwg: vec3<u32>,
+) {
+    let blocks = num_blocks();
+    for (var block = wid.x; block < blocks; block += nwg.x) {
+        let total = scan_counts(lid, count_rows(block, lid));
+        if lid == 0u {
+            mem.block_counts[block] = total;
+        }
+    }
+}
+
+// exclusive prefix sum over the counts of all blocks in a single workgroup
+@compute @workgroup_size(select_wg_size)
+fn select_scan(@builtin(local_invocation_index) lid: u32) {
+    let blocks = num_blocks();
+    let blocks_per_invocation = (blocks + select_wg_size - 1u) / select_wg_size;
+    let start = lid * blocks_per_invocation;
+    let end = min(start + blocks_per_invocation, blocks);
+
+    var sum = vec2<u32>(0u);
+    for (var block = start; block < end; block++) {
+        sum += mem.block_counts[block];
+    }
+    scan_counts(lid, sum);
+
+    var offset = local_counts[lid];
+    for (var block = start; block < end; block++) {
+        let counts = mem.block_counts[block];
+        mem.block_counts[block] = offset;
+        offset += counts;
+    }
+}
+
+@compute @workgroup_size(select_wg_size)
+fn select_scatter(
+    @builtin(local_invocation_index) lid: u32,
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(num_workgroups) nwg: vec3<u32>,
+) {
+    let blocks = num_blocks();
+    let kth = mem.state.prefix;
+    let remaining = mem.state.remaining;
+    for (var block = wid.x; block < blocks; block += nwg.x) {
+        scan_counts(lid, count_rows(block, lid));
+        var before = mem.block_counts[block] + local_counts[lid];
+
+        let start = block * select_block_kvs + lid * select_block_rows;
+        for (var i = start; i < min(start + select_block_rows, infos.num_keys); i++) {
+            let key = select_key(keys[i]);
+            // only the first remaining keys equal to the k-th key are selected
+            if key < kth || (key == kth && before.y < remaining) {
+                let pos = before.x + min(before.y, remaining);
+                out_keys[pos] = keys[i];
+                out_values[pos] = values[i];
+            }
+            if key < kth {
+                before.x += 1u;
+            } else if key == kth {
+                before.y += 1u;
+            }
+        }
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Order statistics
+// --------------------------------------------------------------------------------------------------------------
+@compute @workgroup_size(select_wg_size)
+fn statistic_init(@builtin(local_invocation_index) lid: u32) {
+    if lid == 0u {
+        let last = max(infos.num_keys, 1u) - 1u;
+        let rank = min(query_ranks[mem.state.query], last);
+        mem.state.pass_ = 0u;
+        mem.state.prefix = 0u;
+        mem.state.mask = 0u;
+        mem.state.remaining = rank + 1u;
+    }
+    for (var i = lid; i < 256u; i += select_wg_size) {
+        atomicStore(&mem.histogram[i], 0u);
+    }
+}
+
+@compute @workgroup_size(1)
+fn statistic_store() {
+    results[mem.state.query] = unselect_key(mem.state.prefix);
+    mem.state.query += 1u;
+}
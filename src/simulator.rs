@@ -0,0 +1,227 @@
+/*
+    Pure-Rust simulation of the radix sort shaders in radix_sort.wgsl.
+
+    Every phase of the gpu pipeline (zero_histograms, calculate_histogram, prefix_histogram,
+    scatter_even and scatter_odd) is reimplemented on the CPU and leaves the state, internal memory,
+    key and payload buffers in the same state as the shaders do.
+    The simulator follows the shaders of a sorter with the given SortLayout (block rows and digit width). The buffers can be compared byte by byte
+    against the gpu buffers after every phase, which makes debugging the shaders a lot easier.
+
+    Payload accesses of padding keys outside of the payload buffer are treated like robust buffer access:
+    reads return zero and writes are discarded.
+*/
+
+use std::num::NonZeroU32;
+
+use crate::{
+    GPUSorter, SortLayout, SorterState, PARTITION_STATUS_PREFIX, PARTITION_STATUS_REDUCTION, PARTITION_STATUS_SHIFT,
+    RS_KEYVAL_SIZE,
+};
+
+/// CPU copy of all buffers used by the radix sort ([crate::SortBuffers]).
+///
+/// The buffers have the same sizes as the gpu buffers and are zero initialized like them.
+pub struct RadixSortSimulator {
+    /// content of [crate::SortBuffers::state_buffer]
+    pub state: SorterState,
+    /// histograms and partitions, content of [crate::SortBuffers::internal_mem_buffer]
+    pub internal_mem: Vec<u32>,
+    /// content of [crate::SortBuffers::keys]
+    pub keys_a: Vec<u32>,
+    /// intermediate keys written by the even scatter passes
+    pub keys_b: Vec<u32>,
+    /// content of [crate::SortBuffers::values]
+    pub payload_a: Vec<u32>,
+    /// intermediate payloads written by the even scatter passes
+    pub payload_b: Vec<u32>,
+    /// block rows and digit width of the simulated sorter
+    layout: SortLayout,
+}
+
+impl RadixSortSimulator {
+    /// Creates the buffers of a sorter with the given layout, e.g. [SortLayout::DEFAULT]
+    /// for sorters created with [GPUSorter::new] or [GPUSorter::layout].
+    pub fn new(length: NonZeroU32, layout: SortLayout) -> Self {
+        let length = length.get();
+        let keys_size = (layout.keys_buffer_size(length) * RS_KEYVAL_SIZE) as usize;
+        Self {
+            state: GPUSorter::general_info_data(length, layout),
+            internal_mem: vec![0; layout.partition_offset(layout.scatter_blocks_ru(length))],
+            keys_a: vec![0; keys_size],
+            keys_b: vec![0; keys_size],
+            payload_a: vec![0; length as usize],
+            payload_b: vec![0; length as usize],
+            layout,
+        }
+    }
+
+    /// offset of the histogram of a pass in the internal memory buffer (in u32 words)
+    pub fn histogram_offset(&self, pass: u32) -> usize {
+        self.layout.histogram_offset(pass)
+    }
+
+    /// offset of the partition of a scatter block in the internal memory buffer (in u32 words)
+    pub fn partition_offset(&self, block: u32) -> usize {
+        self.layout.partition_offset(block)
+    }
+
+    /// Runs all phases in the same order as [GPUSorter::sort] with the radix sort path.
+    pub fn sort(&mut self, sort_first_n: Option<u32>) {
+        self.state.num_keys = sort_first_n.unwrap_or(self.payload_a.len() as u32);
+
+        self.zero_histograms();
+        self.calculate_histogram();
+        self.prefix_histogram();
+        for _ in 0..self.layout.num_passes() / 2 {
+            self.scatter_even();
+            self.scatter_odd();
+        }
+    }
+
+    /// resets the pass infos, zeroes histograms and partitions and fills the keys padding with 0xFFFFFFFF
+    pub fn zero_histograms(&mut self) {
+        self.state.even_pass = 0;
+        // wraps to 0 on the first even pass
+        self.state.odd_pass = self.layout.num_passes() / 2 - 1;
+
+        let num_keys = self.state.num_keys as usize;
+        // the partition of the last scatter block is never used
+        let zeroed = self.partition_offset(self.layout.scatter_blocks_ru(self.state.num_keys))
+            - self.layout.radix_size() as usize;
+        self.internal_mem[..zeroed].fill(0);
+        if num_keys < self.state.padded_size as usize {
+            self.keys_a[num_keys..self.state.padded_size as usize].fill(u32::MAX);
+        }
+    }
+
+    /// accumulates the digit histograms of all passes over all scatter blocks
+    pub fn calculate_histogram(&mut self) {
+        let len = (self.layout.scatter_blocks_ru(self.state.num_keys) * self.layout.block_kvs()) as usize;
+        for key in &self.keys_a[..len] {
+            for pass in 0..self.layout.num_passes() {
+                let idx = self.histogram_offset(pass) + self.layout.digit(*key, pass) as usize;
+                self.internal_mem[idx] = self.internal_mem[idx].wrapping_add(1);
+            }
+        }
+    }
+
+    /// exclusive prefix sum over the histogram of every pass
+    pub fn prefix_histogram(&mut self) {
+        for pass in 0..self.layout.num_passes() {
+            let offset = self.histogram_offset(pass);
+            let mut sum = 0u32;
+            for count in &mut self.internal_mem[offset..offset + self.layout.radix_size() as usize] {
+                let c = *count;
+                *count = sum;
+                sum = sum.wrapping_add(c);
+            }
+        }
+    }
+
+    /// scatters from keys_a/payload_a into keys_b/payload_b
+    pub fn scatter_even(&mut self) {
+        self.state.odd_pass = (self.state.odd_pass + 1) % (self.layout.num_passes() / 2);
+        let pass = self.state.even_pass * 2;
+        let (src_keys, src_payload) = (&self.keys_a, &self.payload_a);
+        let (dst_keys, dst_payload) = (&mut self.keys_b, &mut self.payload_b);
+        scatter(
+            self.layout,
+            pass,
+            self.state.num_keys,
+            &mut self.internal_mem,
+            (src_keys, src_payload),
+            (dst_keys, dst_payload),
+            (PARTITION_STATUS_REDUCTION, PARTITION_STATUS_PREFIX),
+        );
+    }
+
+    /// scatters from keys_b/payload_b into keys_a/payload_a
+    pub fn scatter_odd(&mut self) {
+        self.state.even_pass = (self.state.even_pass + 1) % (self.layout.num_passes() / 2);
+        let pass = self.state.odd_pass * 2 + 1;
+        // the statuses are rotated by two like in the scatter_odd shader
+        let (src_keys, src_payload) = (&self.keys_b, &self.payload_b);
+        let (dst_keys, dst_payload) = (&mut self.keys_a, &mut self.payload_a);
+        scatter(
+            self.layout,
+            pass,
+            self.state.num_keys,
+            &mut self.internal_mem,
+            (src_keys, src_payload),
+            (dst_keys, dst_payload),
+            (odd_status(PARTITION_STATUS_REDUCTION), odd_status(PARTITION_STATUS_PREFIX)),
+        );
+    }
+}
+
+impl SortLayout {
+    fn digit(self, key: u32, pass: u32) -> u32 {
+        (key >> (pass * self.radix_log2)) & (self.radix_size() - 1)
+    }
+
+    fn histogram_offset(self, pass: u32) -> usize {
+        (pass * self.radix_size()) as usize
+    }
+
+    fn partition_offset(self, block: u32) -> usize {
+        ((self.num_passes() + block) * self.radix_size()) as usize
+    }
+}
+
+/// partition status of the odd passes, rotated by two so the prefixes left by the even pass read as invalid
+fn odd_status(status: u32) -> u32 {
+    (status + 2) % 4
+}
+
+/// Stable counting sort of all scatter blocks by the digit of the pass.
+/// Also writes the partitions (inclusive prefix per block and digit) like the decoupled lookback does.
+fn scatter(
+    layout: SortLayout,
+    pass: u32,
+    num_keys: u32,
+    internal_mem: &mut [u32],
+    (src_keys, src_payload): (&[u32], &[u32]),
+    (dst_keys, dst_payload): (&mut [u32], &mut [u32]),
+    (status_reduction, status_prefix): (u32, u32),
+) {
+    let num_blocks = layout.scatter_blocks_ru(num_keys);
+    let radix_size = layout.radix_size() as usize;
+    let hist_offset = layout.histogram_offset(pass);
+    // global offset of every digit, accumulated over the blocks
+    let mut offsets = internal_mem[hist_offset..hist_offset + radix_size].to_vec();
+
+    for block in 0..num_blocks {
+        let start = (block * layout.block_kvs()) as usize;
+        let end = start + layout.block_kvs() as usize;
+
+        let mut reduction = vec![0u32; radix_size];
+        for key in &src_keys[start..end] {
+            reduction[layout.digit(*key, pass) as usize] += 1;
+        }
+
+        // the last block does not store its partition, the first one always does
+        if block == 0 || block < num_blocks - 1 {
+            let partition = layout.partition_offset(block);
+            for d in 0..radix_size {
+                let exc = offsets[d];
+                let red = reduction[d];
+                internal_mem[partition + d] = if block == 0 {
+                    exc.wrapping_add(red) | (status_prefix << PARTITION_STATUS_SHIFT)
+                } else {
+                    // reduction is stored first and then turned into a prefix by adding the exclusive prefix
+                    (red | (status_reduction << PARTITION_STATUS_SHIFT)).wrapping_add(exc | (1 << PARTITION_STATUS_SHIFT))
+                };
+            }
+        }
+
+        for (i, &key) in src_keys.iter().enumerate().take(end).skip(start) {
+            let d = layout.digit(key, pass) as usize;
+            let dst = offsets[d] as usize;
+            offsets[d] += 1;
+            dst_keys[dst] = key;
+            if dst < dst_payload.len() {
+                dst_payload[dst] = src_payload.get(i).copied().unwrap_or(0);
+            }
+        }
+    }
+}
@@ -0,0 +1,336 @@
+/*
+    Morton and Hilbert key generation for spatially coherent sorting of points.
+
+    GPUSpatialEncoder writes a 30 bit key and the index of every position into sort buffers,
+    sorting them orders the indices along a space filling curve:
+    - spatial_bounds computes the bounding box of the positions, unless a bounding box is given
+    - spatial_keys normalizes every position with the bounding box, quantizes it to 10 bits per axis
+      and interleaves the bits (Morton) or walks the Hilbert curve through the cells
+
+    The positions are three f32 at a byte offset and stride, e.g. vec3<f32> or vec4<f32> or a field of a larger struct:
+
+    let spatial_encoder = GPUSpatialEncoder::new(&device);
+    let spatial_buffers = spatial_encoder.create_spatial_buffers(
+        &device, &sort_buffers, &positions, PositionLayout::VEC4, None, SpaceFillingCurve::Hilbert,
+    );
+    spatial_encoder.generate_keys(&mut encoder, &queue, &sort_buffers, &spatial_buffers, None);
+    sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+
+    The shaders can be found in spatial.wgsl
+*/
+
+use std::mem;
+
+use bytemuck::bytes_of;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label, storage_layout_entry,
+    typed::{self, TypedDispatch},
+    utils::download_buffer,
+    word_aligned, SortBuffers,
+};
+
+/// workgroup size of the spatial shaders
+const SPATIAL_WG_SIZE: u32 = 256;
+
+/// Space filling curve along which the keys are ordered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceFillingCurve {
+    /// bits of the cell coordinates interleaved as `x y z` from the most significant bit
+    Morton = 0,
+    /// index on the Hilbert curve through the cells, neighbouring keys are neighbouring cells
+    Hilbert = 1,
+}
+
+/// Position of the three f32 coordinates of every point in the positions buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PositionLayout {
+    /// byte offset of the x coordinate of the first point
+    pub offset: u32,
+    /// bytes between two points
+    pub stride: u32,
+}
+
+impl PositionLayout {
+    /// tightly packed `[f32; 3]`
+    pub const VEC3: PositionLayout = PositionLayout { offset: 0, stride: 12 };
+    /// `vec4<f32>` with the position in xyz
+    pub const VEC4: PositionLayout = PositionLayout { offset: 0, stride: 16 };
+}
+
+/// Axis aligned bounding box of the positions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+/// Layout of the positions and the curve, has to be synced with SpatialInfo in spatial.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct SpatialInfo {
+    offset: u32,
+    stride: u32,
+    curve: u32,
+    _pad: u32,
+}
+
+/// maps floats to unsigned integers with the same order, has to be synced with spatial.wgsl
+fn float_to_ordered(f: f32) -> u32 {
+    let bits = f.to_bits();
+    if bits & 0x8000_0000 != 0 {
+        !bits
+    } else {
+        bits | 0x8000_0000
+    }
+}
+
+fn ordered_to_float(u: u32) -> f32 {
+    if u & 0x8000_0000 != 0 {
+        f32::from_bits(u & 0x7FFF_FFFF)
+    } else {
+        f32::from_bits(!u)
+    }
+}
+
+/// Bounding box and bind group for generating the keys of one [SortBuffers]
+pub struct SpatialBuffers {
+    /// bounding box as order preserving integers, see spatial.wgsl
+    bounds_buffer: wgpu::Buffer,
+    bounds_bind_group: wgpu::BindGroup,
+    keys_bind_group: wgpu::BindGroup,
+    /// true if the bounding box is computed on the gpu
+    compute_bounds: bool,
+    /// number of pairs of the sort buffers
+    input_length: u32,
+    name: Option<String>,
+}
+
+impl SpatialBuffers {
+    /// Downloads the bounding box used for the keys. The function waits for the gpu to finish.
+    pub async fn read_bounds(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Aabb {
+        let bounds: Vec<u32> = download_buffer(&self.bounds_buffer, device, queue, ..).await;
+        Aabb {
+            min: [0, 1, 2].map(|axis| ordered_to_float(!bounds[axis])),
+            max: [0, 1, 2].map(|axis| ordered_to_float(bounds[axis + 3])),
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// Pipelines for generating Morton and Hilbert keys from positions
+pub struct GPUSpatialEncoder {
+    bounds_p: wgpu::ComputePipeline,
+    keys_p: wgpu::ComputePipeline,
+}
+
+impl GPUSpatialEncoder {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self::create(device, None)
+    }
+
+    /// Same as [GPUSpatialEncoder::new] but adds `name` to the labels of the pipelines
+    pub fn new_named(device: &wgpu::Device, name: &str) -> Self {
+        Self::create(device, Some(name))
+    }
+
+    fn create(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("spatial.wgsl");
+        let shader_code = format!("const spatial_wg_size: u32 = {:}u;\n{:}", SPATIAL_WG_SIZE, raw_shader);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Spatial shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let pipeline = |layout: wgpu::BindGroupLayout, entry_point: &str| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(&named_label(name, &format!("{entry_point} pipeline layout"))),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&named_label(name, entry_point)),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        Self {
+            bounds_p: pipeline(Self::bounds_bind_group_layout(device), "spatial_bounds"),
+            keys_p: pipeline(Self::keys_bind_group_layout(device), "spatial_keys"),
+        }
+    }
+
+    fn bounds_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("spatial bounds bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(3, true),
+                storage_layout_entry(4, false),
+                storage_layout_entry(5, true),
+            ],
+        })
+    }
+
+    fn keys_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("spatial keys bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, false),
+                storage_layout_entry(2, false),
+                storage_layout_entry(3, true),
+                storage_layout_entry(4, false),
+                storage_layout_entry(5, true),
+            ],
+        })
+    }
+
+    /// Creates the buffers for writing the keys of `positions` into `sort_buffers`, with the index of every point as value.
+    ///
+    /// If `bounds` is None, the bounding box is computed on the gpu every time the keys are generated.
+    /// Positions outside of the given bounding box are clamped to it.
+    pub fn create_spatial_buffers(
+        &self,
+        device: &wgpu::Device,
+        sort_buffers: &SortBuffers,
+        positions: &wgpu::Buffer,
+        layout: PositionLayout,
+        bounds: Option<Aabb>,
+        curve: SpaceFillingCurve,
+    ) -> SpatialBuffers {
+        let word_size = mem::size_of::<f32>() as u32;
+        assert!(
+            word_aligned(layout.offset) && word_aligned(layout.stride),
+            "offset and stride of the positions must be multiples of 4 bytes"
+        );
+        assert!(layout.stride >= 3 * word_size, "the stride must be at least the size of a position");
+        let length = sort_buffers.len();
+        assert!(
+            positions.size() >= layout.offset as u64 + (length - 1) as u64 * layout.stride as u64 + 3 * word_size as u64,
+            "the positions buffer must contain a position for every pair"
+        );
+        let name = sort_buffers.name();
+
+        // a given bounding box is stored like a computed one
+        let encoded_bounds: [u32; 6] = match bounds {
+            Some(Aabb { min, max }) => {
+                let [min_x, min_y, min_z] = min.map(|v| !float_to_ordered(v));
+                let [max_x, max_y, max_z] = max.map(float_to_ordered);
+                [min_x, min_y, min_z, max_x, max_y, max_z]
+            }
+            None => [0; 6],
+        };
+        let bounds_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "spatial bounds buffer")),
+            contents: bytemuck::cast_slice(&encoded_bounds),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        });
+        let info = SpatialInfo {
+            offset: layout.offset / word_size,
+            stride: layout.stride / word_size,
+            curve: curve as u32,
+            _pad: 0,
+        };
+        let info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "spatial info buffer")),
+            contents: bytes_of(&info),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let bounds_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "spatial bounds bind group")),
+            layout: &Self::bounds_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(3, positions),
+                buffer_entry(4, &bounds_buffer),
+                buffer_entry(5, &info_buffer),
+            ],
+        });
+        let keys_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "spatial keys bind group")),
+            layout: &Self::keys_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, sort_buffers.keys()),
+                buffer_entry(2, sort_buffers.values()),
+                buffer_entry(3, positions),
+                buffer_entry(4, &bounds_buffer),
+                buffer_entry(5, &info_buffer),
+            ],
+        });
+        SpatialBuffers {
+            bounds_buffer,
+            bounds_bind_group,
+            keys_bind_group,
+            compute_bounds: bounds.is_none(),
+            input_length: length,
+            name: name.map(str::to_string),
+        }
+    }
+
+    /// Writes the keys and indices of the first `first_n` (or all) positions into `sort_buffers`.
+    ///
+    /// `sort_buffers` must be the buffers `spatial_buffers` were created for.
+    pub fn generate_keys(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        sort_buffers: &SortBuffers,
+        spatial_buffers: &SpatialBuffers,
+        first_n: Option<u32>,
+    ) {
+        let num_elements = first_n.unwrap_or(spatial_buffers.input_length);
+        assert!(
+            num_elements <= spatial_buffers.input_length,
+            "more positions than the sort buffers can contain"
+        );
+        queue.write_buffer(&sort_buffers.state_buffer, 0, bytes_of(&num_elements));
+        self.record(encoder, spatial_buffers, &TypedDispatch::Direct(num_elements));
+    }
+
+    /// Same as [GPUSpatialEncoder::generate_keys] for the number of positions stored in [SortBuffers::state_buffer].
+    ///
+    /// The dispatch buffer must contain the struct [wgpu::util::DispatchIndirectArgs]
+    /// with at least one workgroup if there are positions.
+    /// The same dispatch buffer as for [GPUSorter::sort_indirect](crate::GPUSorter::sort_indirect) can be used.
+    pub fn generate_keys_indirect(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        spatial_buffers: &SpatialBuffers,
+        dispatch_buffer: &wgpu::Buffer,
+    ) {
+        self.record(encoder, spatial_buffers, &TypedDispatch::Indirect(dispatch_buffer));
+    }
+
+    fn record(&self, encoder: &mut wgpu::CommandEncoder, spatial_buffers: &SpatialBuffers, dispatch: &TypedDispatch) {
+        let name = spatial_buffers.name.as_deref();
+        encoder.push_debug_group(&named_label(name, "spatial keys"));
+        if spatial_buffers.compute_bounds {
+            // the bounds are accumulated with atomicMax
+            encoder.clear_buffer(&spatial_buffers.bounds_buffer, 0, None);
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "spatial bounds")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.bounds_p);
+            pass.set_bind_group(0, &spatial_buffers.bounds_bind_group, &[]);
+            typed::dispatch(&mut pass, dispatch, SPATIAL_WG_SIZE);
+        }
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(&named_label(name, "spatial keys")),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.keys_p);
+            pass.set_bind_group(0, &spatial_buffers.keys_bind_group, &[]);
+            typed::dispatch(&mut pass, dispatch, SPATIAL_WG_SIZE);
+        }
+        encoder.pop_debug_group();
+    }
+}
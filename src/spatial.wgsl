@@ -0,0 +1,165 @@
+// shader generating morton or hilbert keys from positions. More information in spatial.rs
+//
+// spatial_bounds computes the bounding box of the positions with atomics on order preserving integers,
+// spatial_keys quantizes every position to 10 bits per axis inside the bounding box
+// and writes its 30 bit key and its index into the sort buffers.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const spatial_wg_size
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct SpatialInfo {
+    // offset and stride of the positions in words
+    offset: u32,
+    stride: u32,
+    // 0: morton, 1: hilbert
+    curve: u32,
+    _pad: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read> infos: GeneralInfo;
+@group(0) @binding(1)
+var<storage, read_write> keys: array<u32>;
+@group(0) @binding(2)
+var<storage, read_write> values: array<u32>;
+@group(0) @binding(3)
+var<storage, read> positions: array<f32>;
+// order preserving integers of the bounding box, the minimum is stored inverted
+// so both can be computed with atomicMax on a cleared buffer
+@group(0) @binding(4)
+var<storage, read_write> bounds: array<atomic<u32>, 6>;
+@group(0) @binding(5)
+var<storage, read> spatial_info: SpatialInfo;
+
+const BITS_PER_AXIS: u32 = 10u;
+
+fn position(i: u32) -> vec3<f32> {
+    let base = spatial_info.offset + i * spatial_info.stride;
+    return vec3<f32>(positions[base], positions[base + 1u], positions[base + 2u]);
+}
+
+// maps floats to unsigned integers with the same order
+fn float_to_ordered(f: f32) -> u32 {
+    let bits = bitcast<u32>(f);
+    return select(bits | 0x80000000u, ~bits, (bits & 0x80000000u) != 0u);
+}
+
+fn ordered_to_float(u: u32) -> f32 {
+    return bitcast<f32>(select(~u, u & 0x7FFFFFFFu, (u & 0x80000000u) != 0u));
+}
+
+var<workgroup> wg_bounds: array<atomic<u32>, 6>;
+
+@compute @workgroup_size(spatial_wg_size)
+fn spatial_bounds(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(local_invocation_index) lid: u32,
+    @builtin(num_workgroups) nwg: vec3<u32>,
+) {
+    if lid < 6u {
+        atomicStore(&wg_bounds[lid], 0u);
+    }
+    workgroupBarrier();
+
+    let num_keys = infos.num_keys;
+    let line_size = nwg.x * spatial_wg_size;
+    var bounds_min = vec3<u32>(0u);
+    var bounds_max = vec3<u32>(0u);
+    for (var i = gid.x; i < num_keys; i += line_size) {
+        let p = position(i);
+        for (var axis = 0u; axis < 3u; axis++) {
+            let ordered = float_to_ordered(p[axis]);
+            bounds_min[axis] = max(bounds_min[axis], ~ordered);
+            bounds_max[axis] = max(bounds_max[axis], ordered);
+        }
+    }
+    for (var axis = 0u; axis < 3u; axis++) {
+        atomicMax(&wg_bounds[axis], bounds_min[axis]);
+        atomicMax(&wg_bounds[axis + 3u], bounds_max[axis]);
+    }
+    workgroupBarrier();
+    if lid < 6u {
+        atomicMax(&bounds[lid], atomicLoad(&wg_bounds[lid]));
+    }
+}
+
+// spreads the lower 10 bits of v to every third bit
+fn expand_bits(v: u32) -> u32 {
+    var x = v & 0x3FFu;
+    x = (x | (x << 16u)) & 0x030000FFu;
+    x = (x | (x << 8u)) & 0x0300F00Fu;
+    x = (x | (x << 4u)) & 0x030C30C3u;
+    x = (x | (x << 2u)) & 0x09249249u;
+    return x;
+}
+
+fn morton_key(q: vec3<u32>) -> u32 {
+    return (expand_bits(q.x) << 2u) | (expand_bits(q.y) << 1u) | expand_bits(q.z);
+}
+
+// hilbert index with the algorithm of J. Skilling, "Programming the Hilbert curve" (2004):
+// the coordinates are transformed into the transposed hilbert index, whose bits are interleaved like a morton key
+fn hilbert_key(q: vec3<u32>) -> u32 {
+    var x = array<u32, 3>(q.x, q.y, q.z);
+    let m = 1u << (BITS_PER_AXIS - 1u);
+    // inverse undo
+    for (var bit = m; bit > 1u; bit = bit >> 1u) {
+        let p = bit - 1u;
+        for (var i = 0u; i < 3u; i++) {
+            if (x[i] & bit) != 0u {
+                x[0] ^= p;
+            } else {
+                let t = (x[0] ^ x[i]) & p;
+                x[0] ^= t;
+                x[i] ^= t;
+            }
+        }
+    }
+    // gray encode
+    x[1] ^= x[0];
+    x[2] ^= x[1];
+    var t = 0u;
+    for (var bit = m; bit > 1u; bit = bit >> 1u) {
+        if (x[2] & bit) != 0u {
+            t ^= bit - 1u;
+        }
+    }
+    x[0] ^= t;
+    x[1] ^= t;
+    x[2] ^= t;
+    return morton_key(vec3<u32>(x[0], x[1], x[2]));
+}
+
+@compute @workgroup_size(spatial_wg_size)
+fn spatial_keys(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    var bounds_min: vec3<f32>;
+    var bounds_max: vec3<f32>;
+    for (var axis = 0u; axis < 3u; axis++) {
+        bounds_min[axis] = ordered_to_float(~atomicLoad(&bounds[axis]));
+        bounds_max[axis] = ordered_to_float(atomicLoad(&bounds[axis + 3u]));
+    }
+    let extent = bounds_max - bounds_min;
+    // flat axes are mapped to 0
+    let scale = select(vec3<f32>(f32(1u << BITS_PER_AXIS)) / extent, vec3<f32>(0.0), extent <= vec3<f32>(0.0));
+    let max_cell = (1u << BITS_PER_AXIS) - 1u;
+
+    let num_keys = infos.num_keys;
+    let line_size = nwg.x * spatial_wg_size;
+    for (var i = gid.x; i < num_keys; i += line_size) {
+        let cell = clamp((position(i) - bounds_min) * scale, vec3<f32>(0.0), vec3<f32>(f32(max_cell)));
+        let q = vec3<u32>(cell);
+        if spatial_info.curve == 0u {
+            keys[i] = morton_key(q);
+        } else {
+            keys[i] = hilbert_key(q);
+        }
+        values[i] = i;
+    }
+}
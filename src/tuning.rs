@@ -0,0 +1,355 @@
+/*
+    Autotuning of the sorter configuration per adapter.
+
+    The autotuner benchmarks all subgroup sizes that sort correctly on the current adapter (see utils::guess_workgroup_size_quick)
+    and picks the fastest.
+    With this subgroup size the block rows (keys loaded by every invocation of the histogram and scatter shaders)
+    and the digit width (bits sorted per scatter pass) are benchmarked. Both are injected into the shaders
+    like the subgroup size, sort buffers are laid out for them and can only be sorted by sorters with the same configuration.
+    The subgroup size is only tested with the default layout, so every other layout has to sort the same test inputs
+    correctly before it is benchmarked.
+    Afterwards the threshold up to which inputs are sorted by a single workgroup is chosen by comparing
+    the single workgroup sort with the radix sort for growing input sizes.
+
+    The results are stored in a TuningProfile that maps adapters (vendor, device, driver and backend) to configurations.
+    The profile is saved as a simple text file with one section per adapter:
+
+    [adapter]
+    vendor = 4318
+    device = 9860
+    driver = NVIDIA
+    backend = vulkan
+    subgroup_size = 32
+    small_sort_threshold = 1024
+    block_rows = 15
+    radix_log2 = 8
+*/
+
+use std::{fmt, fs, io, num::NonZeroU32, path::Path, str::FromStr, time::Instant};
+
+use wgpu::util::DeviceExt;
+
+use crate::{
+    utils::{guess_workgroup_size_quick, sorts_correctly},
+    GPUSorter, SortLayout, LOCAL_SORT_KVS,
+};
+
+/// number of keys sorted to compare the subgroup sizes
+const BENCH_KEYS: u32 = 1 << 20;
+/// number of timed sorts per candidate
+const BENCH_ITERS: u32 = 10;
+
+/// small sort thresholds compared by [autotune], 0 always uses the radix sort passes
+pub const SMALL_SORT_THRESHOLD_CANDIDATES: [u32; 4] = [0, LOCAL_SORT_KVS / 4, LOCAL_SORT_KVS / 2, LOCAL_SORT_KVS];
+
+/// block rows compared by [autotune], 15 is the default of [GPUSorter::new]
+pub const BLOCK_ROWS_CANDIDATES: [u32; 3] = [8, 12, 15];
+
+/// digit widths in bits compared by [autotune], 8 is the default of [GPUSorter::new]
+pub const RADIX_LOG2_CANDIDATES: [u32; 2] = [4, 8];
+
+/// Configuration of a [GPUSorter] found by [autotune]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SorterConfig {
+    pub subgroup_size: u32,
+    /// see [GPUSorter::set_small_sort_threshold]
+    pub small_sort_threshold: u32,
+    /// see [GPUSorter::block_rows], at most 15
+    pub block_rows: u32,
+    /// see [GPUSorter::radix_log2], 4 or 8
+    pub radix_log2: u32,
+}
+
+impl SorterConfig {
+    pub fn new(subgroup_size: u32, small_sort_threshold: u32, block_rows: u32, radix_log2: u32) -> Self {
+        Self {
+            subgroup_size,
+            small_sort_threshold,
+            block_rows,
+            radix_log2,
+        }
+    }
+}
+
+/// Identifies an adapter in a [TuningProfile]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterKey {
+    pub vendor: u32,
+    pub device: u32,
+    pub driver: String,
+    /// see [wgpu::Backend::to_str]
+    pub backend: String,
+}
+
+impl From<&wgpu::AdapterInfo> for AdapterKey {
+    fn from(info: &wgpu::AdapterInfo) -> Self {
+        Self {
+            vendor: info.vendor,
+            device: info.device,
+            driver: info.driver.clone(),
+            backend: info.backend.to_str().to_string(),
+        }
+    }
+}
+
+/// Tuned configurations of multiple adapters, can be saved to and loaded from disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TuningProfile {
+    entries: Vec<(AdapterKey, SorterConfig)>,
+}
+
+impl TuningProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// configuration of the adapter, None if the adapter was not tuned
+    pub fn get(&self, adapter_info: &wgpu::AdapterInfo) -> Option<SorterConfig> {
+        let key = AdapterKey::from(adapter_info);
+        self.entries
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, config)| *config)
+    }
+
+    /// stores the configuration of the adapter, replaces an existing one
+    pub fn insert(&mut self, adapter_info: &wgpu::AdapterInfo, config: SorterConfig) {
+        let key = AdapterKey::from(adapter_info);
+        self.entries.retain(|(k, _)| *k != key);
+        self.entries.push((key, config));
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for TuningProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, config) in &self.entries {
+            writeln!(f, "[adapter]")?;
+            writeln!(f, "vendor = {}", key.vendor)?;
+            writeln!(f, "device = {}", key.device)?;
+            writeln!(f, "driver = {}", key.driver)?;
+            writeln!(f, "backend = {}", key.backend)?;
+            writeln!(f, "subgroup_size = {}", config.subgroup_size)?;
+            writeln!(f, "small_sort_threshold = {}", config.small_sort_threshold)?;
+            writeln!(f, "block_rows = {}", config.block_rows)?;
+            writeln!(f, "radix_log2 = {}", config.radix_log2)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TuningProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // fields of an adapter section, in the order they are written by Display
+        const FIELDS: [&str; 8] = [
+            "vendor",
+            "device",
+            "driver",
+            "backend",
+            "subgroup_size",
+            "small_sort_threshold",
+            "block_rows",
+            "radix_log2",
+        ];
+        let mut sections: Vec<[Option<String>; 8]> = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[adapter]" {
+                sections.push(Default::default());
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected 'name = value'", i + 1))?;
+            let field = FIELDS
+                .iter()
+                .position(|f| *f == name.trim())
+                .ok_or_else(|| format!("line {}: unknown field '{}'", i + 1, name.trim()))?;
+            let section = sections
+                .last_mut()
+                .ok_or_else(|| format!("line {}: value outside of an [adapter] section", i + 1))?;
+            section[field] = Some(value.trim().to_string());
+        }
+
+        let entries = sections
+            .into_iter()
+            .enumerate()
+            .map(|(i, section)| {
+                let value = |field: usize| {
+                    section[field]
+                        .clone()
+                        .ok_or_else(|| format!("adapter {}: missing field '{}'", i, FIELDS[field]))
+                };
+                let number = |field: usize| {
+                    value(field)?
+                        .parse::<u32>()
+                        .map_err(|err| format!("adapter {}: field '{}': {err}", i, FIELDS[field]))
+                };
+                let key = AdapterKey {
+                    vendor: number(0)?,
+                    device: number(1)?,
+                    driver: value(2)?,
+                    backend: value(3)?,
+                };
+                let config = SorterConfig::new(number(4)?, number(5)?, number(6)?, number(7)?);
+                SortLayout::new(config.block_rows, config.radix_log2).map_err(|err| format!("adapter {}: {err}", i))?;
+                Ok((key, config))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { entries })
+    }
+}
+
+impl GPUSorter {
+    /// Creates a sorter with a configuration found by [autotune].
+    ///
+    /// Panics if the block rows or the digit width are not supported by the shaders.
+    pub fn from_config(device: &wgpu::Device, config: &SorterConfig) -> Self {
+        let layout = SortLayout::new(config.block_rows, config.radix_log2).unwrap_or_else(|err| panic!("{err}"));
+        let mut sorter = Self::create(device, config.subgroup_size, layout, None);
+        sorter.set_small_sort_threshold(config.small_sort_threshold);
+        sorter
+    }
+}
+
+/// Benchmarks the candidate configurations on the device and returns the fastest one.
+///
+/// The subgroup size is one of [SUBGROUP_SIZE_CANDIDATES](crate::utils::SUBGROUP_SIZE_CANDIDATES),
+/// the block rows one of [BLOCK_ROWS_CANDIDATES], the digit width one of [RADIX_LOG2_CANDIDATES]
+/// and the threshold one of [SMALL_SORT_THRESHOLD_CANDIDATES].
+/// Block rows and digit widths are only benchmarked if they sort the test inputs of the subgroup size search correctly.
+///
+/// Returns None if no subgroup size sorts correctly. Takes a few seconds, the result should be stored in a [TuningProfile].
+pub async fn autotune(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<SorterConfig> {
+    // larger subgroup sizes than the ones supported by the device produce wrong results
+    let report = guess_workgroup_size_quick(device, queue).await;
+    let mut best: Option<(u32, f64)> = None;
+    for subgroup_size in report.working_sizes() {
+        let sorter = GPUSorter::new(device, subgroup_size);
+        let time = bench_sort(&sorter, device, queue, BENCH_KEYS);
+        log::debug!("subgroup size {subgroup_size}: {time:.3}ms per sort");
+        // Option::is_none_or requires Rust 1.82
+        #[allow(clippy::unnecessary_map_or)]
+        let faster = best.map_or(true, |(_, best_time)| time < best_time);
+        if faster {
+            best = Some((subgroup_size, time));
+        }
+    }
+    let (subgroup_size, default_time) = best?;
+
+    // the block rows and the digit width of GPUSorter::new were already measured with the subgroup size
+    let mut config = SorterConfig::new(subgroup_size, 0, SortLayout::DEFAULT.block_rows(), SortLayout::DEFAULT.radix_log2());
+    let mut best_time = default_time;
+    for block_rows in BLOCK_ROWS_CANDIDATES {
+        for radix_log2 in RADIX_LOG2_CANDIDATES {
+            let candidate = SorterConfig::new(subgroup_size, 0, block_rows, radix_log2);
+            if candidate == config {
+                continue;
+            }
+            let sorter = GPUSorter::from_config(device, &candidate);
+            // the subgroup size was only tested with the default block rows and digit width
+            if !sorts_correctly(&sorter, device, queue).await {
+                log::debug!("{block_rows} block rows, {radix_log2} bit digits: sorts incorrectly");
+                continue;
+            }
+            let time = bench_sort(&sorter, device, queue, BENCH_KEYS);
+            log::debug!("{block_rows} block rows, {radix_log2} bit digits: {time:.3}ms per sort");
+            if time < best_time {
+                config = candidate;
+                best_time = time;
+            }
+        }
+    }
+
+    // the threshold is the largest candidate up to which the single workgroup sort is always faster
+    let mut sorter = GPUSorter::from_config(device, &config);
+    let mut small_sort_threshold = 0;
+    for n in SMALL_SORT_THRESHOLD_CANDIDATES.into_iter().filter(|&n| n > 0) {
+        sorter.set_small_sort_threshold(LOCAL_SORT_KVS);
+        let single_workgroup = bench_sort(&sorter, device, queue, n);
+        sorter.set_small_sort_threshold(0);
+        let radix = bench_sort(&sorter, device, queue, n);
+        log::debug!("{n} keys: single workgroup {single_workgroup:.3}ms, radix sort {radix:.3}ms");
+        if single_workgroup >= radix {
+            break;
+        }
+        small_sort_threshold = n;
+    }
+
+    config.small_sort_threshold = small_sort_threshold;
+    Some(config)
+}
+
+/// Loads the configuration of the adapter from the profile at `path`.
+///
+/// If the file does not exist or does not contain a configuration for the adapter,
+/// the sorter is tuned with [autotune] and the result is added to the profile.
+pub async fn load_or_autotune(
+    path: impl AsRef<Path>,
+    adapter_info: &wgpu::AdapterInfo,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> Option<SorterConfig> {
+    let path = path.as_ref();
+    let mut profile = match TuningProfile::load(path) {
+        Ok(profile) => profile,
+        Err(err) => {
+            if err.kind() != io::ErrorKind::NotFound {
+                log::warn!("could not load tuning profile {}: {err}", path.display());
+            }
+            TuningProfile::new()
+        }
+    };
+    if let Some(config) = profile.get(adapter_info) {
+        return Some(config);
+    }
+
+    let config = autotune(device, queue).await?;
+    profile.insert(adapter_info, config);
+    if let Err(err) = profile.save(path) {
+        log::warn!("could not save tuning profile {}: {err}", path.display());
+    }
+    Some(config)
+}
+
+/// average time of a sort of n keys in milliseconds
+fn bench_sort(sorter: &GPUSorter, device: &wgpu::Device, queue: &wgpu::Queue, n: u32) -> f64 {
+    let sort_buffers = sorter.create_sort_buffers(device, NonZeroU32::new(n).unwrap());
+    // the scrambled keys are copied into the sort buffers before every sort
+    let keys: Vec<u32> = (0..n).map(|i| i.wrapping_mul(2654435761)).collect();
+    let keys_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("GPUSorter autotune keys"),
+        contents: bytemuck::cast_slice(&keys),
+        usage: wgpu::BufferUsages::COPY_SRC,
+    });
+    let sort = |iters: u32| {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("GPUSorter autotune"),
+        });
+        for _ in 0..iters {
+            encoder.copy_buffer_to_buffer(&keys_buffer, 0, sort_buffers.keys(), 0, keys_buffer.size());
+            sorter.sort(&mut encoder, queue, &sort_buffers, None);
+        }
+        let idx = queue.submit([encoder.finish()]);
+        device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+    };
+    // warm up
+    sort(1);
+    let start = Instant::now();
+    sort(BENCH_ITERS);
+    start.elapsed().as_secs_f64() * 1000. / BENCH_ITERS as f64
+}
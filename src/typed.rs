@@ -0,0 +1,364 @@
+/*
+    Typed sort buffers.
+
+    SortBuffers<K, V> are generic over the key type K ([SortKey]) and value type V ([SortValue]).
+    The radix sort itself only sorts u32 keys with u32 payloads, the other types are mapped onto it:
+    - u32 keys with 4 byte values are sorted directly
+    - i32 and f32 keys with 4 byte values are transformed in place into u32 keys with the same order
+      before sorting and transformed back afterwards
+    - u64 keys and values with more than one word are stored in their own buffers.
+      The radix sort sorts the indices of the pairs by the key words, starting with the lowest word.
+      Afterwards keys and values are gathered with the sorted indices.
+
+    The shaders can be found in typed.wgsl
+*/
+
+use std::mem;
+
+use wgpu::util::DeviceExt;
+
+use crate::{buffer_entry, named_label, storage_layout_entry, MAX_WORKGROUPS};
+
+/// workgroup size of the typed shaders
+const TYPED_WG_SIZE: u32 = 256;
+
+/// How keys are mapped to the u32 keys of the radix sort.
+/// Has to be synced with typed.wgsl
+#[doc(hidden)]
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeyKind {
+    U32 = 0,
+    I32 = 1,
+    F32 = 2,
+    U64 = 3,
+}
+
+/// Key types that can be sorted. Negative i32 and f32 keys are sorted correctly.
+///
+/// f32 keys are sorted by their total order: -NaN < -inf < ... < -0.0 < 0.0 < ... < inf < NaN
+pub trait SortKey: bytemuck::Pod {
+    #[doc(hidden)]
+    const KIND: KeyKind;
+}
+
+impl SortKey for u32 {
+    const KIND: KeyKind = KeyKind::U32;
+}
+impl SortKey for i32 {
+    const KIND: KeyKind = KeyKind::I32;
+}
+impl SortKey for f32 {
+    const KIND: KeyKind = KeyKind::F32;
+}
+impl SortKey for u64 {
+    const KIND: KeyKind = KeyKind::U64;
+}
+
+/// Value types that can be sorted along with the keys. Values must be a multiple of 4 bytes large.
+pub trait SortValue: bytemuck::Pod {
+    #[doc(hidden)]
+    const WORDS: u32 = (mem::size_of::<Self>() / mem::size_of::<u32>()) as u32;
+}
+
+impl SortValue for u32 {}
+impl SortValue for i32 {}
+impl SortValue for f32 {}
+impl<const N: usize> SortValue for [u32; N] where [u32; N]: bytemuck::Pod {}
+impl<const N: usize> SortValue for [f32; N] where [f32; N]: bytemuck::Pod {}
+
+/// Uniform data for the typed shaders
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct TypedInfo {
+    key_kind: u32,
+    key_words: u32,
+    value_words: u32,
+    length: u32,
+}
+
+/// Additional buffers of typed sort buffers
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum TypedBuffers {
+    /// u32 keys and 4 byte values are sorted directly
+    Plain,
+    /// keys are transformed in place
+    Transform { bind_group: wgpu::BindGroup },
+    /// the indices are sorted and keys and values are gathered afterwards
+    Gather {
+        keys: wgpu::Buffer,
+        values: wgpu::Buffer,
+        bind_group: wgpu::BindGroup,
+        /// keys have two words that are sorted one after another
+        two_pass: bool,
+    },
+}
+
+impl TypedBuffers {
+    /// the radix sort buffers `keys_a` and `payload_a` are used by the typed shaders
+    pub(crate) fn new<K: SortKey, V: SortValue>(
+        device: &wgpu::Device,
+        length: u32,
+        state_buffer: &wgpu::Buffer,
+        keys_a: &wgpu::Buffer,
+        payload_a: &wgpu::Buffer,
+        name: Option<&str>,
+    ) -> Self {
+        assert!(
+            V::WORDS > 0 && mem::size_of::<V>() == V::WORDS as usize * mem::size_of::<u32>(),
+            "values must be a multiple of 4 bytes large"
+        );
+        let key_words = (mem::size_of::<K>() / mem::size_of::<u32>()) as u32;
+        let info = TypedInfo {
+            key_kind: K::KIND as u32,
+            key_words,
+            value_words: V::WORDS,
+            length,
+        };
+        if key_words == 1 && V::WORDS == 1 && K::KIND == KeyKind::U32 {
+            return TypedBuffers::Plain;
+        }
+        let info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "typed sort info buffer")),
+            contents: bytemuck::bytes_of(&info),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        if key_words == 1 && V::WORDS == 1 {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(&named_label(name, "typed sort transform bind group")),
+                layout: &TypedPipelines::transform_bind_group_layout(device),
+                entries: &[
+                    buffer_entry(0, state_buffer),
+                    buffer_entry(1, keys_a),
+                    buffer_entry(6, &info_buffer),
+                ],
+            });
+            return TypedBuffers::Transform { bind_group };
+        }
+
+        let typed_buffer = |label, words: u32| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&named_label(name, label)),
+                size: length as u64 * words as u64 * mem::size_of::<u32>() as u64,
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            })
+        };
+        let keys = typed_buffer("typed sort keys buffer", key_words);
+        let values = typed_buffer("typed sort values buffer", V::WORDS);
+        let scratch = typed_buffer("typed sort scratch buffer", key_words + V::WORDS);
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "typed sort gather bind group")),
+            layout: &TypedPipelines::gather_bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, state_buffer),
+                buffer_entry(1, keys_a),
+                buffer_entry(2, payload_a),
+                buffer_entry(3, &keys),
+                buffer_entry(4, &values),
+                buffer_entry(5, &scratch),
+                buffer_entry(6, &info_buffer),
+            ],
+        });
+        TypedBuffers::Gather {
+            keys,
+            values,
+            bind_group,
+            two_pass: key_words == 2,
+        }
+    }
+
+    /// the radix sort has to be run a second time after [TypedPipelines::record_gather_high_keys]
+    pub(crate) fn two_pass(&self) -> bool {
+        matches!(self, TypedBuffers::Gather { two_pass: true, .. })
+    }
+}
+
+/// Number of workgroups of the typed shaders
+pub(crate) enum TypedDispatch<'a> {
+    /// number of keys
+    Direct(u32),
+    /// indirect dispatch buffer of the radix sort
+    Indirect(&'a wgpu::Buffer),
+}
+
+/// Dispatches the pipeline set in `pass`, direct dispatches get one workgroup per `elements_per_workgroup` elements
+pub(crate) fn dispatch<'a>(pass: &mut wgpu::ComputePass<'a>, dispatch: &TypedDispatch<'a>, elements_per_workgroup: u32) {
+    match dispatch {
+        TypedDispatch::Direct(num_elements) => {
+            let wgs = num_elements.div_ceil(elements_per_workgroup).clamp(1, MAX_WORKGROUPS);
+            pass.dispatch_workgroups(wgs, 1, 1);
+        }
+        TypedDispatch::Indirect(dispatch_buffer) => pass.dispatch_workgroups_indirect(dispatch_buffer, 0),
+    }
+}
+
+/// Where the typed passes are recorded to, with the name of the sort buffers used in the labels
+pub(crate) enum RecordTarget<'r, 'a> {
+    /// every dispatch gets its own labelled compute pass
+    Encoder(&'r mut wgpu::CommandEncoder, Option<&'r str>),
+    /// all dispatches are recorded into a pass provided by the caller, each in its own debug group
+    Pass(&'r mut wgpu::ComputePass<'a>, Option<&'r str>),
+}
+
+/// Pipelines of the typed shaders
+pub(crate) struct TypedPipelines {
+    encode_keys_p: wgpu::ComputePipeline,
+    decode_keys_p: wgpu::ComputePipeline,
+    prepare_indices_p: wgpu::ComputePipeline,
+    gather_high_keys_p: wgpu::ComputePipeline,
+    gather_pairs_p: wgpu::ComputePipeline,
+    copy_back_pairs_p: wgpu::ComputePipeline,
+}
+
+impl TypedPipelines {
+    pub(crate) fn new(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("typed.wgsl");
+        let shader_code = format!("const typed_wg_size: u32 = {:}u;\n{:}", TYPED_WG_SIZE, raw_shader);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Typed sort shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+
+        let transform_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&named_label(name, "typed sort transform pipeline layout")),
+            bind_group_layouts: &[&Self::transform_bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let gather_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&named_label(name, "typed sort gather pipeline layout")),
+            bind_group_layouts: &[&Self::gather_bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |layout: &wgpu::PipelineLayout, entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&named_label(name, entry_point)),
+                layout: Some(layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+
+        Self {
+            encode_keys_p: pipeline(&transform_layout, "encode_keys"),
+            decode_keys_p: pipeline(&transform_layout, "decode_keys"),
+            prepare_indices_p: pipeline(&gather_layout, "prepare_indices"),
+            gather_high_keys_p: pipeline(&gather_layout, "gather_high_keys"),
+            gather_pairs_p: pipeline(&gather_layout, "gather_pairs"),
+            copy_back_pairs_p: pipeline(&gather_layout, "copy_back_pairs"),
+        }
+    }
+
+    fn transform_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("typed sort transform bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, false),
+                storage_layout_entry(6, true),
+            ],
+        })
+    }
+
+    fn gather_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("typed sort gather bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, false),
+                storage_layout_entry(2, false),
+                storage_layout_entry(3, false),
+                storage_layout_entry(4, false),
+                storage_layout_entry(5, false),
+                storage_layout_entry(6, true),
+            ],
+        })
+    }
+
+    fn record_pass<'a>(
+        label: &str,
+        pipeline: &'a wgpu::ComputePipeline,
+        bind_group: &'a wgpu::BindGroup,
+        dispatch: &TypedDispatch<'a>,
+        target: &mut RecordTarget<'_, 'a>,
+    ) {
+        match target {
+            RecordTarget::Encoder(encoder, name) => {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some(&named_label(*name, label)),
+                    timestamp_writes: None,
+                });
+                Self::record_dispatch(pipeline, bind_group, dispatch, &mut pass);
+            }
+            RecordTarget::Pass(pass, name) => {
+                pass.push_debug_group(&named_label(*name, label));
+                Self::record_dispatch(pipeline, bind_group, dispatch, pass);
+                pass.pop_debug_group();
+            }
+        }
+    }
+
+    fn record_dispatch<'a>(
+        pipeline: &'a wgpu::ComputePipeline,
+        bind_group: &'a wgpu::BindGroup,
+        typed_dispatch: &TypedDispatch<'a>,
+        pass: &mut wgpu::ComputePass<'a>,
+    ) {
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        dispatch(pass, typed_dispatch, TYPED_WG_SIZE);
+    }
+
+    /// records the passes that have to run before the radix sort
+    pub(crate) fn record_prepare<'a>(
+        &'a self,
+        buffers: &'a TypedBuffers,
+        dispatch: &TypedDispatch<'a>,
+        target: &mut RecordTarget<'_, 'a>,
+    ) {
+        match buffers {
+            TypedBuffers::Plain => {}
+            TypedBuffers::Transform { bind_group } => {
+                Self::record_pass("encode keys", &self.encode_keys_p, bind_group, dispatch, target)
+            }
+            TypedBuffers::Gather { bind_group, .. } => {
+                Self::record_pass("prepare indices", &self.prepare_indices_p, bind_group, dispatch, target)
+            }
+        }
+    }
+
+    /// records the passes that have to run between the two radix sorts of two word keys
+    pub(crate) fn record_gather_high_keys<'a>(
+        &'a self,
+        buffers: &'a TypedBuffers,
+        dispatch: &TypedDispatch<'a>,
+        target: &mut RecordTarget<'_, 'a>,
+    ) {
+        if let TypedBuffers::Gather { bind_group, .. } = buffers {
+            Self::record_pass("gather high keys", &self.gather_high_keys_p, bind_group, dispatch, target);
+        }
+    }
+
+    /// records the passes that have to run after the radix sort
+    pub(crate) fn record_finish<'a>(
+        &'a self,
+        buffers: &'a TypedBuffers,
+        dispatch: &TypedDispatch<'a>,
+        target: &mut RecordTarget<'_, 'a>,
+    ) {
+        match buffers {
+            TypedBuffers::Plain => {}
+            TypedBuffers::Transform { bind_group } => {
+                Self::record_pass("decode keys", &self.decode_keys_p, bind_group, dispatch, target)
+            }
+            TypedBuffers::Gather { bind_group, .. } => {
+                Self::record_pass("gather pairs", &self.gather_pairs_p, bind_group, dispatch, target);
+                Self::record_pass("copy back pairs", &self.copy_back_pairs_p, bind_group, dispatch, target);
+            }
+        }
+    }
+}
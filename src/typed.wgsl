@@ -0,0 +1,153 @@
+// shader implementing the key transforms and payload gathering of typed sort buffers. More information in typed.rs
+//
+// i32 and f32 keys are transformed into u32 keys with the same order before sorting and transformed back afterwards.
+// Keys with more than one word (u64) and values with more than one word are not moved by the radix sort directly.
+// Instead the indices of the pairs are sorted and the keys and values are gathered with the sorted indices.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const typed_wg_size
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct TypedInfo {
+    // see KeyKind in typed.rs
+    key_kind: u32,
+    // number of u32 words per key and value
+    key_words: u32,
+    value_words: u32,
+    // number of key-value pairs the buffers can store
+    length: u32,
+};
+
+const key_kind_i32: u32 = 1u;
+const key_kind_f32: u32 = 2u;
+
+@group(0) @binding(0)
+var<storage, read> infos: GeneralInfo;
+// keys and payload of the radix sort
+@group(0) @binding(1)
+var<storage, read_write> keys: array<u32>;
+@group(0) @binding(2)
+var<storage, read_write> payload: array<u32>;
+// typed keys and values, only used if the indices are sorted
+@group(0) @binding(3)
+var<storage, read_write> typed_keys: array<u32>;
+@group(0) @binding(4)
+var<storage, read_write> typed_values: array<u32>;
+// gathered keys followed by gathered values
+@group(0) @binding(5)
+var<storage, read_write> scratch: array<u32>;
+@group(0) @binding(6)
+var<storage, read> typed_info: TypedInfo;
+
+fn encode_key(key: u32) -> u32 {
+    switch typed_info.key_kind {
+        case key_kind_i32: {
+            return key ^ 0x80000000u;
+        }
+        case key_kind_f32: {
+            // negative floats are ordered in reverse
+            if (key & 0x80000000u) != 0u {
+                return ~key;
+            }
+            return key | 0x80000000u;
+        }
+        default: {}
+    }
+    return key;
+}
+
+fn decode_key(key: u32) -> u32 {
+    switch typed_info.key_kind {
+        case key_kind_i32: {
+            return key ^ 0x80000000u;
+        }
+        case key_kind_f32: {
+            if (key & 0x80000000u) != 0u {
+                return key & 0x7FFFFFFFu;
+            }
+            return ~key;
+        }
+        default: {}
+    }
+    return key;
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Transforming the keys in place (bindings 0, 1 and 6)
+// --------------------------------------------------------------------------------------------------------------
+@compute @workgroup_size(typed_wg_size)
+fn encode_keys(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * typed_wg_size;
+    for (var i = gid.x; i < infos.num_keys; i += line_size) {
+        keys[i] = encode_key(keys[i]);
+    }
+}
+
+@compute @workgroup_size(typed_wg_size)
+fn decode_keys(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * typed_wg_size;
+    for (var i = gid.x; i < infos.num_keys; i += line_size) {
+        keys[i] = decode_key(keys[i]);
+    }
+}
+
+// --------------------------------------------------------------------------------------------------------------
+// Sorting the indices and gathering keys and values (all bindings)
+// --------------------------------------------------------------------------------------------------------------
+// the lowest key word is sorted first, the payload contains the index of the pair
+@compute @workgroup_size(typed_wg_size)
+fn prepare_indices(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * typed_wg_size;
+    for (var i = gid.x; i < infos.num_keys; i += line_size) {
+        keys[i] = encode_key(typed_keys[i * typed_info.key_words]);
+        payload[i] = i;
+    }
+}
+
+// the radix sort is stable, so sorting by the high word afterwards sorts by the whole 64 bit key
+@compute @workgroup_size(typed_wg_size)
+fn gather_high_keys(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * typed_wg_size;
+    for (var i = gid.x; i < infos.num_keys; i += line_size) {
+        keys[i] = typed_keys[payload[i] * 2u + 1u];
+    }
+}
+
+@compute @workgroup_size(typed_wg_size)
+fn gather_pairs(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * typed_wg_size;
+    let key_words = typed_info.key_words;
+    let value_words = typed_info.value_words;
+    let values_offset = typed_info.length * key_words;
+    for (var i = gid.x; i < infos.num_keys; i += line_size) {
+        let src = payload[i];
+        for (var w = 0u; w < key_words; w++) {
+            scratch[i * key_words + w] = typed_keys[src * key_words + w];
+        }
+        for (var w = 0u; w < value_words; w++) {
+            scratch[values_offset + i * value_words + w] = typed_values[src * value_words + w];
+        }
+    }
+}
+
+@compute @workgroup_size(typed_wg_size)
+fn copy_back_pairs(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>) {
+    let line_size = nwg.x * typed_wg_size;
+    let key_words = typed_info.key_words;
+    let value_words = typed_info.value_words;
+    let values_offset = typed_info.length * key_words;
+    for (var i = gid.x; i < infos.num_keys; i += line_size) {
+        for (var w = 0u; w < key_words; w++) {
+            typed_keys[i * key_words + w] = scratch[i * key_words + w];
+        }
+        for (var w = 0u; w < value_words; w++) {
+            typed_values[i * value_words + w] = scratch[values_offset + i * value_words + w];
+        }
+    }
+}
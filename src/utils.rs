@@ -5,7 +5,7 @@ use std::{
 
 use wgpu::util::DeviceExt;
 
-use crate::GPUSorter;
+use crate::{GPUSorter, SortBuffers, SCATTER_BLOCK_KVS};
 
 #[doc(hidden)]
 /// only used for testing 
@@ -57,59 +57,285 @@ pub async fn download_buffer<T: Clone + bytemuck::Pod>(
     return bytemuck::cast_slice(data.deref()).to_vec();
 }
 
-async fn test_sort(sorter: &GPUSorter, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
-    // simply runs a small sort and check if the sorting result is correct
-    let n = 8192; // means that 2 workgroups are needed for sorting
-    let scrambled_data: Vec<f32> = (0..n).rev().map(|x| x as f32).collect();
-    let sorted_data: Vec<f32> = (0..n).map(|x| x as f32).collect();
+/// subgroup sizes tested by [guess_workgroup_size]
+pub const SUBGROUP_SIZE_CANDIDATES: [u32; 6] = [1, 8, 16, 32, 64, 128];
 
-    let sort_buffers = sorter.create_sort_buffers(device, NonZeroU32::new(n).unwrap());
+/// number of times every test case of [guess_workgroup_size] is repeated to detect nondeterministic results
+const SUBGROUP_SIZE_TRIALS: u32 = 3;
 
-    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-        label: Some("GPURSSorter test_sort"),
-    });
-    upload_to_buffer(
-        &mut encoder,
-        &sort_buffers.keys(),
-        device,
-        scrambled_data.as_slice(),
-    );
-
-    sorter.sort(&mut encoder, queue, &sort_buffers,None);
-    let idx = queue.submit([encoder.finish()]);
-    device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
-
-    let sorted = download_buffer::<f32>(
-        &sort_buffers.keys(),
-        device,
-        queue,
-        0..sort_buffers.keys_valid_size(),
-    )
-    .await;
-    return sorted.into_iter().zip(sorted_data.into_iter()).all(|(a,b)|a==b);
-}
-
-/// Function guesses the best subgroup size by testing the sorter with
-/// subgroup sizes 1,8,16,32,64,128 and returning the largest subgroup size that worked.
-pub async fn guess_workgroup_size(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<u32> {
-    let mut cur_sorter: GPUSorter;
+/// number of keys of the test cases, all of them are sorted by the radix sort
+/// (more than one scatter block, not a multiple of the block size and many blocks)
+const SUBGROUP_SIZE_TEST_LENGTHS: [u32; 3] = [8192, 3 * SCATTER_BLOCK_KVS + 17, 1 << 17];
 
-    log::debug!("Searching for the maximum subgroup size (wgpu currently does not allow to query subgroup sizes)");
+/// number of keys of the test cases of [guess_workgroup_size_quick]
+const SUBGROUP_SIZE_QUICK_LENGTH: u32 = 3 * SCATTER_BLOCK_KVS + 17;
 
-    let mut best = None;
-    for subgroup_size in [1, 8, 16, 32, 64, 128] {
-        log::debug!("Checking sorting with subgroupsize {}", subgroup_size);
+/// number of spins after which the lookback of a probe gives up (see [SortBuffers::set_lookback_limit]),
+/// a wrong subgroup size can otherwise wait forever for a partition that is never published
+const SUBGROUP_SIZE_LOOKBACK_LIMIT: u32 = 1 << 20;
+
+/// Input distributions used to test the subgroup sizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyDistribution {
+    Reversed,
+    Sorted,
+    /// pseudo random keys covering all bits
+    Random,
+    /// only 16 different keys
+    FewUnique,
+    AllEqual,
+}
+
+impl KeyDistribution {
+    pub const ALL: [KeyDistribution; 5] = [
+        KeyDistribution::Reversed,
+        KeyDistribution::Sorted,
+        KeyDistribution::Random,
+        KeyDistribution::FewUnique,
+        KeyDistribution::AllEqual,
+    ];
+
+    fn keys(&self, n: u32) -> Vec<u32> {
+        // xorshift, the sequence is the same for every trial
+        let mut state = 0x9E3779B9u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        (0..n)
+            .map(|i| match self {
+                KeyDistribution::Reversed => n - i,
+                KeyDistribution::Sorted => i,
+                KeyDistribution::Random => random(),
+                KeyDistribution::FewUnique => random() % 16,
+                KeyDistribution::AllEqual => 42,
+            })
+            .collect()
+    }
+}
+
+/// A test case that sorted incorrectly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubgroupSizeFailure {
+    pub distribution: KeyDistribution,
+    /// number of keys
+    pub length: u32,
+    /// number of trials of the test case that failed
+    pub failed_trials: u32,
+    /// number of failed trials in which the lookback timed out
+    pub timed_out_trials: u32,
+}
+
+/// Test results of a single subgroup size
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubgroupSizeResult {
+    pub subgroup_size: u32,
+    /// number of sorts that were run
+    pub trials: u32,
+    /// number of times every test case was sorted
+    pub trials_per_case: u32,
+    pub failures: Vec<SubgroupSizeFailure>,
+}
+
+impl SubgroupSizeResult {
+    /// true if all trials sorted correctly
+    pub fn works(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// true if a test case sorted correctly in some trials and incorrectly in others.
+    /// Always false if every test case was only sorted once.
+    pub fn is_nondeterministic(&self) -> bool {
+        self.failures
+            .iter()
+            .any(|failure| failure.failed_trials < self.trials_per_case)
+    }
+}
+
+/// Report of [guess_workgroup_size] with the test results of every candidate subgroup size
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubgroupSizeReport {
+    pub results: Vec<SubgroupSizeResult>,
+}
+
+impl SubgroupSizeReport {
+    /// largest subgroup size that sorted all test cases correctly
+    pub fn best(&self) -> Option<u32> {
+        self.working_sizes().max()
+    }
 
-        cur_sorter = GPUSorter::new(device, subgroup_size);
-        let sort_success = test_sort(&cur_sorter, device, queue).await;
+    /// all subgroup sizes that sorted all test cases correctly
+    pub fn working_sizes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.results
+            .iter()
+            .filter(|result| result.works())
+            .map(|result| result.subgroup_size)
+    }
+}
+
+struct TestCase {
+    distribution: KeyDistribution,
+    keys: Vec<u32>,
+    /// keys and their original indices sorted stable on the cpu
+    expected: Vec<(u32, u32)>,
+}
+
+impl TestCase {
+    fn new(distribution: KeyDistribution, n: u32) -> Self {
+        let keys = distribution.keys(n);
+        let mut expected: Vec<(u32, u32)> = keys.iter().copied().zip(0..n).collect();
+        expected.sort_by_key(|(key, _)| *key);
+        Self {
+            distribution,
+            keys,
+            expected,
+        }
+    }
 
-        log::debug!("{} worked: {}", subgroup_size, sort_success);
+    // sorts the keys with their indices as values and compares the result with the expected pairs
+    async fn run(
+        &self,
+        sorter: &GPUSorter,
+        sort_buffers: &SortBuffers,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> TrialResult {
+        let n = self.keys.len() as u32;
+        let values: Vec<u32> = (0..n).collect();
+        sort_buffers.write_keys(queue, &self.keys);
+        sort_buffers.write_values(queue, &values);
+        sort_buffers.set_lookback_limit(queue, NonZeroU32::new(SUBGROUP_SIZE_LOOKBACK_LIMIT));
+        sort_buffers.clear_errors(queue);
 
-        if !sort_success {
-            break;
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("GPURSSorter test_sort"),
+        });
+        sorter.sort(&mut encoder, queue, sort_buffers, Some(n));
+        let idx = queue.submit([encoder.finish()]);
+        device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+
+        if sort_buffers.read_errors(device, queue).await != 0 {
+            return TrialResult::TimedOut;
+        }
+        let keys_sorted = sort_buffers.read_keys(device, queue).await;
+        let values_sorted = sort_buffers.read_values(device, queue).await;
+        let sorted = self
+            .expected
+            .iter()
+            .zip(keys_sorted.iter().zip(values_sorted.iter()))
+            .all(|(&(key, value), (&k, &v))| key == k && value == v);
+        if sorted {
+            TrialResult::Sorted
         } else {
-            best = Some(subgroup_size)
+            TrialResult::Unsorted
         }
     }
-    return best;
+}
+
+#[derive(PartialEq, Eq)]
+enum TrialResult {
+    Sorted,
+    Unsorted,
+    /// the lookback gave up waiting, the keys are not checked
+    TimedOut,
+}
+
+/// Tests the sorter with every subgroup size in [SUBGROUP_SIZE_CANDIDATES].
+///
+/// Every size sorts every input distribution with several lengths, and every test case is repeated
+/// to detect nondeterministic results. A size fails if the keys are not sorted, equal keys do not keep
+/// the order of their values or the lookback of a scatter pass times out (see [SortBuffers::set_lookback_limit]).
+/// Failing sizes do not stop the search. [SubgroupSizeReport::best] returns the largest size that always worked.
+///
+/// Use [guess_workgroup_size_quick] if startup time matters more than reliability.
+pub async fn guess_workgroup_size(device: &wgpu::Device, queue: &wgpu::Queue) -> SubgroupSizeReport {
+    search_subgroup_size(device, queue, &SUBGROUP_SIZE_TEST_LENGTHS, SUBGROUP_SIZE_TRIALS).await
+}
+
+/// Like [guess_workgroup_size] but sorts every input distribution only once with a single length
+/// that spans several scatter blocks. Much faster, but cannot detect nondeterministic results.
+pub async fn guess_workgroup_size_quick(device: &wgpu::Device, queue: &wgpu::Queue) -> SubgroupSizeReport {
+    search_subgroup_size(device, queue, &[SUBGROUP_SIZE_QUICK_LENGTH], 1).await
+}
+
+/// Sorts the test cases of [guess_workgroup_size_quick] with a sorter created with [GPUSorter::from_config].
+///
+/// The subgroup sizes are only tested with the block rows and digit width of [GPUSorter::new],
+/// other configurations have to be checked before they are used. The length is scaled to the block size of the sorter.
+pub(crate) async fn sorts_correctly(sorter: &GPUSorter, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+    let cases = test_cases(&[3 * sorter.histo_block_kvs() + 17]);
+    run_test_cases(sorter, &cases, 1, device, queue).await.is_empty()
+}
+
+/// every input distribution with every length
+fn test_cases(lengths: &[u32]) -> Vec<TestCase> {
+    lengths
+        .iter()
+        .flat_map(|&n| KeyDistribution::ALL.map(|distribution| TestCase::new(distribution, n)))
+        .collect()
+}
+
+/// runs every test case `trials` times and returns the failed ones
+async fn run_test_cases(
+    sorter: &GPUSorter,
+    cases: &[TestCase],
+    trials: u32,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> Vec<SubgroupSizeFailure> {
+    let max_length = cases.iter().map(|case| case.keys.len() as u32).max().unwrap();
+    let sort_buffers = sorter.create_sort_buffers(device, NonZeroU32::new(max_length).unwrap());
+    let mut failures = Vec::new();
+    for case in cases {
+        let mut failed_trials = 0;
+        let mut timed_out_trials = 0;
+        for _ in 0..trials {
+            match case.run(sorter, &sort_buffers, device, queue).await {
+                TrialResult::Sorted => {}
+                TrialResult::Unsorted => failed_trials += 1,
+                TrialResult::TimedOut => {
+                    failed_trials += 1;
+                    timed_out_trials += 1;
+                }
+            }
+        }
+        if failed_trials > 0 {
+            failures.push(SubgroupSizeFailure {
+                distribution: case.distribution,
+                length: case.keys.len() as u32,
+                failed_trials,
+                timed_out_trials,
+            });
+        }
+    }
+    failures
+}
+
+async fn search_subgroup_size(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    lengths: &[u32],
+    trials: u32,
+) -> SubgroupSizeReport {
+    log::debug!("Searching for the maximum subgroup size (wgpu currently does not allow to query subgroup sizes)");
+
+    let cases = test_cases(lengths);
+    let mut results = Vec::new();
+    for subgroup_size in SUBGROUP_SIZE_CANDIDATES {
+        log::debug!("Checking sorting with subgroupsize {}", subgroup_size);
+
+        let sorter = GPUSorter::new(device, subgroup_size);
+        let result = SubgroupSizeResult {
+            subgroup_size,
+            trials: trials * cases.len() as u32,
+            trials_per_case: trials,
+            failures: run_test_cases(&sorter, &cases, trials, device, queue).await,
+        };
+
+        log::debug!("{} worked: {} ({} failed cases)", subgroup_size, result.works(), result.failures.len());
+        results.push(result);
+    }
+    SubgroupSizeReport { results }
 }
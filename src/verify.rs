@@ -0,0 +1,241 @@
+/*
+    GPU side validation of sort results.
+
+    Checks that a sort produced ordered keys and a permutation of the input without downloading the keys and values:
+    - checksum_before sums the keys and values (and their hashes) and hashes of the key-value pairs before the sort
+    - verify sums them again after the sort, counts adjacent key pairs that are out of order
+      and counts padding keys of the radix sort that are not 0xFFFFFFFF anymore
+
+    All results are accumulated in a small buffer that can be read with VerifyBuffers::read_report.
+    The checks are cheap compared to the sort, but still meant for debug builds:
+
+    let verifier = GPUSortVerifier::new(&device);
+    let verify_buffers = verifier.create_verify_buffers(&device, &sorter, &sort_buffers);
+    verifier.record_checksum(&mut encoder, &verify_buffers);
+    sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+    verifier.verify(&mut encoder, &verify_buffers);
+    queue.submit([encoder.finish()]);
+    assert!(verify_buffers.read_report(&device, &queue).await.is_valid());
+
+    The shaders can be found in verify.wgsl
+*/
+
+use std::mem;
+
+use wgpu::util::DeviceExt;
+
+use crate::{
+    buffer_entry, named_label, storage_layout_entry,
+    typed::{self, SortKey, SortValue, TypedDispatch},
+    utils::download_buffer,
+    GPUSorter, SortBuffers,
+};
+
+/// workgroup size of the verify shaders
+const VERIFY_WG_SIZE: u32 = 256;
+
+/// Data for the verify shaders
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+struct VerifyInfo {
+    key_kind: u32,
+    key_words: u32,
+    value_words: u32,
+    small_sort_threshold: u32,
+}
+
+/// Result of [GPUSortVerifier::verify]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct VerifyReport {
+    /// number of adjacent keys that are not in order
+    pub unsorted_pairs: u32,
+    /// number of padding keys of the radix sort that are not 0xFFFFFFFF
+    pub padding_errors: u32,
+    /// sum and hash sum of the keys and the values and hash sum of the pairs before the sort
+    pub checksum_before: [u32; 5],
+    /// sum and hash sum of the keys and the values and hash sum of the pairs after the sort
+    pub checksum_after: [u32; 5],
+}
+
+impl VerifyReport {
+    pub fn is_sorted(&self) -> bool {
+        self.unsorted_pairs == 0
+    }
+
+    /// true if the key-value pairs after the sort are (very likely) a permutation of the ones before
+    pub fn checksum_matches(&self) -> bool {
+        self.checksum_before == self.checksum_after
+    }
+
+    pub fn padding_intact(&self) -> bool {
+        self.padding_errors == 0
+    }
+
+    /// true if all checks passed
+    pub fn is_valid(&self) -> bool {
+        self.is_sorted() && self.checksum_matches() && self.padding_intact()
+    }
+}
+
+/// Result buffer and bind group for verifying one [SortBuffers]
+pub struct VerifyBuffers {
+    result_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// padded size of the sort buffers
+    length: u32,
+    name: Option<String>,
+}
+
+impl VerifyBuffers {
+    /// Buffer containing a [VerifyReport]
+    pub fn result_buffer(&self) -> &wgpu::Buffer {
+        &self.result_buffer
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Downloads the report of the last verified sort. The function waits for the gpu to finish.
+    pub async fn read_report(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> VerifyReport {
+        download_buffer::<VerifyReport>(&self.result_buffer, device, queue, ..).await[0]
+    }
+}
+
+/// Pipelines for validating sort results on the gpu
+pub struct GPUSortVerifier {
+    checksum_before_p: wgpu::ComputePipeline,
+    verify_p: wgpu::ComputePipeline,
+}
+
+impl GPUSortVerifier {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self::create(device, None)
+    }
+
+    /// Same as [GPUSortVerifier::new] but adds `name` to the labels of the pipelines
+    pub fn new_named(device: &wgpu::Device, name: &str) -> Self {
+        Self::create(device, Some(name))
+    }
+
+    fn create(device: &wgpu::Device, name: Option<&str>) -> Self {
+        let raw_shader: &str = include_str!("verify.wgsl");
+        let shader_code = format!("const verify_wg_size: u32 = {:}u;\n{:}", VERIFY_WG_SIZE, raw_shader);
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&named_label(name, "Verify sort shader")),
+            source: wgpu::ShaderSource::Wgsl(shader_code.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&named_label(name, "verify sort pipeline layout")),
+            bind_group_layouts: &[&Self::bind_group_layout(device)],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&named_label(name, entry_point)),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        Self {
+            checksum_before_p: pipeline("checksum_before"),
+            verify_p: pipeline("verify"),
+        }
+    }
+
+    fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("verify sort bind group layout"),
+            entries: &[
+                storage_layout_entry(0, true),
+                storage_layout_entry(1, true),
+                storage_layout_entry(2, true),
+                storage_layout_entry(3, true),
+                storage_layout_entry(4, true),
+                storage_layout_entry(5, false),
+            ],
+        })
+    }
+
+    /// Creates the buffers for verifying sorts of `sort_buffers` by `sorter`.
+    ///
+    /// The padding check depends on [GPUSorter::small_sort_threshold],
+    /// the buffers have to be recreated if the threshold is changed.
+    pub fn create_verify_buffers<K: SortKey, V: SortValue>(
+        &self,
+        device: &wgpu::Device,
+        sorter: &GPUSorter,
+        sort_buffers: &SortBuffers<K, V>,
+    ) -> VerifyBuffers {
+        let name = sort_buffers.name();
+        let info = VerifyInfo {
+            key_kind: K::KIND as u32,
+            key_words: (mem::size_of::<K>() / mem::size_of::<u32>()) as u32,
+            value_words: V::WORDS,
+            small_sort_threshold: sorter.small_sort_threshold(),
+        };
+        let info_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&named_label(name, "verify sort info buffer")),
+            contents: bytemuck::bytes_of(&info),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let result_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&named_label(name, "verify sort result buffer")),
+            size: mem::size_of::<VerifyReport>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&named_label(name, "verify sort bind group")),
+            layout: &Self::bind_group_layout(device),
+            entries: &[
+                buffer_entry(0, &sort_buffers.state_buffer),
+                buffer_entry(1, &sort_buffers.keys_a),
+                buffer_entry(2, sort_buffers.keys()),
+                buffer_entry(3, sort_buffers.values()),
+                buffer_entry(4, &info_buffer),
+                buffer_entry(5, &result_buffer),
+            ],
+        });
+        let length = (sort_buffers.keys_a.size() / mem::size_of::<u32>() as u64) as u32;
+        VerifyBuffers {
+            result_buffer,
+            bind_group,
+            length,
+            name: name.map(str::to_string),
+        }
+    }
+
+    /// Clears the report and records the checksum of the keys and values.
+    /// Has to be recorded before the sort.
+    pub fn record_checksum(&self, encoder: &mut wgpu::CommandEncoder, verify_buffers: &VerifyBuffers) {
+        encoder.clear_buffer(&verify_buffers.result_buffer, 0, None);
+        self.record(encoder, verify_buffers, &self.checksum_before_p, "verify sort checksum");
+    }
+
+    /// Records the validation of the sorted keys and values. Has to be recorded after the sort.
+    ///
+    /// Without a preceding [GPUSortVerifier::record_checksum] the checksums do not match.
+    pub fn verify(&self, encoder: &mut wgpu::CommandEncoder, verify_buffers: &VerifyBuffers) {
+        self.record(encoder, verify_buffers, &self.verify_p, "verify sort");
+    }
+
+    fn record(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        verify_buffers: &VerifyBuffers,
+        pipeline: &wgpu::ComputePipeline,
+        label: &str,
+    ) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&named_label(verify_buffers.name(), label)),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &verify_buffers.bind_group, &[]);
+        typed::dispatch(&mut pass, &TypedDispatch::Direct(verify_buffers.length), VERIFY_WG_SIZE);
+    }
+}
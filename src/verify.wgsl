@@ -0,0 +1,179 @@
+// shader for validating the result of a sort on the gpu. More information in verify.rs
+//
+// checksum_before is run before the sort and sums the keys, the values and a hash of every key-value pair.
+// verify is run after the sort, sums them again, counts the adjacent keys that are not ordered
+// and counts the padding keys of the radix sort that are not 0xFFFFFFFF.
+//
+// before the pipeline is started the following constant definitions are prepended to this shadercode
+// const verify_wg_size
+
+struct GeneralInfo {
+    num_keys: u32,
+    padded_size: u32,
+    even_pass: u32,
+    odd_pass: u32,
+};
+
+struct VerifyInfo {
+    // see KeyKind in typed.rs
+    key_kind: u32,
+    // number of u32 words per key and value
+    key_words: u32,
+    value_words: u32,
+    // inputs with at most this many keys are sorted by a single workgroup and have no padding
+    small_sort_threshold: u32,
+};
+
+// number of checksum words for the keys, the values and the pairs
+const num_checksums: u32 = 5u;
+
+struct VerifyResult {
+    unsorted_pairs: atomic<u32>,
+    padding_errors: atomic<u32>,
+    // sum and hash sum of the keys, sum and hash sum of the values and hash sum of the pairs
+    checksum_before: array<atomic<u32>, num_checksums>,
+    checksum_after: array<atomic<u32>, num_checksums>,
+};
+
+const key_kind_i32: u32 = 1u;
+const key_kind_f32: u32 = 2u;
+
+@group(0) @binding(0)
+var<storage, read> infos: GeneralInfo;
+// keys of the radix sort, containing the padding
+@group(0) @binding(1)
+var<storage, read> radix_keys: array<u32>;
+// sorted keys and values (SortBuffers::keys and SortBuffers::values)
+@group(0) @binding(2)
+var<storage, read> keys: array<u32>;
+@group(0) @binding(3)
+var<storage, read> values: array<u32>;
+@group(0) @binding(4)
+var<storage, read> verify_info: VerifyInfo;
+@group(0) @binding(5)
+var<storage, read_write> result: VerifyResult;
+
+var<workgroup> checksums: array<atomic<u32>, num_checksums>;
+
+// same order preserving transform as in typed.wgsl
+fn encode_key(key: u32) -> u32 {
+    switch verify_info.key_kind {
+        case key_kind_i32: {
+            return key ^ 0x80000000u;
+        }
+        case key_kind_f32: {
+            if (key & 0x80000000u) != 0u {
+                return ~key;
+            }
+            return key | 0x80000000u;
+        }
+        default: {}
+    }
+    return key;
+}
+
+// mixes the bits of a word, so that the hash sums of different permutations are very likely to differ
+fn hash(x: u32) -> u32 {
+    var h = x * 0x9E3779B1u;
+    h ^= h >> 15u;
+    h *= 0x85EBCA77u;
+    h ^= h >> 13u;
+    return h;
+}
+
+// true if key i is larger than key i + 1, the most significant word of multi word keys is the last one
+fn unordered(i: u32) -> bool {
+    let key_words = verify_info.key_words;
+    for (var w = key_words; w > 0u; w--) {
+        let a = encode_key(keys[i * key_words + w - 1u]);
+        let b = encode_key(keys[(i + 1u) * key_words + w - 1u]);
+        if a != b {
+            return a > b;
+        }
+    }
+    return false;
+}
+
+fn checksum(lid: u32, gid: u32, line_size: u32, before: bool) {
+    if lid < num_checksums {
+        atomicStore(&checksums[lid], 0u);
+    }
+    workgroupBarrier();
+
+    var sums = array<u32, num_checksums>(0u, 0u, 0u, 0u, 0u);
+    for (var i = gid; i < infos.num_keys * verify_info.key_words; i += line_size) {
+        sums[0] += keys[i];
+        sums[1] += hash(keys[i] ^ (i % verify_info.key_words));
+    }
+    for (var i = gid; i < infos.num_keys * verify_info.value_words; i += line_size) {
+        sums[2] += values[i];
+        sums[3] += hash(values[i] ^ (i % verify_info.value_words));
+    }
+    // detects values that were moved without their key
+    for (var i = gid; i < infos.num_keys; i += line_size) {
+        var key = 0u;
+        for (var w = 0u; w < verify_info.key_words; w++) {
+            key = hash(key ^ keys[i * verify_info.key_words + w]);
+        }
+        var value = 0u;
+        for (var w = 0u; w < verify_info.value_words; w++) {
+            value = hash(value ^ values[i * verify_info.value_words + w]);
+        }
+        sums[4] += hash(key ^ hash(value));
+    }
+    for (var c = 0u; c < num_checksums; c++) {
+        atomicAdd(&checksums[c], sums[c]);
+    }
+    workgroupBarrier();
+
+    if lid < num_checksums {
+        let sum = atomicLoad(&checksums[lid]);
+        if before {
+            atomicAdd(&result.checksum_before[lid], sum);
+        } else {
+            atomicAdd(&result.checksum_after[lid], sum);
+        }
+    }
+}
+
+@compute @workgroup_size(verify_wg_size)
+fn checksum_before(
+    @builtin(local_invocation_index) lid: u32,
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) nwg: vec3<u32>,
+) {
+    checksum(lid, gid.x, nwg.x * verify_wg_size, true);
+}
+
+@compute @workgroup_size(verify_wg_size)
+fn verify(
+    @builtin(local_invocation_index) lid: u32,
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(num_workgroups) nwg: vec3<u32>,
+) {
+    let line_size = nwg.x * verify_wg_size;
+    checksum(lid, gid.x, line_size, false);
+
+    var unsorted = 0u;
+    for (var i = gid.x; i + 1u < infos.num_keys; i += line_size) {
+        if unordered(i) {
+            unsorted += 1u;
+        }
+    }
+    if unsorted > 0u {
+        atomicAdd(&result.unsorted_pairs, unsorted);
+    }
+
+    // the radix sort fills the keys after the sorted ones with 0xFFFFFFFF, which stay at the end
+    if infos.num_keys > verify_info.small_sort_threshold {
+        var padding_errors = 0u;
+        for (var i = infos.num_keys + gid.x; i < infos.padded_size; i += line_size) {
+            if radix_keys[i] != 0xFFFFFFFFu {
+                padding_errors += 1u;
+            }
+        }
+        if padding_errors > 0u {
+            atomicAdd(&result.padding_errors, padding_errors);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Debug, num::NonZeroU32};
+use std::{fmt::Debug, num::NonZeroU32, sync::OnceLock};
 
 use bytemuck::bytes_of;
 use float_ord::FloatOrd;
@@ -9,8 +9,29 @@ use rand::{
 };
 use wgpu::util::DeviceExt;
 use wgpu_sort::{
-    utils::{download_buffer, guess_workgroup_size, upload_to_buffer},
-    GPUSorter, SortBuffers, HISTO_BLOCK_KVS,
+    chunked::ChunkedSorter,
+    compact::GPUCompactor,
+    cpu::{AutoSortBuffers, AutoSorter, CPUSorter},
+    depth::{DepthCamera, DepthOrder, GPUDepthEncoder},
+    permute::GPUPermuter,
+    ranges::GPURangeFinder,
+    reduce::{GPUReducer, Run},
+    simulator::RadixSortSimulator,
+    spatial::{Aabb, GPUSpatialEncoder, PositionLayout, SpaceFillingCurve},
+    tuning::{
+        autotune, SorterConfig, TuningProfile, BLOCK_ROWS_CANDIDATES, RADIX_LOG2_CANDIDATES,
+        SMALL_SORT_THRESHOLD_CANDIDATES,
+    },
+    typed::{SortKey, SortValue},
+    scan::{GPUScanner, ScanMode, ScanOp, SCAN_BLOCK_KVS},
+    segmented::SegmentedSorter,
+    select::SelectOrder,
+    utils::{
+        download_buffer, guess_workgroup_size, guess_workgroup_size_quick, upload_to_buffer,
+        SUBGROUP_SIZE_CANDIDATES,
+    },
+    verify::GPUSortVerifier,
+    GPUSorter, SortBuffers, SortLayout, HISTO_BLOCK_KVS, LOCAL_SORT_KVS,
 };
 
 
@@ -20,6 +41,19 @@ async fn sort_u32_small() {
     test_sort::<u32>(2,&apply_sort,None).await;
 }
 
+/// tests sorting of pairs that fit into a single workgroup
+#[pollster::test]
+async fn sort_u32_single_workgroup() {
+    test_sort::<u32>(LOCAL_SORT_KVS,&apply_sort,None).await;
+}
+
+/// tests the pairs at the limit of the single workgroup sort and one pair more, which uses the radix sort
+#[pollster::test]
+async fn sort_single_workgroup_limit() {
+    test_sort_stable(LOCAL_SORT_KVS, &apply_sort, None).await;
+    test_sort_stable(LOCAL_SORT_KVS + 1, &apply_sort, None).await;
+}
+
 /// tests sorting of one million pairs with u32 keys
 #[pollster::test]
 async fn sort_u32_large() {
@@ -38,6 +72,49 @@ async fn sort_half() {
     test_sort::<u32>(1_000_000,&apply_sort,Some(500_00)).await;
 }
 
+/// tests sorting host slices twice with the cached buffers
+#[pollster::test]
+async fn sort_slices() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for n in [100_000, 50_000] {
+        let keys_scrambled: Vec<f32> = (0..n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let values_scrambled: Vec<u32> = (0..n).collect();
+        let mut pairs: Vec<(f32, u32)> =
+            keys_scrambled.iter().copied().zip(values_scrambled.iter().copied()).collect();
+        pairs.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+        let (keys_sorted_gpu, values_sorted_gpu) =
+            sorter.sort_slices(&device, &queue, &keys_scrambled, &values_scrambled).await;
+        assert_eq!(keys_sorted_gpu, pairs.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+        assert_eq!(values_sorted_gpu, pairs.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+    }
+}
+
+/// tests sorting of typed keys and values
+#[pollster::test]
+async fn sort_typed() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+
+    let n = 100_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys_i32: Vec<i32> = (0..n).map(|_| rng.gen_range(-1000..1000)).collect();
+    let keys_f32: Vec<f32> = (0..n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+    let keys_u64: Vec<u64> = (0..n).map(|_| rng.gen_range(0..1 << 40)).collect();
+    let values: Vec<[u32; 3]> = (0..n).map(|i| [i, rng.gen(), rng.gen()]).collect();
+
+    test_sort_typed(&sorter, &device, &queue, &keys_i32, &values, |a, b| a.cmp(b)).await;
+    test_sort_typed(&sorter, &device, &queue, &keys_f32, &values, |a, b| a.total_cmp(b)).await;
+    test_sort_typed(&sorter, &device, &queue, &keys_u64, &values, |a, b| a.cmp(b)).await;
+    let values_u32: Vec<u32> = (0..n).collect();
+    test_sort_typed(&sorter, &device, &queue, &keys_f32, &values_u32, |a, b| a.total_cmp(b)).await;
+}
+
 // INDIRECT SORTING
 
 
@@ -63,7 +140,1661 @@ async fn sort_indirect_half() {
     test_sort::<u32>(1_000_000,&apply_sort_indirect,Some(500_00)).await;
 }
 
+// SORTING INTO A COMPUTE PASS
+
+/// tests sorting pairs that fit into a single workgroup
+/// recorded into a single compute pass
+#[pollster::test]
+async fn sort_into_pass_small() {
+    test_sort::<u32>(LOCAL_SORT_KVS,&apply_sort_into_pass,None).await;
+}
+
+/// tests sorting only first half of one million pairs
+/// recorded into a single compute pass
+#[pollster::test]
+async fn sort_into_pass_half() {
+    test_sort::<u32>(1_000_000,&apply_sort_into_pass,Some(50_000)).await;
+}
+
+/// tests sorting only first half of one million pairs
+/// indirect dispatch recorded into a single compute pass
+#[pollster::test]
+async fn sort_indirect_into_pass_half() {
+    test_sort::<u32>(1_000_000,&apply_sort_indirect_into_pass,Some(50_000)).await;
+}
+
+// STABILITY
+
+/// tests that equal keys keep their order when sorted by a single workgroup
+#[pollster::test]
+async fn sort_stable_small() {
+    test_sort_stable(LOCAL_SORT_KVS, &apply_sort, None).await;
+}
+
+/// tests that equal keys keep their order when sorted by the radix sort
+#[pollster::test]
+async fn sort_stable_large() {
+    test_sort_stable(100_000, &apply_sort, None).await;
+}
+
+/// tests that equal keys keep their order when only the first half is sorted
+#[pollster::test]
+async fn sort_stable_half() {
+    test_sort_stable(100_000, &apply_sort, Some(50_000)).await;
+}
+
+/// tests that equal keys keep their order with indirect dispatch
+#[pollster::test]
+async fn sort_stable_indirect() {
+    test_sort_stable(100_000, &apply_sort_indirect, Some(50_000)).await;
+}
+
+/// tests that equal keys keep their order when recorded into a single compute pass
+#[pollster::test]
+async fn sort_stable_into_pass() {
+    test_sort_stable(100_000, &apply_sort_into_pass, None).await;
+    test_sort_stable(100_000, &apply_sort_indirect_into_pass, Some(50_000)).await;
+}
+
+// DEBUG LABELS
+
+/// tests that the names of the sorter and buffers are combined and that named sorting works
+#[pollster::test]
+async fn sort_named() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new_named(&device, subgroup_size, "particles");
+    assert_eq!(sorter.name(), Some("particles"));
+
+    let sort_buffers = sorter.create_named_sort_buffers::<f32, u32>(&device, NonZeroU32::new(100_000).unwrap(), "depth");
+    assert_eq!(sort_buffers.name(), Some("particles/depth"));
+    assert_eq!(sorter.create_sort_buffers(&device, NonZeroU32::new(1).unwrap()).name(), Some("particles"));
+
+    let keys: Vec<f32> = (0..sort_buffers.len()).map(|i| (i % 1000) as f32 - 500.).collect();
+    let values: Vec<u32> = (0..sort_buffers.len()).collect();
+    sort_buffers.write_keys(&queue, &keys);
+    sort_buffers.write_values(&queue, &values);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURSSorter sort_named"),
+    });
+    sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+    queue.submit([encoder.finish()]);
+
+    let keys_sorted = sort_buffers.read_keys(&device, &queue).await;
+    assert!(keys_sorted.windows(2).all(|w| w[0] <= w[1]));
+}
+
+/// tests that chunked and segmented sorters pass their name on to the sorter of the chunks and large segments
+#[pollster::test]
+async fn named_chunked_and_segmented() {
+    let (device, _queue) = setup().await;
+    let chunked = ChunkedSorter::new_named(&device, 32, "chunks");
+    assert_eq!(chunked.sorter().name(), Some("chunks"));
+    let segmented = SegmentedSorter::new_named(&device, 32, "segments");
+    assert_eq!(segmented.sorter().name(), Some("segments"));
+    assert_eq!(SegmentedSorter::new(&device, 32).sorter().name(), None);
+}
+
+// SUBGROUP SIZE DETECTION
+
+/// tests that every candidate subgroup size is tested and reported
+#[pollster::test]
+async fn guess_subgroup_size() {
+    let (device, queue) = setup().await;
+    let report = guess_workgroup_size(&device, &queue).await;
+
+    let sizes: Vec<u32> = report.results.iter().map(|result| result.subgroup_size).collect();
+    assert_eq!(sizes, SUBGROUP_SIZE_CANDIDATES);
+    assert!(report.results.iter().all(|result| result.trials > 0 && result.trials_per_case > 1));
+
+    let best = report.best().expect("no working subgroup size");
+    assert!(report.working_sizes().all(|size| size <= best));
+    for result in &report.results {
+        assert_eq!(result.works(), result.failures.is_empty());
+        for failure in &result.failures {
+            assert!(failure.timed_out_trials <= failure.failed_trials);
+        }
+    }
+}
+
+// AUTOTUNING
+
+/// tests saving and loading tuning profiles
+#[test]
+fn tuning_profile() {
+    let adapter = |device: u32| wgpu::AdapterInfo {
+        name: "test adapter".to_string(),
+        vendor: 4318,
+        device,
+        device_type: wgpu::DeviceType::DiscreteGpu,
+        driver: "driver = 1.2".to_string(),
+        driver_info: String::new(),
+        backend: wgpu::Backend::Vulkan,
+    };
+    let mut profile = TuningProfile::new();
+    profile.insert(&adapter(1), SorterConfig::new(32, 1024, 15, 8));
+    profile.insert(&adapter(2), SorterConfig::new(64, 0, 8, 4));
+    profile.insert(&adapter(1), SorterConfig::new(16, 512, 12, 8));
+
+    let path = std::env::temp_dir().join(format!("wgpu_sort_tuning_profile_{}.txt", std::process::id()));
+    profile.save(&path).unwrap();
+    let loaded = TuningProfile::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded, profile);
+    assert_eq!(loaded.get(&adapter(1)), Some(SorterConfig::new(16, 512, 12, 8)));
+    assert_eq!(loaded.get(&adapter(2)), Some(SorterConfig::new(64, 0, 8, 4)));
+    assert_eq!(loaded.get(&adapter(3)), None);
+
+    assert!("[adapter]\nvendor = 1\n".parse::<TuningProfile>().is_err());
+    let mut unknown_field = profile.to_string();
+    unknown_field.push_str("scatter_block_rows = 15\n");
+    assert!(unknown_field.parse::<TuningProfile>().is_err());
+    // block rows and digit widths the shaders do not support
+    for (field, value) in [("block_rows = 12", "block_rows = 16"), ("radix_log2 = 4", "radix_log2 = 16")] {
+        assert!(profile.to_string().replace(field, value).parse::<TuningProfile>().is_err());
+    }
+}
+
+/// tests that the tuned configuration consists of candidate values and sorts correctly
+#[pollster::test]
+async fn autotune_config() {
+    let (device, queue) = setup().await;
+    let config = autotune(&device, &queue).await.expect("no working subgroup size");
+    assert!(SUBGROUP_SIZE_CANDIDATES.contains(&config.subgroup_size));
+    assert!(SMALL_SORT_THRESHOLD_CANDIDATES.contains(&config.small_sort_threshold));
+    assert!(BLOCK_ROWS_CANDIDATES.contains(&config.block_rows));
+    assert!(RADIX_LOG2_CANDIDATES.contains(&config.radix_log2));
+
+    let sorter = GPUSorter::from_config(&device, &config);
+    assert_eq!(sorter.small_sort_threshold(), config.small_sort_threshold);
+    assert_eq!(sorter.block_rows(), config.block_rows);
+    assert_eq!(sorter.radix_log2(), config.radix_log2);
+    let mut rng = StdRng::seed_from_u64(0);
+    // sorted by the single workgroup if the threshold allows it and by the radix sort passes
+    for n in [LOCAL_SORT_KVS, 100_000] {
+        let keys: Vec<f32> = (0..n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let values: Vec<u32> = (0..n).collect();
+        let mut pairs: Vec<(f32, u32)> = keys.iter().copied().zip(values.iter().copied()).collect();
+        pairs.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+        let (keys_sorted, values_sorted) = sorter.sort_slices(&device, &queue, &keys, &values).await;
+        assert_eq!(keys_sorted, pairs.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+        assert_eq!(values_sorted, pairs.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+    }
+}
+
+/// tests direct, indirect and batched sorts and the compaction with other block rows and digit widths
+/// than the ones of GPUSorter::new
+#[pollster::test]
+async fn sort_configured() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    // several blocks of GPUSorter::new, so all dispatches depend on the block size
+    let n = 3 * HISTO_BLOCK_KVS + 17;
+    let mut rng = StdRng::seed_from_u64(0);
+    // duplicates in every digit of 4 and 8 bits
+    let keys: Vec<u32> = (0..n).map(|_| rng.gen::<u32>() & 0x3333_3333).collect();
+    let values: Vec<u32> = (0..n).collect();
+    let sorted = |keys: &[u32], values: &[u32]| {
+        let mut pairs: Vec<(u32, u32)> = keys.iter().copied().zip(values.iter().copied()).collect();
+        pairs.sort_by_key(|(k, _)| *k);
+        pairs.into_iter().unzip::<_, _, Vec<u32>, Vec<u32>>()
+    };
+
+    for (block_rows, radix_log2) in [(8, 4), (12, 8)] {
+        let sorter = GPUSorter::from_config(&device, &SorterConfig::new(subgroup_size, 0, block_rows, radix_log2));
+        assert_eq!(sorter.num_passes(), 32 / radix_log2);
+        assert_eq!(sorter.histo_block_kvs(), 256 * block_rows);
+        let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+
+        // the first half directly and all pairs indirectly
+        for (n_sorted, indirect) in [(n / 2, false), (n, true)] {
+            sort_buffers.write_keys(&queue, &keys);
+            sort_buffers.write_values(&queue, &values);
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("GPURSSorter sort_configured"),
+            });
+            if indirect {
+                queue.write_buffer(sort_buffers.state_buffer(), 0, bytes_of(&n_sorted));
+                let dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("dispatch indirect buffer"),
+                    contents: wgpu::util::DispatchIndirectArgs {
+                        x: n_sorted.div_ceil(sorter.histo_block_kvs()),
+                        y: 1,
+                        z: 1,
+                    }
+                    .as_bytes(),
+                    usage: wgpu::BufferUsages::INDIRECT,
+                });
+                sorter.sort_indirect(&mut encoder, &sort_buffers, &dispatch_buffer);
+            } else {
+                sorter.sort(&mut encoder, &queue, &sort_buffers, Some(n_sorted));
+            }
+            queue.submit([encoder.finish()]);
+
+            let n_sorted = n_sorted as usize;
+            let (keys_sorted, values_sorted) = sorted(&keys[..n_sorted], &values[..n_sorted]);
+            let keys_sorted_gpu = sort_buffers.read_keys(&device, &queue).await;
+            let values_sorted_gpu = sort_buffers.read_values(&device, &queue).await;
+            assert_eq!(keys_sorted_gpu[..n_sorted], keys_sorted, "{block_rows} block rows, {radix_log2} bit digits");
+            assert_eq!(values_sorted_gpu[..n_sorted], values_sorted, "{block_rows} block rows, {radix_log2} bit digits");
+        }
+
+        // the dispatch buffer of the compaction uses the block size of the sorter
+        sort_buffers.write_keys(&queue, &keys);
+        sort_buffers.write_values(&queue, &values);
+        let compactor = GPUCompactor::with_predicate(&device, "(key & 1u) == 0u");
+        let compact_buffers = compactor.create_compact_buffers(&device, &sorter, &sort_buffers, None);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("GPUCompactor sort_configured"),
+        });
+        compactor.compact(&mut encoder, &queue, &sort_buffers, &compact_buffers, None);
+        sorter.sort_indirect(&mut encoder, compact_buffers.output(), compact_buffers.dispatch_buffer());
+        queue.submit([encoder.finish()]);
+        let (kept_keys, kept_values): (Vec<u32>, Vec<u32>) =
+            keys.iter().copied().zip(values.iter().copied()).filter(|(k, _)| k % 2 == 0).unzip();
+        let (keys_sorted, values_sorted) = sorted(&kept_keys, &kept_values);
+        assert_eq!(compact_buffers.read_keys(&device, &queue).await, keys_sorted);
+        assert_eq!(compact_buffers.read_values(&device, &queue).await, values_sorted);
+
+        // three rows of different blocks
+        let (batch, row_len) = (3, n / 3);
+        let batched_buffers = sorter.create_batched_sort_buffers(
+            &device,
+            NonZeroU32::new(batch).unwrap(),
+            NonZeroU32::new(row_len).unwrap(),
+        );
+        let len = (batch * row_len) as usize;
+        batched_buffers.write_keys(&queue, &keys[..len]);
+        batched_buffers.write_values(&queue, &values[..len]);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("GPURSSorter sort_configured batched"),
+        });
+        sorter.sort_batched(&mut encoder, &queue, &batched_buffers, batch, row_len);
+        queue.submit([encoder.finish()]);
+        let keys_sorted_gpu = batched_buffers.read_keys(&device, &queue).await;
+        let values_sorted_gpu = batched_buffers.read_values(&device, &queue).await;
+        for row in 0..batch as usize {
+            let range = row * row_len as usize..(row + 1) * row_len as usize;
+            let (keys_sorted, values_sorted) = sorted(&keys[range.clone()], &values[range.clone()]);
+            assert_eq!(keys_sorted_gpu[range.clone()], keys_sorted);
+            assert_eq!(values_sorted_gpu[range], values_sorted);
+        }
+    }
+}
+
+// PROFILING
+
+/// tests that every phase of a profiled sort is timed. If timestamp queries are not supported,
+/// tests that profiling is rejected, no timings are reported and the sort still works
+#[pollster::test]
+async fn sort_profiled() {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = wgpu::util::initialize_adapter_from_env_or_default(&instance, None)
+        .await
+        .unwrap();
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                required_features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
+                required_limits: wgpu::Limits::default(),
+                label: None,
+            },
+            None,
+        )
+        .await
+        .unwrap();
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let mut sorter = GPUSorter::new(&device, subgroup_size);
+    assert_eq!(sorter.read_timings(&device, &queue).await, None);
+
+    let profiling = sorter.enable_profiling(&device);
+    assert_eq!(profiling, device.features().contains(wgpu::Features::TIMESTAMP_QUERY));
+
+    for (n, single_workgroup) in [(LOCAL_SORT_KVS, true), (100_000, false)] {
+        let keys: Vec<u32> = (0..n).rev().collect();
+        let (keys_sorted, values_sorted) = sorter.sort_slices(&device, &queue, &keys, &keys).await;
+        assert_eq!(keys_sorted, (0..n).collect::<Vec<_>>(), "keys sorted with profiling enabled: {profiling}");
+        assert_eq!(values_sorted, keys_sorted, "values sorted with profiling enabled: {profiling}");
+
+        let timings = sorter.read_timings(&device, &queue).await;
+        if !profiling {
+            assert_eq!(timings, None, "no timings without timestamp queries");
+            continue;
+        }
+        let timings = timings.unwrap();
+        assert_eq!(timings.single_workgroup.is_zero(), !single_workgroup);
+        assert_eq!(timings.scatter[..sorter.num_passes() as usize].iter().all(|t| t.is_zero()), single_workgroup);
+        assert!(!timings.total().is_zero());
+    }
+}
+
+// PREFIX SCAN
+
+/// tests all scan operations and modes against a scan on the cpu, with direct and indirect dispatch,
+/// less workgroups than blocks and a lookback limit
+#[pollster::test]
+async fn prefix_scan() {
+    let (device, queue) = setup().await;
+    let scanner = GPUScanner::new(&device);
+
+    let n: u32 = 100_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let input: Vec<u32> = (0..n).map(|_| rng.gen()).collect();
+    let input_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("scan input"),
+        contents: bytemuck::cast_slice(&input),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("scan output"),
+        size: n as u64 * 4,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+
+    // the last values are not scanned
+    let scan_first_n = n - 1000;
+    let dispatch_buffer = |workgroups: u32| {
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("scan dispatch buffer"),
+            contents: wgpu::util::DispatchIndirectArgs {
+                x: workgroups,
+                y: 1,
+                z: 1,
+            }
+            .as_bytes(),
+            usage: wgpu::BufferUsages::INDIRECT,
+        })
+    };
+    let one_per_block = dispatch_buffer(scan_first_n.div_ceil(SCAN_BLOCK_KVS));
+    // every workgroup scans several blocks one after another
+    let less_than_blocks = dispatch_buffer(3);
+
+    for (op, identity, combine) in [
+        (ScanOp::Add, 0, u32::wrapping_add as fn(u32, u32) -> u32),
+        (ScanOp::Max, 0, u32::max),
+        (ScanOp::Min, u32::MAX, u32::min),
+    ] {
+        for mode in [ScanMode::Exclusive, ScanMode::Inclusive] {
+            let expected: Vec<u32> = input[..scan_first_n as usize]
+                .iter()
+                .scan(identity, |acc, &v| {
+                    let exclusive = *acc;
+                    *acc = combine(*acc, v);
+                    Some(if mode == ScanMode::Inclusive { *acc } else { exclusive })
+                })
+                .collect();
+            let scan_buffers =
+                scanner.create_scan_buffers(&device, &input_buffer, &output_buffer, NonZeroU32::new(n).unwrap(), op, mode);
+            // a limit that is never reached must not change the result
+            scan_buffers.set_lookback_limit(&queue, NonZeroU32::new(1 << 24));
+
+            for (dispatch, indirect) in [
+                ("direct", None),
+                ("indirect", Some(&one_per_block)),
+                ("indirect with 3 workgroups", Some(&less_than_blocks)),
+            ] {
+                let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("GPUScanner scan"),
+                });
+                if let Some(dispatch_buffer) = indirect {
+                    queue.write_buffer(scan_buffers.state_buffer(), 0, bytes_of(&scan_first_n));
+                    scanner.scan_indirect(&mut encoder, &scan_buffers, dispatch_buffer);
+                } else {
+                    scanner.scan(&mut encoder, &queue, &scan_buffers, Some(scan_first_n));
+                }
+                queue.submit([encoder.finish()]);
+
+                let output: Vec<u32> = download_buffer(&output_buffer, &device, &queue, ..).await;
+                assert_eq!(output[..scan_first_n as usize], expected, "{op:?} {mode:?} {dispatch}");
+                assert!(!scan_buffers.lookback_timed_out(&device, &queue).await);
+            }
+        }
+    }
+}
+
+// STREAM COMPACTION
+
+/// tests compacting with a predicate followed by an indirect sort of the kept pairs, and compacting with flags
+#[pollster::test]
+async fn compact() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+
+    let n = 100_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    // every third key is marked as invalid
+    let keys: Vec<u32> = (0..n)
+        .map(|i| if i % 3 == 0 { u32::MAX } else { rng.gen_range(0..1 << 20) })
+        .collect();
+    let values: Vec<u32> = (0..n).collect();
+    let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &keys);
+    sort_buffers.write_values(&queue, &values);
+
+    let compactor = GPUCompactor::with_predicate(&device, "key != 0xFFFFFFFFu");
+    let compact_buffers = compactor.create_compact_buffers(&device, &sorter, &sort_buffers, None);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPUCompactor compact"),
+    });
+    compactor.compact(&mut encoder, &queue, &sort_buffers, &compact_buffers, None);
+    sorter.sort_indirect(&mut encoder, compact_buffers.output(), compact_buffers.dispatch_buffer());
+    queue.submit([encoder.finish()]);
+
+    let mut expected: Vec<(u32, u32)> = keys.iter().copied().zip(values.iter().copied()).filter(|(k, _)| *k != u32::MAX).collect();
+    expected.sort_by_key(|(k, _)| *k);
+    assert_eq!(compact_buffers.read_count(&device, &queue).await, expected.len() as u32);
+    assert_eq!(compact_buffers.read_keys(&device, &queue).await, expected.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+    assert_eq!(compact_buffers.read_values(&device, &queue).await, expected.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+
+    // flags select the pairs with odd values, the kept pairs stay in input order
+    let flags: Vec<u32> = values.iter().map(|v| v % 2).collect();
+    let flags_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("compact flags"),
+        contents: bytemuck::cast_slice(&flags),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let compactor = GPUCompactor::new(&device);
+    let compact_buffers = compactor.create_compact_buffers(&device, &sorter, &sort_buffers, Some(&flags_buffer));
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPUCompactor compact flags"),
+    });
+    compactor.compact(&mut encoder, &queue, &sort_buffers, &compact_buffers, None);
+    queue.submit([encoder.finish()]);
+    let expected: Vec<u32> = values.iter().copied().filter(|v| v % 2 == 1).collect();
+    assert_eq!(compact_buffers.read_values(&device, &queue).await, expected);
+}
+
+// REDUCE BY KEY
+
+/// tests finding the runs of sorted keys and summing up their values, directly and indirectly
+#[pollster::test]
+async fn reduce_by_key() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+    let reducer = GPUReducer::new(&device);
+
+    let n: u32 = 200_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut keys: Vec<u32> = (0..n).map(|_| rng.gen_range(0..50_000)).collect();
+    keys.sort();
+    let values: Vec<u32> = (0..n).map(|_| rng.gen_range(0..1000)).collect();
+
+    // expected runs of the first `first_n` pairs
+    let expected_runs = |first_n: usize| {
+        let mut runs: Vec<(u32, Run, u32)> = Vec::new();
+        for (i, (&k, &v)) in keys[..first_n].iter().zip(values.iter()).enumerate() {
+            match runs.last_mut() {
+                Some((key, run, sum)) if *key == k => {
+                    run.count += 1;
+                    *sum += v;
+                }
+                _ => runs.push((k, Run { start: i as u32, count: 1 }, v)),
+            }
+        }
+        runs
+    };
+
+    let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &keys);
+    sort_buffers.write_values(&queue, &values);
+    let run_buffers = reducer.create_reduce_buffers(&device, &sort_buffers);
+
+    let first_n = n - 1234;
+    let dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("reduce dispatch buffer"),
+        contents: wgpu::util::DispatchIndirectArgs {
+            x: first_n.div_ceil(HISTO_BLOCK_KVS),
+            y: 1,
+            z: 1,
+        }
+        .as_bytes(),
+        usage: wgpu::BufferUsages::INDIRECT,
+    });
+    for indirect in [false, true] {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("GPUReducer reduce_by_key"),
+        });
+        if indirect {
+            queue.write_buffer(sort_buffers.state_buffer(), 0, bytes_of(&first_n));
+            reducer.reduce_by_key_indirect(&mut encoder, &run_buffers, &dispatch_buffer);
+        } else {
+            reducer.reduce_by_key(&mut encoder, &queue, &sort_buffers, &run_buffers, None);
+        }
+        queue.submit([encoder.finish()]);
+
+        let expected = expected_runs(if indirect { first_n } else { n } as usize);
+        assert_eq!(run_buffers.read_count(&device, &queue).await, expected.len() as u32, "indirect {indirect}");
+        assert_eq!(
+            run_buffers.read_unique_keys(&device, &queue).await,
+            expected.iter().map(|(k, _, _)| *k).collect::<Vec<_>>(),
+            "indirect {indirect}"
+        );
+        assert_eq!(
+            run_buffers.read_runs(&device, &queue).await,
+            expected.iter().map(|(_, r, _)| *r).collect::<Vec<_>>(),
+            "indirect {indirect}"
+        );
+        assert_eq!(
+            run_buffers.read_sums(&device, &queue).await,
+            expected.iter().map(|(_, _, s)| *s).collect::<Vec<_>>(),
+            "indirect {indirect}"
+        );
+    }
+
+    // run-length encoding of float keys with float sums
+    let float_keys: Vec<f32> = keys.iter().map(|k| *k as f32 * 0.5).collect();
+    let float_values: Vec<f32> = values.iter().map(|v| *v as f32 * 0.25).collect();
+    let sort_buffers = sorter.create_typed_sort_buffers::<f32, f32>(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &float_keys);
+    sort_buffers.write_values(&queue, &float_values);
+    let run_buffers = reducer.create_reduce_buffers(&device, &sort_buffers);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPUReducer reduce_by_key f32"),
+    });
+    reducer.reduce_by_key(&mut encoder, &queue, &sort_buffers, &run_buffers, None);
+    queue.submit([encoder.finish()]);
+
+    let expected = expected_runs(n as usize);
+    let unique_keys = run_buffers.read_unique_keys(&device, &queue).await;
+    let sums = run_buffers.read_sums(&device, &queue).await;
+    assert_eq!(unique_keys, expected.iter().map(|(k, _, _)| *k as f32 * 0.5).collect::<Vec<_>>());
+    for ((_, _, expected_sum), sum) in expected.iter().zip(sums) {
+        // values are multiples of 0.25 and small enough to be summed up exactly
+        assert_eq!(sum, *expected_sum as f32 * 0.25);
+    }
+}
+
+// KEY RANGES
+
+/// tests finding the range of every tile in sorted (tile << 32) | depth keys, with empty tiles and skipped ids
+#[pollster::test]
+async fn key_ranges() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+    let range_finder = GPURangeFinder::new(&device);
+
+    let n: u32 = 100_000;
+    let num_tiles: u32 = 1000;
+    let mut rng = StdRng::seed_from_u64(0);
+    // every tile id is even and ids past the key space are skipped
+    let mut keys: Vec<u64> = (0..n)
+        .map(|_| ((rng.gen_range(0..num_tiles / 2 + 50) * 2) as u64) << 32 | rng.gen::<u32>() as u64)
+        .collect();
+    keys.sort();
+    let mut expected = vec![[0u32; 2]; num_tiles as usize];
+    for (i, key) in keys.iter().enumerate() {
+        let tile = (key >> 32) as usize;
+        if tile < num_tiles as usize {
+            if i == 0 || keys[i - 1] >> 32 != key >> 32 {
+                expected[tile][0] = i as u32;
+            }
+            expected[tile][1] = i as u32 + 1;
+        }
+    }
+
+    let sort_buffers = sorter.create_typed_sort_buffers::<u64, u32>(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &keys);
+    let range_buffers = range_finder.create_range_buffers(&device, &sort_buffers, NonZeroU32::new(num_tiles).unwrap(), 32);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURangeFinder find_ranges"),
+    });
+    range_finder.find_ranges(&mut encoder, &queue, &sort_buffers, &range_buffers, None);
+    queue.submit([encoder.finish()]);
+    assert_eq!(range_buffers.read_ranges(&device, &queue).await, expected);
+
+    // u32 cell ids in the upper bits, the ranges of the previous run must be cleared
+    let first_n = n / 2;
+    let cells: Vec<u32> = keys[..first_n as usize].iter().map(|k| ((k >> 32) as u32) << 8 | (*k as u32 & 0xFF)).collect();
+    let mut expected = vec![[0u32; 2]; num_tiles as usize];
+    for (i, cell) in cells.iter().enumerate() {
+        let id = (cell >> 8) as usize;
+        if id < num_tiles as usize {
+            if i == 0 || cells[i - 1] >> 8 != cell >> 8 {
+                expected[id][0] = i as u32;
+            }
+            expected[id][1] = i as u32 + 1;
+        }
+    }
+    let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &cells);
+    let range_buffers = range_finder.create_range_buffers(&device, &sort_buffers, NonZeroU32::new(num_tiles).unwrap(), 8);
+    let dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("ranges dispatch buffer"),
+        contents: wgpu::util::DispatchIndirectArgs {
+            x: first_n.div_ceil(HISTO_BLOCK_KVS),
+            y: 1,
+            z: 1,
+        }
+        .as_bytes(),
+        usage: wgpu::BufferUsages::INDIRECT,
+    });
+    queue.write_buffer(range_buffers.ranges_buffer(), 0, &vec![0xFF; num_tiles as usize * 8]);
+    queue.write_buffer(sort_buffers.state_buffer(), 0, bytes_of(&first_n));
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURangeFinder find_ranges_indirect"),
+    });
+    range_finder.find_ranges_indirect(&mut encoder, &range_buffers, &dispatch_buffer);
+    queue.submit([encoder.finish()]);
+    assert_eq!(range_buffers.read_ranges(&device, &queue).await, expected);
+}
+
+/// tests that u64 keys whose id needs more than 32 bits are skipped instead of truncated
+#[pollster::test]
+async fn key_ranges_u64_small_shift() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+    let range_finder = GPURangeFinder::new(&device);
+
+    let n: u32 = 10_000;
+    let num_ids: u32 = 100;
+    let id_shift = 8;
+    let mut rng = StdRng::seed_from_u64(0);
+    // the lower 32 bits of the ids of the keys with high bits set are valid ids as well
+    let mut keys: Vec<u64> = (0..n)
+        .map(|_| (rng.gen_range(0..3u64) << 32) | (rng.gen_range(0..num_ids + 10) as u64) << id_shift | rng.gen_range(0..256u64))
+        .collect();
+    keys.sort();
+    let mut expected = vec![[0u32; 2]; num_ids as usize];
+    for (i, key) in keys.iter().enumerate() {
+        let id = key >> id_shift;
+        if id < num_ids as u64 {
+            if i == 0 || keys[i - 1] >> id_shift != id {
+                expected[id as usize][0] = i as u32;
+            }
+            expected[id as usize][1] = i as u32 + 1;
+        }
+    }
+
+    let sort_buffers = sorter.create_typed_sort_buffers::<u64, u32>(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &keys);
+    let range_buffers =
+        range_finder.create_range_buffers(&device, &sort_buffers, NonZeroU32::new(num_ids).unwrap(), id_shift);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURangeFinder find_ranges"),
+    });
+    range_finder.find_ranges(&mut encoder, &queue, &sort_buffers, &range_buffers, None);
+    queue.submit([encoder.finish()]);
+    assert_eq!(range_buffers.read_ranges(&device, &queue).await, expected);
+}
+
+// SPATIAL KEYS
+
+/// tests morton keys against the interleaved cell coordinates and the neighbourhood of cells along the hilbert curve
+#[pollster::test]
+async fn spatial_keys() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+    let spatial_encoder = GPUSpatialEncoder::new(&device);
+
+    // one point in every block of an 8x8x8 grid, stored as vec4 with the block size 128 of the 1024 cells per axis
+    let blocks: Vec<[u32; 3]> = (0..512).map(|i| [i % 8, i / 8 % 8, i / 64]).collect();
+    let mut rng = StdRng::seed_from_u64(0);
+    let positions: Vec<[f32; 4]> = blocks
+        .iter()
+        .map(|b| b.map(|c| (c * 128 + rng.gen_range(0..128)) as f32 + 0.5))
+        .map(|[x, y, z]| [x, y, z, 1.0])
+        .collect();
+    let positions_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("positions"),
+        contents: bytemuck::cast_slice(&positions),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let bounds = Aabb {
+        min: [0.0; 3],
+        max: [1024.0; 3],
+    };
+    let n = positions.len() as u32;
+    let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+
+    let generate = |bounds: Option<Aabb>, curve: SpaceFillingCurve| {
+        let spatial_buffers =
+            spatial_encoder.create_spatial_buffers(&device, &sort_buffers, &positions_buffer, PositionLayout::VEC4, bounds, curve);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("GPUSpatialEncoder generate_keys"),
+        });
+        spatial_encoder.generate_keys(&mut encoder, &queue, &sort_buffers, &spatial_buffers, None);
+        queue.submit([encoder.finish()]);
+        spatial_buffers
+    };
+
+    generate(Some(bounds), SpaceFillingCurve::Morton);
+    let keys: Vec<u32> = download_buffer(sort_buffers.keys(), &device, &queue, ..).await;
+    let values: Vec<u32> = download_buffer(sort_buffers.values(), &device, &queue, ..).await;
+    let expected: Vec<u32> = positions
+        .iter()
+        .map(|p| {
+            let cell = [p[0] as u32, p[1] as u32, p[2] as u32];
+            (0..30).fold(0, |key, bit| key | ((cell[2 - bit % 3] >> (bit / 3)) & 1) << bit)
+        })
+        .collect();
+    assert_eq!(keys[..n as usize], expected);
+    assert_eq!(values[..n as usize], (0..n).collect::<Vec<_>>());
+
+    // the computed bounding box encloses the points, sorting by the hilbert keys visits neighbouring blocks one after another
+    let spatial_buffers = generate(None, SpaceFillingCurve::Hilbert);
+    let computed = spatial_buffers.read_bounds(&device, &queue).await;
+    for axis in 0..3 {
+        assert_eq!(computed.min[axis], positions.iter().map(|p| p[axis]).fold(f32::MAX, f32::min));
+        assert_eq!(computed.max[axis], positions.iter().map(|p| p[axis]).fold(f32::MIN, f32::max));
+    }
+    generate(Some(bounds), SpaceFillingCurve::Hilbert);
+    let keys: Vec<u32> = download_buffer(sort_buffers.keys(), &device, &queue, ..).await;
+    let mut order: Vec<usize> = (0..n as usize).collect();
+    order.sort_by_key(|&i| keys[i]);
+    for pair in order.windows(2) {
+        let distance: u32 = (0..3).map(|axis| blocks[pair[0]][axis].abs_diff(blocks[pair[1]][axis])).sum();
+        assert_eq!(distance, 1, "blocks {:?} and {:?} are not neighbours", blocks[pair[0]], blocks[pair[1]]);
+    }
+}
+
+// DEPTH KEYS
+
+/// tests culling points outside of the frustum and sorting the visible points back to front with sort_indirect.
+/// Points with equal depth have to keep their index order
+#[pollster::test]
+async fn depth_keys() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+    let depth_encoder = GPUDepthEncoder::new(&device);
+
+    // points in front of the camera in groups of four with equal depth,
+    // about half of them are outside of the 90 degree frustum
+    let n: u32 = 10_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let positions: Vec<[f32; 3]> = (0..n)
+        .map(|i| {
+            let depth = 2.0 + (i / 4) as f32 * 0.02;
+            [rng.gen_range(-2.0 * depth..2.0 * depth), 0.0, -depth]
+        })
+        .collect();
+    let positions_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("positions"),
+        contents: bytemuck::cast_slice(&positions),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let identity = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+    let camera = DepthCamera {
+        view: identity,
+        // perspective projection with w = -z, the frustum contains the points with |x| <= depth
+        proj: [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, -1.0, -1.0], [0.0, 0.0, -0.1, 0.0]],
+        near: 1.0,
+        far: 50.0,
+    };
+    let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+    let depth_buffers =
+        depth_encoder.create_depth_buffers(&device, &sort_buffers, &positions_buffer, PositionLayout::VEC3, DepthOrder::BackToFront, 0.0);
+
+    let visible: Vec<u32> = (0..n)
+        .filter(|&i| {
+            let [x, _, z] = positions[i as usize];
+            -z <= camera.far && x.abs() <= -z
+        })
+        .collect();
+
+    // the visible points are written in index order
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPUDepthEncoder generate_depth_keys"),
+    });
+    depth_encoder.generate_depth_keys(&mut encoder, &queue, &depth_buffers, &camera, None);
+    queue.submit([encoder.finish()]);
+    let count = depth_buffers.read_visible_count(&device, &queue).await;
+    assert_eq!(count, visible.len() as u32);
+    let state: Vec<u32> = download_buffer(sort_buffers.state_buffer(), &device, &queue, ..4).await;
+    assert_eq!(state[0], count);
+    let values = sort_buffers.read_values(&device, &queue).await;
+    assert_eq!(values[..count as usize], visible);
+
+    // the depth grows with the group, back to front is descending group order with ascending indices in a group
+    let mut expected = visible.clone();
+    expected.sort_by_key(|&i| std::cmp::Reverse(i / 4));
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPUDepthEncoder generate_depth_keys sort"),
+    });
+    depth_encoder.generate_depth_keys(&mut encoder, &queue, &depth_buffers, &camera, None);
+    sorter.sort_indirect(&mut encoder, &sort_buffers, depth_buffers.dispatch_buffer());
+    queue.submit([encoder.finish()]);
+    let values = sort_buffers.read_values(&device, &queue).await;
+    assert_eq!(values[..count as usize], expected);
+}
+
+// PERMUTATION
+
+/// tests gathering 52 byte elements with a permutation, scattering them back and gathering the first n indirectly
+#[pollster::test]
+async fn permute() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+    let permuter = GPUPermuter::new(&device);
+
+    let n: u32 = 50_000;
+    let words = 13;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut indices: Vec<u32> = (0..n).collect();
+    for i in (1..n as usize).rev() {
+        indices.swap(i, rng.gen_range(0..=i));
+    }
+    let source: Vec<u32> = (0..n * words).map(|_| rng.gen()).collect();
+    let sort_buffers = sorter.create_typed_sort_buffers::<f32, u32>(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_values(&queue, &indices);
+
+    let buffer = |label: &str, contents: &[u32]| {
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents: bytemuck::cast_slice(contents),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        })
+    };
+    let source_buffer = buffer("source", &source);
+    let gathered_buffer = buffer("gathered", &vec![0; source.len()]);
+    let scattered_buffer = buffer("scattered", &vec![0; source.len()]);
+    let gather = permuter.create_permute_buffers(&device, &sort_buffers, &source_buffer, &gathered_buffer, words * 4);
+    let scatter = permuter.create_permute_buffers(&device, &sort_buffers, &gathered_buffer, &scattered_buffer, words * 4);
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPUPermuter gather scatter"),
+    });
+    permuter.gather(&mut encoder, &queue, &sort_buffers, &gather, None);
+    permuter.scatter(&mut encoder, &queue, &sort_buffers, &scatter, None);
+    queue.submit([encoder.finish()]);
+
+    let element = |data: &[u32], i: u32| data[(i * words) as usize..((i + 1) * words) as usize].to_vec();
+    let expected: Vec<u32> = indices.iter().flat_map(|&i| element(&source, i)).collect();
+    let gathered: Vec<u32> = download_buffer(&gathered_buffer, &device, &queue, ..).await;
+    assert_eq!(gathered, expected);
+    let scattered: Vec<u32> = download_buffer(&scattered_buffer, &device, &queue, ..).await;
+    assert_eq!(scattered, source);
+
+    // only the first n elements are gathered, the rest of the destination is untouched
+    let first_n = n / 3;
+    let dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("permute dispatch buffer"),
+        contents: wgpu::util::DispatchIndirectArgs {
+            x: first_n.div_ceil(HISTO_BLOCK_KVS),
+            y: 1,
+            z: 1,
+        }
+        .as_bytes(),
+        usage: wgpu::BufferUsages::INDIRECT,
+    });
+    queue.write_buffer(&gathered_buffer, 0, bytemuck::cast_slice(&vec![0u32; source.len()]));
+    queue.write_buffer(sort_buffers.state_buffer(), 0, bytes_of(&first_n));
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPUPermuter gather_indirect"),
+    });
+    permuter.gather_indirect(&mut encoder, &gather, &dispatch_buffer);
+    queue.submit([encoder.finish()]);
+    let gathered: Vec<u32> = download_buffer(&gathered_buffer, &device, &queue, ..).await;
+    let split = (first_n * words) as usize;
+    assert_eq!(gathered[..split], expected[..split]);
+    assert!(gathered[split..].iter().all(|&w| w == 0));
+}
+
+/// tests sorting and gathering the first k elements recorded into the same encoder,
+/// the gather must not change the number of keys of the sort
+#[pollster::test]
+async fn sort_and_permute_first_n() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+    let permuter = GPUPermuter::new(&device);
+
+    let n: u32 = 50_000;
+    let k: u32 = 1000;
+    let words = 3;
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys: Vec<f32> = (0..n).map(|_| rng.gen_range(-1.0..1.0)).collect();
+    let indices: Vec<u32> = (0..n).collect();
+    let source: Vec<u32> = (0..n * words).map(|_| rng.gen()).collect();
+    let sort_buffers = sorter.create_typed_sort_buffers::<f32, u32>(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &keys);
+    sort_buffers.write_values(&queue, &indices);
+
+    let buffer = |label: &str, contents: &[u32]| {
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents: bytemuck::cast_slice(contents),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        })
+    };
+    let source_buffer = buffer("source", &source);
+    let gathered_buffer = buffer("gathered", &vec![0; source.len()]);
+    let gather = permuter.create_permute_buffers(&device, &sort_buffers, &source_buffer, &gathered_buffer, words * 4);
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPUPermuter sort and gather"),
+    });
+    sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+    permuter.gather(&mut encoder, &queue, &sort_buffers, &gather, Some(k));
+    queue.submit([encoder.finish()]);
+
+    let mut pairs: Vec<(f32, u32)> = keys.iter().copied().zip(indices.iter().copied()).collect();
+    pairs.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    let values_sorted = sort_buffers.read_values(&device, &queue).await;
+    assert_eq!(values_sorted, pairs.iter().map(|(_, v)| *v).collect::<Vec<_>>(), "all keys are sorted");
+
+    let expected: Vec<u32> = pairs[..k as usize]
+        .iter()
+        .flat_map(|&(_, i)| source[(i * words) as usize..((i + 1) * words) as usize].to_vec())
+        .collect();
+    let gathered: Vec<u32> = download_buffer(&gathered_buffer, &device, &queue, ..).await;
+    let split = (k * words) as usize;
+    assert_eq!(gathered[..split], expected[..]);
+    assert!(gathered[split..].iter().all(|&w| w == 0));
+}
+
+// TOP K SELECTION
+
+/// tests selecting the smallest and largest pairs, unsorted in input order and sorted
+#[pollster::test]
+async fn select_top_k() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+
+    let n = 100_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    // few different keys, so that many pairs are equal to the k-th key
+    let keys: Vec<f32> = (0..n).map(|_| rng.gen_range(-50..50) as f32 * 0.5).collect();
+    let values: Vec<u32> = (0..n).collect();
+    let sort_buffers = sorter.create_typed_sort_buffers::<f32, u32>(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &keys);
+    sort_buffers.write_values(&queue, &values);
+
+    let k = 1000;
+    for order in [SelectOrder::Smallest, SelectOrder::Largest] {
+        let top_k = sorter.create_top_k_buffers(&device, &sort_buffers, NonZeroU32::new(k).unwrap(), order);
+        let mut expected: Vec<(f32, u32)> = keys.iter().copied().zip(values.iter().copied()).collect();
+        expected.sort_by(|(a, _), (b, _)| match order {
+            SelectOrder::Smallest => a.total_cmp(b),
+            SelectOrder::Largest => b.total_cmp(a),
+        });
+        expected.truncate(k as usize);
+
+        for sorted in [false, true] {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("GPURSSorter select_top_k"),
+            });
+            sorter.select_top_k(&mut encoder, &queue, &sort_buffers, &top_k, None, sorted);
+            queue.submit([encoder.finish()]);
+
+            if sorted {
+                expected.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+            } else {
+                expected.sort_by_key(|(_, v)| *v);
+            }
+            let keys_selected = top_k.read_keys(&device, &queue).await;
+            let values_selected = top_k.read_values(&device, &queue).await;
+            assert_eq!(keys_selected, expected.iter().map(|(k, _)| *k).collect::<Vec<_>>(), "{order:?} sorted {sorted}");
+            assert_eq!(values_selected, expected.iter().map(|(_, v)| *v).collect::<Vec<_>>(), "{order:?} sorted {sorted}");
+        }
+    }
+}
+
+/// tests the median, percentiles and n-th keys against the sorted keys
+#[pollster::test]
+async fn order_statistics() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+
+    let n = 100_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys: Vec<i32> = (0..n).map(|_| rng.gen_range(-1000..1000)).collect();
+    let sort_buffers = sorter.create_typed_sort_buffers::<i32, u32>(&device, NonZeroU32::new(n).unwrap());
+    sort_buffers.write_keys(&queue, &keys);
+    let statistics = sorter.create_order_statistics_buffers(&device, &sort_buffers, NonZeroU32::new(5).unwrap());
+
+    for first_n in [None, Some(777)] {
+        let num_keys = first_n.unwrap_or(n) as usize;
+        let mut sorted = keys[..num_keys].to_vec();
+        sorted.sort();
+
+        let percentiles = [0., 0.25, 0.5, 0.9, 1.];
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("GPURSSorter percentiles"),
+        });
+        sorter.percentiles(&mut encoder, &queue, &sort_buffers, &statistics, &percentiles, first_n);
+        queue.submit([encoder.finish()]);
+        let expected: Vec<i32> = percentiles
+            .iter()
+            .map(|p| sorted[(p * (num_keys - 1) as f32).round() as usize])
+            .collect();
+        assert_eq!(statistics.read_results(&device, &queue).await, expected, "first_n {first_n:?}");
+
+        for nth in [0, 123, num_keys as u32 - 1] {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("GPURSSorter nth_element"),
+            });
+            sorter.nth_element(&mut encoder, &queue, &sort_buffers, &statistics, nth, first_n);
+            queue.submit([encoder.finish()]);
+            let result = statistics.read_results(&device, &queue).await[0];
+            assert_eq!(result, sorted[nth as usize], "first_n {first_n:?} nth {nth}");
+        }
+    }
+}
+
+// BOUNDED LOOKBACK
+
+/// tests that a sort with a bounded lookback succeeds without setting the error bit
+#[pollster::test]
+async fn sort_bounded_lookback() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+
+    let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(100_000).unwrap());
+    sort_buffers.set_lookback_limit(&queue, NonZeroU32::new(1 << 20));
+    let keys: Vec<u32> = (0..sort_buffers.len()).rev().collect();
+    sort_buffers.write_keys(&queue, &keys);
+    sort_buffers.write_values(&queue, &keys);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURSSorter sort_bounded_lookback"),
+    });
+    sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+    queue.submit([encoder.finish()]);
+
+    let keys_sorted = sort_buffers.read_keys(&device, &queue).await;
+    assert!(keys_sorted.windows(2).all(|w| w[0] <= w[1]));
+    assert!(!sort_buffers.lookback_timed_out(&device, &queue).await);
+    assert_eq!(sort_buffers.read_errors(&device, &queue).await, 0);
+}
+
+// GPU VERIFICATION
+
+/// tests that correct sorts pass the gpu verification and corrupted ones are detected,
+/// including values that were swapped without their keys
+#[pollster::test]
+async fn sort_verified() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+    let verifier = GPUSortVerifier::new(&device);
+
+    for n in [LOCAL_SORT_KVS, 100_000] {
+        let sort_buffers = sorter.create_typed_sort_buffers::<f32, u32>(&device, NonZeroU32::new(n).unwrap());
+        let verify_buffers = verifier.create_verify_buffers(&device, &sorter, &sort_buffers);
+        let swap_buffers: Vec<wgpu::Buffer> = (0..2)
+            .map(|_| {
+                device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("GPURSSorter sort_verified swap"),
+                    size: 4,
+                    usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+        for (corrupt, swap) in [(false, false), (true, false), (false, true)] {
+            let keys: Vec<f32> = (0..n).map(|i| (i % 1000) as f32 - 500.).collect();
+            let values: Vec<u32> = (0..n).collect();
+            sort_buffers.write_keys(&queue, &keys);
+            sort_buffers.write_values(&queue, &values);
+
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("GPURSSorter sort_verified"),
+            });
+            verifier.record_checksum(&mut encoder, &verify_buffers);
+            sorter.sort(&mut encoder, &queue, &sort_buffers, None);
+            if corrupt {
+                // overwrites the largest key and a value with zero
+                encoder.clear_buffer(sort_buffers.keys(), sort_buffers.keys_valid_size() - 4, None);
+                encoder.clear_buffer(sort_buffers.values(), 0, Some(4));
+            }
+            if swap {
+                // swaps the first and the last value, keys and values stay permutations of the input
+                let last = (n as u64 - 1) * 4;
+                encoder.copy_buffer_to_buffer(sort_buffers.values(), 0, &swap_buffers[0], 0, 4);
+                encoder.copy_buffer_to_buffer(sort_buffers.values(), last, &swap_buffers[1], 0, 4);
+                encoder.copy_buffer_to_buffer(&swap_buffers[1], 0, sort_buffers.values(), 0, 4);
+                encoder.copy_buffer_to_buffer(&swap_buffers[0], 0, sort_buffers.values(), last, 4);
+            }
+            verifier.verify(&mut encoder, &verify_buffers);
+            queue.submit([encoder.finish()]);
+
+            let report = verify_buffers.read_report(&device, &queue).await;
+            assert_eq!(report.is_valid(), !corrupt && !swap, "{n} keys: {report:?}");
+            assert_eq!(report.is_sorted(), !corrupt);
+            assert_eq!(report.checksum_matches(), !corrupt && !swap);
+            if swap {
+                assert_eq!(report.checksum_before[..4], report.checksum_after[..4], "keys and values are unchanged");
+            }
+            // the radix sort pads the keys, zeroing the end of the keys buffer corrupts the padding
+            assert_eq!(report.padding_intact(), !corrupt || n <= sorter.small_sort_threshold());
+        }
+    }
+}
+
+// BATCHED SORTING
+
+/// tests sorting 100 rows of 5000 pairs independently
+#[pollster::test]
+async fn sort_batched() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+
+    let (batch, row_len) = (100, 5000);
+    let sort_buffers = sorter.create_batched_sort_buffers(
+        &device,
+        NonZeroU32::new(batch).unwrap(),
+        NonZeroU32::new(row_len).unwrap(),
+    );
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys_scrambled: Vec<u32> = (0..batch * row_len).map(|_| rng.gen()).collect();
+    let values_scrambled: Vec<u32> = (0..batch * row_len).collect();
+    let mut keys_sorted = Vec::with_capacity(keys_scrambled.len());
+    let mut values_sorted = Vec::with_capacity(values_scrambled.len());
+    for (keys, values) in keys_scrambled
+        .chunks(row_len as usize)
+        .zip(values_scrambled.chunks(row_len as usize))
+    {
+        let mut pairs: Vec<(u32, u32)> = keys.iter().copied().zip(values.iter().copied()).collect();
+        pairs.sort_by_key(|(k, _)| *k);
+        keys_sorted.extend(pairs.iter().map(|(k, _)| *k));
+        values_sorted.extend(pairs.iter().map(|(_, v)| *v));
+    }
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURSSorter test_sort_batched"),
+    });
+    upload_to_buffer(&mut encoder, sort_buffers.keys(), &device, keys_scrambled.as_slice());
+    upload_to_buffer(&mut encoder, sort_buffers.values(), &device, values_scrambled.as_slice());
+    sorter.sort_batched(&mut encoder, &queue, &sort_buffers, batch, row_len);
+    let idx = queue.submit([encoder.finish()]);
+    device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+
+    let keys_sorted_gpu = download_buffer::<u32>(
+        sort_buffers.keys(),
+        &device,
+        &queue,
+        0..sort_buffers.keys_valid_size(),
+    )
+    .await;
+    assert_eq!(keys_sorted_gpu, keys_sorted, "GPU keys equal to keys sorted on CPU");
+
+    let values_sorted_gpu = download_buffer::<u32>(sort_buffers.values(), &device, &queue, ..).await;
+    assert_eq!(values_sorted_gpu, values_sorted, "GPU values equal to values sorted on CPU");
+}
+
+// CHUNKED SORTING
+
+/// tests sorting 100k pairs in chunks of 30k pairs
+#[pollster::test]
+async fn sort_chunked() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = ChunkedSorter::new(&device, subgroup_size);
+
+    let n = 100_000;
+    let sort_buffers = sorter.create_sort_buffers(
+        &device,
+        NonZeroU32::new(n).unwrap(),
+        NonZeroU32::new(30_000).unwrap(),
+    );
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys_scrambled: Vec<u32> = (0..n).map(|_| rng.gen()).collect();
+    let values_scrambled: Vec<u32> = (0..n).collect();
+    let mut pairs_sorted: Vec<(u32, u32)> = keys_scrambled
+        .iter()
+        .copied()
+        .zip(values_scrambled.iter().copied())
+        .collect();
+    pairs_sorted.sort_by_key(|(k, _)| *k);
+
+    sort_buffers.upload(&device, &queue, &keys_scrambled, &values_scrambled);
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURSSorter test_sort_chunked"),
+    });
+    sorter.sort(&mut encoder, &queue, &sort_buffers);
+    let idx = queue.submit([encoder.finish()]);
+    device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+
+    let mut pairs_sorted_gpu = Vec::with_capacity(n as usize);
+    for i in 0..sort_buffers.num_chunks() {
+        let keys = download_buffer::<u32>(sort_buffers.output_keys(i), &device, &queue, ..).await;
+        let values = download_buffer::<u32>(sort_buffers.output_values(i), &device, &queue, ..).await;
+        pairs_sorted_gpu.extend(keys.into_iter().zip(values));
+    }
+    assert_eq!(
+        pairs_sorted_gpu, pairs_sorted,
+        "GPU pairs equal to pairs sorted on CPU"
+    );
+}
+
+/// tests that buffers for chunks of the maximum chunk length stay within the device limits
+#[pollster::test]
+async fn chunked_max_chunk_len() {
+    let (device, _queue) = setup().await;
+    let sorter = ChunkedSorter::new(&device, 32);
+
+    let max_chunk_len = sorter.max_chunk_len(&device.limits());
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let sort_buffers = sorter.create_sort_buffers(
+        &device,
+        NonZeroU32::new(max_chunk_len).unwrap(),
+        NonZeroU32::new(max_chunk_len).unwrap(),
+    );
+    let error = device.pop_error_scope().await;
+    assert!(error.is_none(), "buffer creation failed: {:?}", error);
+    assert_eq!(sort_buffers.num_chunks(), 1);
+    assert_eq!(sort_buffers.chunk_len(), max_chunk_len);
+}
+
+// SEGMENTED SORTING
+
+/// tests sorting of small and large segments with many duplicate keys
+#[pollster::test]
+async fn sort_segmented() {
+    let n = 100_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    // segments right at the limit of the local sort
+    let mut segment_offsets = vec![0, LOCAL_SORT_KVS, 2 * LOCAL_SORT_KVS + 1];
+    while *segment_offsets.last().unwrap() < n {
+        let len = if rng.gen_bool(0.05) {
+            rng.gen_range(LOCAL_SORT_KVS + 1..20_000)
+        } else {
+            rng.gen_range(0..=LOCAL_SORT_KVS)
+        };
+        segment_offsets.push((segment_offsets.last().unwrap() + len).min(n));
+    }
+    test_segmented_sort(&segment_offsets, false).await;
+}
+
+/// tests sorting of segments that all fit into the local sort
+#[pollster::test]
+async fn sort_segmented_local() {
+    let n = 100_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut segment_offsets = vec![0, LOCAL_SORT_KVS];
+    while *segment_offsets.last().unwrap() < n {
+        let len = rng.gen_range(0..=LOCAL_SORT_KVS);
+        segment_offsets.push((segment_offsets.last().unwrap() + len).min(n));
+    }
+    test_segmented_sort(&segment_offsets, false).await;
+}
+
+/// tests that only the large segments go through the radix sort when small and large segments are mixed
+#[pollster::test]
+async fn sort_segmented_mixed() {
+    let lens = [
+        3,
+        LOCAL_SORT_KVS + 1,
+        LOCAL_SORT_KVS,
+        0,
+        5000,
+        700,
+        1,
+        3 * LOCAL_SORT_KVS,
+        LOCAL_SORT_KVS - 1,
+    ];
+    let mut segment_offsets = vec![0];
+    for len in lens {
+        segment_offsets.push(segment_offsets.last().unwrap() + len);
+    }
+    test_segmented_sort(&segment_offsets, false).await;
+}
+
+/// tests sorting of segments with a number of segments that is stored on the gpu
+#[pollster::test]
+async fn sort_segmented_indirect() {
+    // only small segments, then a single segment that is one pair too large for the local sort
+    test_segmented_sort(&[0, 17, LOCAL_SORT_KVS + 17, LOCAL_SORT_KVS + 500], true).await;
+    test_segmented_sort(&[0, 17, LOCAL_SORT_KVS + 18, LOCAL_SORT_KVS + 500], true).await;
+}
+
+/// sorts the segments described by `segment_offsets` and compares the result with a stable sort on the cpu
+async fn test_segmented_sort(segment_offsets: &[u32], indirect: bool) {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = SegmentedSorter::new(&device, subgroup_size);
+
+    let n = *segment_offsets.last().unwrap();
+    let sort_buffers = sorter
+        .sorter()
+        .create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+
+    let num_segments = segment_offsets.len() as u32 - 1;
+    let offsets_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("segment offsets buffer"),
+        contents: bytemuck::cast_slice(segment_offsets),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let segment_buffers = sorter.create_segmented_buffers(&device, &sort_buffers, &offsets_buffer);
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let keys_scrambled: Vec<u32> = (0..n).map(|_| rng.gen_range(0..100)).collect();
+    let values_scrambled: Vec<u32> = (0..n).collect();
+    let mut keys_sorted = keys_scrambled.clone();
+    let mut values_sorted = values_scrambled.clone();
+    for segment in segment_offsets.windows(2) {
+        let range = segment[0] as usize..segment[1] as usize;
+        // sort_by_key is stable
+        let mut pairs: Vec<(u32, u32)> = keys_scrambled[range.clone()]
+            .iter()
+            .copied()
+            .zip(values_scrambled[range.clone()].iter().copied())
+            .collect();
+        pairs.sort_by_key(|(k, _)| *k);
+        for (i, (k, v)) in range.zip(pairs) {
+            keys_sorted[i] = k;
+            values_sorted[i] = v;
+        }
+    }
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURSSorter test_sort_segmented"),
+    });
+    upload_to_buffer(&mut encoder, sort_buffers.keys(), &device, keys_scrambled.as_slice());
+    upload_to_buffer(&mut encoder, sort_buffers.values(), &device, values_scrambled.as_slice());
+    if indirect {
+        queue.write_buffer(segment_buffers.state_buffer(), 0, bytes_of(&num_segments));
+        sorter.sort_indirect(&mut encoder, &segment_buffers);
+    } else {
+        sorter.sort(&mut encoder, &queue, &segment_buffers, num_segments);
+    }
+    let idx = queue.submit([encoder.finish()]);
+    device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+
+    let keys_sorted_gpu = download_buffer::<u32>(
+        sort_buffers.keys(),
+        &device,
+        &queue,
+        0..sort_buffers.keys_valid_size(),
+    )
+    .await;
+    assert_eq!(keys_sorted_gpu, keys_sorted, "GPU keys equal to keys sorted on CPU");
+
+    let values_sorted_gpu = download_buffer::<u32>(sort_buffers.values(), &device, &queue, ..).await;
+    assert_eq!(values_sorted_gpu, values_sorted, "GPU values equal to values sorted on CPU");
+
+    // the small segments are sorted locally, only the pairs of the large segments are radix sorted
+    let large_lens: Vec<u32> = segment_offsets
+        .windows(2)
+        .map(|segment| segment[1] - segment[0])
+        .filter(|len| *len > LOCAL_SORT_KVS)
+        .collect();
+    let state = segment_buffers.read_state(&device, &queue).await;
+    assert_eq!(state.large_count, large_lens.len() as u32, "number of large segments");
+    assert_eq!(state.large_pairs, large_lens.iter().sum::<u32>(), "number of radix sorted pairs");
+}
+
+// CPU SORTING
+
+/// tests the cpu sorter against the std sort, including partial and batched sorting
+#[test]
+fn sort_cpu() {
+    let sorter = CPUSorter::new();
+    let n = 10_000;
+    let mut sort_buffers = sorter.create_sort_buffers(NonZeroU32::new(n).unwrap());
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys_scrambled: Vec<u32> = (0..n).map(|_| rng.gen_range(0..100)).collect();
+    let values_scrambled: Vec<u32> = (0..n).collect();
+
+    for (sort_first_n, batch) in [(None, 1), (Some(5000), 1), (Some(100), 1), (None, 8)] {
+        sort_buffers.keys_mut()[..n as usize].copy_from_slice(&keys_scrambled);
+        sort_buffers.values_mut().copy_from_slice(&values_scrambled);
+        let row_len = sort_first_n.unwrap_or(n / batch) as usize;
+
+        let mut pairs: Vec<(u32, u32)> =
+            keys_scrambled.iter().copied().zip(values_scrambled.iter().copied()).collect();
+        for row in pairs[..row_len * batch as usize].chunks_mut(row_len) {
+            row.sort_by_key(|(k, _)| *k);
+        }
+        if batch > 1 {
+            sorter.sort_batched(&mut sort_buffers, batch, row_len as u32);
+        } else {
+            sorter.sort(&mut sort_buffers, sort_first_n);
+        }
+
+        let keys_sorted: Vec<u32> = pairs.iter().map(|(k, _)| *k).collect();
+        let values_sorted: Vec<u32> = pairs.iter().map(|(_, v)| *v).collect();
+        let sorted_len = row_len * batch as usize;
+        assert_eq!(sort_buffers.keys()[..sorted_len], keys_sorted[..sorted_len]);
+        assert_eq!(sort_buffers.values(), values_sorted, "sort is stable");
+        if sort_first_n == Some(5000) {
+            assert!(sort_buffers.keys()[sorted_len..].iter().all(|k| *k == u32::MAX), "padding is filled with max keys");
+        } else if sort_first_n == Some(100) {
+            assert_eq!(sort_buffers.keys()[sorted_len..n as usize], keys_scrambled[sorted_len..], "small sort leaves the rest untouched");
+        }
+    }
+}
+
+/// tests the cpu sorter with typed keys and values against the std sort
+#[test]
+fn sort_cpu_typed() {
+    let sorter = CPUSorter::new();
+    let n = 10_000;
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let keys: Vec<f32> = (0..n).map(|_| rng.gen_range(-100..100) as f32 * 0.5).collect();
+    let values: Vec<u32> = (0..n).collect();
+    let mut sort_buffers = sorter.create_typed_sort_buffers::<f32, u32>(NonZeroU32::new(n).unwrap());
+    sort_buffers.keys_mut()[..n as usize].copy_from_slice(&keys);
+    sort_buffers.values_mut().copy_from_slice(&values);
+    sorter.sort(&mut sort_buffers, None);
+    let mut pairs: Vec<(f32, u32)> = keys.iter().copied().zip(values.iter().copied()).collect();
+    pairs.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    assert_eq!(sort_buffers.keys()[..n as usize], pairs.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+    assert_eq!(sort_buffers.values(), pairs.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+
+    let keys: Vec<u64> = (0..n).map(|_| rng.gen_range(0..100) << 32 | rng.gen_range(0..100)).collect();
+    let values: Vec<[u32; 2]> = (0..n).map(|i| [i, n - i]).collect();
+    let mut sort_buffers = sorter.create_typed_sort_buffers::<u64, [u32; 2]>(NonZeroU32::new(n).unwrap());
+    assert_eq!(sort_buffers.keys().len(), n as usize, "keys sorted by index have no padding");
+    sort_buffers.keys_mut().copy_from_slice(&keys);
+    sort_buffers.values_mut().copy_from_slice(&values);
+    sorter.sort(&mut sort_buffers, None);
+    let mut pairs: Vec<(u64, [u32; 2])> = keys.iter().copied().zip(values.iter().copied()).collect();
+    pairs.sort_by_key(|(k, _)| *k);
+    assert_eq!(sort_buffers.keys(), pairs.iter().map(|(k, _)| *k).collect::<Vec<_>>());
+    assert_eq!(sort_buffers.values(), pairs.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+}
+
+/// tests that gpu and cpu sorter produce identical buffers
+#[pollster::test]
+async fn sort_cpu_reference() {
+    let (device, queue) = setup().await;
+    let gpu_sorter = AutoSorter::from_device(device, queue).await;
+    assert!(gpu_sorter.is_gpu());
+    let cpu_sorter = AutoSorter::cpu();
+
+    let n = 100_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys: Vec<u32> = (0..n).map(|_| rng.gen_range(0..1000)).collect();
+    let values: Vec<u32> = (0..n).collect();
+
+    let mut results = Vec::new();
+    for sorter in [&gpu_sorter, &cpu_sorter] {
+        let mut sort_buffers = sorter.create_sort_buffers(NonZeroU32::new(n).unwrap());
+        sorter.write(&mut sort_buffers, &keys, &values);
+        sorter.sort(&mut sort_buffers, Some(n / 2));
+        results.push((
+            sorter.read_keys(&sort_buffers).await,
+            sorter.read_values(&sort_buffers).await,
+        ));
+    }
+    assert_eq!(results[0].0, results[1].0, "GPU keys equal to CPU sorter keys");
+    assert_eq!(results[0].1, results[1].1, "GPU values equal to CPU sorter values");
+}
+
+/// tests that the indirect sort of few pairs leaves the whole keys buffer, including the padding,
+/// in the same state on the gpu and on the cpu
+#[pollster::test]
+async fn sort_indirect_cpu_reference() {
+    let (device, queue) = setup().await;
+    let gpu_sorter = AutoSorter::from_device(device, queue).await;
+    assert!(gpu_sorter.is_gpu());
+    let cpu_sorter = AutoSorter::cpu();
+
+    let n = 10_000;
+    let num_keys = LOCAL_SORT_KVS / 2;
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys: Vec<u32> = (0..n).map(|_| rng.gen_range(0..1000)).collect();
+    let values: Vec<u32> = (0..n).collect();
+
+    let AutoSorter::GPU { device, queue, .. } = &gpu_sorter else {
+        unreachable!()
+    };
+    let mut results = Vec::new();
+    for sorter in [&gpu_sorter, &cpu_sorter] {
+        let mut sort_buffers = sorter.create_sort_buffers(NonZeroU32::new(n).unwrap());
+        sorter.write(&mut sort_buffers, &keys, &values);
+        sorter.write_num_keys(&mut sort_buffers, num_keys);
+        sorter.sort_indirect(&mut sort_buffers);
+        let values_sorted = sorter.read_values(&sort_buffers).await;
+        results.push((sort_buffers, values_sorted));
+    }
+    let (AutoSortBuffers::GPU { buffers: gpu_buffers, .. }, AutoSortBuffers::CPU(cpu_buffers)) = (&results[0].0, &results[1].0) else {
+        panic!("sort buffers were created by the wrong backend");
+    };
+    let cpu_keys = cpu_buffers.keys();
+    let gpu_keys: Vec<u32> = download_buffer(gpu_buffers.keys(), device, queue, 0..cpu_keys.len() as u64 * 4).await;
+    assert_eq!(gpu_keys, cpu_keys, "GPU keys buffer equal to CPU sorter keys buffer");
+    assert!(cpu_keys[num_keys as usize..].iter().all(|&k| k == u32::MAX), "keys after the sorted pairs are padded");
+    assert_eq!(results[0].1, results[1].1, "GPU values equal to CPU sorter values");
+}
+
+// SIMULATOR
+
+/// tests that the simulated radix sort sorts like the cpu sorter
+#[test]
+fn simulate_sort() {
+    let n = 10_000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys_scrambled: Vec<u32> = (0..n).map(|_| rng.gen()).collect();
+    let values_scrambled: Vec<u32> = (0..n).collect();
+
+    for sort_first_n in [None, Some(5000)] {
+        let mut simulator = RadixSortSimulator::new(NonZeroU32::new(n).unwrap(), SortLayout::DEFAULT);
+        simulator.keys_a[..n as usize].copy_from_slice(&keys_scrambled);
+        simulator.payload_a.copy_from_slice(&values_scrambled);
+        simulator.sort(sort_first_n);
+
+        let sorter = CPUSorter::new();
+        let mut sort_buffers = sorter.create_sort_buffers(NonZeroU32::new(n).unwrap());
+        sort_buffers.keys_mut()[..n as usize].copy_from_slice(&keys_scrambled);
+        sort_buffers.values_mut().copy_from_slice(&values_scrambled);
+        sorter.sort(&mut sort_buffers, sort_first_n);
+
+        assert_eq!(simulator.keys_a[..sort_buffers.keys().len()], *sort_buffers.keys());
+        assert_eq!(simulator.payload_a, sort_buffers.values());
+        // every digit histogram of the padded keys ends with the total number of keys
+        let padded = (simulator.state.num_keys as usize).div_ceil(HISTO_BLOCK_KVS as usize) * HISTO_BLOCK_KVS as usize;
+        for pass in 0..4 {
+            let last = simulator.histogram_offset(pass) + 255;
+            let count = simulator.keys_a[..padded].iter().filter(|k| (*k >> (pass * 8)) & 0xFF == 0xFF).count();
+            assert_eq!(simulator.internal_mem[last] as usize + count, padded);
+        }
+    }
+}
+
+/// tests that the gpu buffers are byte-identical to the simulated buffers after every phase,
+/// for the default layout and for fewer block rows with 4 bit digits
+#[pollster::test]
+async fn simulate_sort_gpu() {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorters = [
+        GPUSorter::new(&device, subgroup_size),
+        GPUSorter::from_config(&device, &SorterConfig::new(subgroup_size, 0, 8, 4)),
+    ];
+
+    let n = 100_000;
+    let num_keys = n - 1000;
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys_scrambled: Vec<u32> = (0..n).map(|_| rng.gen()).collect();
+    let values_scrambled: Vec<u32> = (0..n).collect();
+
+    for sorter in &sorters {
+        let layout = sorter.layout();
+        let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+        let mut simulator = RadixSortSimulator::new(NonZeroU32::new(n).unwrap(), layout);
+        simulator.keys_a[..n as usize].copy_from_slice(&keys_scrambled);
+        simulator.payload_a.copy_from_slice(&values_scrambled);
+        simulator.state.num_keys = num_keys;
 
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("GPURSSorter simulate_sort_gpu upload"),
+        });
+        upload_to_buffer(&mut encoder, sort_buffers.keys(), &device, keys_scrambled.as_slice());
+        upload_to_buffer(&mut encoder, sort_buffers.values(), &device, values_scrambled.as_slice());
+        queue.write_buffer(sort_buffers.state_buffer(), 0, bytes_of(&num_keys));
+        queue.submit([encoder.finish()]);
+
+        let phases = ["zero histograms".to_string(), "calculate histogram".to_string(), "prefix histogram".to_string()]
+            .into_iter()
+            .chain((0..sorter.num_passes()).map(|pass| format!("scatter {pass}")));
+        for (i, phase) in phases.enumerate() {
+            let phase = format!("{phase} ({layout:?})");
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("GPURSSorter simulate_sort_gpu"),
+            });
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some(&phase),
+                    timestamp_writes: None,
+                });
+                match i {
+                    0 => sorter.record_zero_histograms(&mut pass, &sort_buffers, num_keys),
+                    1 => sorter.record_calculate_histogram(&mut pass, &sort_buffers, num_keys),
+                    2 => sorter.record_prefix_histogram(&mut pass, &sort_buffers),
+                    _ => sorter.record_scatter_pass(&mut pass, &sort_buffers, i as u32 - 3, num_keys),
+                }
+            }
+            let idx = queue.submit([encoder.finish()]);
+            device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+            match i {
+                0 => simulator.zero_histograms(),
+                1 => simulator.calculate_histogram(),
+                2 => simulator.prefix_histogram(),
+                _ if i % 2 == 1 => simulator.scatter_even(),
+                _ => simulator.scatter_odd(),
+            }
+
+            let state = download_buffer::<u8>(sort_buffers.state_buffer(), &device, &queue, ..).await;
+            assert_eq!(state, bytes_of(&simulator.state), "GPU state equal to simulated state after {phase}");
+            let internal_mem = download_buffer::<u32>(sort_buffers.internal_mem_buffer(), &device, &queue, ..).await;
+            assert_eq!(internal_mem, simulator.internal_mem, "GPU histograms and partitions equal to simulated ones after {phase}");
+            let keys = download_buffer::<u32>(sort_buffers.keys(), &device, &queue, ..).await;
+            assert_eq!(keys, simulator.keys_a, "GPU keys equal to simulated keys after {phase}");
+            let values = download_buffer::<u32>(sort_buffers.values(), &device, &queue, ..).await;
+            assert_eq!(values, simulator.payload_a, "GPU values equal to simulated values after {phase}");
+            // the even passes scatter into the auxiliary buffers, the odd passes back into keys and values
+            let keys_b = download_buffer::<u32>(sort_buffers.keys_b(), &device, &queue, ..).await;
+            assert_eq!(keys_b, simulator.keys_b, "GPU auxiliary keys equal to simulated ones after {phase}");
+            let payload_b = download_buffer::<u32>(sort_buffers.payload_b(), &device, &queue, ..).await;
+            assert_eq!(payload_b, simulator.payload_b, "GPU auxiliary values equal to simulated ones after {phase}");
+        }
+        // the simulated sort sorts like the cpu
+        let mut sorted = keys_scrambled[..num_keys as usize].to_vec();
+        sorted.sort();
+        assert_eq!(simulator.keys_a[..num_keys as usize], sorted, "simulated keys sorted ({layout:?})");
+    }
+}
+
+
+/// subgroup size of the test adapter, guessed by the first test that needs it and shared with all other tests
+async fn subgroup_size(device: &wgpu::Device, queue: &wgpu::Queue) -> u32 {
+    static SUBGROUP_SIZE: OnceLock<u32> = OnceLock::new();
+    if let Some(subgroup_size) = SUBGROUP_SIZE.get() {
+        return *subgroup_size;
+    }
+    let subgroup_size = guess_workgroup_size_quick(device, queue).await.best().expect("no working subgroup size");
+    *SUBGROUP_SIZE.get_or_init(|| subgroup_size)
+}
 
 async fn setup() -> (wgpu::Device, wgpu::Queue) {
     let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
@@ -120,15 +1851,46 @@ fn apply_sort_indirect(encoder:&mut wgpu::CommandEncoder,device:&wgpu::Device,qu
     sorter.sort_indirect(encoder, &sort_buffers,&dispatch_buffer);
 }
 
+/// applies gpu sort recorded into a single compute pass
+fn apply_sort_into_pass(encoder:&mut wgpu::CommandEncoder,_device:&wgpu::Device,queue:&wgpu::Queue,sorter:&GPUSorter,sort_buffers:&SortBuffers,n:Option<u32>){
+    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+        label: Some("sort into pass"),
+        timestamp_writes: None,
+    });
+    sorter.sort_into_pass(&mut pass, queue, sort_buffers, n);
+}
+
+/// applies gpu sort with indirect dispatch recorded into a single compute pass
+fn apply_sort_indirect_into_pass(encoder:&mut wgpu::CommandEncoder,device:&wgpu::Device,queue:&wgpu::Queue,sorter:&GPUSorter,sort_buffers:&SortBuffers,n:Option<u32>){
+    let nelm = n.unwrap_or(sort_buffers.len());
+    let dispatch_indirect = wgpu::util::DispatchIndirectArgs{
+        x: nelm.div_ceil(HISTO_BLOCK_KVS),
+        y: 1,
+        z: 1
+    };
+    queue.write_buffer(sort_buffers.state_buffer(), 0, bytes_of(&nelm));
+
+    let dispatch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+        label: Some("dispatch indirect buffer"),
+        contents: dispatch_indirect.as_bytes(),
+        usage: wgpu::BufferUsages::INDIRECT,
+    });
+
+    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+        label: Some("sort indirect into pass"),
+        timestamp_writes: None,
+    });
+    sorter.sort_indirect_into_pass(&mut pass, sort_buffers, &dispatch_buffer);
+}
+
 async fn test_sort<T>(n: u32,sort_fn:&SortFn,sort_first_n:Option<u32>)
 where
     Standard: Distribution<T>,
     T: PartialEq + Clone + Copy + Debug + bytemuck::Pod + Ord
 {
     let (device, queue) = setup().await;
-    let subgroup_size = guess_workgroup_size(&device, &queue).await;
-    assert_ne!(subgroup_size, None);
-    let sorter = GPUSorter::new(&device, subgroup_size.unwrap());
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
 
     let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
     let n_sorted = sort_first_n.unwrap_or(sort_buffers.len());
@@ -186,6 +1948,73 @@ where
 }
 
 
+/// sorts keys with many duplicates and the original positions as values,
+/// equal keys have to keep the order of their values
+async fn test_sort_stable(n: u32, sort_fn: &SortFn, sort_first_n: Option<u32>) {
+    let (device, queue) = setup().await;
+    let subgroup_size = subgroup_size(&device, &queue).await;
+    let sorter = GPUSorter::new(&device, subgroup_size);
+
+    let sort_buffers = sorter.create_sort_buffers(&device, NonZeroU32::new(n).unwrap());
+    let n_sorted = sort_first_n.unwrap_or(sort_buffers.len()) as usize;
+
+    // only 16 different keys, most of them spread over the highest byte
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys_scrambled: Vec<u32> = (0..n).map(|_| rng.gen_range(0..16u32).rotate_right(4)).collect();
+    let values_scrambled: Vec<u32> = (0..n).collect();
+    let mut pairs: Vec<(u32, u32)> = keys_scrambled.iter().copied().zip(values_scrambled.iter().copied()).collect();
+    pairs[..n_sorted].sort_by_key(|(k, _)| *k);
+
+    sort_buffers.write_keys(&queue, &keys_scrambled);
+    sort_buffers.write_values(&queue, &values_scrambled);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURSSorter test_sort_stable"),
+    });
+    sort_fn(&mut encoder, &device, &queue, &sorter, &sort_buffers, sort_first_n);
+    queue.submit([encoder.finish()]);
+
+    let keys_sorted_gpu = sort_buffers.read_keys(&device, &queue).await;
+    let values_sorted_gpu = sort_buffers.read_values(&device, &queue).await;
+    let expected_keys: Vec<u32> = pairs[..n_sorted].iter().map(|(k, _)| *k).collect();
+    let expected_values: Vec<u32> = pairs[..n_sorted].iter().map(|(_, v)| *v).collect();
+    assert_eq!(keys_sorted_gpu[..n_sorted], expected_keys, "GPU keys equal to keys sorted on CPU");
+    assert_eq!(
+        values_sorted_gpu[..n_sorted], expected_values,
+        "equal keys keep the order of their values"
+    );
+}
+
+/// sorts the typed pairs on the gpu and compares them with a stable sort on the cpu
+async fn test_sort_typed<K, V>(
+    sorter: &GPUSorter,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    keys: &[K],
+    values: &[V],
+    cmp: impl Fn(&K, &K) -> std::cmp::Ordering,
+) where
+    K: SortKey + PartialEq + Debug,
+    V: SortValue + PartialEq + Debug,
+{
+    let sort_buffers = sorter.create_typed_sort_buffers::<K, V>(device, NonZeroU32::new(keys.len() as u32).unwrap());
+    sort_buffers.write_keys(queue, keys);
+    sort_buffers.write_values(queue, values);
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("GPURSSorter test_sort_typed"),
+    });
+    sorter.sort(&mut encoder, queue, &sort_buffers, None);
+    let idx = queue.submit([encoder.finish()]);
+    device.poll(wgpu::Maintain::WaitForSubmissionIndex(idx));
+
+    let mut pairs: Vec<(K, V)> = keys.iter().copied().zip(values.iter().copied()).collect();
+    pairs.sort_by(|(a, _), (b, _)| cmp(a, b));
+    let keys_sorted_gpu = sort_buffers.read_keys(device, queue).await;
+    assert_eq!(keys_sorted_gpu, pairs.iter().map(|(k, _)| *k).collect::<Vec<_>>(), "GPU keys equal to keys sorted on CPU");
+    let values_sorted_gpu = sort_buffers.read_values(device, queue).await;
+    assert_eq!(values_sorted_gpu, pairs.iter().map(|(_, v)| *v).collect::<Vec<_>>(), "GPU values equal to values sorted on CPU");
+}
+
 // ordered float
 #[repr(C)]
 #[derive(PartialEq,Debug,Clone, Copy,bytemuck::Pod,bytemuck::Zeroable)]